@@ -0,0 +1,67 @@
+use crate::logfile::JsonLog;
+use nostr_sdk::Timestamp;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// Log of HTTP requests served
+#[derive(Clone, Debug)]
+pub struct AccessLog(JsonLog);
+
+#[derive(Serialize)]
+struct Access<'a> {
+    timestamp: u64,
+    ip: String,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    bytes: u64,
+    duration_ms: u64,
+}
+
+/// Request in flight, logged once the response body is done
+pub struct AccessEntry {
+    log: AccessLog,
+    start: Instant,
+    remote: SocketAddr,
+    method: String,
+    path: String,
+    status: u16,
+}
+
+impl AccessLog {
+    pub fn spawn(path: PathBuf, max_size: u64) -> Self {
+        Self(JsonLog::spawn("Access log", path, max_size))
+    }
+
+    pub fn start(&self, remote: SocketAddr, method: &str, path: &str) -> AccessEntry {
+        AccessEntry {
+            log: self.clone(),
+            start: Instant::now(),
+            remote,
+            method: method.to_string(),
+            path: path.to_string(),
+            status: 0,
+        }
+    }
+}
+
+impl AccessEntry {
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    /// Write the entry with the number of body bytes actually sent
+    pub fn finish(self, bytes: u64) {
+        self.log.0.write(&Access {
+            timestamp: Timestamp::now().as_secs(),
+            ip: self.remote.ip().to_string(),
+            method: &self.method,
+            path: &self.path,
+            status: self.status,
+            bytes,
+            duration_ms: self.start.elapsed().as_millis() as u64,
+        });
+    }
+}
@@ -0,0 +1,231 @@
+use crate::db::{ArchiveDatabase, is_active, open_archive};
+use crate::rollup::{ZapStats, read_rollup};
+use crate::sanity::LimitCounts;
+use crate::seen::SeenTotals;
+use crate::summary::{ArchiveSummary, read_summary};
+use anyhow::Result;
+use log::warn;
+use nostr_archive_cursor::ArchiveFile;
+use nostr_sdk::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const DAY: u64 = 86_400;
+
+/// Default and maximum length of the per-day series
+pub const DEFAULT_DAYS: u64 = 30;
+pub const MAX_DAYS: u64 = 366;
+
+/// Per kind event counts of one archive file
+struct FileCounts {
+    /// Size when counted, the active file is recounted as it grows
+    size: u64,
+    kinds: HashMap<u16, u64>,
+    /// Written for finalized archives, counts are taken from it
+    summary: Option<ArchiveSummary>,
+}
+
+/// Event counts derived from the archive files, each file is counted once and cached
+#[derive(Clone, Default)]
+pub struct ActivityStats {
+    files: Arc<Mutex<HashMap<PathBuf, Arc<FileCounts>>>>,
+}
+
+#[derive(Serialize)]
+pub struct Activity {
+    pub total_events: u64,
+    pub total_bytes: u64,
+    /// Events per kind over the requested days
+    pub kinds: BTreeMap<u16, u64>,
+    /// Events per UTC day, oldest first
+    pub days: Vec<DayCount>,
+    /// Totals of the archives in the requested days that have a summary
+    pub archives: Vec<ArchiveTotals>,
+    /// Events kept out of the archive by `event_limits`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<LimitCounts>,
+    /// Estimated unique pubkeys of every archived event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique_authors: Option<u64>,
+    /// Deliveries of already archived events, needs `track_seen`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rebroadcasts: Option<SeenTotals>,
+    /// Archived events of the `?author=` pubkey, needs `index_authors`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author_events: Option<u64>,
+    /// Reactions and zaps of the archives in the requested days that have a rollup,
+    /// needs `zap_rollups`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zaps: Option<ZapStats>,
+}
+
+#[derive(Serialize)]
+pub struct ArchiveTotals {
+    pub name: String,
+    pub events: u64,
+    /// Unique pubkeys
+    pub authors: u64,
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct DayCount {
+    pub date: String,
+    pub events: u64,
+}
+
+#[derive(Deserialize)]
+struct KindOnly {
+    kind: u16,
+}
+
+impl ActivityStats {
+    /// Counts for the last `days` days, the series only includes `kind` when set
+    pub async fn get(
+        &self,
+        db: &ArchiveDatabase,
+        days: u64,
+        kind: Option<u16>,
+    ) -> Result<Activity> {
+        let days = days.clamp(1, MAX_DAYS);
+        let files = db.list_archives().await?;
+        let today = Timestamp::now().as_secs() / DAY;
+        let first = today.saturating_sub(days - 1);
+
+        let mut cache = self.files.lock().await;
+        cache.retain(|p, _| files.iter().any(|f| &f.path == p));
+
+        let mut series = vec![0u64; days as usize];
+        let mut kinds = BTreeMap::new();
+        let mut archives = Vec::new();
+        let mut zaps: Option<ZapStats> = None;
+        for f in files.iter() {
+            // archives are written one file per day
+            let day = f.timestamp.timestamp().max(0) as u64 / DAY;
+            if day < first || day > today {
+                continue;
+            }
+            if !is_active(&f.path)
+                && let Some(r) = read_rollup(&f.path).await
+            {
+                zaps.get_or_insert_default()
+                    .add(f.timestamp.format("%Y-%m-%d").to_string(), &r);
+            }
+            let counts = file_counts(&mut cache, f).await?;
+            if let Some(s) = &counts.summary {
+                archives.push(ArchiveTotals {
+                    name: db.archive_name(&f.path),
+                    events: s.events,
+                    authors: s.authors,
+                    first: s.first,
+                    last: s.last,
+                });
+            }
+            for (k, n) in &counts.kinds {
+                *kinds.entry(*k).or_default() += n;
+                if kind.is_none_or(|x| x == *k) {
+                    series[(day - first) as usize] += n;
+                }
+            }
+        }
+
+        Ok(Activity {
+            total_events: db.count_keys()?,
+            total_bytes: files.iter().fold(0u64, |acc, f| acc + f.size),
+            kinds,
+            days: series
+                .into_iter()
+                .enumerate()
+                .map(|(i, events)| DayCount {
+                    date: chrono::DateTime::from_timestamp(((first + i as u64) * DAY) as i64, 0)
+                        .map(|d| d.format("%Y-%m-%d").to_string())
+                        .unwrap_or_default(),
+                    events,
+                })
+                .collect(),
+            archives,
+            limits: db.limits().map(|l| l.counts()),
+            unique_authors: db.unique_authors(),
+            rebroadcasts: db.seen_totals(),
+            author_events: None,
+            zaps,
+        })
+    }
+
+    /// Archived events of `kinds` across every archive, for NIP-45 COUNT
+    pub async fn kind_total(&self, db: &ArchiveDatabase, kinds: &HashSet<u16>) -> Result<u64> {
+        let files = db.list_archives().await?;
+        let mut cache = self.files.lock().await;
+        cache.retain(|p, _| files.iter().any(|f| &f.path == p));
+        let mut total = 0;
+        for f in files.iter() {
+            let counts = file_counts(&mut cache, f).await?;
+            total += kinds
+                .iter()
+                .filter_map(|k| counts.kinds.get(k))
+                .sum::<u64>();
+        }
+        Ok(total)
+    }
+}
+
+/// Counts of `f`, from the cache while its size is unchanged
+async fn file_counts(
+    cache: &mut HashMap<PathBuf, Arc<FileCounts>>,
+    f: &ArchiveFile,
+) -> Result<Arc<FileCounts>> {
+    let counts = match cache.get(&f.path) {
+        Some(c) if c.size == f.size && c.summary.is_some() => c.clone(),
+        // a summary may have been written since the file was counted
+        _ if !is_active(&f.path)
+            && let Some(s) = read_summary(&f.path).await =>
+        {
+            let c = Arc::new(FileCounts {
+                size: f.size,
+                kinds: s.kinds.iter().map(|(k, n)| (*k, *n)).collect(),
+                summary: Some(s),
+            });
+            cache.insert(f.path.clone(), c.clone());
+            c
+        }
+        Some(c) if c.size == f.size => c.clone(),
+        _ => {
+            let (path, size) = (f.path.clone(), f.size);
+            let c = match tokio::task::spawn_blocking(move || count_file(&path, size)).await? {
+                Ok(c) => Arc::new(c),
+                Err(e) => {
+                    // cached as empty so a broken file isn't rescanned on every request
+                    warn!("Failed to count events in {}: {}", f.path.display(), e);
+                    Arc::new(FileCounts {
+                        size: f.size,
+                        kinds: HashMap::new(),
+                        summary: None,
+                    })
+                }
+            };
+            cache.insert(f.path.clone(), c.clone());
+            c
+        }
+    };
+    Ok(counts)
+}
+
+fn count_file(path: &Path, size: u64) -> Result<FileCounts> {
+    let mut kinds = HashMap::new();
+    for line in open_archive(path)?.split(b'\n') {
+        // the active file may end in a partial line
+        if let Ok(e) = serde_json::from_slice::<KindOnly>(&line?) {
+            *kinds.entry(e.kind).or_default() += 1;
+        }
+    }
+    Ok(FileCounts {
+        size,
+        kinds,
+        summary: None,
+    })
+}
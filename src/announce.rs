@@ -0,0 +1,126 @@
+use crate::db::ArchiveDatabase;
+use crate::kinds::KindSet;
+use crate::publish::Publisher;
+use anyhow::{Result, bail};
+use log::{error, info};
+use nostr_sdk::{EventBuilder, Kind, RelayUrl, Tag};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use url::Url;
+
+/// Relay monitor announcement (NIP-66), how often this relay describes itself
+pub const MONITOR_KIND: Kind = Kind::Custom(10166);
+
+/// Relay discovery (NIP-66), `d` tag is the relay url
+pub const DISCOVERY_KIND: Kind = Kind::Custom(30166);
+
+#[derive(Deserialize, Clone, Default)]
+pub struct AnnounceSettings {
+    /// Publish relay discovery events signed by `relay_secret_key`
+    pub enabled: Option<bool>,
+
+    /// Hours between discovery events, default 1
+    pub interval_hours: Option<u64>,
+
+    /// Relays to publish to, defaults to `relays`
+    pub relays: Option<Vec<String>>,
+}
+
+/// What this relay says about itself
+pub struct SelfDescription {
+    pub url: RelayUrl,
+    pub name: String,
+    pub description: String,
+    /// Archived kinds, None for all
+    pub kinds: Option<KindSet>,
+}
+
+/// Content of the discovery event, NIP-11 fields and archive totals
+#[derive(Serialize)]
+struct DiscoveryContent<'a> {
+    name: &'a str,
+    description: &'a str,
+    software: &'static str,
+    version: &'static str,
+    archive_bytes: u64,
+    archives: usize,
+    events: u64,
+}
+
+/// Websocket url of the relay served at `public_url`
+pub fn relay_url(public_url: &str) -> Result<RelayUrl> {
+    let mut url = Url::parse(public_url)?;
+    let scheme = match url.scheme() {
+        "https" | "wss" => "wss",
+        "http" | "ws" => "ws",
+        s => bail!("public_url {} has unsupported scheme {}", public_url, s),
+    };
+    if url.set_scheme(scheme).is_err() {
+        bail!("public_url {} can't be a websocket url", public_url);
+    }
+    Ok(RelayUrl::parse(url.as_str())?)
+}
+
+/// Publish the monitor announcement once, then a discovery event every `interval_hours`
+pub async fn run_announce(
+    db: ArchiveDatabase,
+    publisher: Publisher,
+    info: SelfDescription,
+    settings: AnnounceSettings,
+) -> Result<()> {
+    let interval = Duration::from_secs(settings.interval_hours.unwrap_or(1).max(1) * 60 * 60);
+    match publisher.sign(EventBuilder::new(MONITOR_KIND, "").tags([
+        Tag::parse(["frequency", &interval.as_secs().to_string()])?,
+        Tag::parse(["k", &DISCOVERY_KIND.as_u16().to_string()])?,
+    ])) {
+        Ok(ev) => {
+            publisher.send(&ev).await;
+        }
+        Err(e) => error!("Failed to sign relay announcement: {}", e),
+    }
+    loop {
+        match discovery(&db, &publisher, &info).await {
+            Ok(true) => info!("Published relay discovery for {}", info.url),
+            Ok(false) => {}
+            Err(e) => error!("Failed to build relay discovery: {}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Sign and send the discovery event, returns whether any relay accepted it
+async fn discovery(
+    db: &ArchiveDatabase,
+    publisher: &Publisher,
+    info: &SelfDescription,
+) -> Result<bool> {
+    let files = db.list_archives().await?;
+    let content = DiscoveryContent {
+        name: &info.name,
+        description: &info.description,
+        software: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        archive_bytes: files.iter().map(|f| f.size).sum(),
+        archives: files.len(),
+        events: db.count_keys()?,
+    };
+    let network = if info.url.is_onion() {
+        "tor"
+    } else {
+        "clearnet"
+    };
+    let mut tags = vec![
+        Tag::identifier(info.url.as_str_without_trailing_slash()),
+        Tag::parse(["n", network])?,
+        Tag::parse(["T", "Archival"])?,
+    ];
+    // ranges too large to list are left out, like in upstream filters
+    if let Some(kinds) = info.kinds.as_ref().and_then(|k| k.filter_kinds()) {
+        for k in kinds {
+            tags.push(Tag::parse(["k", &k.as_u16().to_string()])?);
+        }
+    }
+    let ev = publisher
+        .sign(EventBuilder::new(DISCOVERY_KIND, serde_json::to_string(&content)?).tags(tags))?;
+    Ok(publisher.send(&ev).await)
+}
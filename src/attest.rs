@@ -0,0 +1,226 @@
+use crate::db::{
+    ArchiveDatabase, decode_archive_strict, is_active, is_archive, sha256_file_blocking,
+};
+use crate::jobs::{Scanned, scan_files};
+use crate::layout::{flat_name, is_date_dir};
+use crate::publish::Publisher;
+use crate::scan::scan_lines;
+use crate::upstream::Upstream;
+use anyhow::{Result, anyhow};
+use log::{error, info};
+use nostr_sdk::prelude::{JsonUtil, NostrDatabase};
+use nostr_sdk::{Event, EventBuilder, Filter, Kind, PublicKey, Tag};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Addressable application data, `d` tag is the archive file name
+pub const ATTESTATION_KIND: Kind = Kind::Custom(30078);
+
+/// How often finalized archives are checked for missing attestations
+const ATTEST_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Signed statement of an archive's contents
+#[derive(Serialize, Deserialize)]
+pub struct Attestation {
+    pub sha256: String,
+    pub size: u64,
+    pub events: u64,
+    /// Oldest and newest created_at in the file
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct CreatedAt {
+    created_at: u64,
+}
+
+/// Event count and created_at range of an archive, read on a blocking thread
+async fn scan(path: PathBuf) -> Result<(u64, Option<u64>, Option<u64>)> {
+    tokio::task::spawn_blocking(move || {
+        let (mut first, mut last) = (None::<u64>, None::<u64>);
+        let input = decode_archive_strict(&path, std::fs::File::open(&path)?)?;
+        let scan = scan_lines(&path, input, |line| {
+            let Ok(e) = serde_json::from_slice::<CreatedAt>(line) else {
+                return false;
+            };
+            first = Some(first.map_or(e.created_at, |f| f.min(e.created_at)));
+            last = Some(last.map_or(e.created_at, |l| l.max(e.created_at)));
+            true
+        })?;
+        Ok((scan.events, first, last))
+    })
+    .await?
+}
+
+/// Marker next to the archive holding its signed attestation
+fn sidecar(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}.attestation", name))
+}
+
+/// Is this a finalized archive, false for the active file
+fn is_finalized(path: &Path) -> bool {
+    is_archive(path) && !is_active(path)
+}
+
+/// Periodically sign attestations for finalized archives, store them in the archive
+/// and publish them to the upstream relays
+pub async fn run_attest(db: ArchiveDatabase, publisher: Publisher) -> Result<()> {
+    loop {
+        match db.list_archives().await {
+            Ok(files) => {
+                for f in files.iter() {
+                    if !is_finalized(&f.path)
+                        || db.is_pending(&f.path).await
+                        || db.awaits_encryption(&f.path)
+                    {
+                        continue;
+                    }
+                    let marker = sidecar(&f.path);
+                    if tokio::fs::metadata(&marker).await.is_ok() {
+                        continue;
+                    }
+                    // partitioned archives are named by their path, eg. kind-1/events_20250101.jsonl.zst,
+                    // without the `YYYY/MM` directory of the nested layout
+                    let name = &flat_name(&db.archive_name(&f.path));
+                    match attest(&db, &publisher, &f.path, name).await {
+                        Ok(ev) => {
+                            info!("Signed attestation {} for {}", ev.id, name);
+                            tokio::fs::write(&marker, ev.as_json()).await?;
+                        }
+                        Err(e) => error!("Failed to attest {}: {}", name, e),
+                    }
+                }
+            }
+            Err(e) => error!("Failed to list archives: {}", e),
+        }
+        tokio::time::sleep(ATTEST_INTERVAL).await;
+    }
+}
+
+async fn attest(
+    db: &ArchiveDatabase,
+    publisher: &Publisher,
+    path: &Path,
+    name: &str,
+) -> Result<Event> {
+    let file = db.get_file(&format!("/{}", db.archive_name(path)))?;
+    let sha256 = db
+        .checksum(&file)
+        .await?
+        .ok_or(anyhow!("No checksum for {}", name))?;
+    let (events, first, last) = scan(path.to_path_buf()).await?;
+    let content = Attestation {
+        sha256: sha256.clone(),
+        size: file.size,
+        events,
+        first,
+        last,
+    };
+    let ev = publisher.sign(
+        EventBuilder::new(ATTESTATION_KIND, serde_json::to_string(&content)?)
+            .tag(Tag::identifier(name))
+            .tag(Tag::parse(["x", sha256.as_str()])?),
+    )?;
+
+    db.save_event(&ev).await?;
+    publisher.send(&ev).await;
+    Ok(ev)
+}
+
+/// Re-hash local archives and check them against attestations on `relays`
+///
+/// Returns false when any attested archive is missing or differs
+pub async fn verify(
+    out_dir: &Path,
+    upstream: &Upstream,
+    relays: &[String],
+    author: PublicKey,
+) -> Result<bool> {
+    let client = upstream.client(relays).await?;
+    let attestations: HashMap<String, Attestation> = client
+        .fetch_events(
+            Filter::new().kind(ATTESTATION_KIND).author(author),
+            Duration::from_secs(30),
+        )
+        .await?
+        .into_iter()
+        .filter_map(|e| {
+            let name = e.tags.identifier()?.to_string();
+            Some((name, serde_json::from_str(&e.content).ok()?))
+        })
+        .collect();
+    client.disconnect().await;
+
+    let mut local = Vec::new();
+    // archives in out_dir and one level of partition directories, by their flat name
+    let mut dirs = vec![(out_dir.to_path_buf(), String::new())];
+    while let Some((d, prefix)) = dirs.pop() {
+        let mut dir = tokio::fs::read_dir(&d).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type().await?.is_dir() {
+                if (prefix.is_empty() && !name.starts_with('.')) || is_date_dir(&name) {
+                    dirs.push((path, format!("{}{}/", prefix, name)));
+                }
+            } else if is_finalized(&path) {
+                local.push((flat_name(&format!("{}{}", prefix, name)), path));
+            }
+        }
+    }
+    local.sort();
+
+    let (mut attested, mut names) = (Vec::new(), HashMap::new());
+    for (name, path) in &local {
+        if attestations.contains_key(name) {
+            attested.push(path.clone());
+            names.insert(path.clone(), name.clone());
+        } else {
+            println!("unattested {}", name);
+        }
+    }
+    let missing: Vec<String> = attestations
+        .keys()
+        .filter(|n| !local.iter().any(|(l, _)| l == *n))
+        .cloned()
+        .collect();
+    // archives are hashed in parallel, reported as they finish
+    let mut ok = tokio::task::spawn_blocking(move || {
+        let mut ok = true;
+        scan_files::<(), _>(
+            &attested,
+            |path, _| sha256_file_blocking(path),
+            |path, msg, progress| {
+                let Scanned::Done(sha256) = msg else {
+                    return Ok(());
+                };
+                let (name, sha256) = (&names[path], sha256?);
+                let a = &attestations[name];
+                if sha256 == a.sha256 {
+                    println!("ok         {} {} events {}", name, a.events, progress);
+                } else {
+                    println!(
+                        "MISMATCH   {} expected {} got {} {}",
+                        name, a.sha256, sha256, progress
+                    );
+                    ok = false;
+                }
+                Ok(())
+            },
+        )?;
+        anyhow::Ok(ok)
+    })
+    .await??;
+    for name in missing {
+        println!("MISSING    {}", name);
+        ok = false;
+    }
+    Ok(ok)
+}
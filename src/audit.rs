@@ -0,0 +1,37 @@
+use crate::logfile::JsonLog;
+use nostr_sdk::{Event, Timestamp};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+#[derive(Serialize)]
+struct Rejection {
+    timestamp: u64,
+    remote: String,
+    id: String,
+    kind: u16,
+    pubkey: String,
+    reason: String,
+}
+
+/// Log of events rejected by write policies
+#[derive(Clone, Debug)]
+pub struct AuditLog(JsonLog);
+
+impl AuditLog {
+    pub fn spawn(path: PathBuf, max_size: u64) -> Self {
+        Self(JsonLog::spawn("Audit log", path, max_size))
+    }
+
+    /// Queue a rejection, never waits on the writer
+    pub fn rejected(&self, event: &Event, addr: &SocketAddr, reason: &str) {
+        self.0.write(&Rejection {
+            timestamp: Timestamp::now().as_secs(),
+            remote: addr.to_string(),
+            id: event.id.to_hex(),
+            kind: event.kind.as_u16(),
+            pubkey: event.pubkey.to_hex(),
+            reason: reason.to_string(),
+        });
+    }
+}
@@ -0,0 +1,142 @@
+use hyper::Request;
+use hyper::header::AUTHORIZATION;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+#[derive(Deserialize, Clone)]
+pub struct DownloadAuthSettings {
+    /// Accepted bearer tokens
+    pub tokens: Vec<String>,
+
+    /// Keep the landing page and file listing public
+    pub allow_index: Option<bool>,
+}
+
+/// Bearer token check for archive downloads
+#[derive(Clone)]
+pub struct DownloadAuth {
+    tokens: Vec<String>,
+    allow_index: bool,
+}
+
+impl DownloadAuth {
+    pub fn new(settings: DownloadAuthSettings) -> Self {
+        Self {
+            tokens: settings.tokens,
+            allow_index: settings.allow_index.unwrap_or(true),
+        }
+    }
+
+    /// Does this request need a token, index pages are public when `allow_index` is set
+    pub fn is_protected(&self, path: &str) -> bool {
+        let index = matches!(
+            path,
+            "/" | "/index.html"
+                | "/feed.xml"
+                | "/api/files"
+                | "/api/relays"
+                | "/api/stats"
+                | "/api/health"
+                | "/healthz"
+        );
+        !(index && self.allow_index)
+    }
+
+    /// Token from `Authorization: Bearer` or the `token` query param is valid
+    pub fn is_authorized<B>(&self, req: &Request<B>) -> bool {
+        self.token_index(req).is_some()
+    }
+
+    /// Index in `tokens` of the request's valid token
+    pub fn token_index<B>(&self, req: &Request<B>) -> Option<usize> {
+        let header = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            // the auth scheme is case-insensitive (RFC 7235)
+            .and_then(|h| h.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, t)| t.trim().to_string());
+        let query = || {
+            req.uri().query().and_then(|q| {
+                url::form_urlencoded::parse(q.as_bytes())
+                    .find(|(k, _)| k == "token")
+                    .map(|(_, v)| v.to_string())
+            })
+        };
+        let token = header.or_else(query)?;
+        // check every token so timing doesn't reveal which one matched
+        self.tokens.iter().enumerate().fold(None, |found, (i, t)| {
+            if bool::from(t.as_bytes().ct_eq(token.as_bytes())) {
+                Some(i)
+            } else {
+                found
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(allow_index: bool) -> DownloadAuth {
+        DownloadAuth::new(DownloadAuthSettings {
+            tokens: vec!["first".to_string(), "second".to_string()],
+            allow_index: Some(allow_index),
+        })
+    }
+
+    fn request(path: &str, authorization: Option<&str>) -> Request<()> {
+        let mut req = Request::builder().uri(path);
+        if let Some(a) = authorization {
+            req = req.header(AUTHORIZATION, a);
+        }
+        req.body(()).unwrap()
+    }
+
+    /// Is a request for `path` let through, like the http server checks it
+    fn check(auth: &DownloadAuth, path: &str, authorization: Option<&str>) -> bool {
+        let req = request(path, authorization);
+        !auth.is_protected(req.uri().path()) || auth.is_authorized(&req)
+    }
+
+    const FILE: &str = "/events_20240101.jsonl.zst";
+
+    #[test]
+    fn missing_token() {
+        assert!(check(&auth(true), "/", None));
+        assert!(!check(&auth(false), "/", None));
+        assert!(!check(&auth(true), FILE, None));
+        assert!(!check(&auth(true), FILE, Some("Basic Zmlyc3Q=")));
+        assert!(!check(&auth(true), FILE, Some("Bearer")));
+    }
+
+    #[test]
+    fn wrong_token() {
+        for path in ["/", FILE] {
+            assert!(!check(&auth(false), path, Some("Bearer third")));
+            assert!(!check(&auth(false), path, Some("Bearer firs")));
+            assert!(!check(&auth(false), &format!("{}?token=third", path), None));
+        }
+    }
+
+    #[test]
+    fn valid_token() {
+        for path in ["/", FILE] {
+            assert!(check(&auth(false), path, Some("Bearer first")));
+            assert!(check(&auth(false), path, Some("Bearer  second ")));
+            assert!(check(&auth(false), &format!("{}?token=second", path), None));
+        }
+        let req = request(FILE, Some("Bearer second"));
+        assert_eq!(auth(false).token_index(&req), Some(1));
+    }
+
+    #[test]
+    fn scheme_is_case_insensitive() {
+        for scheme in ["bearer", "BEARER", "bEaReR"] {
+            let header = format!("{} first", scheme);
+            assert!(check(&auth(false), FILE, Some(&header)));
+        }
+    }
+}
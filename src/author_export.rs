@@ -0,0 +1,227 @@
+use crate::db::{ArchiveDatabase, decode_archive_strict};
+use crate::jobs::JOB_BUFFER;
+use anyhow::Result;
+use dashmap::DashSet;
+use hyper::body::Bytes;
+use log::warn;
+use nostr_sdk::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Chunks buffered per export before the scan waits for the client
+const EXPORT_QUEUE: usize = 16;
+
+/// Bytes collected before a chunk is sent
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Download auth tokens with an export running, by index in `tokens`
+#[derive(Clone, Default)]
+pub struct ExportSlots(Arc<DashSet<usize>>);
+
+/// Held while an export streams, frees the token's slot when dropped
+pub struct ExportSlot {
+    slots: ExportSlots,
+    token: usize,
+}
+
+impl ExportSlots {
+    /// Take the export slot of `token`, None while another export with it is running
+    pub fn acquire(&self, token: usize) -> Option<ExportSlot> {
+        self.0.insert(token).then(|| ExportSlot {
+            slots: self.clone(),
+            token,
+        })
+    }
+}
+
+impl Drop for ExportSlot {
+    fn drop(&mut self) {
+        self.slots.0.remove(&self.token);
+    }
+}
+
+/// Last line of an export
+#[derive(Serialize)]
+struct ExportEnd {
+    /// Every archive that could hold events of the author was read
+    export_complete: bool,
+    events: u64,
+    archives: usize,
+    /// Archives that couldn't be read
+    failed: usize,
+}
+
+#[derive(Deserialize)]
+struct Author<'a> {
+    pubkey: &'a str,
+}
+
+/// Archives that may contain events of `author`, narrowed by the author index and bloom
+/// filters when enabled
+///
+/// Archives the index hasn't seen yet (imported or mirrored since the last rebuild) are
+/// always read
+async fn candidates(db: &ArchiveDatabase, author: &PublicKey) -> Result<Vec<PathBuf>> {
+    let files = db.list_archives().await?;
+    let (index, blooms) = (db.authors().cloned(), db.blooms().cloned());
+    let author = *author;
+    tokio::task::spawn_blocking(move || {
+        let lookup = index
+            .as_ref()
+            .map(|i| anyhow::Ok((i, i.archives(&author)?, i.indexed()?)))
+            .transpose()?;
+        let key = author.to_bytes();
+        Ok(files
+            .iter()
+            .filter(|f| {
+                lookup.as_ref().is_none_or(|(i, archives, indexed)| {
+                    i.archive_name(&f.path)
+                        .is_none_or(|n| archives.contains_key(&n) || !indexed.contains(&n))
+                })
+            })
+            .filter(|f| {
+                blooms
+                    .as_ref()
+                    .is_none_or(|b| b.may_contain_author(&f.path, &key))
+            })
+            .map(|f| f.path.clone())
+            .collect())
+    })
+    .await?
+}
+
+/// Sends the output of an export in chunks, zstd compressed when requested
+struct Output {
+    tx: mpsc::Sender<Bytes>,
+    zstd: Option<zstd::Encoder<'static, Vec<u8>>>,
+    buf: Vec<u8>,
+}
+
+impl Output {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match &mut self.zstd {
+            Some(z) => z.write_all(data)?,
+            None => self.buf.extend_from_slice(data),
+        }
+        let pending = self
+            .zstd
+            .as_ref()
+            .map_or(self.buf.len(), |z| z.get_ref().len());
+        if pending >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    /// Send what was written, fails once the client went away
+    fn send(&mut self) -> std::io::Result<()> {
+        let data = match &mut self.zstd {
+            Some(z) => std::mem::take(z.get_mut()),
+            None => std::mem::take(&mut self.buf),
+        };
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.tx
+            .blocking_send(Bytes::from(data))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        if let Some(z) = self.zstd.take() {
+            self.buf = z.finish()?;
+        }
+        self.send()
+    }
+}
+
+/// Stream every archived event of `author` as jsonl into `tx`, ending with an
+/// [ExportEnd] line; `slot` is held until the export ends
+pub fn export_author(
+    db: ArchiveDatabase,
+    author: PublicKey,
+    zstd: bool,
+    slot: ExportSlot,
+) -> mpsc::Receiver<Bytes> {
+    let (tx, rx) = mpsc::channel(EXPORT_QUEUE);
+    tokio::spawn(async move {
+        let files = match candidates(&db, &author).await {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("Failed to list archives for export of {}: {}", author, e);
+                Vec::new()
+            }
+        };
+        let res = tokio::task::spawn_blocking(move || {
+            let _slot = slot;
+            let mut out = Output {
+                tx,
+                zstd: zstd
+                    .then(|| zstd::Encoder::new(Vec::new(), 0))
+                    .transpose()?,
+                buf: Vec::with_capacity(CHUNK_SIZE),
+            };
+            let hex = author.to_hex();
+            let mut end = ExportEnd {
+                export_complete: true,
+                events: 0,
+                archives: files.len(),
+                failed: 0,
+            };
+            let mut line = Vec::new();
+            for path in files {
+                let mut input = match std::fs::File::open(&path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|f| {
+                        decode_archive_strict(&path, BufReader::with_capacity(JOB_BUFFER, f))
+                    }) {
+                    Ok(i) => i,
+                    Err(e) => {
+                        warn!("Export of {} skipped {}: {}", hex, path.display(), e);
+                        end.failed += 1;
+                        continue;
+                    }
+                };
+                loop {
+                    line.clear();
+                    match input.read_until(b'\n', &mut line) {
+                        Ok(0) => break,
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!(
+                                "Export of {} stopped reading {}: {}",
+                                hex,
+                                path.display(),
+                                e
+                            );
+                            end.failed += 1;
+                            break;
+                        }
+                    }
+                    if serde_json::from_slice::<Author>(&line).is_ok_and(|a| a.pubkey == hex) {
+                        out.write(&line)?;
+                        if !line.ends_with(b"\n") {
+                            out.write(b"\n")?;
+                        }
+                        end.events += 1;
+                    }
+                }
+            }
+            end.export_complete = end.failed == 0;
+            out.write(&serde_json::to_vec(&end)?)?;
+            out.write(b"\n")?;
+            out.finish()?;
+            anyhow::Ok(())
+        })
+        .await;
+        match res {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Export of {} ended: {}", author, e),
+            Err(e) => warn!("Export of {} failed: {}", author, e),
+        }
+    });
+    rx
+}
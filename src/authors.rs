@@ -0,0 +1,240 @@
+use crate::layout::flat_name;
+use crate::naming::archive_day;
+use crate::prune;
+use crate::rocks;
+use anyhow::{Result, anyhow};
+use chrono::NaiveDate;
+use log::warn;
+use nostr_sdk::{Event, PublicKey};
+use rocksdb::{DB, IteratorMode, WriteBatch};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Prefix of `pubkey + archive -> events` keys
+const AUTHOR_PREFIX: u8 = b'a';
+
+/// Prefix of `archive -> events` keys, archives without one were never indexed
+const ARCHIVE_PREFIX: u8 = b'f';
+
+/// Index of pubkey -> event count per archive, so lookups by author only read the
+/// archives holding their events
+///
+/// Archives are keyed by their path relative to `out_dir` up to `.jsonl`, eg.
+/// `kind-1/events_20250101`, which stays the same when they are compressed
+#[derive(Clone)]
+pub struct AuthorIndex {
+    database: Arc<DB>,
+    /// Directory the archives are written to
+    dir: PathBuf,
+    /// Held across the read-modify-write of a count
+    lock: Arc<Mutex<()>>,
+}
+
+fn author_key(pubkey: &[u8; 32], archive: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(33 + archive.len());
+    key.push(AUTHOR_PREFIX);
+    key.extend_from_slice(pubkey);
+    key.extend_from_slice(archive.as_bytes());
+    key
+}
+
+fn archive_key(archive: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + archive.len());
+    key.push(ARCHIVE_PREFIX);
+    key.extend_from_slice(archive.as_bytes());
+    key
+}
+
+fn count(v: &[u8]) -> u64 {
+    v.try_into().map(u64::from_be_bytes).unwrap_or_default()
+}
+
+impl AuthorIndex {
+    pub fn open(path: &Path, dir: PathBuf) -> Result<Self> {
+        let db = rocks::open(path)?;
+        Ok(Self {
+            database: Arc::new(db),
+            dir,
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Forget every indexed archive
+    pub fn clear_all(&self) -> Result<u64> {
+        let _guard = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Author index lock poisoned"))?;
+        prune::clear(&self.database)
+    }
+
+    /// Forget the archives of the days before `before`, returns the number of keys deleted
+    pub fn prune(&self, before: NaiveDate) -> Result<u64> {
+        let _guard = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Author index lock poisoned"))?;
+        prune::delete_where(&self.database, |k, _| {
+            let archive = match k.first() {
+                Some(&AUTHOR_PREFIX) if k.len() >= 33 => &k[33..],
+                Some(&ARCHIVE_PREFIX) => &k[1..],
+                _ => return false,
+            };
+            std::str::from_utf8(archive)
+                .ok()
+                .and_then(|a| archive_day(Path::new(a)))
+                .is_some_and(|d| d < before)
+        })
+    }
+
+    /// Key of the archive at `path`, None outside `dir`; the same in both layouts
+    pub fn archive_name(&self, path: &Path) -> Option<String> {
+        let rel = flat_name(path.strip_prefix(&self.dir).ok()?.to_str()?);
+        Some(rel.split(".jsonl").next().unwrap_or(&rel).to_string())
+    }
+
+    /// No archive was indexed yet
+    pub fn is_empty(&self) -> bool {
+        self.database
+            .prefix_iterator([ARCHIVE_PREFIX])
+            .next()
+            .is_none_or(|x| x.is_ok_and(|(k, _)| k.first() != Some(&ARCHIVE_PREFIX)))
+    }
+
+    fn add(&self, batch: &mut WriteBatch, key: Vec<u8>, n: u64) -> Result<()> {
+        let v = self.database.get(&key).map_err(|e| anyhow!(e))?;
+        let total = v.as_deref().map(count).unwrap_or_default() + n;
+        batch.put(key, total.to_be_bytes());
+        Ok(())
+    }
+
+    /// Count `event` in the archive of `day` (YYYYMMDD) it was written to, called after
+    /// it was saved
+    pub fn record(&self, event: &Event, partition: Option<&str>, day: u32) {
+        let archive = match partition {
+            Some(p) => format!("{}/events_{}", p, day),
+            None => format!("events_{}", day),
+        };
+        let pubkey = event.pubkey.to_bytes();
+        let res = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Author index lock poisoned"))
+            .and_then(|_guard| {
+                let mut batch = WriteBatch::default();
+                self.add(&mut batch, author_key(&pubkey, &archive), 1)?;
+                self.add(&mut batch, archive_key(&archive), 1)?;
+                self.database.write(batch).map_err(|e| anyhow!(e))
+            });
+        if let Err(e) = res {
+            warn!("Failed to update author index: {}", e);
+        }
+    }
+
+    /// Drop the counts of every archive in `dir`, before its archives are indexed again
+    pub fn clear(&self, dir: &Path) -> Result<()> {
+        let Ok(rel) = dir.strip_prefix(&self.dir) else {
+            return Ok(());
+        };
+        let rel = rel.to_str().unwrap_or_default();
+        let in_dir = |archive: &[u8]| {
+            let parent = std::str::from_utf8(archive)
+                .ok()
+                .and_then(|a| Path::new(a).parent())
+                .and_then(|p| p.to_str());
+            parent == Some(rel)
+        };
+        let _guard = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Author index lock poisoned"))?;
+        let mut batch = WriteBatch::default();
+        for x in self.database.iterator(IteratorMode::Start) {
+            let (k, _) = x.map_err(|e| anyhow!(e))?;
+            let archive = match k.first() {
+                Some(&AUTHOR_PREFIX) if k.len() >= 33 => &k[33..],
+                Some(&ARCHIVE_PREFIX) => &k[1..],
+                _ => continue,
+            };
+            if in_dir(archive) {
+                batch.delete(&k);
+            }
+        }
+        self.database.write(batch).map_err(|e| anyhow!(e))
+    }
+
+    /// Store the event counts per author of the archive at `path`
+    pub fn insert_archive(&self, path: &Path, authors: &HashMap<[u8; 32], u64>) -> Result<()> {
+        let Some(archive) = self.archive_name(path) else {
+            return Ok(());
+        };
+        let mut batch = WriteBatch::default();
+        let mut total = 0;
+        for (pubkey, n) in authors {
+            batch.put(author_key(pubkey, &archive), n.to_be_bytes());
+            total += n;
+        }
+        batch.put(archive_key(&archive), total.to_be_bytes());
+        self.database.write(batch).map_err(|e| anyhow!(e))
+    }
+
+    /// Event count per archive of `pubkey`
+    pub fn archives(&self, pubkey: &PublicKey) -> Result<HashMap<String, u64>> {
+        let prefix = author_key(&pubkey.to_bytes(), "");
+        let mut ret = HashMap::new();
+        for x in self.database.prefix_iterator(&prefix) {
+            let (k, v) = x.map_err(|e| anyhow!(e))?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            ret.insert(
+                String::from_utf8_lossy(&k[prefix.len()..]).into_owned(),
+                count(&v),
+            );
+        }
+        Ok(ret)
+    }
+
+    /// Archives that were indexed
+    pub fn indexed(&self) -> Result<HashSet<String>> {
+        let mut ret = HashSet::new();
+        for x in self.database.prefix_iterator([ARCHIVE_PREFIX]) {
+            let (k, _) = x.map_err(|e| anyhow!(e))?;
+            if k.first() != Some(&ARCHIVE_PREFIX) {
+                break;
+            }
+            ret.insert(String::from_utf8_lossy(&k[1..]).into_owned());
+        }
+        Ok(ret)
+    }
+
+    /// Events of `pubkey` in the indexed archives
+    pub fn count(&self, pubkey: &PublicKey) -> Result<u64> {
+        Ok(self.archives(pubkey)?.values().sum())
+    }
+
+    /// Estimated number of entries and the size of the index on disk in bytes
+    pub fn size(&self) -> (u64, u64) {
+        let keys = self
+            .database
+            .property_int_value("rocksdb.estimate-num-keys")
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        (keys, dir_size(self.database.path()))
+    }
+}
+
+/// Bytes of the files in `dir`
+pub fn dir_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .map(|d| {
+            d.flatten()
+                .filter_map(|e| e.metadata().ok())
+                .filter(|m| m.is_file())
+                .map(|m| m.len())
+                .sum()
+        })
+        .unwrap_or_default()
+}
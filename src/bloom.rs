@@ -0,0 +1,523 @@
+use crate::db::{ArchiveDatabase, decode_archive_strict, is_active, is_archive, is_fresh};
+use crate::jobs::{ArchiveJobs, open_input};
+use crate::layout::is_date_dir;
+use crate::scan::{is_reserved_dir, scan_lines};
+use anyhow::{Result, bail};
+use dashmap::DashMap;
+use log::{error, info, warn};
+use nostr_sdk::prelude::JsonUtil;
+use nostr_sdk::{Event, EventId};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How often finalized archives are checked for a missing bloom filter
+const BLOOM_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+const MAGIC: &[u8; 4] = b"HBLM";
+const VERSION: u8 = 1;
+
+/// Section tags of a bloom file
+const IDS: u8 = b'i';
+const AUTHORS: u8 = b'a';
+
+/// Most probes per key, reached at a false positive rate of about 1e-19
+const MAX_PROBES: u32 = 64;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct BloomSettings {
+    pub enabled: Option<bool>,
+    /// False positive rate of each filter, default 0.01
+    pub false_positive_rate: Option<f64>,
+    /// Also add the pubkeys of the archive, default true
+    pub authors: Option<bool>,
+}
+
+impl BloomSettings {
+    pub fn false_positive_rate(&self) -> f64 {
+        self.false_positive_rate
+            .filter(|p| *p > 0.0 && *p < 1.0)
+            .unwrap_or(0.01)
+    }
+}
+
+/// Bloom filter over 32 byte keys, probed with double hashing of the last 16 bytes
+///
+/// Ids and pubkeys are already uniformly distributed except for their leading bits,
+/// which proof of work and vanity keys choose
+#[derive(Clone, PartialEq, Debug)]
+pub struct BloomFilter {
+    /// Number of bits
+    m: u64,
+    /// Probes per key
+    k: u32,
+    /// Keys added
+    n: u64,
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Bits and probes for `n` keys at false positive rate `p`
+    /// (m = -n ln p / ln² 2, k = m / n ln 2)
+    pub fn optimal(n: u64, p: f64) -> (u64, u32) {
+        let n = n.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let m = (-n * p.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let k = (m / n * ln2).round().clamp(1.0, MAX_PROBES as f64);
+        (m as u64, k as u32)
+    }
+
+    /// Expected false positive rate, (1 - e^(-kn/m))^k
+    pub fn false_positive_rate(&self) -> f64 {
+        let (m, k, n) = (self.m as f64, self.k as f64, self.n as f64);
+        (1.0 - (-k * n / m).exp()).powf(k)
+    }
+
+    pub fn new(n: u64, p: f64) -> Self {
+        let (m, k) = Self::optimal(n, p);
+        Self {
+            m,
+            k,
+            n: 0,
+            bits: vec![0; m.div_ceil(64) as usize],
+        }
+    }
+
+    fn probes(&self, key: &[u8; 32]) -> impl Iterator<Item = usize> + use<> {
+        let h1 = u64::from_be_bytes(key[16..24].try_into().unwrap_or_default());
+        let h2 = u64::from_be_bytes(key[24..32].try_into().unwrap_or_default()) | 1;
+        let m = self.m;
+        (0..self.k as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+
+    pub fn insert(&mut self, key: &[u8; 32]) {
+        for b in self.probes(key) {
+            self.bits[b / 64] |= 1 << (b % 64);
+        }
+        self.n += 1;
+    }
+
+    /// False when `key` was definitely not added
+    pub fn may_contain(&self, key: &[u8; 32]) -> bool {
+        self.probes(key)
+            .all(|b| self.bits[b / 64] & (1 << (b % 64)) != 0)
+    }
+
+    fn write(&self, tag: u8, out: &mut impl Write) -> std::io::Result<()> {
+        out.write_all(&[tag])?;
+        out.write_all(&self.k.to_be_bytes())?;
+        out.write_all(&self.n.to_be_bytes())?;
+        out.write_all(&self.m.to_be_bytes())?;
+        for w in &self.bits {
+            out.write_all(&w.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Read a section from `input`, `m` has to fit in what is left of it so a corrupt
+    /// header can't allocate more than the file holds
+    fn read(input: &mut &[u8]) -> Result<(u8, Self)> {
+        let mut head = [0u8; 21];
+        input.read_exact(&mut head)?;
+        let k = u32::from_be_bytes(head[1..5].try_into()?);
+        let n = u64::from_be_bytes(head[5..13].try_into()?);
+        let m = u64::from_be_bytes(head[13..21].try_into()?);
+        if m == 0 || k == 0 || k > MAX_PROBES || m.div_ceil(64) > input.len() as u64 / 8 {
+            bail!("Invalid bloom filter m={} k={}", m, k);
+        }
+        let (words, rest) = input.split_at(m.div_ceil(64) as usize * 8);
+        let bits = words
+            .chunks_exact(8)
+            .map(|w| u64::from_be_bytes(w.try_into().unwrap_or_default()))
+            .collect();
+        *input = rest;
+        Ok((head[0], Self { m, k, n, bits }))
+    }
+}
+
+/// Filters of one archive, written to [bloom_path]
+#[derive(Clone, PartialEq, Debug)]
+pub struct ArchiveBloom {
+    pub ids: BloomFilter,
+    pub authors: Option<BloomFilter>,
+}
+
+impl ArchiveBloom {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(
+            16 + (self.ids.bits.len() + self.authors.as_ref().map_or(0, |a| a.bits.len())) * 8,
+        );
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.push(1 + self.authors.is_some() as u8);
+        // writing to a vec can't fail
+        let _ = self.ids.write(IDS, &mut out);
+        if let Some(a) = &self.authors {
+            let _ = a.write(AUTHORS, &mut out);
+        }
+        out
+    }
+
+    pub fn from_bytes(mut data: &[u8]) -> Result<Self> {
+        let mut head = [0u8; 6];
+        data.read_exact(&mut head)?;
+        if &head[..4] != MAGIC || head[4] != VERSION {
+            bail!("Not a bloom filter");
+        }
+        let (mut ids, mut authors) = (None, None);
+        for _ in 0..head[5] {
+            match BloomFilter::read(&mut data)? {
+                (IDS, f) => ids = Some(f),
+                (AUTHORS, f) => authors = Some(f),
+                (t, _) => bail!("Unknown bloom filter section {}", t),
+            }
+        }
+        let Some(ids) = ids else {
+            bail!("Bloom filter without ids");
+        };
+        Ok(Self { ids, authors })
+    }
+}
+
+/// `events_YYYYMMDD.bloom` in the directory of the archive, imported archives keep
+/// their suffix (`events_YYYYMMDD.import-1700000000.bloom`)
+pub fn bloom_path(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let stem = name.split(".jsonl").next().unwrap_or(name);
+    archive.with_file_name(format!("{}.bloom", stem))
+}
+
+#[derive(Deserialize)]
+struct BloomFields<'a> {
+    id: &'a str,
+    pubkey: &'a str,
+}
+
+fn decode_key(hex: &str) -> Option<[u8; 32]> {
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(hex, &mut key).ok()?;
+    Some(key)
+}
+
+/// Read the ids (and pubkeys) of an archive and build its filters
+fn build(path: &Path, settings: &BloomSettings) -> Result<ArchiveBloom> {
+    let mut ids = Vec::new();
+    let mut authors = settings.authors.unwrap_or(true).then(HashSet::new);
+    let input = decode_archive_strict(path, open_input(path, "Building bloom filter of")?)?;
+    scan_lines(path, input, |line| {
+        let Some((id, pubkey)) = serde_json::from_slice::<BloomFields>(line)
+            .ok()
+            .and_then(|e| Some((decode_key(e.id)?, decode_key(e.pubkey)?)))
+        else {
+            return false;
+        };
+        ids.push(id);
+        if let Some(a) = &mut authors {
+            a.insert(pubkey);
+        }
+        true
+    })?;
+    let p = settings.false_positive_rate();
+    let mut bloom = ArchiveBloom {
+        ids: BloomFilter::new(ids.len() as u64, p),
+        authors: authors
+            .as_ref()
+            .map(|a| BloomFilter::new(a.len() as u64, p)),
+    };
+    for id in &ids {
+        bloom.ids.insert(id);
+    }
+    if let (Some(f), Some(a)) = (&mut bloom.authors, &authors) {
+        for pubkey in a {
+            f.insert(pubkey);
+        }
+    }
+    Ok(bloom)
+}
+
+/// Build the filters of an archive and write them next to it
+pub async fn write_bloom(
+    archive: &Path,
+    settings: &BloomSettings,
+    jobs: &ArchiveJobs,
+) -> Result<ArchiveBloom> {
+    let (p, s) = (archive.to_path_buf(), settings.clone());
+    let bloom = jobs.run(move || build(&p, &s)).await?;
+    let dst = bloom_path(archive);
+    let tmp = dst.with_extension("bloom.tmp");
+    tokio::fs::write(&tmp, bloom.to_bytes()).await?;
+    tokio::fs::rename(&tmp, &dst).await?;
+    Ok(bloom)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Modification time of the archive the filters were read for, None without a bloom file
+type CachedBloom = (SystemTime, Option<Arc<ArchiveBloom>>);
+
+/// Bloom filters of finalized archives, read from disk on first use
+#[derive(Clone, Default)]
+pub struct BloomFilters {
+    /// Filters by archive path
+    cache: Arc<DashMap<PathBuf, CachedBloom>>,
+}
+
+impl BloomFilters {
+    /// Filters of `archive`, None when it has no up to date bloom file
+    ///
+    /// Blocking, the file is read when the archive changed since it was cached
+    pub fn get(&self, archive: &Path) -> Option<Arc<ArchiveBloom>> {
+        let mtime = modified(archive)?;
+        if let Some(e) = self.cache.get(archive)
+            && e.0 == mtime
+        {
+            return e.1.clone();
+        }
+        let path = bloom_path(archive);
+        let bloom = modified(&path)
+            .filter(|b| *b >= mtime)
+            .and_then(
+                |_| match std::fs::read(&path).map_err(anyhow::Error::from) {
+                    Ok(b) => ArchiveBloom::from_bytes(&b)
+                        .inspect_err(|e| warn!("{}: {}", path.display(), e))
+                        .ok(),
+                    Err(e) => {
+                        warn!("Failed to read {}: {}", path.display(), e);
+                        None
+                    }
+                },
+            )
+            .map(Arc::new);
+        self.cache
+            .insert(archive.to_path_buf(), (mtime, bloom.clone()));
+        bloom
+    }
+
+    /// False when the filters of `archive` rule out events by `pubkey`
+    pub fn may_contain_author(&self, archive: &Path, pubkey: &[u8; 32]) -> bool {
+        self.get(archive)
+            .and_then(|b| b.authors.as_ref().map(|a| a.may_contain(pubkey)))
+            .unwrap_or(true)
+    }
+}
+
+#[derive(Deserialize)]
+struct IdField<'a> {
+    id: &'a str,
+}
+
+/// Read `files` until the event `id` is found, blocking
+pub fn find_event(files: &[PathBuf], id: &EventId) -> Result<Option<Event>> {
+    let hex = id.to_hex();
+    let mut line = Vec::new();
+    for path in files {
+        let mut input = decode_archive_strict(path, open_input(path, "Looking up event in")?)?;
+        loop {
+            line.clear();
+            match input.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(
+                        "Lookup of {} stopped reading {}: {}",
+                        hex,
+                        path.display(),
+                        e
+                    );
+                    break;
+                }
+            }
+            if serde_json::from_slice::<IdField>(&line).is_ok_and(|e| e.id == hex)
+                && let Ok(ev) = Event::from_json(line.trim_ascii_end())
+                && ev.id == *id
+            {
+                return Ok(Some(ev));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Build filters of archives finalized while running, older ones are built with
+/// `bloom --rebuild`
+pub async fn run_blooms(
+    db: ArchiveDatabase,
+    settings: BloomSettings,
+    jobs: ArchiveJobs,
+) -> Result<()> {
+    let started = SystemTime::now();
+    loop {
+        match db.list_archives().await {
+            Ok(files) => {
+                for f in files.iter() {
+                    if is_active(&f.path)
+                        || db.is_pending(&f.path).await
+                        || is_fresh(&bloom_path(&f.path), &f.path).await
+                        || tokio::fs::metadata(&f.path)
+                            .await
+                            .and_then(|m| m.modified())
+                            .is_ok_and(|m| m < started)
+                    {
+                        continue;
+                    }
+                    let name = db.archive_name(&f.path);
+                    match write_bloom(&f.path, &settings, &jobs).await {
+                        Ok(b) => info!(
+                            "Wrote bloom filter of {}: {} ids, ~{:.4} false positives",
+                            name,
+                            b.ids.n,
+                            b.ids.false_positive_rate()
+                        ),
+                        Err(e) => error!("Failed to write bloom filter of {}: {}", name, e),
+                    }
+                }
+            }
+            Err(e) => error!("Failed to list archives: {}", e),
+        }
+        tokio::time::sleep(BLOOM_INTERVAL).await;
+    }
+}
+
+/// Write missing bloom filters for all finalized archives in `out_dir` and one level of
+/// partition directories, nested `YYYY/MM` directories included; returns the number
+/// written and the number that failed
+pub async fn rebuild(out_dir: &Path, settings: &BloomSettings) -> Result<(usize, usize)> {
+    let jobs = ArchiveJobs::new(1);
+    let (mut written, mut failed) = (0, 0);
+    let mut dirs = vec![(out_dir.to_path_buf(), true)];
+    while let Some((d, top)) = dirs.pop() {
+        let mut dir = tokio::fs::read_dir(&d).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if (top && !name.starts_with('.') && !is_reserved_dir(&name)) || is_date_dir(&name)
+                {
+                    dirs.push((path, false));
+                }
+                continue;
+            }
+            if !is_archive(&path) || is_active(&path) || is_fresh(&bloom_path(&path), &path).await {
+                continue;
+            }
+            match write_bloom(&path, settings, &jobs).await {
+                Ok(b) => {
+                    println!(
+                        "{} {} ids{}",
+                        path.display(),
+                        b.ids.n,
+                        b.authors
+                            .map(|a| format!(", {} authors", a.n))
+                            .unwrap_or_default()
+                    );
+                    written += 1;
+                }
+                Err(e) => {
+                    println!("{} failed: {}", path.display(), e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    Ok((written, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    /// Uniformly distributed test key `i` of a `set`
+    fn key(set: &str, i: u64) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(set)
+            .chain_update(i.to_be_bytes())
+            .finalize()
+            .into()
+    }
+
+    fn filled(set: &str, n: u64, p: f64) -> BloomFilter {
+        let mut f = BloomFilter::new(n, p);
+        for i in 0..n {
+            f.insert(&key(set, i));
+        }
+        f
+    }
+
+    #[test]
+    fn round_trip() {
+        for authors in [None, Some(filled("authors", 100, 0.001))] {
+            let bloom = ArchiveBloom {
+                ids: filled("ids", 1000, 0.01),
+                authors,
+            };
+            let read = ArchiveBloom::from_bytes(&bloom.to_bytes()).unwrap();
+            assert_eq!(read, bloom);
+            assert!((0..1000).all(|i| read.ids.may_contain(&key("ids", i))));
+        }
+    }
+
+    #[test]
+    fn measured_false_positive_rate() {
+        for p in [0.01, 0.001] {
+            let n = 10_000;
+            let f = filled("ids", n, p);
+            assert!((0..n).all(|i| f.may_contain(&key("ids", i))));
+            let probes = 200_000;
+            let hits = (0..probes)
+                .filter(|i| f.may_contain(&key("others", *i)))
+                .count();
+            let measured = hits as f64 / probes as f64;
+            assert!(
+                measured < p * 1.5,
+                "measured {} for p={} (expected {})",
+                measured,
+                p,
+                f.false_positive_rate()
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt_headers_are_rejected() {
+        let mut bytes = ArchiveBloom {
+            ids: filled("ids", 10, 0.01),
+            authors: None,
+        }
+        .to_bytes();
+        // m claims far more bits than the file holds
+        bytes[19..27].copy_from_slice(&(1u64 << 39).to_be_bytes());
+        assert!(ArchiveBloom::from_bytes(&bytes).is_err());
+
+        let mut bytes = ArchiveBloom {
+            ids: filled("ids", 10, 0.01),
+            authors: None,
+        }
+        .to_bytes();
+        bytes[7..11].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(ArchiveBloom::from_bytes(&bytes).is_err());
+
+        let mut truncated = ArchiveBloom {
+            ids: filled("ids", 10, 0.01),
+            authors: None,
+        }
+        .to_bytes();
+        truncated.pop();
+        assert!(ArchiveBloom::from_bytes(&truncated).is_err());
+    }
+
+    #[test]
+    fn probes_are_capped() {
+        let (_, k) = BloomFilter::optimal(10, 1e-300);
+        assert_eq!(k, MAX_PROBES);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::db::{ArchiveDatabase, is_active};
+use crate::layout::flat_name;
+use crate::rocks;
+use anyhow::{Result, anyhow};
+use log::{error, info, warn};
+use nostr_sdk::Timestamp;
+use rocksdb::{DB, IteratorMode, WriteBatch};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often the archive files are compared with the recorded ones
+const CHANGES_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Key of the generation counter, file keys start with [FILE_PREFIX]
+const GENERATION_KEY: &[u8] = b"generation";
+
+const FILE_PREFIX: &[u8] = b"f:";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeType {
+    New,
+    /// Size, checksum or url changed, eg. a rewrite or a move into the nested layout
+    Replaced,
+    /// Kept as a tombstone, listed again as new when a file of the same name appears
+    Deleted,
+}
+
+/// Last recorded change of an archive file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileChange {
+    /// Path relative to out_dir, as used in urls
+    pub name: String,
+    pub size: u64,
+    /// None for deleted files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    pub change: ChangeType,
+    /// When the change was noticed
+    pub at: u64,
+    /// Generation the change was recorded in
+    pub generation: u64,
+}
+
+/// Finalized archive as listed now
+pub struct ListedFile {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Journal of the finalized archive files, keyed by their name without the `YYYY/MM`
+/// directory; the generation is bumped on every change of the file set so mirrors
+/// can tell they missed one
+#[derive(Clone)]
+pub struct ArchiveChanges {
+    database: Arc<DB>,
+    generation: Arc<AtomicU64>,
+    /// Held across comparing and writing the file set
+    lock: Arc<Mutex<()>>,
+}
+
+impl ArchiveChanges {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = rocks::open(path)?;
+        let generation = match db.get(GENERATION_KEY).map_err(|e| anyhow!(e))? {
+            Some(v) => u64::from_le_bytes(
+                v.as_slice()
+                    .try_into()
+                    .map_err(|_| anyhow!("Invalid generation"))?,
+            ),
+            None => 0,
+        };
+        Ok(Self {
+            database: Arc::new(db),
+            generation: Arc::new(AtomicU64::new(generation)),
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    fn files(&self) -> Result<HashMap<String, FileChange>> {
+        let mut ret = HashMap::new();
+        for kv in self.database.iterator(IteratorMode::Start) {
+            let (k, v) = kv.map_err(|e| anyhow!(e))?;
+            if let Some(key) = k.strip_prefix(FILE_PREFIX) {
+                ret.insert(
+                    String::from_utf8_lossy(key).to_string(),
+                    serde_json::from_slice(&v)?,
+                );
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Files changed after `since`, oldest first
+    pub fn since(&self, since: u64) -> Result<Vec<FileChange>> {
+        let mut ret: Vec<FileChange> = self
+            .files()?
+            .into_values()
+            .filter(|f| f.at > since)
+            .collect();
+        ret.sort_by(|a, b| (a.generation, &a.name).cmp(&(b.generation, &b.name)));
+        Ok(ret)
+    }
+
+    /// Record the differences between `listed` and the known files, files in `busy` are
+    /// being rewritten and aren't deleted; returns the number of changes
+    pub fn update(&self, listed: Vec<ListedFile>, busy: &HashSet<String>) -> Result<usize> {
+        let _lock = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Changes lock poisoned"))?;
+        let known = self.files()?;
+        let generation = self.generation() + 1;
+        let at = Timestamp::now().as_secs();
+        let mut changes = Vec::new();
+        let mut present = HashSet::new();
+        for f in listed {
+            let key = flat_name(&f.name);
+            let change = match known.get(&key) {
+                None => Some(ChangeType::New),
+                Some(k) if k.change == ChangeType::Deleted => Some(ChangeType::New),
+                Some(k) if k.name != f.name || k.size != f.size => Some(ChangeType::Replaced),
+                Some(k) if k.sha256.as_deref() != Some(f.sha256.as_str()) => {
+                    Some(ChangeType::Replaced)
+                }
+                Some(_) => None,
+            };
+            if let Some(change) = change {
+                changes.push((
+                    key.clone(),
+                    FileChange {
+                        name: f.name,
+                        size: f.size,
+                        sha256: Some(f.sha256),
+                        change,
+                        at,
+                        generation,
+                    },
+                ));
+            }
+            present.insert(key);
+        }
+        for (key, k) in known {
+            if k.change != ChangeType::Deleted && !present.contains(&key) && !busy.contains(&key) {
+                changes.push((
+                    key,
+                    FileChange {
+                        sha256: None,
+                        change: ChangeType::Deleted,
+                        at,
+                        generation,
+                        ..k
+                    },
+                ));
+            }
+        }
+        if changes.is_empty() {
+            return Ok(0);
+        }
+        let mut batch = WriteBatch::default();
+        for (key, f) in &changes {
+            batch.put(
+                [FILE_PREFIX, key.as_bytes()].concat(),
+                serde_json::to_vec(f)?,
+            );
+        }
+        batch.put(GENERATION_KEY, generation.to_le_bytes());
+        self.database.write(batch).map_err(|e| anyhow!(e))?;
+        self.generation.store(generation, Ordering::Relaxed);
+        Ok(changes.len())
+    }
+}
+
+/// Compare the finalized archives with the journal once a minute, archives still being
+/// written, rewritten or encrypted are left until they are done
+pub async fn run_changes(db: ArchiveDatabase, changes: ArchiveChanges) -> Result<()> {
+    loop {
+        match list(&db).await {
+            Ok((listed, busy)) => {
+                let c = changes.clone();
+                match tokio::task::spawn_blocking(move || c.update(listed, &busy)).await? {
+                    Ok(0) => {}
+                    Ok(n) => info!(
+                        "Recorded {} archive file changes, generation {}",
+                        n,
+                        changes.generation()
+                    ),
+                    Err(e) => error!("Failed to record archive file changes: {}", e),
+                }
+            }
+            Err(e) => error!("Failed to list archives: {}", e),
+        }
+        tokio::time::sleep(CHANGES_INTERVAL).await;
+    }
+}
+
+async fn list(db: &ArchiveDatabase) -> Result<(Vec<ListedFile>, HashSet<String>)> {
+    let (mut listed, mut busy) = (Vec::new(), HashSet::new());
+    for f in db.list_archives().await?.iter() {
+        let name = db.archive_name(&f.path);
+        if is_active(&f.path) {
+            continue;
+        }
+        match db.checksum(f).await {
+            Ok(Some(sha256)) => listed.push(ListedFile {
+                name,
+                size: f.size,
+                sha256,
+            }),
+            Ok(None) => {
+                busy.insert(flat_name(&name));
+            }
+            Err(e) => {
+                warn!("Failed to hash {}: {}", name, e);
+                busy.insert(flat_name(&name));
+            }
+        }
+    }
+    Ok((listed, busy))
+}
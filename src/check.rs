@@ -0,0 +1,556 @@
+use crate::announce::relay_url;
+use crate::durability::Durability;
+use crate::import::WriteMode;
+use crate::ingest_jobs::{self, JobDefaults};
+use crate::kinds::KindSet;
+use crate::listen::Listener;
+use crate::naming::FileNameTemplate;
+use crate::partition::PartitionSettings;
+use crate::peers::TrustedPeers;
+use crate::policy::{PolicyChain, PolicyConfig};
+use crate::proxy::TrustedProxies;
+use crate::sensitive::SensitiveKinds;
+use crate::tls::ReloadableTls;
+use crate::torrent::TorrentMaker;
+use crate::upstream::Upstream;
+use crate::wot::{WotPolicy, WotSet};
+use crate::writer::WriterOptions;
+use crate::{RunMode, Settings};
+use anyhow::{Result, anyhow, bail};
+use nostr_sdk::Keys;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:8001";
+
+/// Fail unless files can be created in `dir`, or in the closest parent that exists
+fn check_writable(dir: &Path) -> Result<()> {
+    let mut existing = dir;
+    while !existing.exists() {
+        existing = match existing.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+    }
+    if !existing.is_dir() {
+        bail!("{} is not a directory", existing.display());
+    }
+    let probe = existing.join(format!(".nostrhole-check-{}", std::process::id()));
+    std::fs::write(&probe, b"")
+        .map_err(|e| anyhow!("{} is not writable: {}", existing.display(), e))?;
+    std::fs::remove_file(&probe)?;
+    Ok(())
+}
+
+/// Directory a log file is written to
+fn log_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+impl Settings {
+    pub fn out_dir(&self) -> PathBuf {
+        self.out_dir.clone().unwrap_or(PathBuf::from("./data"))
+    }
+
+    /// `listen`, or `listen_relay` from older configs
+    pub fn listen_addrs(&self) -> Vec<String> {
+        self.listen.clone().unwrap_or_else(|| {
+            vec![
+                self.listen_relay
+                    .clone()
+                    .unwrap_or(DEFAULT_LISTEN.to_string()),
+            ]
+        })
+    }
+
+    /// `policies`, defaults to ephemeral, expiration and kinds
+    pub fn effective_policies(&self) -> Vec<PolicyConfig> {
+        self.policies.clone().unwrap_or_else(|| {
+            let mut p = vec![PolicyConfig::Ephemeral];
+            if !self.keep_expired.unwrap_or(false) {
+                p.push(PolicyConfig::Expiration);
+            }
+            if let Some(k) = &self.kinds {
+                p.push(PolicyConfig::Kinds { kinds: k.clone() });
+            }
+            p
+        })
+    }
+
+    /// Check everything that can be checked without opening the archives or binding a
+    /// socket; every problem is reported, not just the first
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = Vec::new();
+        let mut check = |key: &str, r: Result<()>| {
+            if let Err(e) = r {
+                errors.push(format!("{}: {}", key, e));
+            }
+        };
+        let out_dir = self.out_dir();
+        let mode = self.mode.unwrap_or_default();
+        let relays = self.relays.clone().unwrap_or_default();
+
+        check("out_dir", check_writable(&out_dir));
+        if let Some(p) = &self.audit_log {
+            check("audit_log", check_writable(log_dir(p)));
+        }
+        if let Some(p) = &self.access_log {
+            check("access_log", check_writable(log_dir(p)));
+        }
+        if let Some(t) = &self.file_name_template {
+            check(
+                "file_name_template",
+                FileNameTemplate::parse(t, self.instance_name.as_deref(), &out_dir).map(|_| ()),
+            );
+        }
+
+        for spec in self.listen_addrs() {
+            check("listen", Listener::check(&spec));
+        }
+        for spec in self.metrics_listen.iter().flatten() {
+            check("metrics_listen", Listener::check(spec));
+        }
+        if let Some(m) = &self.unix_socket_mode {
+            check(
+                "unix_socket_mode",
+                u32::from_str_radix(m, 8)
+                    .map(|_| ())
+                    .map_err(|e| anyhow!("{}: {}", m, e)),
+            );
+        }
+        if let Some(t) = &self.tls {
+            check(
+                "tls",
+                ReloadableTls::check(t, self.http.clone().unwrap_or_default().http2()),
+            );
+        }
+
+        let keys = self.relay_secret_key.as_deref().map(Keys::parse);
+        if let Some(Err(e)) = &keys {
+            check("relay_secret_key", Err(anyhow!("{}", e)));
+        }
+        match Upstream::new(&self.upstream) {
+            Ok(upstream) => {
+                check("relays", upstream.validate(&relays));
+                if matches!(keys, Some(Ok(_)))
+                    && mode != RunMode::Serve
+                    && !relays.is_empty()
+                    && !upstream.is_writable(&relays)
+                {
+                    check(
+                        "relay_options",
+                        Err(anyhow!(
+                            "Attestations are published to relays but none is writable, set write: true for one"
+                        )),
+                    );
+                }
+            }
+            Err(e) => check("upstream", Err(e)),
+        }
+        if let Some(m) = &self.mirror {
+            check(
+                "mirror",
+                url::Url::parse(&m.upstream)
+                    .map(|_| ())
+                    .map_err(|e| anyhow!("upstream {}: {}", m.upstream, e)),
+            );
+        }
+
+        check("limits", self.limits.clone().unwrap_or_default().validate());
+        if let Some(p) = &self.trusted_peers {
+            check("trusted_peers", TrustedPeers::parse(p).map(|_| ()));
+        }
+        check(
+            "trusted_proxies",
+            TrustedProxies::parse(self.trusted_proxies.as_deref().unwrap_or_default()).map(|_| ()),
+        );
+        if self.write_mode == Some(WriteMode::Verbatim) {
+            // nostr-sdk only hands over parsed events, the relay's writer serializes them again
+            check(
+                "write_mode",
+                Err(anyhow!(
+                    "verbatim only applies to import and merge, set it as import.write_mode"
+                )),
+            );
+        }
+        if let Some(p) = &self.partition_by_kind {
+            check("partition_by_kind", p.validate());
+        }
+        if let Some(e) = &self.encryption {
+            check("encryption", e.recipients().map(|_| ()));
+        }
+        if let Some(t) = &self.torrent {
+            check(
+                "torrent",
+                TorrentMaker::new(t.clone(), self.public_url.clone()).map(|_| ()),
+            );
+        }
+        if let Some(w) = &self.wot {
+            check("wot", WotPolicy::new(WotSet::default(), w).map(|_| ()));
+        }
+        if self
+            .announce
+            .as_ref()
+            .is_some_and(|a| a.enabled.unwrap_or(false))
+            && let Some(url) = &self.public_url
+        {
+            check("public_url", relay_url(url).map(|_| ()));
+        }
+        if mode != RunMode::Serve {
+            check(
+                "policies",
+                PolicyChain::from_config(&self.effective_policies()).map(|_| ()),
+            );
+        }
+
+        let kinds = self.kinds.as_deref().map(KindSet::parse).transpose();
+        let sensitive = SensitiveKinds::new(
+            self.sensitive_kinds.as_deref().unwrap_or_default(),
+            self.allow_sensitive_kinds.unwrap_or(false),
+        );
+        match (kinds, sensitive) {
+            (Ok(kinds), Ok(sensitive)) => {
+                let filter_kinds = kinds
+                    .as_ref()
+                    .and_then(|k| k.filter_kinds())
+                    .map(|k| sensitive.filter(k));
+                if filter_kinds.as_ref().is_some_and(|k| k.is_empty()) {
+                    check(
+                        "kinds",
+                        Err(anyhow!(
+                            "only sensitive kinds are listed, set allow_sensitive_kinds to archive them"
+                        )),
+                    );
+                }
+                if let Some(jobs) = &self.ingest_jobs {
+                    let defaults = JobDefaults {
+                        ingest: self.ingest.clone().unwrap_or_default(),
+                        kinds,
+                        filter_kinds,
+                        sensitive,
+                        relays,
+                    };
+                    if let Err(e) = ingest_jobs::validate(jobs, &defaults) {
+                        errors.push(e.to_string());
+                    }
+                }
+            }
+            (kinds, sensitive) => {
+                check("kinds", kinds.map(|_| ()));
+                check("sensitive_kinds", sensitive.map(|_| ()));
+            }
+        }
+
+        if !errors.is_empty() {
+            bail!("Invalid config:\n  {}", errors.join("\n  "));
+        }
+        Ok(())
+    }
+
+    /// Effective configuration with the defaults filled in, for `check`
+    pub fn summary(&self) -> String {
+        let list = |v: &[String]| {
+            if v.is_empty() {
+                "none".to_string()
+            } else {
+                v.join(", ")
+            }
+        };
+        let mode = match self.mode.unwrap_or_default() {
+            RunMode::Full => "full",
+            RunMode::Serve => "serve",
+            RunMode::Archive => "archive",
+        };
+        let kinds = self
+            .kinds
+            .as_deref()
+            .and_then(|k| KindSet::parse(k).ok())
+            .map(|k| k.to_string())
+            .unwrap_or("all".to_string());
+        let compression = self.compression.clone().unwrap_or_default().format();
+        let compression = format!("{:?}", compression).to_lowercase();
+        let partitions = match &self.partition_by_kind {
+            None | Some(PartitionSettings::PerKind(false)) => "off".to_string(),
+            Some(PartitionSettings::PerKind(true)) => "per kind".to_string(),
+            Some(PartitionSettings::Named(e)) => {
+                list(&e.iter().map(|e| e.name.clone()).collect::<Vec<_>>())
+            }
+        };
+        let policies: Vec<String> = match self.mode.unwrap_or_default() {
+            RunMode::Serve => vec!["read_only".to_string()],
+            _ => self
+                .effective_policies()
+                .iter()
+                .map(|p| p.name().to_string())
+                .collect(),
+        };
+        let jobs: Vec<String> = self
+            .ingest_jobs
+            .iter()
+            .flatten()
+            .map(|j| j.name.clone())
+            .collect();
+        let enabled: Vec<String> = [
+            ("track_replaceable", self.track_replaceable, false),
+            ("keep_expired", self.keep_expired, false),
+            ("archive_ephemeral", self.archive_ephemeral, true),
+            ("archive_protected", self.archive_protected, false),
+            ("allow_sensitive_kinds", self.allow_sensitive_kinds, false),
+            ("track_deletions", self.track_deletions, false),
+            ("event_lookup", self.event_lookup, false),
+            ("track_sources", self.track_sources, false),
+            ("track_seen", self.track_seen, false),
+            ("track_changes", self.track_changes, false),
+            ("index_authors", self.index_authors, false),
+            ("zap_rollups", self.zap_rollups, false),
+            ("auto_recover_index", self.auto_recover_index, false),
+            ("allow_wipe", self.allow_wipe, false),
+            ("count", self.count, true),
+        ]
+        .into_iter()
+        .filter(|(_, v, default)| v.unwrap_or(*default))
+        .map(|(k, _, _)| k.to_string())
+        .collect();
+
+        let mut s = String::new();
+        let _ = writeln!(s, "mode: {}", mode);
+        let _ = writeln!(s, "out_dir: {}", self.out_dir().display());
+        let _ = writeln!(
+            s,
+            "listen: {}{}",
+            list(&self.listen_addrs()),
+            if self.tls.is_some() { " (tls)" } else { "" }
+        );
+        let _ = writeln!(
+            s,
+            "metrics_listen: {}",
+            list(self.metrics_listen.as_deref().unwrap_or_default())
+        );
+        let _ = writeln!(
+            s,
+            "relays: {}",
+            list(self.relays.as_deref().unwrap_or_default())
+        );
+        let _ = writeln!(s, "kinds: {}", kinds);
+        let _ = writeln!(s, "partition_by_kind: {}", partitions);
+        let _ = writeln!(s, "compression: {}", compression);
+        let _ = writeln!(
+            s,
+            "layout: {}",
+            format!("{:?}", self.layout.unwrap_or_default()).to_lowercase()
+        );
+        let _ = writeln!(
+            s,
+            "import write_mode: {}",
+            format!("{:?}", self.import.clone().unwrap_or_default().write_mode()).to_lowercase()
+        );
+        let _ = writeln!(
+            s,
+            "durability: {}",
+            match self.durability.unwrap_or_default() {
+                Durability::None => "none",
+                Durability::Interval => "interval",
+                Durability::EveryEvent => "every_event",
+            }
+        );
+        let writer = WriterOptions::new(
+            &self.writer.clone().unwrap_or_default(),
+            Default::default(),
+            Default::default(),
+        );
+        let _ = writeln!(
+            s,
+            "writer: {} KiB buffer, flushed every {} ms",
+            writer.buffer / 1024,
+            writer.flush_interval.as_millis()
+        );
+        let _ = writeln!(
+            s,
+            "index: {}",
+            self.index
+                .as_ref()
+                .and_then(|i| i.backend)
+                .unwrap_or_default()
+                .name()
+        );
+        let rocksdb = self.rocksdb.clone().unwrap_or_default();
+        let _ = writeln!(
+            s,
+            "rocksdb: {} MiB shared cache, {} x {} MiB memtables per store",
+            rocksdb.cache_bytes() / 1024 / 1024,
+            rocksdb.max_write_buffers(),
+            rocksdb.write_buffer_bytes() / 1024 / 1024
+        );
+        let _ = writeln!(
+            s,
+            "write_compressed: {}",
+            self.write_compressed.unwrap_or(false)
+        );
+        let _ = writeln!(s, "archive_jobs: {}", self.archive_jobs.unwrap_or(1));
+        let _ = writeln!(s, "policies: {}", list(&policies));
+        let _ = writeln!(s, "ingest_jobs: {}", list(&jobs));
+        let _ = writeln!(
+            s,
+            "attestations: {}",
+            if self.relay_secret_key.is_some() {
+                "on"
+            } else {
+                "off"
+            }
+        );
+        let _ = writeln!(s, "enabled: {}", list(&enabled));
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::SensitiveKindPolicy;
+    use config::Config;
+    use nostr_relay_builder::prelude::{PolicyResult, WritePolicy};
+    use nostr_sdk::{EventBuilder, Kind, Tag, Timestamp};
+
+    fn settings(yaml: &str) -> Settings {
+        Config::builder()
+            .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    async fn admitted(chain: &PolicyChain, builder: EventBuilder) -> bool {
+        let e = builder.sign_with_keys(&Keys::generate()).unwrap();
+        matches!(
+            chain
+                .admit_event(&e, &"192.0.2.1:4000".parse().unwrap())
+                .await,
+            PolicyResult::Accept
+        )
+    }
+
+    #[test]
+    fn verbatim_is_only_accepted_for_imports() {
+        let dir = crate::test_util::TempDir::new();
+        let out_dir = format!("out_dir: {}\n", dir.path().display());
+        assert!(settings(&out_dir).validate().is_ok());
+        let top = settings(&format!("{}write_mode: verbatim", out_dir));
+        let e = top.validate().unwrap_err().to_string();
+        assert!(e.contains("write_mode: verbatim only applies"), "{}", e);
+        let import = settings(&format!("{}import:\n  write_mode: verbatim", out_dir));
+        assert!(import.validate().is_ok());
+        assert_eq!(import.import.unwrap().write_mode(), WriteMode::Verbatim);
+    }
+
+    #[tokio::test]
+    async fn kinds_are_added_to_the_default_policies() {
+        // setting kinds used to replace the ephemeral policy instead of adding to it
+        let config = settings("kinds: [1, 20001]");
+        let chain = PolicyChain::from_config(&config.effective_policies()).unwrap();
+        assert!(admitted(&chain, EventBuilder::new(Kind::from(1), "")).await);
+        assert!(!admitted(&chain, EventBuilder::new(Kind::from(2), "")).await);
+        assert!(!admitted(&chain, EventBuilder::new(Kind::from(20001), "")).await);
+        let expired =
+            EventBuilder::new(Kind::from(1), "").tag(Tag::expiration(Timestamp::from_secs(1000)));
+        assert!(!admitted(&chain, expired).await);
+
+        let config = settings("kinds: [1]\nkeep_expired: true");
+        let chain = PolicyChain::from_config(&config.effective_policies()).unwrap();
+        let expired =
+            EventBuilder::new(Kind::from(1), "").tag(Tag::expiration(Timestamp::from_secs(1000)));
+        assert!(admitted(&chain, expired).await);
+        assert!(!admitted(&chain, EventBuilder::new(Kind::from(20001), "")).await);
+    }
+
+    #[tokio::test]
+    async fn policies_replace_the_defaults_and_all_apply() {
+        let config = settings(
+            "kinds: [2]
+policies:
+  - name: kinds
+    kinds: [1, 20001]
+  - name: max_size
+    bytes: 400",
+        );
+        let chain = PolicyChain::from_config(&config.effective_policies()).unwrap();
+        assert!(admitted(&chain, EventBuilder::new(Kind::from(1), "")).await);
+        // no ephemeral policy in the list
+        assert!(admitted(&chain, EventBuilder::new(Kind::from(20001), "")).await);
+        assert!(!admitted(&chain, EventBuilder::new(Kind::from(2), "")).await);
+        // the later policy still applies
+        assert!(!admitted(&chain, EventBuilder::new(Kind::from(1), "x".repeat(400))).await);
+    }
+
+    #[tokio::test]
+    async fn sensitive_kinds_follow_kinds_allow_and_download_auth() {
+        // wired the way `run` does: the write policy, the upstream filter and the
+        // kinds hidden from http
+        for kinds in ["", "kinds: [all]\n", "kinds: [1]\n", "kinds: [1, 4]\n"] {
+            for allow in [false, true] {
+                for auth in [false, true] {
+                    let mut yaml = format!("{}allow_sensitive_kinds: {}\n", kinds, allow);
+                    if auth {
+                        yaml.push_str("download_auth:\n  tokens: [secret]\n");
+                    }
+                    let config = settings(&yaml);
+                    let sensitive = SensitiveKinds::new(
+                        config.sensitive_kinds.as_deref().unwrap_or_default(),
+                        config.allow_sensitive_kinds.unwrap_or(false),
+                    )
+                    .unwrap();
+                    let mut chain = PolicyChain::from_config(&config.effective_policies()).unwrap();
+                    if let Some(b) = sensitive.blocked() {
+                        chain = chain.with_policy(
+                            "sensitive_kinds",
+                            Box::new(SensitiveKindPolicy(b.clone())),
+                        );
+                    }
+                    let listed = !kinds.contains('1') || kinds.contains('4');
+                    assert_eq!(
+                        admitted(&chain, EventBuilder::new(Kind::from(4), "")).await,
+                        allow && listed,
+                        "{}",
+                        yaml
+                    );
+                    assert_eq!(
+                        admitted(&chain, EventBuilder::new(Kind::from(1059), "")).await,
+                        allow && !kinds.contains('1'),
+                        "{}",
+                        yaml
+                    );
+                    assert!(
+                        admitted(&chain, EventBuilder::new(Kind::from(1), "")).await,
+                        "{}",
+                        yaml
+                    );
+
+                    let filter = config
+                        .kinds
+                        .as_deref()
+                        .map(KindSet::parse)
+                        .transpose()
+                        .unwrap()
+                        .and_then(|k| k.filter_kinds())
+                        .map(|k| sensitive.filter(k));
+                    let expected = match kinds {
+                        // "all" is too many kinds for a filter
+                        "" | "kinds: [all]\n" => None,
+                        "kinds: [1, 4]\n" if allow => Some(vec![Kind::from(1), Kind::from(4)]),
+                        _ => Some(vec![Kind::from(1)]),
+                    };
+                    assert_eq!(filter, expected, "{}", yaml);
+
+                    assert_eq!(
+                        sensitive.hidden(config.download_auth.is_some()).is_some(),
+                        allow && !auth,
+                        "{}",
+                        yaml
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use crate::db::{ArchiveDatabase, decode_archive_strict, is_active, is_published};
+use crate::jobs::{ArchiveJobs, JOB_BUFFER, open_input};
+use crate::layout::is_date_dir;
+use crate::metrics;
+use crate::scan::is_reserved_dir;
+use anyhow::{Result, bail};
+use chrono::Utc;
+use dashmap::DashSet;
+use flate2::write::GzEncoder;
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often finalized archives are checked for the configured format
+const RECOMPRESS_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionFormat {
+    #[default]
+    Zstd,
+    Gzip,
+    /// Finalized archives are kept as plain jsonl
+    None,
+}
+
+impl CompressionFormat {
+    /// Extension added to `.jsonl` by the format, None when archives stay plain
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            CompressionFormat::Zstd => Some("zst"),
+            CompressionFormat::Gzip => Some("gz"),
+            CompressionFormat::None => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct CompressionSettings {
+    /// Format of finalized archives, zstd (default), gzip or none
+    pub format: Option<CompressionFormat>,
+}
+
+impl CompressionSettings {
+    pub fn format(&self) -> CompressionFormat {
+        self.format.unwrap_or_default()
+    }
+}
+
+/// Converts zstd archives into another format, those written with `write_compressed` or
+/// before the format was changed
+#[derive(Clone)]
+pub struct Recompress {
+    format: CompressionFormat,
+    jobs: ArchiveJobs,
+    /// Archives that failed to convert, left as they are
+    failed: Arc<DashSet<PathBuf>>,
+}
+
+impl Recompress {
+    pub fn new(format: CompressionFormat, jobs: ArchiveJobs) -> Self {
+        Self {
+            format,
+            jobs,
+            failed: Arc::new(DashSet::new()),
+        }
+    }
+
+    /// Finalized zstd archive that will still be converted, its checksum isn't published
+    /// until then
+    ///
+    /// Archives that already have a checksum, torrent or attestation keep their format
+    pub async fn is_pending(&self, path: &Path) -> bool {
+        self.format != CompressionFormat::Zstd
+            && matches!(
+                path.extension().and_then(|e| e.to_str()),
+                Some("zst")
+            )
+            && !self.failed.contains(path)
+            // the writer is still compressing while the jsonl exists
+            && !tokio::fs::try_exists(path.with_extension(""))
+                .await
+                .unwrap_or(true)
+            && !is_active(path)
+            && !is_published(path).await
+    }
+
+    async fn convert(&self, path: &Path) -> Result<PathBuf> {
+        let format = self.format;
+        let p = path.to_path_buf();
+        let res = self.jobs.run(move || convert(&p, format)).await;
+        if res.is_err() {
+            self.failed.insert(path.to_path_buf());
+        }
+        res
+    }
+}
+
+/// Periodically convert newly finalized zstd archives to the configured format
+pub async fn run_recompress(db: ArchiveDatabase, recompress: Recompress) -> Result<()> {
+    loop {
+        match db.list_archives().await {
+            Ok(files) => {
+                let mut converted = 0;
+                for f in files.iter() {
+                    if !recompress.is_pending(&f.path).await {
+                        continue;
+                    }
+                    match recompress.convert(&f.path).await {
+                        Ok(dst) => {
+                            info!(
+                                "Converted {} to {}",
+                                db.archive_name(&f.path),
+                                db.archive_name(&dst)
+                            );
+                            converted += 1;
+                        }
+                        Err(e) => error!("Failed to convert {}: {}", f.path.display(), e),
+                    }
+                }
+                if converted > 0 {
+                    db.invalidate_archives().await;
+                }
+            }
+            Err(e) => error!("Failed to list archives: {}", e),
+        }
+        tokio::time::sleep(RECOMPRESS_INTERVAL).await;
+    }
+}
+
+/// Decompress `path` into its `format` sibling, removing the zstd file once the
+/// new one is in place
+fn convert(path: &Path, format: CompressionFormat) -> Result<PathBuf> {
+    let dst = match format {
+        CompressionFormat::Gzip => path.with_extension("gz"),
+        CompressionFormat::None => path.with_extension(""),
+        f => bail!("Can't convert to {:?}", f),
+    };
+    let name = dst.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let tmp = dst.with_file_name(format!("{}.tmp", name));
+    let res = write_converted(path, &tmp, format);
+    if let Err(e) = res {
+        if let Err(e) = std::fs::remove_file(&tmp) {
+            warn!("Failed to remove {}: {}", tmp.display(), e);
+        }
+        return Err(e);
+    }
+    std::fs::rename(&tmp, &dst)?;
+    std::fs::remove_file(path)?;
+    Ok(dst)
+}
+
+fn write_converted(path: &Path, tmp: &Path, format: CompressionFormat) -> Result<()> {
+    // decode errors must fail the conversion, a truncated archive is left untouched
+    let mut input = BufReader::with_capacity(
+        JOB_BUFFER,
+        zstd::Decoder::with_buffer(open_input(path, "Converting")?)?,
+    );
+    let mut out = BufWriter::with_capacity(JOB_BUFFER, File::create(tmp)?);
+    if format == CompressionFormat::Gzip {
+        let started = Instant::now();
+        let mut enc = GzEncoder::new(out, flate2::Compression::default());
+        let n = std::io::copy(&mut input, &mut enc)?;
+        out = enc.finish()?;
+        metrics::record_compression(n, started.elapsed());
+    } else {
+        std::io::copy(&mut input, &mut out)?;
+    }
+    out.into_inner()?.sync_all()?;
+    Ok(())
+}
+
+/// Day (YYYYMMDD) of a plain archive named by the writer, `events_YYYYMMDD.jsonl`
+fn writer_day(path: &Path) -> Option<&str> {
+    let day = path
+        .file_name()?
+        .to_str()?
+        .strip_prefix("events_")?
+        .strip_suffix(".jsonl")?;
+    (day.len() == 8 && day.bytes().all(|b| b.is_ascii_digit())).then_some(day)
+}
+
+/// Events (non-blank lines) of an archive, failing on decode errors
+fn count_events(input: impl Read) -> Result<u64> {
+    let mut n = 0;
+    for line in BufReader::with_capacity(JOB_BUFFER, input).split(b'\n') {
+        if !line?.iter().all(u8::is_ascii_whitespace) {
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+/// Does the compressed archive `dst` hold every event of the plain archive `plain`
+fn is_complete(plain: &Path, dst: &Path) -> Result<bool> {
+    let compressed = count_events(decode_archive_strict(dst, File::open(dst)?)?)?;
+    Ok(compressed == count_events(File::open(plain)?)?)
+}
+
+/// Clean up after the writer was stopped while compressing, before anything reads the
+/// archives of `out_dir` and its partition directories
+///
+/// When the `.jsonl` of a past day has a `.jsonl.zst` or `.jsonl.gz` next to
+/// it, that is kept if it decodes fully and holds as many events, otherwise it is a partial
+/// write and removed.
+/// Returns the `.jsonl` files of past days left to compress, none with
+/// `compression.format: none` where they are the finalized archives
+pub fn recover_compression(out_dir: &Path, format: CompressionFormat) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![out_dir.to_path_buf()];
+    for e in std::fs::read_dir(out_dir)? {
+        let e = e?;
+        let name = e.file_name().to_string_lossy().to_string();
+        if e.file_type()?.is_dir()
+            && !name.starts_with('.')
+            && !is_date_dir(&name)
+            && name != "index"
+            && !is_reserved_dir(&name)
+        {
+            dirs.push(e.path());
+        }
+    }
+    let today = Utc::now().format("%Y%m%d").to_string();
+    let mut ret = Vec::new();
+    for dir in dirs {
+        for e in std::fs::read_dir(&dir)? {
+            let path = e?.path();
+            let Some(day) = writer_day(&path) else {
+                continue;
+            };
+            if day >= today.as_str() {
+                continue;
+            }
+            // our own compression below, interrupted
+            for ext in ["jsonl.zst.tmp", "jsonl.gz.tmp"] {
+                let tmp = path.with_extension(ext);
+                if tmp.exists() {
+                    std::fs::remove_file(&tmp)?;
+                }
+            }
+            let mut done = false;
+            for ext in ["jsonl.zst", "jsonl.gz"] {
+                let dst = path.with_extension(ext);
+                if done || !dst.exists() {
+                    continue;
+                }
+                match is_complete(&path, &dst) {
+                    Ok(true) => {
+                        info!(
+                            "{} was compressed before the last shutdown, removing {}",
+                            dst.display(),
+                            path.display()
+                        );
+                        std::fs::remove_file(&path)?;
+                        done = true;
+                        continue;
+                    }
+                    Ok(false) => warn!(
+                        "{} is missing events of {}, removing the partial archive",
+                        dst.display(),
+                        path.display()
+                    ),
+                    Err(e) => warn!(
+                        "{} doesn't decode, removing the partial archive: {}",
+                        dst.display(),
+                        e
+                    ),
+                }
+                std::fs::remove_file(&dst)?;
+            }
+            if !done && format != CompressionFormat::None {
+                ret.push(path);
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Compress a plain archive of a past day to `format` like the writer does at rotation,
+/// removing the jsonl once the archive is in place
+pub fn compress_plain(path: &Path, format: CompressionFormat) -> Result<PathBuf> {
+    let Some(ext) = format.extension() else {
+        bail!("Archives aren't compressed with format none");
+    };
+    let dst = path.with_extension(format!("jsonl.{}", ext));
+    let tmp = path.with_extension(format!("jsonl.{}.tmp", ext));
+    let res = (|| -> Result<()> {
+        let started = Instant::now();
+        let mut input = open_input(path, "Compressing")?;
+        let out = BufWriter::with_capacity(JOB_BUFFER, File::create(&tmp)?);
+        let (n, out) = if format == CompressionFormat::Gzip {
+            let mut enc = GzEncoder::new(out, flate2::Compression::default());
+            (std::io::copy(&mut input, &mut enc)?, enc.finish()?)
+        } else {
+            let mut enc = zstd::Encoder::new(out, 0)?;
+            (std::io::copy(&mut input, &mut enc)?, enc.finish()?)
+        };
+        out.into_inner()?.sync_all()?;
+        metrics::record_compression(n, started.elapsed());
+        Ok(())
+    })();
+    if let Err(e) = res {
+        if let Err(e) = std::fs::remove_file(&tmp) {
+            warn!("Failed to remove {}: {}", tmp.display(), e);
+        }
+        return Err(e);
+    }
+    std::fs::rename(&tmp, &dst)?;
+    std::fs::remove_file(path)?;
+    Ok(dst)
+}
+
+/// Compress the plain archives [recover_compression] found, the rest of the pipeline
+/// (seek tables, checksums) picks them up as finalized archives
+pub async fn compress_leftovers(
+    db: ArchiveDatabase,
+    jobs: ArchiveJobs,
+    files: Vec<PathBuf>,
+    format: CompressionFormat,
+) {
+    for f in files {
+        let p = f.clone();
+        match jobs.run(move || compress_plain(&p, format)).await {
+            Ok(dst) => info!("Compressed leftover {}", db.archive_name(&dst)),
+            Err(e) => error!("Failed to compress {}: {}", f.display(), e),
+        }
+    }
+    db.invalidate_archives().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, event, lines};
+    use nostr_sdk::JsonUtil;
+
+    /// Plain archive of a past day holding `n` events
+    fn archive(dir: &Path, n: usize) -> PathBuf {
+        let path = dir.join("events_20200101.jsonl");
+        let text: String = (0..n)
+            .map(|i| format!("{}\n", event(1, &i.to_string()).as_json()))
+            .collect();
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn zst(data: &[u8]) -> Vec<u8> {
+        zstd::encode_all(data, 0).unwrap()
+    }
+
+    #[test]
+    fn complete_compression_removes_the_source() {
+        let dir = TempDir::new();
+        let path = archive(dir.path(), 20);
+        let dst = path.with_extension("jsonl.zst");
+        std::fs::write(&dst, zst(&std::fs::read(&path).unwrap())).unwrap();
+
+        let left = recover_compression(dir.path(), CompressionFormat::Zstd).unwrap();
+        assert!(left.is_empty());
+        assert!(!path.exists());
+        assert_eq!(
+            count_events(zstd::Decoder::new(File::open(&dst).unwrap()).unwrap()).unwrap(),
+            20
+        );
+    }
+
+    #[test]
+    fn partial_compression_is_discarded() {
+        let dir = TempDir::new();
+        let path = archive(dir.path(), 20);
+        let data = std::fs::read(&path).unwrap();
+        let dst = path.with_extension("jsonl.zst");
+        // a valid archive the writer stopped writing halfway
+        let half = data.len() / 2;
+        let cut = data[..half].iter().rposition(|b| *b == b'\n').unwrap() + 1;
+        std::fs::write(&dst, zst(&data[..cut])).unwrap();
+
+        let left = recover_compression(dir.path(), CompressionFormat::Zstd).unwrap();
+        assert_eq!(left, vec![path.clone()]);
+        assert!(!dst.exists());
+        assert_eq!(lines(&path).len(), 20);
+    }
+
+    #[test]
+    fn corrupt_compression_is_discarded() {
+        let dir = TempDir::new();
+        let path = archive(dir.path(), 20);
+        let data = zst(&std::fs::read(&path).unwrap());
+        let dst = path.with_extension("jsonl.zst");
+        // the frame cut off mid block
+        std::fs::write(&dst, &data[..data.len() - 10]).unwrap();
+        // and our own compression into a temp file, interrupted
+        let tmp = path.with_extension("jsonl.zst.tmp");
+        std::fs::write(&tmp, &data[..data.len() / 2]).unwrap();
+
+        let left = recover_compression(dir.path(), CompressionFormat::Zstd).unwrap();
+        assert_eq!(left, vec![path.clone()]);
+        assert!(!dst.exists());
+        assert!(!tmp.exists());
+        assert_eq!(lines(&path).len(), 20);
+
+        // compressed again from the kept source
+        let dst = compress_plain(&path, CompressionFormat::Zstd).unwrap();
+        assert!(!path.exists());
+        assert_eq!(
+            count_events(zstd::Decoder::new(File::open(&dst).unwrap()).unwrap()).unwrap(),
+            20
+        );
+    }
+
+    #[test]
+    fn gzip_archives_are_recovered_and_compressed() {
+        let dir = TempDir::new();
+        let path = archive(dir.path(), 20);
+        let dst = compress_plain(&path, CompressionFormat::Gzip).unwrap();
+        assert_eq!(dst, path.with_extension("jsonl.gz"));
+        assert!(!path.exists());
+        let gz = std::fs::read(&dst).unwrap();
+        let text = std::io::read_to_string(flate2::read::GzDecoder::new(gz.as_slice())).unwrap();
+        assert_eq!(text.lines().count(), 20);
+
+        // the gzip stream cut short next to its source
+        std::fs::write(&path, &text).unwrap();
+        std::fs::write(&dst, &gz[..gz.len() / 2]).unwrap();
+        let left = recover_compression(dir.path(), CompressionFormat::Gzip).unwrap();
+        assert_eq!(left, vec![path.clone()]);
+        assert!(!dst.exists());
+        assert_eq!(lines(&path).len(), 20);
+
+        // and complete
+        std::fs::write(&dst, &gz).unwrap();
+        assert!(
+            recover_compression(dir.path(), CompressionFormat::Gzip)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(!path.exists() && dst.exists());
+    }
+
+    #[test]
+    fn uncompressed_archives_are_kept_with_format_none() {
+        let dir = TempDir::new();
+        let path = archive(dir.path(), 5);
+        let today = dir
+            .path()
+            .join(format!("events_{}.jsonl", Utc::now().format("%Y%m%d")));
+        std::fs::write(&today, "").unwrap();
+
+        assert!(
+            recover_compression(dir.path(), CompressionFormat::None)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            recover_compression(dir.path(), CompressionFormat::Zstd).unwrap(),
+            vec![path.clone()]
+        );
+        assert!(path.exists() && today.exists());
+    }
+}
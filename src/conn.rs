@@ -0,0 +1,145 @@
+use serde::Deserialize;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Deserialize, Clone, Default)]
+pub struct HttpSettings {
+    /// Seconds a client has to send the headers of a request, default 30
+    pub header_timeout_secs: Option<u64>,
+
+    /// Seconds without a read or write before a connection is closed, default 120; websocket
+    /// connections are exempt once upgraded
+    pub idle_timeout_secs: Option<u64>,
+
+    /// Headers allowed per request, default 100
+    pub max_headers: Option<usize>,
+
+    /// Serve HTTP/2 besides HTTP/1.1, default true; offered over ALPN with `tls` and
+    /// accepted with prior knowledge (h2c) otherwise. Websockets always need HTTP/1.1
+    pub http2: Option<bool>,
+}
+
+impl HttpSettings {
+    pub fn header_timeout(&self) -> Duration {
+        Duration::from_secs(self.header_timeout_secs.unwrap_or(30).max(1))
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs.unwrap_or(120).max(1))
+    }
+
+    pub fn http2(&self) -> bool {
+        self.http2.unwrap_or(true)
+    }
+}
+
+/// Time of the last read or write on a connection
+#[derive(Clone)]
+pub struct Activity {
+    start: Instant,
+    /// Milliseconds after `start`
+    last: Arc<AtomicU64>,
+}
+
+impl Activity {
+    fn touch(&self) {
+        self.last
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Resolves once nothing was read or written for `timeout`
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = last + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+/// Stream updating its [Activity] on every read or write that moved bytes
+pub struct IdleIo<I> {
+    inner: I,
+    activity: Activity,
+}
+
+impl<I> IdleIo<I> {
+    pub fn new(inner: I) -> (Self, Activity) {
+        let activity = Activity {
+            start: Instant::now(),
+            last: Arc::new(AtomicU64::new(0)),
+        };
+        (
+            Self {
+                inner,
+                activity: activity.clone(),
+            },
+            activity,
+        )
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for IdleIo<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let ret = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > before {
+            self.activity.touch();
+        }
+        ret
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for IdleIo<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let ret = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = ret
+            && n > 0
+        {
+            self.activity.touch();
+        }
+        ret
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let ret = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = ret
+            && n > 0
+        {
+            self.activity.touch();
+        }
+        ret
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,160 @@
+use crate::kinds::{KindEntry, KindSet};
+use anyhow::Result;
+use nostr_relay_builder::prelude::{PolicyResult, WritePolicy};
+use nostr_sdk::Event;
+use nostr_sdk::prelude::BoxedFuture;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Clone)]
+pub struct ContentDedupSettings {
+    /// Copies of the same content accepted per window
+    pub max_repeats: u32,
+
+    /// Length of the window (minutes), default 60
+    pub window_minutes: Option<u64>,
+
+    /// Kinds checked, default 1 and 7
+    pub kinds: Option<Vec<KindEntry>>,
+
+    /// Content shorter than this (characters, after trimming) is never counted, default 8
+    pub min_length: Option<usize>,
+
+    /// Content that is never counted, compared after trimming
+    pub allow: Option<Vec<String>>,
+
+    /// Compare content case sensitively
+    pub case_sensitive: Option<bool>,
+
+    /// Distinct contents tracked at once, the oldest are forgotten first
+    pub max_entries: Option<usize>,
+}
+
+/// Copies of one content accepted within the window
+#[derive(Debug)]
+struct Seen {
+    since: Instant,
+    accepted: u32,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    seen: HashMap<u64, Seen>,
+    /// Hashes by first sighting, the oldest are dropped once `max_entries` are tracked
+    order: VecDeque<u64>,
+}
+
+/// Rejects events whose normalized content was already accepted `max_repeats` times within
+/// the window, for bots posting the same text from many pubkeys
+///
+/// Content hashes are kept in a fixed window per hash, at most `max_entries` at once
+#[derive(Debug)]
+pub struct ContentDedupPolicy {
+    kinds: KindSet,
+    max_repeats: u32,
+    window: Duration,
+    /// Shorter content (after normalizing) is never counted, eg. "+" reactions
+    min_length: usize,
+    /// Normalized content that is never counted
+    allow: HashSet<String>,
+    lowercase: bool,
+    max_entries: usize,
+    hasher: RandomState,
+    state: Mutex<Window>,
+}
+
+impl ContentDedupPolicy {
+    pub fn new(settings: &ContentDedupSettings) -> Result<Self> {
+        let kinds = match &settings.kinds {
+            Some(k) => KindSet::parse(k)?,
+            None => KindSet::parse(&[KindEntry::Kind(1), KindEntry::Kind(7)])?,
+        };
+        let mut ret = Self {
+            kinds,
+            max_repeats: settings.max_repeats,
+            window: Duration::from_secs(settings.window_minutes.unwrap_or(60) * 60),
+            min_length: settings.min_length.unwrap_or(8),
+            allow: HashSet::new(),
+            lowercase: !settings.case_sensitive.unwrap_or(false),
+            max_entries: settings.max_entries.unwrap_or(100_000).max(1),
+            hasher: RandomState::new(),
+            state: Mutex::new(Window::default()),
+        };
+        ret.allow = settings
+            .allow
+            .iter()
+            .flatten()
+            .map(|a| ret.normalize(a))
+            .collect();
+        Ok(ret)
+    }
+
+    fn normalize(&self, content: &str) -> String {
+        let content = content.trim();
+        if self.lowercase {
+            content.to_lowercase()
+        } else {
+            content.to_string()
+        }
+    }
+
+    /// Count `content`, false if it was accepted too often already
+    fn check(&self, content: &str) -> bool {
+        let content = self.normalize(content);
+        if content.chars().count() < self.min_length || self.allow.contains(&content) {
+            return true;
+        }
+        let hash = self.hasher.hash_one(&content);
+        let now = Instant::now();
+        let Ok(mut state) = self.state.lock() else {
+            return true;
+        };
+        let state = &mut *state;
+        if !state.seen.contains_key(&hash) {
+            while state.seen.len() >= self.max_entries {
+                let Some(old) = state.order.pop_front() else {
+                    break;
+                };
+                state.seen.remove(&old);
+            }
+            state.order.push_back(hash);
+        }
+        let seen = state.seen.entry(hash).or_insert(Seen {
+            since: now,
+            accepted: 0,
+        });
+        if now.duration_since(seen.since) > self.window {
+            *seen = Seen {
+                since: now,
+                accepted: 0,
+            };
+        }
+        if seen.accepted >= self.max_repeats {
+            false
+        } else {
+            seen.accepted += 1;
+            true
+        }
+    }
+}
+
+impl WritePolicy for ContentDedupPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        let ok = !self.kinds.contains(event.kind) || self.check(&event.content);
+        Box::pin(async move {
+            if ok {
+                PolicyResult::Accept
+            } else {
+                PolicyResult::Reject("Duplicate content".to_string())
+            }
+        })
+    }
+}
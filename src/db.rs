@@ -0,0 +1,1746 @@
+use crate::activity::ActivityStats;
+use crate::authors::AuthorIndex;
+use crate::bloom::{BloomFilters, find_event};
+use crate::compression::Recompress;
+use crate::deadletter::DeadLetters;
+use crate::deletions::Deletions;
+use crate::disk::DiskGuard;
+use crate::encrypt::{ENCRYPTED_EXT, Encryption, decrypt, is_encrypted};
+use crate::kinds::KindSet;
+use crate::layout::{self, Layout};
+use crate::limit::IpRateLimit;
+use crate::metrics;
+use crate::naming::archive_day;
+use crate::offsets::EventOffsets;
+use crate::partition::Partitions;
+use crate::peers::TrustedPeers;
+use crate::policy::ExpirationPolicy;
+use crate::prune::PRUNE_BATCH;
+use crate::published::PublishedView;
+use crate::replaceable::ReplaceableIndex;
+use crate::sanity::EventLimits;
+use crate::scan::DELETIONS_DIR;
+use crate::seekable::Seekable;
+use crate::seen::{EventSeen, SeenInfo, SeenTotals};
+use crate::sources::EventSources;
+use crate::status::LiveStatus;
+use crate::store::EventStore;
+use crate::times::TimeIndex;
+use crate::torrent::{PieceHasher, TorrentMaker};
+use anyhow::{Result, bail};
+use chrono::{DateTime, NaiveTime, Utc};
+use itertools::Itertools;
+use log::{Level, debug, info, log_enabled, warn};
+use nostr_archive_cursor::ArchiveFile;
+use nostr_sdk::prelude::{
+    Backend, BoxedFuture, DatabaseError, DatabaseEventStatus, Events, NostrDatabase,
+    RejectedReason, SaveEventStatus,
+};
+use nostr_sdk::{Event, EventId, Filter, Kind, PublicKey, Timestamp};
+use sha2::{Digest, Sha256};
+use std::fmt::{Debug, Formatter};
+use std::io::{BufRead, BufReader, Error, Read};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, broadcast};
+
+tokio::task_local! {
+    /// Remote address of the websocket connection being served, unset for our own client
+    pub static REMOTE_ADDR: SocketAddr;
+}
+
+/// Archive database, wraps the [EventStore] of `out_dir` with hole specific save path behaviour
+#[derive(Clone)]
+pub struct ArchiveDatabase {
+    inner: EventStore,
+    /// Directory where flat files are contained
+    out_dir: PathBuf,
+    /// Newest version of replaceable events
+    replaceable: Option<ReplaceableIndex>,
+    /// Reject events past their NIP-40 expiration
+    reject_expired: bool,
+    /// Negentropy reconciliation limit for websocket peers, disabled when unset
+    negentropy: Option<IpRateLimit>,
+    /// Only save these kinds, used when the upstream filter can't list them
+    kinds: Option<KindSet>,
+    /// Never save these kinds, the sensitive kinds unless they are allowed
+    blocked_kinds: Option<KindSet>,
+    /// Cached archive listing, shared by all clones
+    archives: Arc<Mutex<Option<ArchiveCache>>>,
+    /// Newly saved events are sent here for live consumers
+    live: Option<broadcast::Sender<Event>>,
+    /// Where each saved event was written, for lookups by id
+    offsets: Option<EventOffsets>,
+    /// Generate torrents next to finalized archives
+    torrents: Option<TorrentMaker>,
+    /// Sanity limits for tags and content
+    limits: Option<EventLimits>,
+    /// Write events into per-kind subdirectories instead of `inner`
+    partitions: Option<Partitions>,
+    /// Finalized zstd archives are rewritten with a seek table
+    seekable: Option<Seekable>,
+    /// Finalized zstd archives are converted to another format
+    recompress: Option<Recompress>,
+    /// Upstream relays each event was delivered by
+    sources: Option<EventSources>,
+    /// Nothing is saved while the disk guard has paused ingestion
+    disk: Option<DiskGuard>,
+    /// Counts websocket writes of trusted peers and everyone else
+    peers: Option<TrustedPeers>,
+    /// Events that failed to save are appended here
+    dead_letters: Option<DeadLetters>,
+    /// Archives holding the events of each pubkey
+    authors: Option<AuthorIndex>,
+    /// Bloom filters of finalized archives, used to find events by id without offsets
+    blooms: Option<BloomFilters>,
+    /// [NostrDatabase::wipe] clears the indexes instead of failing
+    allow_wipe: bool,
+    /// Per-kind counts answering NIP-45 COUNT, disabled when unset
+    count: Option<ActivityStats>,
+    /// Event ids by created_at, for time ranges
+    times: Option<TimeIndex>,
+    /// Ephemeral events are only sent to the firehose, not archived
+    skip_ephemeral: bool,
+    /// Deliveries of events that were already archived
+    seen: Option<EventSeen>,
+    /// Finalized archives are encrypted and their plaintext deleted
+    encryption: Option<Encryption>,
+    /// Finalized archives are moved into `YYYY/MM` directories
+    layout: Layout,
+    /// Recent saves for the landing page
+    status: Option<LiveStatus>,
+    /// Saved kind 5 events are also written to daily deletion files
+    deletions: Option<Deletions>,
+    published: Option<PublishedView>,
+}
+
+/// How long the archive listing is cached
+pub const ARCHIVE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+struct ArchiveCache {
+    files: Arc<Vec<ArchiveFile>>,
+    loaded: Instant,
+    /// UTC day number the listing was taken on
+    day: u64,
+}
+
+impl Debug for ArchiveDatabase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArchiveDatabase").finish_non_exhaustive()
+    }
+}
+
+impl ArchiveDatabase {
+    pub fn new(inner: EventStore, out_dir: PathBuf) -> Self {
+        Self {
+            inner,
+            out_dir,
+            replaceable: None,
+            reject_expired: false,
+            negentropy: None,
+            kinds: None,
+            blocked_kinds: None,
+            archives: Arc::new(Mutex::new(None)),
+            live: None,
+            offsets: None,
+            torrents: None,
+            limits: None,
+            partitions: None,
+            seekable: None,
+            recompress: None,
+            sources: None,
+            disk: None,
+            peers: None,
+            dead_letters: None,
+            authors: None,
+            blooms: None,
+            allow_wipe: false,
+            count: None,
+            times: None,
+            skip_ephemeral: false,
+            seen: None,
+            encryption: None,
+            layout: Layout::Flat,
+            status: None,
+            deletions: None,
+            published: None,
+        }
+    }
+
+    pub fn with_deletions(mut self, deletions: Deletions) -> Self {
+        self.deletions = Some(deletions);
+        self
+    }
+
+    pub fn deletions(&self) -> Option<&Deletions> {
+        self.deletions.as_ref()
+    }
+
+    pub fn with_published(mut self, view: PublishedView) -> Self {
+        self.published = Some(view);
+        self
+    }
+
+    pub fn published(&self) -> Option<&PublishedView> {
+        self.published.as_ref()
+    }
+
+    /// Reject events with kinds outside the set
+    pub fn with_kinds(mut self, kinds: KindSet) -> Self {
+        self.kinds = Some(kinds);
+        self
+    }
+
+    /// Reject events with kinds in the set
+    pub fn with_blocked_kinds(mut self, kinds: KindSet) -> Self {
+        self.blocked_kinds = Some(kinds);
+        self
+    }
+
+    /// Allow websocket peers to reconcile against the archive (NIP-77)
+    pub fn with_negentropy(mut self, limit: IpRateLimit) -> Self {
+        self.negentropy = Some(limit);
+        self
+    }
+
+    /// Reject events past their NIP-40 expiration
+    pub fn with_reject_expired(mut self) -> Self {
+        self.reject_expired = true;
+        self
+    }
+
+    /// Record the archive position of saved events so they can be looked up by id
+    pub fn with_offsets(mut self, offsets: EventOffsets) -> Self {
+        self.offsets = Some(offsets);
+        self
+    }
+
+    /// Build `.torrent` files when finalized archives are hashed
+    pub fn with_torrents(mut self, torrents: TorrentMaker) -> Self {
+        self.torrents = Some(torrents);
+        self
+    }
+
+    /// Keep the upstream relays of each event, recorded by [crate::sources::SourceAdmit]
+    pub fn with_sources(mut self, sources: EventSources) -> Self {
+        self.sources = Some(sources);
+        self
+    }
+
+    /// Reject events while free space is below `stop_free_gb`
+    pub fn with_disk_guard(mut self, disk: DiskGuard) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    /// Events are rejected until the disk guard resumes ingestion
+    pub fn is_paused(&self) -> bool {
+        self.disk.as_ref().is_some_and(|d| d.is_paused())
+    }
+
+    /// Index events by author for per-author counts and exports
+    pub fn with_authors(mut self, authors: AuthorIndex) -> Self {
+        self.authors = Some(authors);
+        self
+    }
+
+    pub fn authors(&self) -> Option<&AuthorIndex> {
+        self.authors.as_ref()
+    }
+
+    pub fn with_allow_wipe(mut self) -> Self {
+        self.allow_wipe = true;
+        self
+    }
+
+    /// Answer NIP-45 COUNT for the filters the indexes can count exactly
+    pub fn with_count(mut self, stats: ActivityStats) -> Self {
+        self.count = Some(stats);
+        self
+    }
+
+    /// Record saved events by created_at
+    pub fn with_times(mut self, times: TimeIndex) -> Self {
+        self.times = Some(times);
+        self
+    }
+
+    /// Estimated unique authors of every archived event
+    pub fn unique_authors(&self) -> Option<u64> {
+        self.times.as_ref().map(|t| t.sketch().estimate())
+    }
+
+    /// The time index, once it holds every archived event
+    fn complete_times(&self) -> Option<TimeIndex> {
+        self.times.as_ref().filter(|t| t.is_complete()).cloned()
+    }
+
+    /// Event indexes of the archives and every partition
+    fn event_indexes(&self) -> Vec<EventStore> {
+        let mut dbs = vec![self.inner.clone()];
+        dbs.extend(self.partitions.iter().flat_map(|p| p.all()));
+        dbs
+    }
+
+    /// Per-kind counts shared with `/api/stats` when COUNT is enabled
+    pub fn count_stats(&self) -> Option<&ActivityStats> {
+        self.count.as_ref()
+    }
+
+    /// Clear the event id indexes and the lookup indexes, archived events are accepted
+    /// again afterwards; archives are never deleted
+    ///
+    /// Returns the number of entries cleared
+    async fn wipe_indexes(&self) -> Result<u64> {
+        if !self.allow_wipe {
+            bail!("wipe is disabled, set allow_wipe to clear the indexes");
+        }
+        let mut cleared = 0;
+        if let Some(o) = &self.offsets {
+            cleared += o.clear()?;
+        }
+        let (dbs, times, sources, replaceable, authors, seen) = (
+            self.event_indexes(),
+            self.times.clone(),
+            self.sources.clone(),
+            self.replaceable.clone(),
+            self.authors.clone(),
+            self.seen.clone(),
+        );
+        cleared += tokio::task::spawn_blocking(move || {
+            let mut n = 0;
+            for db in dbs {
+                n += db.clear()?;
+            }
+            if let Some(t) = times {
+                n += t.clear()?;
+            }
+            if let Some(s) = sources {
+                n += s.clear()?;
+            }
+            if let Some(r) = replaceable {
+                n += r.clear()?;
+            }
+            if let Some(a) = authors {
+                n += a.clear_all()?;
+            }
+            if let Some(s) = seen {
+                n += s.clear()?;
+            }
+            anyhow::Ok(n)
+        })
+        .await??;
+        self.invalidate_archives().await;
+        warn!("Wiped {} index entries", cleared);
+        Ok(cleared)
+    }
+
+    /// Delete the index entries of events created before `before` from the event id
+    /// indexes and the lookups keyed by id, and the replaceable versions and author counts
+    /// older than it; archives are never deleted
+    ///
+    /// Old events are found through the time index when it is complete, otherwise every
+    /// entry of the id indexes is read. Returns the number of events pruned
+    pub async fn prune_ids_older_than(&self, before: Timestamp) -> Result<u64> {
+        let Some(until) = before.as_secs().checked_sub(1) else {
+            return Ok(0);
+        };
+        let db = self.clone();
+        let pruned = tokio::task::spawn_blocking(move || {
+            let dbs = db.event_indexes();
+            let mut pruned = 0;
+            if let Some(t) = db.complete_times() {
+                loop {
+                    // entries are deleted as they are read, each pass starts from the oldest left
+                    let entries = t
+                        .ids_in_range(0, until)
+                        .take(PRUNE_BATCH)
+                        .collect::<Result<Vec<_>>>()?;
+                    if entries.is_empty() {
+                        break;
+                    }
+                    let ids: Vec<EventId> = entries.iter().map(|(id, _)| *id).collect();
+                    // the id indexes don't record which partition holds an event
+                    for d in &dbs {
+                        d.remove(&ids)?;
+                    }
+                    db.remove_lookups(&entries)?;
+                    pruned += entries.len() as u64;
+                }
+            } else {
+                for d in &dbs {
+                    for chunk in d.list_ids(0, until)?.chunks(PRUNE_BATCH) {
+                        let ids: Vec<EventId> = chunk.iter().map(|(id, _)| *id).collect();
+                        d.remove(&ids)?;
+                        db.remove_lookups(chunk)?;
+                        pruned += chunk.len() as u64;
+                    }
+                }
+            }
+            if let Some(r) = &db.replaceable {
+                let n = r.prune(before)?;
+                info!("Pruned {} replaceable versions", n);
+            }
+            if let (Some(a), Some(day)) = (
+                &db.authors,
+                DateTime::from_timestamp(before.as_secs() as i64, 0),
+            ) {
+                let n = a.prune(day.date_naive())?;
+                info!("Pruned {} author index entries", n);
+            }
+            anyhow::Ok(pruned)
+        })
+        .await??;
+        info!("Pruned {} events created before {}", pruned, before);
+        Ok(pruned)
+    }
+
+    /// Delete the pruned `entries` from the time index and the lookups keyed by id
+    fn remove_lookups(&self, entries: &[(EventId, Timestamp)]) -> Result<()> {
+        let keys: Vec<[u8; 32]> = entries.iter().map(|(id, _)| id.to_bytes()).collect();
+        if let Some(t) = &self.times {
+            let entries: Vec<(u64, [u8; 32])> = entries
+                .iter()
+                .map(|(id, t)| (t.as_secs(), id.to_bytes()))
+                .collect();
+            t.delete_batch(&entries)?;
+        }
+        if let Some(o) = &self.offsets {
+            o.remove(&keys)?;
+        }
+        if let Some(s) = &self.sources {
+            s.remove(&keys)?;
+        }
+        if let Some(s) = &self.seen {
+            s.remove(&keys)?;
+        }
+        Ok(())
+    }
+
+    pub fn with_blooms(mut self, blooms: BloomFilters) -> Self {
+        self.blooms = Some(blooms);
+        self
+    }
+
+    pub fn blooms(&self) -> Option<&BloomFilters> {
+        self.blooms.as_ref()
+    }
+
+    /// Read the event from the active archives and the finalized ones whose bloom filter
+    /// may contain it, archives without a bloom filter are skipped
+    async fn find_by_bloom(&self, blooms: BloomFilters, id: EventId) -> Result<Option<Event>> {
+        if !matches!(self.find_id(&id).await?, DatabaseEventStatus::Saved) {
+            return Ok(None);
+        }
+        let files = self.list_archives().await?;
+        tokio::task::spawn_blocking(move || {
+            let key = id.to_bytes();
+            let candidates: Vec<PathBuf> = files
+                .iter()
+                .filter(|f| {
+                    is_active(&f.path)
+                        || blooms.get(&f.path).is_some_and(|b| b.ids.may_contain(&key))
+                })
+                .map(|f| f.path.clone())
+                .collect();
+            find_event(&candidates, &id)
+        })
+        .await?
+    }
+
+    /// Archived events of `pubkey`, None without `index_authors`
+    pub fn events_by_author_count(&self, pubkey: &PublicKey) -> Result<Option<u64>> {
+        self.authors.as_ref().map(|a| a.count(pubkey)).transpose()
+    }
+
+    /// Relays that delivered an event, None when sources aren't tracked
+    pub fn event_sources(&self, id: &EventId) -> Result<Option<Vec<String>>> {
+        self.sources.as_ref().map(|s| s.get(id)).transpose()
+    }
+
+    /// Count already archived events that are delivered again
+    pub fn with_seen(mut self, seen: EventSeen) -> Self {
+        self.seen = Some(seen);
+        self
+    }
+
+    /// Deliveries of `id` after it was archived, None when sightings aren't tracked or it
+    /// wasn't delivered again
+    pub fn seen_info(&self, id: &EventId) -> Option<SeenInfo> {
+        self.seen.as_ref().and_then(|s| s.get(id))
+    }
+
+    pub fn seen_totals(&self) -> Option<SeenTotals> {
+        self.seen.as_ref().map(|s| s.totals())
+    }
+
+    /// Is `id` in the event index of the archives or a partition
+    fn find_id<'a>(
+        &'a self,
+        event_id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<DatabaseEventStatus, DatabaseError>> {
+        Box::pin(async move {
+            for db in self.event_indexes() {
+                if db
+                    .contains(event_id)
+                    .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))?
+                {
+                    return Ok(DatabaseEventStatus::Saved);
+                }
+            }
+            Ok(DatabaseEventStatus::NotExistent)
+        })
+    }
+
+    /// Write events into per-kind subdirectories
+    pub fn with_partitions(mut self, partitions: Partitions) -> Self {
+        self.partitions = Some(partitions);
+        self
+    }
+
+    /// Hold back checksums of zstd archives until they are rewritten with a seek table
+    pub fn with_seekable(mut self, seekable: Seekable) -> Self {
+        self.seekable = Some(seekable);
+        self
+    }
+
+    /// Hold back checksums of zstd archives until they are converted to another format
+    pub fn with_recompress(mut self, recompress: Recompress) -> Self {
+        self.recompress = Some(recompress);
+        self
+    }
+
+    /// Archive waiting to be rewritten in the seekable format, converted or moved into
+    /// the nested layout
+    pub async fn is_pending(&self, path: &Path) -> bool {
+        self.layout.awaits_move(path) || self.awaits_rewrite(path).await
+    }
+
+    /// Archive waiting to be rewritten in the seekable format or converted
+    pub async fn awaits_rewrite(&self, path: &Path) -> bool {
+        if let Some(s) = &self.seekable
+            && s.is_pending(path).await
+        {
+            return true;
+        }
+        match &self.recompress {
+            Some(r) => r.is_pending(path).await,
+            None => false,
+        }
+    }
+
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Plaintext archive that will be encrypted, nothing is published for it
+    pub fn awaits_encryption(&self, path: &Path) -> bool {
+        self.encryption.as_ref().is_some_and(|e| e.is_pending(path))
+    }
+
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        self.partitions.is_some()
+    }
+
+    /// Keep events breaking the limits out of the archive
+    pub fn with_limits(mut self, limits: EventLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    pub fn limits(&self) -> Option<&EventLimits> {
+        self.limits.as_ref()
+    }
+
+    pub fn with_trusted_peers(mut self, peers: TrustedPeers) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    pub fn trusted_peers(&self) -> Option<&TrustedPeers> {
+        self.peers.as_ref()
+    }
+
+    /// Keep events that failed to save in a dead-letter file
+    pub fn with_dead_letters(mut self, dead_letters: DeadLetters) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    pub fn dead_letters(&self) -> Option<&DeadLetters> {
+        self.dead_letters.as_ref()
+    }
+
+    fn dead_letter(&self, event: &Event, error: &DatabaseError) {
+        if let Some(d) = &self.dead_letters {
+            d.record(event, error);
+        }
+    }
+
+    /// Publish newly saved events to the firehose
+    pub fn with_live(mut self, live: broadcast::Sender<Event>) -> Self {
+        self.live = Some(live);
+        self
+    }
+
+    /// Don't archive ephemeral events (kinds 20000-29999), they still reach the firehose
+    pub fn with_skip_ephemeral(mut self) -> Self {
+        self.skip_ephemeral = true;
+        self
+    }
+
+    /// Count saved events in `status`
+    pub fn with_status(mut self, status: LiveStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Send an event that isn't archived to the firehose
+    pub fn publish_live(&self, event: &Event) {
+        if let Some(l) = &self.live {
+            // no receivers is not an error
+            let _ = l.send(event.clone());
+        }
+    }
+
+    /// Reject replaceable events older than the stored latest version
+    pub fn with_replaceable(mut self, index: ReplaceableIndex) -> Self {
+        self.replaceable = Some(index);
+        self
+    }
+
+    pub fn replaceable(&self) -> Option<&ReplaceableIndex> {
+        self.replaceable.as_ref()
+    }
+
+    pub fn out_dir(&self) -> &Path {
+        &self.out_dir
+    }
+
+    /// List event archives, skipping sidecar files like checksums
+    ///
+    /// The listing is cached for [ARCHIVE_CACHE_TTL] or until the UTC day changes,
+    /// which is when the writer rotates to a new file
+    pub async fn list_archives(&self) -> Result<Arc<Vec<ArchiveFile>>> {
+        let day = Timestamp::now().as_secs() / 86_400;
+        let mut cache = self.archives.lock().await;
+        if let Some(c) = cache.as_ref()
+            && c.day == day
+            && c.loaded.elapsed() < ARCHIVE_CACHE_TTL
+        {
+            return Ok(c.files.clone());
+        }
+
+        let mut files = list_dir(&self.out_dir).await?;
+        for p in self.partitions.iter().flat_map(|p| p.names()) {
+            files.extend(list_dir(&self.out_dir.join(p)).await?);
+        }
+        let dir = self.out_dir.clone();
+        for p in tokio::task::spawn_blocking(move || layout::nested_archives(&dir)).await?? {
+            match archive_file(&p) {
+                Ok(f) => files.push(f),
+                Err(e) => warn!("Failed to read {}: {}", p.display(), e),
+            }
+        }
+        let files: Arc<Vec<ArchiveFile>> =
+            Arc::new(files.into_iter().filter(|f| is_archive(&f.path)).collect());
+        *cache = Some(ArchiveCache {
+            files: files.clone(),
+            loaded: Instant::now(),
+            day,
+        });
+        Ok(files)
+    }
+
+    /// Drop the cached archive listing after files were added
+    pub async fn invalidate_archives(&self) {
+        self.archives.lock().await.take();
+        if let Some(p) = &self.published {
+            p.wake();
+        }
+    }
+
+    /// SHA-256 of a finalized archive, cached in a `.sha256` sidecar file
+    ///
+    /// Returns None for the active (uncompressed) file since it is still being written,
+    /// and for archives not yet rewritten or converted
+    pub async fn checksum(&self, file: &ArchiveFile) -> Result<Option<String>> {
+        if is_active(&file.path) {
+            return Ok(None);
+        }
+        let sidecar = sidecar_path(&file.path, "sha256");
+        if let Ok(s) = tokio::fs::read_to_string(&sidecar).await
+            && let Some(hash) = s.split_whitespace().next()
+            && is_fresh(&sidecar, &file.path).await
+        {
+            return Ok(Some(hash.to_string()));
+        }
+        if self.is_pending(&file.path).await || self.awaits_encryption(&file.path) {
+            return Ok(None);
+        }
+
+        // the file is read anyway, build a missing torrent in the same pass
+        let torrent = self.torrents.is_some()
+            && !is_fresh(&TorrentMaker::path_for(&file.path), &file.path).await;
+        Ok(Some(self.hash_archive(file, torrent).await?))
+    }
+
+    /// Write missing checksum and torrent sidecars for a finalized archive
+    ///
+    /// Returns true when a torrent was generated
+    pub async fn finalize(&self, file: &ArchiveFile) -> Result<bool> {
+        if self.torrents.is_none()
+            || is_active(&file.path)
+            || self.is_pending(&file.path).await
+            || self.awaits_encryption(&file.path)
+        {
+            return Ok(false);
+        }
+        if is_fresh(&TorrentMaker::path_for(&file.path), &file.path).await {
+            self.checksum(file).await?;
+            return Ok(false);
+        }
+        self.hash_archive(file, true).await?;
+        Ok(true)
+    }
+
+    /// Path of an archive relative to `out_dir`, as used in urls
+    pub fn archive_name(&self, path: &Path) -> String {
+        path.strip_prefix(&self.out_dir)
+            .unwrap_or(path)
+            .components()
+            .filter_map(|c| c.as_os_str().to_str())
+            .join("/")
+    }
+
+    /// Hash an archive in one pass, writing the `.sha256` and optionally `.torrent` sidecars
+    async fn hash_archive(&self, file: &ArchiveFile, torrent: bool) -> Result<String> {
+        let name = file
+            .path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let torrents = self.torrents.as_ref().filter(|_| torrent);
+        let (hash, pieces, len) = hash_file(
+            file.path.clone(),
+            torrents.map(|t| PieceHasher::new(t.piece_len())),
+        )
+        .await?;
+        // same format as sha256sum so `sha256sum -c` works on the sidecar
+        tokio::fs::write(
+            sidecar_path(&file.path, "sha256"),
+            format!("{}  {}\n", hash, name),
+        )
+        .await?;
+        if let (Some(t), Some(pieces)) = (torrents, pieces) {
+            tokio::fs::write(
+                TorrentMaker::path_for(&file.path),
+                t.build(name, &self.archive_name(&file.path), len, &pieces),
+            )
+            .await?;
+        }
+        Ok(hash)
+    }
+
+    /// Magnet link for a finalized archive once its torrent was generated
+    pub async fn magnet(&self, file: &ArchiveFile) -> Option<String> {
+        self.torrents
+            .as_ref()?
+            .magnet(&file.path, &self.archive_name(&file.path))
+            .await
+    }
+
+    /// Archive or sidecar at `/name` or `/<partition>/name`, either in a `YYYY/MM`
+    /// directory; with the nested layout flat paths of moved archives still resolve
+    pub fn get_file(&self, path: &str) -> Result<ArchiveFile> {
+        let rel = path.trim_start_matches('/');
+        if let Some(d) = &self.deletions
+            && let Some(name) = rel
+                .strip_prefix(DELETIONS_DIR)
+                .and_then(|n| n.strip_prefix('/'))
+        {
+            return d.get_file(name);
+        }
+        let depth = Path::new(&layout::flat_name(rel)).components().count();
+        if !Path::new(rel)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+            || depth == 0
+            || depth > if self.partitions.is_some() { 2 } else { 1 }
+        {
+            bail!("Invalid archive path");
+        }
+        let flat = self.out_dir.join(rel);
+        archive_file(&flat).or_else(|e| {
+            let placed = self.layout.place(&flat);
+            if placed != flat {
+                archive_file(&placed)
+            } else {
+                Err(e)
+            }
+        })
+    }
+
+    /// Can the archive at `path` hold events of `kinds`, false only for archives of a
+    /// partition that none of them are written to
+    pub fn may_hold_kinds(&self, path: &Path, kinds: &KindSet) -> bool {
+        let Ok(rel) = path.strip_prefix(&self.out_dir) else {
+            return true;
+        };
+        let parts: Vec<&str> = rel.iter().filter_map(|c| c.to_str()).collect();
+        if parts.first() == Some(&DELETIONS_DIR) {
+            return kinds.contains(Kind::EventDeletion);
+        }
+        let Some(partitions) = &self.partitions else {
+            return true;
+        };
+        match parts[..] {
+            [p, _, ..] if !layout::is_date_dir(p) => partitions.may_hold(p, kinds),
+            // archives written before partitioning was enabled
+            _ => true,
+        }
+    }
+
+    /// Bytes on disk of the event id index of the archives and every partition
+    pub fn index_size(&self) -> u64 {
+        self.event_indexes().iter().map(|s| s.index_size()).sum()
+    }
+
+    /// Returns once the events saved so far were written to the archives
+    pub async fn flush(&self) -> Result<()> {
+        for db in self.event_indexes() {
+            db.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub fn count_keys(&self) -> Result<u64> {
+        Ok(self.inner.count_keys()?
+            + self
+                .partitions
+                .iter()
+                .flat_map(|p| p.all())
+                .map(|p| p.count_keys())
+                .sum::<Result<u64>>()?)
+    }
+}
+
+impl NostrDatabase for ArchiveDatabase {
+    fn backend(&self) -> Backend {
+        Backend::Custom("JsonFileDatabase".to_owned())
+    }
+
+    fn save_event<'a>(
+        &'a self,
+        event: &'a Event,
+    ) -> BoxedFuture<'a, Result<SaveEventStatus, DatabaseError>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            if self.is_paused() {
+                return Ok(SaveEventStatus::Rejected(RejectedReason::Other));
+            }
+
+            if let Some(k) = &self.kinds
+                && !k.contains(event.kind)
+            {
+                return Ok(SaveEventStatus::Rejected(RejectedReason::Other));
+            }
+
+            if let Some(k) = &self.blocked_kinds
+                && k.contains(event.kind)
+            {
+                return Ok(SaveEventStatus::Rejected(RejectedReason::Other));
+            }
+
+            if self.reject_expired && ExpirationPolicy::is_expired(event) {
+                return Ok(SaveEventStatus::Rejected(RejectedReason::Expired));
+            }
+
+            if let Some(l) = &self.limits
+                && let Some(reason) = l.check(event)
+            {
+                l.quarantine(event, &reason).await;
+                return Ok(SaveEventStatus::Rejected(RejectedReason::Other));
+            }
+
+            if self.skip_ephemeral && event.kind.is_ephemeral() {
+                self.publish_live(event);
+                return Ok(SaveEventStatus::Rejected(RejectedReason::Ephemeral));
+            }
+
+            if let Some(r) = self
+                .replaceable
+                .as_ref()
+                .filter(|_| ReplaceableIndex::is_tracked(event))
+            {
+                match r.is_replaced(event) {
+                    Ok(true) => return Ok(SaveEventStatus::Rejected(RejectedReason::Replaced)),
+                    Ok(false) => {}
+                    Err(e) => warn!("Failed to check replaceable index: {}", e),
+                }
+            }
+
+            let (partition, inner) = match &self.partitions {
+                Some(p) => {
+                    let (name, db) = p
+                        .get(event.kind)
+                        .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))
+                        .inspect_err(|e| self.dead_letter(event, e))?;
+                    (Some(name), db)
+                }
+                None => (None, self.inner.clone()),
+            };
+            // the same event from two sources at once, only the first is written
+            let write_started = Instant::now();
+            let written = inner
+                .save(event)
+                .await
+                .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))
+                .inspect_err(|e| self.dead_letter(event, e))?;
+            if let (Some(w), Some(o)) = (written, &self.offsets) {
+                o.record(event, partition.as_deref(), w);
+            }
+            let status = match written {
+                Some(_) => SaveEventStatus::Success,
+                None => SaveEventStatus::Rejected(RejectedReason::Duplicate),
+            };
+            if let SaveEventStatus::Success = status {
+                metrics::WRITE_LATENCY.record_duration(write_started.elapsed());
+                metrics::record_event_size(event);
+            }
+            if let (Some(w), Some(a)) = (written, &self.authors) {
+                a.record(event, partition.as_deref(), w.day);
+            }
+            if let (SaveEventStatus::Success, Some(t)) = (&status, &self.times) {
+                t.record(event);
+            }
+            if let (SaveEventStatus::Success, Some(r)) = (&status, &self.replaceable)
+                && ReplaceableIndex::is_tracked(event)
+                && let Err(e) = r.update(event)
+            {
+                warn!("Failed to update replaceable index: {}", e);
+            }
+            if let SaveEventStatus::Success = status {
+                self.publish_live(event);
+            }
+            if let (SaveEventStatus::Success, Some(s)) = (&status, &self.status) {
+                s.saved();
+            }
+            if let (SaveEventStatus::Success, Some(d)) = (&status, &self.deletions)
+                && event.kind == Kind::EventDeletion
+            {
+                d.record(event).await;
+            }
+            if let (SaveEventStatus::Success, Some(p)) = (&status, &self.peers)
+                && let Ok(ip) = REMOTE_ADDR.try_with(|a| a.ip())
+            {
+                p.record(ip);
+            }
+            // delivered twice before either was saved
+            if let (SaveEventStatus::Rejected(RejectedReason::Duplicate), Some(s)) =
+                (&status, &self.seen)
+            {
+                s.record(&event.id);
+            }
+            if let SaveEventStatus::Success = status {
+                metrics::SAVE_LATENCY.record_duration(started.elapsed());
+            }
+            if let Some(w) = written
+                && log_enabled!(Level::Debug)
+            {
+                let file = match &partition {
+                    Some(p) => format!("{}/events_{}.jsonl", p, w.day),
+                    None => format!("events_{}.jsonl", w.day),
+                };
+                match REMOTE_ADDR.try_with(|a| a.ip()) {
+                    Ok(ip) => debug!(id:% = event.id, file:% = file, ip:% = ip; "Saved event"),
+                    Err(_) => debug!(id:% = event.id, file:% = file; "Saved event"),
+                }
+            }
+            Ok(status)
+        })
+    }
+
+    fn check_id<'a>(
+        &'a self,
+        event_id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<DatabaseEventStatus, DatabaseError>> {
+        let Some(seen) = &self.seen else {
+            return self.find_id(event_id);
+        };
+        Box::pin(async move {
+            let status = self.find_id(event_id).await?;
+            // the relay pool and the relay check every event they receive before saving it
+            if let DatabaseEventStatus::Saved = status {
+                seen.record(event_id);
+            }
+            Ok(status)
+        })
+    }
+
+    fn event_by_id<'a>(
+        &'a self,
+        event_id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<Option<Event>, DatabaseError>> {
+        let (offsets, blooms) = (self.offsets.clone(), self.blooms.clone());
+        let id = *event_id;
+        Box::pin(async move {
+            if let Some(offsets) = offsets {
+                let found = tokio::task::spawn_blocking(move || offsets.read(&id))
+                    .await
+                    .map_err(DatabaseError::backend)?
+                    .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))?;
+                if found.is_some() {
+                    return Ok(found);
+                }
+            }
+            let Some(blooms) = blooms else {
+                return Ok(None);
+            };
+            self.find_by_bloom(blooms, id)
+                .await
+                .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))
+        })
+    }
+
+    fn count(&self, filter: Filter) -> BoxedFuture<'_, Result<usize, DatabaseError>> {
+        Box::pin(async move {
+            let Some(stats) = &self.count else {
+                return Err(DatabaseError::NotSupported);
+            };
+            let n = match filter {
+                // limit doesn't apply to counts
+                Filter {
+                    ids: None,
+                    authors: None,
+                    kinds: None,
+                    search: None,
+                    since: None,
+                    until: None,
+                    generic_tags,
+                    ..
+                } if generic_tags.is_empty() => self
+                    .count_keys()
+                    .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))?,
+                Filter {
+                    ids: None,
+                    authors: None,
+                    kinds: Some(kinds),
+                    search: None,
+                    since: None,
+                    until: None,
+                    generic_tags,
+                    ..
+                } if generic_tags.is_empty() => {
+                    let kinds = kinds.iter().map(|k| k.as_u16()).collect();
+                    stats
+                        .kind_total(self, &kinds)
+                        .await
+                        .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))?
+                }
+                Filter {
+                    ids: None,
+                    authors: Some(authors),
+                    kinds: None,
+                    search: None,
+                    since: None,
+                    until: None,
+                    generic_tags,
+                    ..
+                } if generic_tags.is_empty() && self.authors.is_some() => {
+                    let index = self.authors.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let index = index.as_ref().expect("author index");
+                        authors.iter().map(|a| index.count(a)).sum::<Result<u64>>()
+                    })
+                    .await
+                    .map_err(DatabaseError::backend)?
+                    .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))?
+                }
+                Filter {
+                    ids: None,
+                    authors: None,
+                    kinds: None,
+                    search: None,
+                    since,
+                    until,
+                    generic_tags,
+                    ..
+                } if generic_tags.is_empty() => {
+                    let (times, dbs) = (self.complete_times(), self.event_indexes());
+                    let since = since.map(|t| t.as_secs()).unwrap_or(0);
+                    let until = until.map(|t| t.as_secs()).unwrap_or(u64::MAX);
+                    tokio::task::spawn_blocking(move || match times {
+                        Some(t) => t
+                            .ids_in_range(since, until)
+                            .try_fold(0, |n, x| x.map(|_| n + 1)),
+                        // the event indexes aren't ordered by time, every entry is read
+                        None => dbs
+                            .iter()
+                            .map(|db| db.list_ids(since, until).map(|ids| ids.len() as u64))
+                            .sum(),
+                    })
+                    .await
+                    .map_err(DatabaseError::backend)?
+                    .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))?
+                }
+                _ => return Err(DatabaseError::NotSupported),
+            };
+            Ok(n as usize)
+        })
+    }
+
+    fn query(&self, filter: Filter) -> BoxedFuture<'_, Result<Events, DatabaseError>> {
+        Box::pin(async move { Ok(Events::new(&filter)) })
+    }
+
+    fn negentropy_items(
+        &self,
+        filter: Filter,
+    ) -> BoxedFuture<'_, Result<Vec<(EventId, Timestamp)>, DatabaseError>> {
+        let (times, dbs) = (self.complete_times(), self.event_indexes());
+        Box::pin(async move {
+            if let Ok(addr) = REMOTE_ADDR.try_with(|a| *a) {
+                match &self.negentropy {
+                    Some(limit) if limit.check(addr.ip()) => {}
+                    Some(_) => {
+                        debug!("Negentropy rate limited for {}", addr);
+                        return Err(DatabaseError::backend(Error::other("rate limited")));
+                    }
+                    None => return Err(DatabaseError::NotSupported),
+                }
+            }
+
+            let since = filter.since.map(|t| t.as_secs()).unwrap_or(0);
+            let until = filter.until.map(|t| t.as_secs()).unwrap_or(u64::MAX);
+            tokio::task::spawn_blocking(move || match times {
+                Some(t) => t.ids_in_range(since, until).collect(),
+                None => dbs.iter().try_fold(Vec::new(), |mut items, db| {
+                    items.extend(db.list_ids(since, until)?);
+                    Ok(items)
+                }),
+            })
+            .await
+            .map_err(DatabaseError::backend)?
+            .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))
+        })
+    }
+
+    fn delete(&self, _filter: Filter) -> BoxedFuture<'_, Result<(), DatabaseError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn wipe(&self) -> BoxedFuture<'_, Result<(), DatabaseError>> {
+        Box::pin(async move {
+            self.wipe_indexes()
+                .await
+                .map(|_| ())
+                .map_err(|e| DatabaseError::Backend(e.into_boxed_dyn_error()))
+        })
+    }
+}
+
+/// Is this path an event archive (`*.jsonl` optionally compressed)
+pub fn is_archive(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    if name.starts_with('.') {
+        return false;
+    }
+    let name = name
+        .strip_suffix(&format!(".{}", ENCRYPTED_EXT))
+        .unwrap_or(name);
+    let name = ["gz", "zst", "bz2"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(&format!(".{}", ext)))
+        .unwrap_or(name);
+    name.ends_with(".jsonl")
+}
+
+/// Open an archive for reading lines, decompressing by file extension
+pub fn open_archive(path: &Path) -> Result<Box<dyn BufRead + Send>> {
+    decode_archive(path, std::fs::File::open(path)?)
+}
+
+/// Decompress `input` read from the archive at `path`
+pub fn decode_archive<R: Read + Send + 'static>(
+    path: &Path,
+    input: R,
+) -> Result<Box<dyn BufRead + Send>> {
+    decode(path, input, true)
+}
+
+/// [decode_archive] returning zstd decode errors instead of ending the stream
+pub fn decode_archive_strict<R: Read + Send + 'static>(
+    path: &Path,
+    input: R,
+) -> Result<Box<dyn BufRead + Send>> {
+    decode(path, input, false)
+}
+
+fn decode<R: Read + Send + 'static>(
+    path: &Path,
+    input: R,
+    truncated_eof: bool,
+) -> Result<Box<dyn BufRead + Send>> {
+    // encrypted archives are decompressed by the extension before `.enc`
+    let plain;
+    let (path, input): (&Path, Box<dyn Read + Send>) = if is_encrypted(path) {
+        plain = path.with_extension("");
+        (&plain, Box::new(decrypt(path, input)?))
+    } else {
+        (path, Box::new(input))
+    };
+    Ok(match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(input))),
+        Some("zst") if truncated_eof => Box::new(BufReader::new(TruncatedEof {
+            inner: zstd::Decoder::new(input)?,
+            path: path.to_path_buf(),
+        })),
+        Some("zst") => Box::new(BufReader::new(zstd::Decoder::new(input)?)),
+        Some("bz2") => Box::new(BufReader::new(bzip2::read::MultiBzDecoder::new(input))),
+        _ => Box::new(BufReader::new(input)),
+    })
+}
+
+/// Ends the stream at a decode error instead of failing, a zstd file still being
+/// written (or cut short by a crash) is read up to its last complete block
+struct TruncatedEof<R> {
+    inner: R,
+    path: PathBuf,
+}
+
+impl<R: Read> Read for TruncatedEof<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.inner.read(buf) {
+            Err(e) if e.kind() != std::io::ErrorKind::Interrupted => {
+                warn!(
+                    "{} is truncated, reading stopped: {}",
+                    self.path.display(),
+                    e
+                );
+                Ok(0)
+            }
+            r => r,
+        }
+    }
+}
+
+/// Hex SHA-256 of a file, hashed on a blocking thread
+pub async fn sha256_file(path: PathBuf) -> Result<String> {
+    Ok(hash_file(path, None).await?.0)
+}
+
+/// Hex SHA-256 of a file, hashed on the calling thread
+pub fn sha256_file_blocking(path: &Path) -> Result<String> {
+    Ok(hash_file_blocking(path, None)?.0)
+}
+
+/// Hex SHA-256, torrent piece hashes and length of a file in a single read
+async fn hash_file(
+    path: PathBuf,
+    pieces: Option<PieceHasher>,
+) -> Result<(String, Option<Vec<u8>>, u64)> {
+    tokio::task::spawn_blocking(move || hash_file_blocking(&path, pieces)).await?
+}
+
+fn hash_file_blocking(
+    path: &Path,
+    mut pieces: Option<PieceHasher>,
+) -> Result<(String, Option<Vec<u8>>, u64)> {
+    let mut f = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut len = 0u64;
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        if let Some(p) = pieces.as_mut() {
+            p.update(&buf[..n]);
+        }
+        len += n as u64;
+    }
+    Ok((
+        hex::encode(hasher.finalize()),
+        pieces.map(|p| p.finish()),
+        len,
+    ))
+}
+
+/// The archive currently being written to, plain or with `write_compressed`, or one the
+/// writer is still compressing
+///
+/// Plain jsonl archives of past days are finalized when archives aren't compressed
+pub fn is_active(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    let today = format!("events_{}", Utc::now().format("%Y%m%d"));
+    match name.strip_suffix(".jsonl") {
+        Some(stem) => {
+            stem == today
+                || path.with_extension("jsonl.zst").exists()
+                || path.with_extension("jsonl.zst.tmp").exists()
+        }
+        None => name.strip_suffix(".jsonl.zst") == Some(today.as_str()),
+    }
+}
+
+/// [is_published] on the calling thread
+pub fn is_published_blocking(path: &Path) -> bool {
+    [
+        sidecar_path(path, "sha256"),
+        sidecar_path(path, "attestation"),
+        TorrentMaker::path_for(path),
+    ]
+    .iter()
+    .any(|p| p.try_exists().unwrap_or(true))
+}
+
+/// Has a checksum, torrent or attestation been written for the archive,
+/// its bytes must not change after that
+pub async fn is_published(path: &Path) -> bool {
+    for p in [
+        sidecar_path(path, "sha256"),
+        sidecar_path(path, "attestation"),
+        TorrentMaker::path_for(path),
+    ] {
+        if tokio::fs::try_exists(&p).await.unwrap_or(true) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Content type an archive or sidecar is served with
+pub fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("torrent") => "application/x-bittorrent",
+        Some("gz") => "application/gzip",
+        Some("zst") => "application/zstd",
+        Some("bz2") => "application/x-bzip2",
+        Some("jsonl") => "application/x-ndjson",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Archive or sidecar at `path`, dated by its name
+fn archive_file(path: &Path) -> Result<ArchiveFile> {
+    if !path.is_file() {
+        bail!("No such file or directory");
+    }
+    let meta = path.metadata()?;
+    let Some(day) = archive_day(path) else {
+        bail!("Filename invalid");
+    };
+    Ok(ArchiveFile {
+        path: path.to_path_buf(),
+        size: meta.len(),
+        created: meta.created()?.into(),
+        timestamp: day.and_time(NaiveTime::MIN).and_utc(),
+    })
+}
+
+/// Files directly in `dir`, dated by their name or else when they were created
+pub async fn list_dir(dir: &Path) -> Result<Vec<ArchiveFile>> {
+    let mut list = tokio::fs::read_dir(dir).await?;
+    let mut files = Vec::new();
+    while let Some(entry) = list.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            continue;
+        }
+        let meta = entry.metadata().await?;
+        let created: DateTime<Utc> = meta.created()?.into();
+        let path = entry.path();
+        files.push(ArchiveFile {
+            timestamp: archive_day(&path)
+                .map(|d| d.and_time(NaiveTime::MIN).and_utc())
+                .unwrap_or(created),
+            path,
+            size: meta.len(),
+            created,
+        });
+    }
+    Ok(files)
+}
+
+/// `<archive>.<ext>` next to the archive
+pub fn sidecar_path(path: &Path, ext: &str) -> PathBuf {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}.{}", name, ext))
+}
+
+/// Sidecar exists and was written after the archive last changed
+pub async fn is_fresh(sidecar: &Path, archive: &Path) -> bool {
+    match (
+        tokio::fs::metadata(sidecar)
+            .await
+            .and_then(|m| m.modified()),
+        tokio::fs::metadata(archive)
+            .await
+            .and_then(|m| m.modified()),
+    ) {
+        (Ok(s), Ok(a)) => s >= a,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexBackend;
+    use crate::test_util::{TempDir, event, lines};
+    use crate::writer::WriterOptions;
+    use nostr_sdk::{EventBuilder, JsonUtil, Keys};
+    use std::collections::{HashMap, HashSet};
+    use tokio::task::JoinSet;
+
+    fn open(dir: &Path) -> ArchiveDatabase {
+        ArchiveDatabase::new(
+            EventStore::open(
+                dir.to_path_buf(),
+                IndexBackend::default(),
+                WriterOptions::default(),
+            )
+            .unwrap(),
+            dir.to_path_buf(),
+        )
+    }
+
+    fn today(dir: &Path) -> PathBuf {
+        dir.join(format!("events_{}.jsonl", Utc::now().format("%Y%m%d")))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_saves_are_all_written() {
+        let dir = TempDir::new();
+        let db = open(dir.path());
+        let shared = event(1, "everyone");
+        let mut tasks = JoinSet::new();
+        for t in 0..48 {
+            let (db, shared) = (db.clone(), shared.clone());
+            tasks.spawn(async move {
+                let mut saved = 0;
+                for i in 0..50 {
+                    let e = event(1, &format!("{} {}", t, i));
+                    assert!(matches!(
+                        db.save_event(&e).await,
+                        Ok(SaveEventStatus::Success)
+                    ));
+                    saved += 1;
+                    if i == t {
+                        db.save_event(&shared).await.unwrap();
+                    }
+                }
+                saved
+            });
+        }
+        let mut saved = tasks.join_all().await.into_iter().sum::<usize>();
+        saved += 1;
+        db.flush().await.unwrap();
+
+        let lines = lines(&today(dir.path()));
+        assert_eq!(lines.len(), saved);
+        let ids: HashSet<EventId> = lines
+            .iter()
+            .map(|l| Event::from_json(l).unwrap().id)
+            .collect();
+        assert_eq!(ids.len(), saved);
+        assert_eq!(db.count_keys().unwrap(), saved as u64);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn same_event_from_many_sources_is_written_once() {
+        for backend in [IndexBackend::Rocksdb, IndexBackend::Redb] {
+            let dir = TempDir::new();
+            let db = ArchiveDatabase::new(
+                EventStore::open(dir.path().to_path_buf(), backend, WriterOptions::default())
+                    .unwrap(),
+                dir.path().to_path_buf(),
+            );
+            let e = event(1, "from every relay");
+            let start = Arc::new(tokio::sync::Barrier::new(32));
+            let mut tasks = JoinSet::new();
+            for _ in 0..32 {
+                let (db, e, start) = (db.clone(), e.clone(), start.clone());
+                tasks.spawn(async move {
+                    start.wait().await;
+                    db.save_event(&e).await.unwrap()
+                });
+            }
+            let statuses = tasks.join_all().await;
+            db.flush().await.unwrap();
+
+            let saved = statuses
+                .iter()
+                .filter(|s| matches!(s, SaveEventStatus::Success))
+                .count();
+            assert_eq!(saved, 1, "{:?}", backend);
+            assert!(statuses.iter().all(|s| matches!(
+                s,
+                SaveEventStatus::Success | SaveEventStatus::Rejected(RejectedReason::Duplicate)
+            )));
+            assert_eq!(lines(&today(dir.path())), vec![e.as_json()]);
+            assert_eq!(db.count_keys().unwrap(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn wipe_clears_lookups_and_the_id_index() {
+        let dir = TempDir::new();
+        let offsets =
+            EventOffsets::open(&dir.path().join("offsets"), dir.path().to_path_buf()).unwrap();
+        let db = open(dir.path()).with_offsets(offsets.clone());
+        let events: Vec<Event> = (0..5).map(|i| event(1, &i.to_string())).collect();
+        for e in &events {
+            db.save_event(e).await.unwrap();
+        }
+        db.flush().await.unwrap();
+        assert_eq!(
+            offsets.read(&events[0].id).unwrap(),
+            Some(events[0].clone())
+        );
+
+        // a caller wiping by mistake
+        assert!(db.wipe().await.is_err());
+        assert!(offsets.read(&events[0].id).unwrap().is_some());
+        assert_eq!(db.count_keys().unwrap(), 5);
+
+        let db = db.with_allow_wipe();
+        assert_eq!(db.wipe_indexes().await.unwrap(), 10);
+        assert!(offsets.read(&events[0].id).unwrap().is_none());
+        assert_eq!(db.count_keys().unwrap(), 0);
+        assert_eq!(db.wipe_indexes().await.unwrap(), 0);
+        // archived events are accepted again
+        assert!(matches!(
+            db.save_event(&events[0]).await.unwrap(),
+            SaveEventStatus::Success
+        ));
+    }
+
+    #[tokio::test]
+    async fn prune_drops_old_ids_and_their_lookups() {
+        let dir = TempDir::new();
+        let offsets =
+            EventOffsets::open(&dir.path().join("offsets"), dir.path().to_path_buf()).unwrap();
+        let db = open(dir.path()).with_offsets(offsets.clone());
+        let keys = Keys::generate();
+        let events: Vec<Event> = [100, 200, 300, 5000, 6000]
+            .iter()
+            .map(|t| {
+                EventBuilder::text_note(t.to_string())
+                    .custom_created_at(Timestamp::from_secs(*t))
+                    .sign_with_keys(&keys)
+                    .unwrap()
+            })
+            .collect();
+        for e in &events {
+            db.save_event(e).await.unwrap();
+        }
+        db.flush().await.unwrap();
+
+        assert_eq!(
+            db.prune_ids_older_than(Timestamp::from_secs(0))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            db.prune_ids_older_than(Timestamp::from_secs(1000))
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(db.count_keys().unwrap(), 2);
+        assert!(offsets.read(&events[0].id).unwrap().is_none());
+        assert_eq!(
+            offsets.read(&events[3].id).unwrap(),
+            Some(events[3].clone())
+        );
+        let mut listed: Vec<EventId> = db
+            .inner
+            .list_ids(0, u64::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        let mut kept = vec![events[3].id, events[4].id];
+        listed.sort();
+        kept.sort();
+        assert_eq!(listed, kept);
+    }
+
+    #[tokio::test]
+    async fn prune_through_the_time_index_drops_old_lookups() {
+        let dir = TempDir::new();
+        let day = 86_400;
+        let times = TimeIndex::open(&dir.path().join("times")).unwrap();
+        times.set_complete().unwrap();
+        let seen = EventSeen::open(&dir.path().join("seen")).unwrap();
+        let replaceable = ReplaceableIndex::open(&dir.path().join("replaceable")).unwrap();
+        let authors =
+            AuthorIndex::open(&dir.path().join("authors"), dir.path().to_path_buf()).unwrap();
+        let db = open(dir.path())
+            .with_times(times.clone())
+            .with_seen(seen.clone())
+            .with_replaceable(replaceable.clone())
+            .with_authors(authors.clone());
+        let keys = Keys::generate();
+        let note = |kind: u16, at: u64| {
+            EventBuilder::new(Kind::from(kind), "")
+                .custom_created_at(Timestamp::from_secs(at))
+                .sign_with_keys(&keys)
+                .unwrap()
+        };
+        let (old, new) = (note(1, 100), note(1, 5 * day));
+        let (old_meta, new_meta) = (note(10002, 100), note(0, 5 * day));
+        for e in [&old, &new, &old_meta, &new_meta] {
+            db.save_event(e).await.unwrap();
+            seen.record(&e.id);
+        }
+        db.flush().await.unwrap();
+        let counts = HashMap::from([(keys.public_key().to_bytes(), 1)]);
+        for name in ["events_19700101.jsonl.zst", "events_19700106.jsonl"] {
+            authors
+                .insert_archive(&dir.path().join(name), &counts)
+                .unwrap();
+        }
+
+        assert_eq!(
+            db.prune_ids_older_than(Timestamp::from_secs(2 * day))
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(db.count_keys().unwrap(), 2);
+        assert!(!db.inner.contains(&old.id).unwrap());
+        assert!(db.inner.contains(&new.id).unwrap());
+        let timed: Vec<EventId> = times
+            .ids_in_range(0, u64::MAX)
+            .map(|x| x.unwrap().0)
+            .collect();
+        assert_eq!(timed.len(), 2);
+        assert!(!timed.contains(&old.id) && !timed.contains(&old_meta.id));
+        assert!(seen.get(&old.id).is_none());
+        assert!(seen.get(&new.id).is_some());
+        let kept: Vec<EventId> = replaceable.iter().map(|v| v.id).collect();
+        assert_eq!(kept, vec![new_meta.id]);
+        // the saves above counted today's archive too
+        let indexed = authors.indexed().unwrap();
+        assert!(!indexed.contains("events_19700101"));
+        assert!(indexed.contains("events_19700106"));
+    }
+
+    #[tokio::test]
+    async fn count_answers_each_filter_shape() {
+        let dir = TempDir::new();
+        let authors =
+            AuthorIndex::open(&dir.path().join("authors"), dir.path().to_path_buf()).unwrap();
+        let db = open(dir.path())
+            .with_count(ActivityStats::default())
+            .with_authors(authors);
+        let alice = Keys::generate();
+        for (kind, created_at, keys) in [
+            (1, 1000, &alice),
+            (1, 2000, &alice),
+            (1, 3000, &Keys::generate()),
+            (7, 4000, &Keys::generate()),
+            (7, 5000, &Keys::generate()),
+            (0, 6000, &Keys::generate()),
+        ] {
+            let e = EventBuilder::new(Kind::from(kind), "")
+                .custom_created_at(Timestamp::from_secs(created_at))
+                .sign_with_keys(keys)
+                .unwrap();
+            assert!(matches!(
+                db.save_event(&e).await.unwrap(),
+                SaveEventStatus::Success
+            ));
+        }
+        db.flush().await.unwrap();
+
+        let count = |f: Filter| {
+            let db = db.clone();
+            async move { db.count(f).await }
+        };
+        assert_eq!(count(Filter::new()).await.unwrap(), 6);
+        // limit doesn't apply
+        assert_eq!(count(Filter::new().limit(1)).await.unwrap(), 6);
+        assert_eq!(count(Filter::new().kind(Kind::from(1))).await.unwrap(), 3);
+        assert_eq!(
+            count(Filter::new().kinds([Kind::from(1), Kind::from(7)]))
+                .await
+                .unwrap(),
+            5
+        );
+        assert_eq!(count(Filter::new().kind(Kind::from(3))).await.unwrap(), 0);
+        assert_eq!(
+            count(Filter::new().author(alice.public_key()))
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            count(Filter::new().since(Timestamp::from_secs(2000)))
+                .await
+                .unwrap(),
+            5
+        );
+        assert_eq!(
+            count(
+                Filter::new()
+                    .since(Timestamp::from_secs(2000))
+                    .until(Timestamp::from_secs(4000))
+            )
+            .await
+            .unwrap(),
+            3
+        );
+        assert_eq!(
+            count(Filter::new().until(Timestamp::from_secs(999)))
+                .await
+                .unwrap(),
+            0
+        );
+
+        // shapes the indexes can't count exactly
+        for f in [
+            Filter::new()
+                .kind(Kind::from(1))
+                .since(Timestamp::from_secs(2000)),
+            Filter::new().kind(Kind::from(1)).author(alice.public_key()),
+            Filter::new().id(EventId::all_zeros()),
+            Filter::new().hashtag("nostr"),
+            Filter::new().search("x"),
+        ] {
+            assert!(matches!(count(f).await, Err(DatabaseError::NotSupported)));
+        }
+
+        // count: false
+        let other = TempDir::new();
+        assert!(matches!(
+            open(other.path()).count(Filter::new()).await,
+            Err(DatabaseError::NotSupported)
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::db::ArchiveDatabase;
+use crate::scan::QUARANTINE_DIR;
+use anyhow::Result;
+use log::{error, info, warn};
+use nostr_sdk::Event;
+use nostr_sdk::prelude::{JsonUtil, NostrDatabase, SaveEventStatus};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Events that failed to save, in the quarantine directory
+pub const DEAD_LETTER_FILE: &str = "deadletter.jsonl";
+
+#[derive(Deserialize, Clone, Default)]
+pub struct DeadLetterSettings {
+    /// Append events that failed to save to `quarantine/deadletter.jsonl`, default true
+    pub enabled: Option<bool>,
+
+    /// Save them again when starting, `nostrhole retry-deadletter` does it on demand
+    pub retry_on_start: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct DeadLetterCounts {
+    pub written: u64,
+    pub recovered: u64,
+    /// Events that couldn't be written to the dead-letter file either
+    pub lost: u64,
+}
+
+/// Result of [DeadLetters::retry]
+#[derive(Default)]
+pub struct RetryReport {
+    pub saved: u64,
+    /// Already archived or rejected by the database now, eg. a kind no longer accepted
+    pub rejected: u64,
+    /// Failed again, they are in the dead-letter file for the next retry
+    pub failed: u64,
+    pub invalid: u64,
+    /// The disk guard paused ingestion, the rest is left for the next retry
+    pub paused: bool,
+}
+
+/// Events the archive failed to save, written with their own file handle so a broken
+/// archive writer doesn't take them along
+#[derive(Clone)]
+pub struct DeadLetters {
+    path: PathBuf,
+    /// Held while appending and while the file is moved away for a retry
+    lock: Arc<Mutex<()>>,
+    written: Arc<AtomicU64>,
+    recovered: Arc<AtomicU64>,
+    lost: Arc<AtomicU64>,
+}
+
+impl DeadLetters {
+    pub fn new(out_dir: &Path) -> Self {
+        Self {
+            path: out_dir.join(QUARANTINE_DIR).join(DEAD_LETTER_FILE),
+            lock: Arc::new(Mutex::new(())),
+            written: Arc::new(AtomicU64::new(0)),
+            recovered: Arc::new(AtomicU64::new(0)),
+            lost: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Events moved away by a retry, read first by the next one if it was interrupted
+    fn retry_path(&self) -> PathBuf {
+        self.path.with_extension("jsonl.retry")
+    }
+
+    /// Append an event that failed to save; a failure here is only logged, it never goes
+    /// back through the database
+    pub fn record(&self, event: &Event, reason: &dyn Display) {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let res = self
+            .path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+            })
+            .and_then(|mut f| f.write_all(format!("{}\n", event.as_json()).as_bytes()));
+        match res {
+            Ok(()) => {
+                self.written.fetch_add(1, Ordering::Relaxed);
+                warn!(id:% = event.id; "Dead-lettered event: {}", reason);
+            }
+            Err(e) => {
+                self.lost.fetch_add(1, Ordering::Relaxed);
+                error!(id:% = event.id; "Lost event, {} and {}: {}", reason, self.path.display(), e);
+            }
+        }
+    }
+
+    pub fn counts(&self) -> DeadLetterCounts {
+        DeadLetterCounts {
+            written: self.written.load(Ordering::Relaxed),
+            recovered: self.recovered.load(Ordering::Relaxed),
+            lost: self.lost.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Move the dead-letter file aside, after the events of an interrupted retry
+    fn take(&self) -> Result<Option<PathBuf>> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let retry = self.retry_path();
+        match (self.path.exists(), retry.exists()) {
+            (false, false) => return Ok(None),
+            (true, false) => std::fs::rename(&self.path, &retry)?,
+            (true, true) => {
+                let mut out = std::fs::OpenOptions::new().append(true).open(&retry)?;
+                std::io::copy(&mut std::fs::File::open(&self.path)?, &mut out)?;
+                out.sync_all()?;
+                std::fs::remove_file(&self.path)?;
+            }
+            (false, true) => {}
+        }
+        Ok(Some(retry))
+    }
+
+    /// Save the dead-lettered events through `db`, which must write its failures here
+    /// again; the events that were handled leave the file
+    pub async fn retry(&self, db: &ArchiveDatabase) -> Result<RetryReport> {
+        let mut report = RetryReport::default();
+        let Some(path) = self.take()? else {
+            return Ok(report);
+        };
+        let f = BufReader::new(std::fs::File::open(&path)?);
+        for line in f.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Some(ev) = Event::from_json(&line).ok().filter(|e| e.verify().is_ok()) else {
+                report.invalid += 1;
+                continue;
+            };
+            if db.is_paused() {
+                warn!("Ingestion is paused, leaving dead-lettered events for the next retry");
+                report.paused = true;
+                return Ok(report);
+            }
+            match db.save_event(&ev).await {
+                Ok(SaveEventStatus::Success) => {
+                    report.saved += 1;
+                    self.recovered.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(SaveEventStatus::Rejected(_)) => report.rejected += 1,
+                Err(_) => report.failed += 1,
+            }
+        }
+        std::fs::remove_file(&path)?;
+        if report.saved + report.rejected + report.failed + report.invalid > 0 {
+            info!(
+                "Retried dead-lettered events: {} saved, {} rejected, {} failed again, {} invalid",
+                report.saved, report.rejected, report.failed, report.invalid
+            );
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,195 @@
+use crate::db::{is_fresh, sidecar_path};
+use crate::scan::DELETIONS_DIR;
+use anyhow::{Result, bail};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use itertools::Itertools;
+use log::{info, warn};
+use nostr_archive_cursor::ArchiveFile;
+use nostr_sdk::prelude::JsonUtil;
+use nostr_sdk::{Event, EventId, Kind, PublicKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Kind 5 events saved each day, written to `deletions/deletions_YYYYMMDD.jsonl` so
+/// consumers of the immutable archives can apply NIP-09 deletions themselves
+#[derive(Clone)]
+pub struct Deletions {
+    dir: PathBuf,
+    /// Held while appending
+    write: Arc<Mutex<()>>,
+}
+
+/// Day of a `deletions_YYYYMMDD.jsonl` file name
+fn file_day(name: &str) -> Option<NaiveDate> {
+    let day = name.strip_prefix("deletions_")?.strip_suffix(".jsonl")?;
+    NaiveDate::parse_from_str(day, "%Y%m%d").ok()
+}
+
+fn today() -> NaiveDate {
+    Utc::now().date_naive()
+}
+
+/// Deletion events of the files in `dir` for days in `from..`, oldest first
+fn read_dir(dir: &Path, from: Option<NaiveDate>) -> Result<Vec<Event>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(day) = path.file_name().and_then(|n| n.to_str()).and_then(file_day)
+            && from.is_none_or(|f| day >= f)
+        {
+            files.push((day, path));
+        }
+    }
+    files.sort();
+    let mut events = Vec::new();
+    for (_, path) in files {
+        for line in BufReader::new(std::fs::File::open(&path)?).lines() {
+            match Event::from_json(line?) {
+                Ok(e) if e.kind == Kind::EventDeletion => events.push(e),
+                Ok(_) => {}
+                Err(e) => warn!("Skipping a line of {}: {}", path.display(), e),
+            }
+        }
+    }
+    Ok(events)
+}
+
+impl Deletions {
+    pub fn new(out_dir: &Path) -> Self {
+        Self {
+            dir: out_dir.join(DELETIONS_DIR),
+            write: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Append a saved kind 5 event to today's file
+    pub async fn record(&self, event: &Event) {
+        let _write = self.write.lock().await;
+        let path = self
+            .dir
+            .join(format!("deletions_{}.jsonl", today().format("%Y%m%d")));
+        let res = async {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            let mut f = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            f.write_all(format!("{}\n", event.as_json()).as_bytes())
+                .await
+        }
+        .await;
+        if let Err(e) = res {
+            warn!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+
+    /// Daily files, newest first
+    pub async fn files(&self) -> Result<Vec<ArchiveFile>> {
+        let mut files = Vec::new();
+        let mut list = match tokio::fs::read_dir(&self.dir).await {
+            Ok(l) => l,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(files),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = list.next_entry().await? {
+            let Some(day) = entry.file_name().to_str().and_then(file_day) else {
+                continue;
+            };
+            let meta = entry.metadata().await?;
+            let created: DateTime<Utc> = meta.created()?.into();
+            files.push(ArchiveFile {
+                path: entry.path(),
+                size: meta.len(),
+                created,
+                timestamp: day.and_time(NaiveTime::MIN).and_utc(),
+            });
+        }
+        files.sort_by_key(|f| std::cmp::Reverse(f.timestamp));
+        Ok(files)
+    }
+
+    /// Daily file `name`, eg. `deletions_20250101.jsonl`
+    pub fn get_file(&self, name: &str) -> Result<ArchiveFile> {
+        let Some(day) = file_day(name) else {
+            bail!("Not a deletions file: {}", name);
+        };
+        let path = self.dir.join(name);
+        let meta = std::fs::metadata(&path)?;
+        Ok(ArchiveFile {
+            size: meta.len(),
+            created: meta.created()?.into(),
+            timestamp: day.and_time(NaiveTime::MIN).and_utc(),
+            path,
+        })
+    }
+
+    /// SHA-256 of a past day's file, cached in a `.sha256` sidecar; None for today's
+    pub async fn checksum(&self, file: &ArchiveFile) -> Result<Option<String>> {
+        if file.timestamp.date_naive() >= today() {
+            return Ok(None);
+        }
+        let sidecar = sidecar_path(&file.path, "sha256");
+        if let Ok(s) = tokio::fs::read_to_string(&sidecar).await
+            && let Some(hash) = s.split_whitespace().next()
+            && is_fresh(&sidecar, &file.path).await
+        {
+            return Ok(Some(hash.to_string()));
+        }
+        let hash = hex::encode(Sha256::digest(tokio::fs::read(&file.path).await?));
+        let name = file.path.file_name().unwrap_or_default().to_string_lossy();
+        tokio::fs::write(&sidecar, format!("{}  {}\n", hash, name)).await?;
+        Ok(Some(hash))
+    }
+
+    /// Event ids listed by the deletions saved on the day of `since` and later
+    pub async fn deleted_ids(&self, since: DateTime<Utc>) -> Result<Vec<EventId>> {
+        let dir = self.dir.clone();
+        let events = tokio::task::spawn_blocking(move || {
+            if !dir.exists() {
+                return Ok(vec![]);
+            }
+            read_dir(&dir, Some(since.date_naive()))
+        })
+        .await??;
+        Ok(events
+            .iter()
+            .flat_map(|e| e.tags.event_ids().copied())
+            .unique()
+            .collect())
+    }
+}
+
+/// Every deletion of `out_dir`, a NIP-09 deletion only applies to events of its author
+pub struct Tombstones(HashSet<(EventId, PublicKey)>);
+
+impl Tombstones {
+    pub fn load(out_dir: &Path) -> Result<Self> {
+        let dir = out_dir.join(DELETIONS_DIR);
+        if !dir.exists() {
+            bail!(
+                "{} doesn't exist, deletions are only kept with track_deletions",
+                dir.display()
+            );
+        }
+        let ids: HashSet<(EventId, PublicKey)> = read_dir(&dir, None)?
+            .iter()
+            .flat_map(|e| e.tags.event_ids().map(|id| (*id, e.pubkey)))
+            .collect();
+        info!(
+            "Loaded {} deleted event ids from {}",
+            ids.len(),
+            dir.display()
+        );
+        Ok(Self(ids))
+    }
+
+    pub fn is_deleted(&self, event: &Event) -> bool {
+        self.0.contains(&(event.id, event.pubkey))
+    }
+}
@@ -0,0 +1,167 @@
+use crate::upstream::Upstream;
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use nostr_sdk::nips::nip65::{RelayMetadata, extract_relay_list};
+use nostr_sdk::{Client, Event, Filter, Kind, PublicKey, RelayUrl};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// Authors per kind 10002 request
+const AUTHORS_PER_REQ: usize = 500;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Clone)]
+pub struct DiscoverSettings {
+    /// Follow the write relays of this pubkey's contacts (NIP-65)
+    pub from_contacts_of: String,
+
+    /// Most discovered relays connected at once
+    pub max_relays: Option<usize>,
+
+    /// Write relays taken from each contact's relay list
+    pub max_per_author: Option<usize>,
+
+    /// Hours between refreshing the relay set
+    pub interval_hours: Option<u64>,
+
+    /// Relays to fetch contact and relay lists from, defaults to `relays`
+    pub bootstrap: Option<Vec<String>>,
+}
+
+/// Periodically derive write relays from the contacts of a pubkey and connect
+/// the ingest client to the ones covering the most contacts
+///
+/// Static relays are never removed, discovered relays are dropped once they
+/// fall out of the refreshed set
+pub async fn run_discover(
+    client: Client,
+    upstream: Upstream,
+    settings: DiscoverSettings,
+    static_relays: Vec<String>,
+) -> Result<()> {
+    let pubkey = PublicKey::parse(&settings.from_contacts_of)?;
+    let bootstrap = settings.bootstrap.clone().unwrap_or(static_relays.clone());
+    if bootstrap.is_empty() {
+        return Err(anyhow!("Relay discovery needs bootstrap or relays"));
+    }
+    upstream.validate(&bootstrap)?;
+    let static_relays: HashSet<RelayUrl> = static_relays
+        .iter()
+        .filter_map(|r| RelayUrl::parse(r).ok())
+        .collect();
+    let interval = Duration::from_secs(settings.interval_hours.unwrap_or(6) * 60 * 60);
+
+    // separate client so discovery queries don't go to every ingest relay
+    let lookup = upstream.client(&bootstrap).await?;
+
+    let onion = upstream.has_proxy();
+    let mut discovered: HashSet<RelayUrl> = HashSet::new();
+    loop {
+        match discover(&lookup, pubkey, &settings, onion).await {
+            Ok(relays) => {
+                let relays: HashSet<RelayUrl> = relays
+                    .into_iter()
+                    .filter(|r| !static_relays.contains(r))
+                    .collect();
+                for r in discovered.difference(&relays) {
+                    if let Err(e) = client.force_remove_relay(r).await {
+                        warn!("Failed to remove relay {}: {}", r, e);
+                    }
+                }
+                for r in relays.difference(&discovered) {
+                    // pool subscriptions are inherited by relays added later
+                    match upstream.add_relay(&client, r.as_str()).await {
+                        Ok(_) => {
+                            if let Err(e) = client.connect_relay(r).await {
+                                warn!("Failed to connect to relay {}: {}", r, e);
+                            }
+                        }
+                        Err(e) => warn!("Failed to add relay {}: {}", r, e),
+                    }
+                }
+                info!(
+                    "Relay discovery: {} relays ({} added, {} removed)",
+                    relays.len(),
+                    relays.difference(&discovered).count(),
+                    discovered.difference(&relays).count()
+                );
+                discovered = relays;
+            }
+            Err(e) => warn!("Relay discovery failed: {}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Top write relays by number of contacts publishing to them, .onion relays only
+/// when a proxy is configured
+async fn discover(
+    lookup: &Client,
+    pubkey: PublicKey,
+    settings: &DiscoverSettings,
+    onion: bool,
+) -> Result<Vec<RelayUrl>> {
+    let contacts = lookup
+        .fetch_events(
+            Filter::new().kind(Kind::ContactList).author(pubkey),
+            FETCH_TIMEOUT,
+        )
+        .await?;
+    let follows: Vec<PublicKey> = contacts
+        .into_iter()
+        .max_by_key(|e| e.created_at)
+        .ok_or(anyhow!("No contact list found for {}", pubkey))?
+        .tags
+        .public_keys()
+        .copied()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    // newest relay list per author
+    let mut lists: HashMap<PublicKey, Event> = HashMap::new();
+    for chunk in follows.chunks(AUTHORS_PER_REQ) {
+        let events = lookup
+            .fetch_events(
+                Filter::new()
+                    .kind(Kind::RelayList)
+                    .authors(chunk.iter().copied()),
+                FETCH_TIMEOUT,
+            )
+            .await?;
+        for e in events {
+            if lists
+                .get(&e.pubkey)
+                .is_none_or(|old| old.created_at < e.created_at)
+            {
+                lists.insert(e.pubkey, e);
+            }
+        }
+    }
+
+    let max_per_author = settings.max_per_author.unwrap_or(4);
+    let mut coverage: HashMap<RelayUrl, usize> = HashMap::new();
+    for e in lists.values() {
+        // one author listing hundreds of relays only counts for the first few
+        for (url, _) in extract_relay_list(e)
+            .filter(|(u, m)| {
+                !matches!(m, Some(RelayMetadata::Read))
+                    && !u.is_local_addr()
+                    && (onion || !u.is_onion())
+            })
+            .take(max_per_author)
+        {
+            *coverage.entry(url.clone()).or_default() += 1;
+        }
+    }
+
+    let mut ranked: Vec<(RelayUrl, usize)> = coverage.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(ranked
+        .into_iter()
+        .take(settings.max_relays.unwrap_or(50))
+        .map(|(r, _)| r)
+        .collect())
+}
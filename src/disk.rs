@@ -0,0 +1,163 @@
+use anyhow::Result;
+use log::{error, info, warn};
+use nostr_sdk::{Client, Filter, SubscriptionId, Timestamp};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+const GB: u64 = 1024 * 1024 * 1024;
+
+/// Free space above `stop_free_gb` needed before ingestion resumes, so it doesn't
+/// flap around the threshold
+const RESUME_MARGIN: u64 = GB;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct DiskGuardSettings {
+    /// Log warnings below this much free space (GB) on the out_dir filesystem
+    pub warn_free_gb: Option<u64>,
+
+    /// Pause ingestion below this much free space (GB), resumed once it is freed
+    pub stop_free_gb: Option<u64>,
+
+    /// Seconds between checks, default 30
+    pub interval_secs: Option<u64>,
+}
+
+#[derive(Default, Debug)]
+struct GuardState {
+    free: AtomicU64,
+    low: AtomicBool,
+    paused: AtomicBool,
+    pauses: AtomicU64,
+    resumes: AtomicU64,
+}
+
+/// Free space of the out_dir filesystem, ingestion is paused while it is too low
+#[derive(Clone, Default, Debug)]
+pub struct DiskGuard(Arc<GuardState>);
+
+#[derive(Serialize)]
+pub struct DiskInfo {
+    pub free_bytes: u64,
+    /// Below `warn_free_gb`
+    pub low: bool,
+    /// Below `stop_free_gb`, nothing is written
+    pub paused: bool,
+    pub pauses: u64,
+    pub resumes: u64,
+}
+
+impl DiskGuard {
+    pub fn is_paused(&self) -> bool {
+        self.0.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> DiskInfo {
+        DiskInfo {
+            free_bytes: self.0.free.load(Ordering::Relaxed),
+            low: self.0.low.load(Ordering::Relaxed),
+            paused: self.is_paused(),
+            pauses: self.0.pauses.load(Ordering::Relaxed),
+            resumes: self.0.resumes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`
+fn free_bytes(path: &Path) -> std::io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Periodically check the free space of `out_dir`, below `stop_free_gb` the ingest
+/// subscriptions are closed until space is freed (eg. by retention)
+pub async fn run_disk_guard(
+    guard: DiskGuard,
+    out_dir: PathBuf,
+    client: Option<Client>,
+    settings: DiskGuardSettings,
+) -> Result<()> {
+    let warn_at = settings.warn_free_gb.map(|g| g * GB);
+    let stop_at = settings.stop_free_gb.map(|g| g * GB);
+    let interval = Duration::from_secs(settings.interval_secs.unwrap_or(30).max(1));
+    // subscriptions closed by the pause and when, re-opened from then on resume
+    let mut closed: Option<(Timestamp, HashMap<SubscriptionId, Filter>)> = None;
+    loop {
+        match free_bytes(&out_dir) {
+            Ok(free) => {
+                guard.0.free.store(free, Ordering::Relaxed);
+                let low = warn_at.is_some_and(|w| free < w);
+                match (guard.0.low.swap(low, Ordering::Relaxed), low) {
+                    (false, true) => {
+                        warn!(
+                            "Low disk space, {} GB free in {}",
+                            free / GB,
+                            out_dir.display()
+                        )
+                    }
+                    (true, false) => info!("Disk space recovered, {} GB free", free / GB),
+                    _ => {}
+                }
+                let paused = guard.is_paused();
+                if !paused && stop_at.is_some_and(|s| free < s) {
+                    warn!(
+                        "Storage full, {} GB free in {}, pausing ingestion",
+                        free / GB,
+                        out_dir.display()
+                    );
+                    guard.0.paused.store(true, Ordering::Relaxed);
+                    guard.0.pauses.fetch_add(1, Ordering::Relaxed);
+                    if let Some(c) = &client {
+                        closed = Some((Timestamp::now(), pause(c).await));
+                    }
+                } else if paused && stop_at.is_none_or(|s| free >= s + RESUME_MARGIN) {
+                    info!("{} GB free, resuming ingestion", free / GB);
+                    guard.0.paused.store(false, Ordering::Relaxed);
+                    guard.0.resumes.fetch_add(1, Ordering::Relaxed);
+                    if let (Some(c), Some((since, subs))) = (&client, closed.take()) {
+                        resume(c, since, subs).await;
+                    }
+                }
+            }
+            Err(e) => error!("Failed to check free space of {}: {}", out_dir.display(), e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Close every upstream subscription, returning their filters
+async fn pause(client: &Client) -> HashMap<SubscriptionId, Filter> {
+    let subs = client
+        .subscriptions()
+        .await
+        .into_iter()
+        .filter_map(|(id, relays)| {
+            let filter = relays.into_values().next()?.into_iter().next()?;
+            Some((id, filter))
+        })
+        .collect();
+    client.unsubscribe_all().await;
+    subs
+}
+
+/// Re-open the subscriptions closed by [pause], asking for events since the pause
+async fn resume(client: &Client, since: Timestamp, subs: HashMap<SubscriptionId, Filter>) {
+    for (id, filter) in subs {
+        if let Err(e) = client
+            .subscribe_with_id(id.clone(), filter.since(since), None)
+            .await
+        {
+            warn!("Failed to resubscribe {}: {}", id, e);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::attest;
+use crate::db::{ArchiveDatabase, is_active, sha256_file, sidecar_path};
+use crate::summary::{read_summary, summarize, summary_path};
+use crate::upstream::Upstream;
+use anyhow::Result;
+use log::{info, warn};
+use nostr_sdk::{PublicKey, Timestamp};
+use serde::Serialize;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Events the index and the archives may differ by before the index counts as degraded
+const TOLERANCE: u64 = 100;
+
+/// ..or this fraction of the indexed events when larger, events saved during the check
+const TOLERANCE_RATIO: f64 = 0.001;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsistencyStatus {
+    Ok,
+    Degraded,
+}
+
+/// Event id index compared with the event counts of the archive summaries
+#[derive(Serialize, Clone)]
+pub struct ConsistencyReport {
+    pub status: ConsistencyStatus,
+    pub checked_at: u64,
+    /// Ids in the event index of the archives and every partition
+    pub indexed: u64,
+    /// Events in the summaries and the plain jsonl files
+    pub archived: u64,
+    /// Archives without a summary, while any are left only missing index entries are
+    /// detected
+    pub unsummarized: usize,
+}
+
+impl ConsistencyReport {
+    fn new(indexed: u64, archived: u64, unsummarized: usize) -> Self {
+        let tolerance = TOLERANCE.max((indexed as f64 * TOLERANCE_RATIO) as u64);
+        let missing = archived.saturating_sub(indexed) > tolerance;
+        let extra = unsummarized == 0 && indexed.saturating_sub(archived) > tolerance;
+        Self {
+            status: if missing || extra {
+                ConsistencyStatus::Degraded
+            } else {
+                ConsistencyStatus::Ok
+            },
+            checked_at: Timestamp::now().as_secs(),
+            indexed,
+            archived,
+            unsummarized,
+        }
+    }
+
+    /// What to run when the index and the archives differ
+    pub fn remediation(&self) -> Vec<&'static str> {
+        let mut ret = Vec::new();
+        if self.status == ConsistencyStatus::Ok {
+            return ret;
+        }
+        if self.archived > self.indexed {
+            ret.push("the index is missing archived events, run `nostrhole index --rebuild`");
+        } else {
+            ret.push(
+                "the index holds events no archive has; after deleting old archives run \
+                 `nostrhole prune-index --before YYYY-MM-DD`, otherwise restore the missing \
+                 archives from a mirror",
+            );
+        }
+        if self.unsummarized > 0 {
+            ret.push("run `nostrhole stats --rebuild` so every archive is compared");
+        }
+        ret
+    }
+}
+
+/// Result of the startup check, None until it finished
+#[derive(Clone, Default)]
+pub struct Consistency(Arc<Mutex<Option<ConsistencyReport>>>);
+
+impl Consistency {
+    pub fn snapshot(&self) -> Option<ConsistencyReport> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Lines of a plain jsonl file, read on a blocking thread
+async fn count_lines(path: PathBuf) -> Result<u64> {
+    tokio::task::spawn_blocking(move || {
+        let mut input = BufReader::new(std::fs::File::open(path)?);
+        let mut buf = vec![0u8; 1024 * 1024];
+        let mut n = 0;
+        loop {
+            match input.read(&mut buf)? {
+                0 => return Ok(n),
+                r => n += buf[..r].iter().filter(|b| **b == b'\n').count() as u64,
+            }
+        }
+    })
+    .await?
+}
+
+fn is_plain(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some("jsonl")
+}
+
+/// Compressed archive still being written from its jsonl file
+pub fn is_compressing(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let day = name.split('.').next().unwrap_or(name);
+    !is_plain(path) && path.with_file_name(format!("{}.jsonl", day)).exists()
+}
+
+/// Compare the event index with the summaries of the archives, plain jsonl files without
+/// a summary are counted line by line
+pub async fn check(db: &ArchiveDatabase) -> Result<ConsistencyReport> {
+    let d = db.clone();
+    let indexed = tokio::task::spawn_blocking(move || d.count_keys()).await??;
+    let (mut archived, mut unsummarized) = (0, 0);
+    for f in db.list_archives().await?.iter() {
+        if is_compressing(&f.path) {
+            continue;
+        }
+        if let Some(s) = read_summary(&f.path).await {
+            archived += s.events;
+        } else if is_plain(&f.path) {
+            archived += count_lines(f.path.clone()).await?;
+        } else {
+            unsummarized += 1;
+        }
+    }
+    Ok(ConsistencyReport::new(indexed, archived, unsummarized))
+}
+
+/// Check the event index once at startup, the result is shown at `/api/health`
+pub async fn run_consistency(db: ArchiveDatabase, state: Consistency) -> Result<()> {
+    let report = check(&db).await?;
+    match report.status {
+        ConsistencyStatus::Ok => info!(
+            "Event index holds {} ids, archives {} events",
+            report.indexed, report.archived
+        ),
+        ConsistencyStatus::Degraded => {
+            warn!(
+                "Event index holds {} ids but archives {} events ({} archives without a \
+                 summary), run `nostrhole doctor`",
+                report.indexed, report.archived, report.unsummarized
+            );
+        }
+    }
+    *state.0.lock().unwrap() = Some(report);
+    Ok(())
+}
+
+/// Re-read every finalized archive, comparing it with its summary and checksum and the
+/// event index with the recounted events; with `attestations` the archives are also
+/// checked against the attestations published by `author`
+///
+/// Prints what was found and what to run, returns false when anything needs attention
+pub async fn doctor(
+    db: &ArchiveDatabase,
+    upstream: &Upstream,
+    relays: &[String],
+    attestations: Option<PublicKey>,
+) -> Result<bool> {
+    let mut ok = true;
+    let mut fixes: Vec<String> = Vec::new();
+    let mut fix = |s: &str| {
+        if !fixes.iter().any(|f| f == s) {
+            fixes.push(s.to_string());
+        }
+    };
+
+    let d = db.clone();
+    let indexed = tokio::task::spawn_blocking(move || d.count_keys()).await??;
+    let mut archived = 0;
+    for f in db.list_archives().await?.iter() {
+        let name = db.archive_name(&f.path);
+        if is_compressing(&f.path) {
+            println!("skipped    {} still being compressed", name);
+            continue;
+        }
+        if is_active(&f.path) {
+            archived += count_lines(f.path.clone()).await?;
+            continue;
+        }
+        let p = f.path.clone();
+        let scan = match tokio::task::spawn_blocking(move || summarize(&p)).await? {
+            Ok(s) => s,
+            Err(e) => {
+                println!("UNREADABLE {}: {}", name, e);
+                fix(
+                    "restore unreadable archives from a mirror, with encrypted archives pass --identity",
+                );
+                ok = false;
+                continue;
+            }
+        };
+        archived += scan.events;
+        if !scan.is_clean() {
+            println!(
+                "DAMAGED    {} {} lines skipped{}",
+                name,
+                scan.skipped,
+                if scan.truncated { ", truncated" } else { "" }
+            );
+            fix("skipped lines were copied to quarantine/, restore damaged archives from a mirror");
+            ok = false;
+        }
+        match read_summary(&f.path).await {
+            Some(s) if s.events == scan.events => {}
+            Some(s) => {
+                println!(
+                    "STALE      {} summary has {} events, read {}",
+                    name, s.events, scan.events
+                );
+                fix(&format!(
+                    "delete stale summaries (eg. {}) and run `nostrhole stats --rebuild`",
+                    summary_path(&f.path).display()
+                ));
+                ok = false;
+            }
+            None => {
+                println!("unsummarized {}", name);
+                fix("run `nostrhole stats --rebuild`");
+            }
+        }
+        let sidecar = sidecar_path(&f.path, "sha256");
+        if let Ok(s) = tokio::fs::read_to_string(&sidecar).await
+            && let Some(expected) = s.split_whitespace().next()
+        {
+            let hash = sha256_file(f.path.clone()).await?;
+            if hash != expected {
+                println!("MISMATCH   {} expected {} got {}", name, expected, hash);
+                fix(
+                    "archives changed after their checksum was published, restore them from a mirror",
+                );
+                ok = false;
+            }
+        }
+    }
+
+    let report = ConsistencyReport::new(indexed, archived, 0);
+    println!(
+        "Event index holds {} ids, the archives {} events",
+        report.indexed, report.archived
+    );
+    if report.status == ConsistencyStatus::Degraded {
+        ok = false;
+        for r in report.remediation() {
+            fix(r);
+        }
+    }
+
+    if let Some(author) = attestations {
+        println!("Checking attestations of {}", author);
+        if !attest::verify(db.out_dir(), upstream, relays, author).await? {
+            fix("attested archives are missing or differ, restore them from a mirror");
+            ok = false;
+        }
+    }
+
+    if !fixes.is_empty() {
+        println!();
+        for f in &fixes {
+            println!("- {}", f);
+        }
+    }
+    Ok(ok)
+}
@@ -0,0 +1,134 @@
+use crate::rocks;
+use anyhow::Result;
+use log::warn;
+use rocksdb::{DB, IteratorMode};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Downloads of one archive
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct DownloadCount {
+    /// Whole file requests sent to the end
+    pub full: u64,
+    /// Range requests sent to the end
+    pub ranges: u64,
+    /// Requests the client dropped before the end
+    pub aborted: u64,
+    /// Bytes served by all requests
+    pub bytes: u64,
+}
+
+impl DownloadCount {
+    fn from_bytes(v: &[u8]) -> Self {
+        let n = |i: usize| {
+            v.get(i * 8..i * 8 + 8)
+                .and_then(|b| b.try_into().ok())
+                .map(u64::from_le_bytes)
+                .unwrap_or_default()
+        };
+        Self {
+            full: n(0),
+            ranges: n(1),
+            aborted: n(2),
+            bytes: n(3),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let mut ret = [0u8; 32];
+        for (i, n) in [self.full, self.ranges, self.aborted, self.bytes]
+            .into_iter()
+            .enumerate()
+        {
+            ret[i * 8..i * 8 + 8].copy_from_slice(&n.to_le_bytes());
+        }
+        ret
+    }
+
+    /// Bytes served as a number of whole files, resumed downloads count once
+    pub fn equivalent(&self, size: u64) -> f64 {
+        self.bytes as f64 / size.max(1) as f64
+    }
+}
+
+/// Download counters per archive name, kept across restarts
+#[derive(Clone)]
+pub struct DownloadCounts {
+    database: Arc<DB>,
+    /// Held across the read-modify-write of a counter
+    lock: Arc<Mutex<()>>,
+}
+
+impl DownloadCounts {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = rocks::open(path)?;
+        Ok(Self {
+            database: Arc::new(db),
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Count a finished or dropped response for `name` that sent `bytes`
+    pub fn record(&self, name: &str, range: bool, finished: bool, bytes: u64) {
+        let Ok(_lock) = self.lock.lock() else {
+            return;
+        };
+        let mut count = self.get(name);
+        match (finished, range) {
+            (false, _) => count.aborted += 1,
+            (true, false) => count.full += 1,
+            (true, true) => count.ranges += 1,
+        }
+        count.bytes += bytes;
+        if let Err(e) = self.database.put(name.as_bytes(), count.to_bytes()) {
+            warn!("Failed to count download of {}: {}", name, e);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> DownloadCount {
+        match self.database.get(name.as_bytes()) {
+            Ok(Some(v)) => DownloadCount::from_bytes(&v),
+            _ => DownloadCount::default(),
+        }
+    }
+
+    /// Every counter by archive name
+    pub fn all(&self) -> Vec<(String, DownloadCount)> {
+        self.database
+            .iterator(IteratorMode::Start)
+            .flatten()
+            .map(|(k, v)| {
+                (
+                    String::from_utf8_lossy(&k).to_string(),
+                    DownloadCount::from_bytes(&v),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Response of `len` bytes of `name`, counted once it is dropped
+pub struct DownloadRecord {
+    counts: DownloadCounts,
+    name: String,
+    range: bool,
+    len: u64,
+}
+
+impl DownloadRecord {
+    pub fn new(counts: DownloadCounts, name: String, range: bool, len: u64) -> Self {
+        Self {
+            counts,
+            name,
+            range,
+            len,
+        }
+    }
+
+    /// Count the response after `bytes` were sent, finished once all of them were
+    pub fn record(self, bytes: u64) {
+        self.counts
+            .record(&self.name, self.range, bytes >= self.len, bytes);
+    }
+}
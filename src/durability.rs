@@ -0,0 +1,52 @@
+use log::warn;
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Leave flushing to the OS and rotation
+    #[default]
+    None,
+    /// fsync the active files every `durability_interval_secs` or `durability_interval_events`
+    Interval,
+    /// fsync the active file before an event is acknowledged
+    EveryEvent,
+}
+
+/// When the archive writers fsync the file they write to
+#[derive(Clone, Copy, Debug)]
+pub struct Fsync {
+    pub mode: Durability,
+    /// Time between syncs with `interval`
+    pub interval: Duration,
+    /// Also sync after this many events with `interval`
+    pub events: Option<u64>,
+}
+
+impl Default for Fsync {
+    fn default() -> Self {
+        Self {
+            mode: Durability::None,
+            interval: Duration::from_secs(5),
+            events: None,
+        }
+    }
+}
+
+impl Fsync {
+    pub fn new(mode: Durability, interval: Duration, events: Option<u64>) -> Self {
+        if mode == Durability::EveryEvent {
+            warn!(
+                "durability: every_event syncs the archive after each event, ingest is limited \
+                 to the fsync rate of the disk (a few hundred events/s on spinning disks), \
+                 use interval for high ingest rates"
+            );
+        }
+        Self {
+            mode,
+            interval: interval.max(Duration::from_secs(1)),
+            events: events.filter(|e| *e > 0),
+        }
+    }
+}
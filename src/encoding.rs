@@ -0,0 +1,104 @@
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use hyper::body::Bytes;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, RandomState};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// Bodies shorter than this are sent as they are
+pub const MIN_ENCODED_SIZE: usize = 1024;
+
+/// Encoded bodies kept for repeated requests, the landing page and file list of each page
+const CACHE_ENTRIES: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// Preferred encoding the client accepts, zstd before gzip, `q=0` entries are refused
+    pub fn negotiate(accept: &str) -> Option<Self> {
+        let accepted = |name: &str| {
+            accept.split(',').any(|e| {
+                let mut parts = e.split(';').map(str::trim);
+                parts.next().is_some_and(|n| n.eq_ignore_ascii_case(name))
+                    && !parts.any(|p| {
+                        p.strip_prefix("q=")
+                            .and_then(|q| q.parse::<f32>().ok())
+                            .is_some_and(|q| q <= 0.0)
+                    })
+            })
+        };
+        if accepted("zstd") {
+            Some(Self::Zstd)
+        } else if accepted("gzip") {
+            Some(Self::Gzip)
+        } else {
+            None
+        }
+    }
+
+    /// `Content-Encoding` value
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    fn encode(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::encode_all(body, 3),
+            Self::Gzip => {
+                let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+                enc.write_all(body)?;
+                enc.finish()
+            }
+        }
+    }
+}
+
+/// Encoded body of a text body with this hash and length
+struct Entry {
+    hash: u64,
+    len: usize,
+    encoding: Encoding,
+    body: Bytes,
+}
+
+/// Recently encoded bodies by hash, so repeated hits of an unchanged page aren't encoded again
+#[derive(Clone, Default)]
+pub struct EncodedCache {
+    hasher: RandomState,
+    entries: Arc<Mutex<VecDeque<Entry>>>,
+}
+
+impl EncodedCache {
+    pub fn encode(&self, body: &str, encoding: Encoding) -> std::io::Result<Bytes> {
+        let hash = self.hasher.hash_one(body);
+        if let Some(hit) = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| e.hash == hash && e.len == body.len() && e.encoding == encoding)
+        {
+            return Ok(hit.body.clone());
+        }
+        let encoded = Bytes::from(encoding.encode(body.as_bytes())?);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= CACHE_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(Entry {
+            hash,
+            len: body.len(),
+            encoding,
+            body: encoded.clone(),
+        });
+        Ok(encoded)
+    }
+}
@@ -0,0 +1,658 @@
+use crate::bloom::bloom_path;
+use crate::db::{ArchiveDatabase, is_active, is_archive, is_fresh, sidecar_path};
+use crate::jobs::{ArchiveJobs, JOB_BUFFER, open_input};
+use crate::layout::is_date_dir;
+use crate::naming::archive_day;
+use crate::rollup::rollup_path;
+use crate::scan::is_reserved_dir;
+use crate::summary::summary_path;
+use crate::torrent::TorrentMaker;
+use anyhow::{Result, anyhow, bail};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use chrono::{Days, Utc};
+use dashmap::DashSet;
+use log::{error, info, warn};
+use nostr_sdk::nips::nip44::v2::ConversationKey;
+use nostr_sdk::{Keys, PublicKey, SecretKey};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+/// Extension added to encrypted archives, eg. `events_20250101.jsonl.zst.enc`
+pub const ENCRYPTED_EXT: &str = "enc";
+
+/// How often finalized archives are checked for encryption
+const ENCRYPT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const MAGIC: &[u8; 16] = b"nostrhole-enc/2\n";
+
+/// Plaintext bytes per sealed chunk, the last chunk is always shorter
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest chunk size accepted from a header
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Magic and chunk size, the part of the header each chunk is sealed with as associated
+/// data
+const PARAMS_SIZE: usize = 16 + 4;
+
+/// Poly1305 tag after each sealed chunk and wrapped key
+const TAG_SIZE: usize = 16;
+
+/// Recipient pubkey, ephemeral pubkey and the wrapped file key
+const STANZA_SIZE: usize = 32 + 32 + 32 + TAG_SIZE;
+
+/// Key encrypted archives are read with, set from `--identity`
+static IDENTITY: OnceLock<Keys> = OnceLock::new();
+
+#[derive(Deserialize, Clone, Default)]
+pub struct EncryptionSettings {
+    /// Pubkeys (npub or hex) finalized archives are encrypted to, any of their secret keys
+    /// can read them
+    pub recipients: Vec<String>,
+}
+
+impl EncryptionSettings {
+    pub fn recipients(&self) -> Result<Vec<PublicKey>> {
+        if self.recipients.is_empty() || self.recipients.len() > u8::MAX as usize {
+            bail!("encryption needs 1 to 255 recipients");
+        }
+        Ok(self
+            .recipients
+            .iter()
+            .map(|r| PublicKey::parse(r))
+            .collect::<Result<_, _>>()?)
+    }
+}
+
+/// Read the secret key (nsec or hex) encrypted archives are decrypted with from `path`
+pub fn set_identity(path: &Path) -> Result<()> {
+    let keys = Keys::parse(std::fs::read_to_string(path)?.trim())?;
+    IDENTITY
+        .set(keys)
+        .map_err(|_| anyhow!("identity is already set"))
+}
+
+/// The `--identity` of tests, the same generated key for all of them
+#[cfg(test)]
+pub fn test_identity() -> &'static Keys {
+    IDENTITY.get_or_init(Keys::generate)
+}
+
+pub fn is_encrypted(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some(ENCRYPTED_EXT)
+}
+
+/// File key wrapped for `recipient` with the NIP-44 conversation key of a new ephemeral key
+fn wrap_key(file_key: &[u8; 32], recipient: &PublicKey) -> Result<[u8; STANZA_SIZE]> {
+    let ephemeral = Keys::generate();
+    let key = ConversationKey::derive(ephemeral.secret_key(), recipient)?;
+    // each ephemeral key wraps a single file key, the nonce is never reused
+    let wrapped = ChaCha20Poly1305::new_from_slice(key.as_bytes())
+        .map_err(|e| anyhow!(e))?
+        .encrypt(&Nonce::default(), file_key.as_slice())
+        .map_err(|e| anyhow!(e))?;
+    let mut stanza = [0u8; STANZA_SIZE];
+    stanza[..32].copy_from_slice(&recipient.to_bytes());
+    stanza[32..64].copy_from_slice(&ephemeral.public_key().to_bytes());
+    stanza[64..].copy_from_slice(&wrapped);
+    Ok(stanza)
+}
+
+/// File key of the stanza addressed to `identity`
+fn unwrap_key(stanzas: &[[u8; STANZA_SIZE]], identity: &Keys) -> Result<[u8; 32]> {
+    let me = identity.public_key().to_bytes();
+    let Some(stanza) = stanzas.iter().find(|s| s[..32] == me) else {
+        bail!("archive isn't encrypted to {}", identity.public_key());
+    };
+    let ephemeral = PublicKey::from_slice(&stanza[32..64])?;
+    let key = ConversationKey::derive(identity.secret_key(), &ephemeral)?;
+    let file_key = ChaCha20Poly1305::new_from_slice(key.as_bytes())
+        .map_err(|e| anyhow!(e))?
+        .decrypt(&Nonce::default(), &stanza[64..])
+        .map_err(|_| anyhow!("file key can't be decrypted"))?;
+    file_key
+        .try_into()
+        .map_err(|_| anyhow!("file key has the wrong size"))
+}
+
+/// Magic and chunk size of the header
+fn params(chunk_size: usize) -> [u8; PARAMS_SIZE] {
+    let mut params = [0u8; PARAMS_SIZE];
+    params[..16].copy_from_slice(MAGIC);
+    params[16..].copy_from_slice(&(chunk_size as u32).to_be_bytes());
+    params
+}
+
+fn write_header(
+    out: &mut impl Write,
+    params: &[u8; PARAMS_SIZE],
+    file_key: &[u8; 32],
+    recipients: &[PublicKey],
+) -> Result<()> {
+    out.write_all(params)?;
+    out.write_all(&[recipients.len() as u8])?;
+    for r in recipients {
+        out.write_all(&wrap_key(file_key, r)?)?;
+    }
+    Ok(())
+}
+
+/// Params and stanzas of the header
+fn read_header(input: &mut impl Read) -> Result<([u8; PARAMS_SIZE], Vec<[u8; STANZA_SIZE]>)> {
+    let mut params = [0u8; PARAMS_SIZE];
+    input.read_exact(&mut params)?;
+    if &params[..16] != MAGIC {
+        bail!("not an encrypted archive or an unknown version");
+    }
+    let mut count = [0u8; 1];
+    input.read_exact(&mut count)?;
+    let mut stanzas = vec![[0u8; STANZA_SIZE]; count[0] as usize];
+    for s in stanzas.iter_mut() {
+        input.read_exact(s)?;
+    }
+    Ok((params, stanzas))
+}
+
+/// Chunk size of the header params
+fn chunk_size(params: &[u8; PARAMS_SIZE]) -> Result<usize> {
+    let size = u32::from_be_bytes(params[16..].try_into()?) as usize;
+    if size == 0 || size > MAX_CHUNK_SIZE {
+        bail!("invalid chunk size {}", size);
+    }
+    Ok(size)
+}
+
+/// Nonce of the chunk at `counter`, the last byte marks the final chunk so a file cut at
+/// a chunk boundary doesn't decrypt
+fn chunk_nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// Encrypts everything written to it for a set of recipients
+///
+/// The header is the magic with the format version, the chunk size, the number of
+/// recipients and one stanza per recipient: their pubkey, an ephemeral pubkey and the
+/// random file key sealed with ChaCha20-Poly1305 under the NIP-44 v2 conversation key of
+/// the two. The body is the plaintext in 64 KiB chunks, each sealed under the file key with
+/// `chunk_nonce` and the magic and chunk size as associated data. The stanzas aren't bound
+/// to the body so `rekey` can replace them, a changed stanza doesn't unwrap the file key
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    params: [u8; PARAMS_SIZE],
+    counter: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, recipients: &[PublicKey]) -> Result<Self> {
+        let file_key = SecretKey::generate().to_secret_bytes();
+        let params = params(CHUNK_SIZE);
+        write_header(&mut inner, &params, &file_key, recipients)?;
+        Ok(Self {
+            inner,
+            cipher: ChaCha20Poly1305::new_from_slice(&file_key).map_err(|e| anyhow!(e))?,
+            params,
+            counter: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    fn seal(&mut self, len: usize, last: bool) -> std::io::Result<()> {
+        let chunk = Payload {
+            msg: &self.buf[..len],
+            aad: &self.params,
+        };
+        let sealed = self
+            .cipher
+            .encrypt(&chunk_nonce(self.counter, last), chunk)
+            .map_err(|_| std::io::Error::other("encryption failed"))?;
+        self.inner.write_all(&sealed)?;
+        self.buf.drain(..len);
+        self.counter += 1;
+        Ok(())
+    }
+
+    /// Seal the last chunk, empty when the plaintext ended on a chunk boundary
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.seal(self.buf.len(), true)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        while self.buf.len() >= CHUNK_SIZE {
+            self.seal(CHUNK_SIZE, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Plaintext of an archive written by [EncryptWriter]
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    params: [u8; PARAMS_SIZE],
+    chunk_size: usize,
+    counter: u64,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, identity: &Keys) -> Result<Self> {
+        let (params, stanzas) = read_header(&mut inner)?;
+        let chunk_size = chunk_size(&params)?;
+        let file_key = unwrap_key(&stanzas, identity)?;
+        Ok(Self {
+            inner,
+            cipher: ChaCha20Poly1305::new_from_slice(&file_key).map_err(|e| anyhow!(e))?,
+            params,
+            chunk_size,
+            counter: 0,
+            plain: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    /// Decrypt the next chunk, a short one is the last
+    fn fill(&mut self) -> std::io::Result<()> {
+        let mut sealed = vec![0u8; self.chunk_size + TAG_SIZE];
+        let mut n = 0;
+        while n < sealed.len() {
+            match self.inner.read(&mut sealed[n..]) {
+                Ok(0) => break,
+                Ok(r) => n += r,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let last = n < sealed.len();
+        let chunk = Payload {
+            msg: &sealed[..n],
+            aad: &self.params,
+        };
+        self.plain = self
+            .cipher
+            .decrypt(&chunk_nonce(self.counter, last), chunk)
+            .map_err(|_| {
+                std::io::Error::new(ErrorKind::InvalidData, "archive was modified or cut short")
+            })?;
+        self.pos = 0;
+        self.counter += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Decrypt an archive read from `input` with the `--identity` key
+pub fn decrypt<R: Read>(path: &Path, input: R) -> Result<DecryptReader<R>> {
+    let Some(identity) = IDENTITY.get() else {
+        bail!(
+            "{} is encrypted, pass --identity with one of its recipient keys",
+            path.display()
+        );
+    };
+    DecryptReader::new(input, identity)
+}
+
+/// Remove the checksum, torrent and attestation of an archive whose bytes were replaced
+fn remove_published(path: &Path) {
+    for p in [
+        sidecar_path(path, "sha256"),
+        sidecar_path(path, "attestation"),
+        TorrentMaker::path_for(path),
+    ] {
+        if let Err(e) = std::fs::remove_file(&p)
+            && e.kind() != ErrorKind::NotFound
+        {
+            warn!("Failed to remove {}: {}", p.display(), e);
+        }
+    }
+}
+
+/// Write `dst` through a `.tmp` file, keeping the modification time of `src` so sidecars
+/// written from it stay current
+fn replace_with(src: &Path, dst: &Path, write: impl FnOnce(File) -> Result<File>) -> Result<()> {
+    let modified = std::fs::metadata(src)?.modified()?;
+    let name = dst.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let tmp = dst.with_file_name(format!("{}.tmp", name));
+    let res = File::create(&tmp)
+        .map_err(anyhow::Error::from)
+        .and_then(write)
+        .and_then(|f| {
+            f.sync_all()?;
+            f.set_modified(modified)?;
+            Ok(())
+        });
+    if let Err(e) = res {
+        if let Err(e) = std::fs::remove_file(&tmp) {
+            warn!("Failed to remove {}: {}", tmp.display(), e);
+        }
+        return Err(e);
+    }
+    std::fs::rename(&tmp, dst)?;
+    Ok(())
+}
+
+/// Encrypt `path` to `<path>.enc` and delete the plaintext
+fn encrypt_file(path: &Path, recipients: &[PublicKey]) -> Result<PathBuf> {
+    let dst = sidecar_path(path, ENCRYPTED_EXT);
+    replace_with(path, &dst, |f| {
+        let mut input = open_input(path, "Encrypting")?;
+        let mut out = EncryptWriter::new(BufWriter::with_capacity(JOB_BUFFER, f), recipients)?;
+        std::io::copy(&mut input, &mut out)?;
+        Ok(out.finish()?.into_inner()?)
+    })?;
+    std::fs::remove_file(path)?;
+    remove_published(path);
+    Ok(dst)
+}
+
+/// Wrap the file key of the encrypted archive at `path` for `recipients`, the body is
+/// copied as it is
+fn rekey_file(path: &Path, identity: &Keys, recipients: &[PublicKey]) -> Result<()> {
+    let mut input = BufReader::with_capacity(JOB_BUFFER, File::open(path)?);
+    let (params, stanzas) = read_header(&mut input)?;
+    let file_key = unwrap_key(&stanzas, identity)?;
+    replace_with(path, path, |f| {
+        let mut out = BufWriter::with_capacity(JOB_BUFFER, f);
+        write_header(&mut out, &params, &file_key, recipients)?;
+        std::io::copy(&mut input, &mut out)?;
+        Ok(out.into_inner()?)
+    })?;
+    remove_published(path);
+    Ok(())
+}
+
+/// Encrypts finalized archives once their compression is final
+#[derive(Clone)]
+pub struct Encryption {
+    recipients: Arc<Vec<PublicKey>>,
+    jobs: ArchiveJobs,
+    /// Bloom filters are built before archives are encrypted
+    blooms: bool,
+    /// ..and zap rollups
+    rollups: bool,
+    /// Finalized archives are plain jsonl, `compression.format: none`
+    plain: bool,
+    started: SystemTime,
+    /// Archives that failed to encrypt, left as they are
+    failed: Arc<DashSet<PathBuf>>,
+}
+
+impl Encryption {
+    pub fn new(recipients: Vec<PublicKey>, jobs: ArchiveJobs, blooms: bool, plain: bool) -> Self {
+        Self {
+            recipients: Arc::new(recipients),
+            jobs,
+            blooms,
+            rollups: false,
+            plain,
+            started: SystemTime::now(),
+            failed: Arc::new(DashSet::new()),
+        }
+    }
+
+    /// Wait for the zap rollup of an archive before encrypting it
+    pub fn with_rollups(mut self) -> Self {
+        self.rollups = true;
+        self
+    }
+
+    /// Finalized archive that will still be encrypted, its checksum isn't published until
+    /// then
+    pub fn is_pending(&self, path: &Path) -> bool {
+        if !is_archive(path) || is_encrypted(path) || is_active(path) || self.failed.contains(path)
+        {
+            return false;
+        }
+        if path.extension().and_then(|e| e.to_str()) == Some("jsonl") {
+            // yesterday's file looks finalized until the writer rotates it, give it a day
+            return self.plain
+                && archive_day(path).is_some_and(|d| d < Utc::now().date_naive() - Days::new(1));
+        }
+        // the writer is still compressing while the jsonl exists
+        !path.with_extension("").exists()
+    }
+
+    /// Summary, bloom filter and rollup are written, or won't be for an archive older than
+    /// the process
+    async fn is_ready(&self, path: &Path) -> bool {
+        let old = tokio::fs::metadata(path)
+            .await
+            .and_then(|m| m.modified())
+            .is_ok_and(|m| m < self.started);
+        if old {
+            return true;
+        }
+        is_fresh(&summary_path(path), path).await
+            && (!self.blooms || is_fresh(&bloom_path(path), path).await)
+            && (!self.rollups || is_fresh(&rollup_path(path), path).await)
+    }
+
+    async fn encrypt(&self, path: &Path) -> Result<PathBuf> {
+        let (p, recipients) = (path.to_path_buf(), self.recipients.clone());
+        let res = self.jobs.run(move || encrypt_file(&p, &recipients)).await;
+        if res.is_err() {
+            self.failed.insert(path.to_path_buf());
+        }
+        res
+    }
+}
+
+/// Periodically encrypt newly finalized archives and delete their plaintext
+pub async fn run_encrypt(db: ArchiveDatabase, encryption: Encryption) -> Result<()> {
+    loop {
+        match db.list_archives().await {
+            Ok(files) => {
+                let mut encrypted = 0;
+                for f in files.iter() {
+                    if !encryption.is_pending(&f.path)
+                        || db.is_pending(&f.path).await
+                        || !encryption.is_ready(&f.path).await
+                    {
+                        continue;
+                    }
+                    match encryption.encrypt(&f.path).await {
+                        Ok(dst) => {
+                            info!("Encrypted {}", db.archive_name(&dst));
+                            encrypted += 1;
+                        }
+                        Err(e) => error!("Failed to encrypt {}: {}", f.path.display(), e),
+                    }
+                }
+                if encrypted > 0 {
+                    db.invalidate_archives().await;
+                }
+            }
+            Err(e) => error!("Failed to list archives: {}", e),
+        }
+        tokio::time::sleep(ENCRYPT_INTERVAL).await;
+    }
+}
+
+/// Re-wrap the file keys of every encrypted archive in `out_dir` and one level of
+/// partition directories, nested `YYYY/MM` directories included, for `recipients`;
+/// needs `--identity`, returns the number rewritten and the number that failed
+pub async fn rekey(out_dir: &Path, recipients: Vec<PublicKey>) -> Result<(usize, usize)> {
+    let Some(identity) = IDENTITY.get() else {
+        bail!("rekey needs --identity with a key the archives are encrypted to");
+    };
+    let recipients = Arc::new(recipients);
+    let (mut written, mut failed) = (0, 0);
+    let mut dirs = vec![(out_dir.to_path_buf(), true)];
+    while let Some((d, top)) = dirs.pop() {
+        let mut dir = tokio::fs::read_dir(&d).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if (top && !name.starts_with('.') && !is_reserved_dir(&name)) || is_date_dir(&name)
+                {
+                    dirs.push((path, false));
+                }
+                continue;
+            }
+            if !is_archive(&path) || !is_encrypted(&path) {
+                continue;
+            }
+            let (p, r) = (path.clone(), recipients.clone());
+            match tokio::task::spawn_blocking(move || rekey_file(&p, identity, &r)).await? {
+                Ok(()) => {
+                    println!("{} rekeyed", path.display());
+                    written += 1;
+                }
+                Err(e) => {
+                    println!("{} failed: {}", path.display(), e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    Ok((written, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sealed size of a full chunk
+    const SEALED_CHUNK: usize = CHUNK_SIZE + TAG_SIZE;
+
+    /// Three full chunks and a short last one
+    fn plaintext() -> Vec<u8> {
+        (0..CHUNK_SIZE * 3 + 100).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn seal(plain: &[u8], recipients: &[PublicKey]) -> Vec<u8> {
+        let mut w = EncryptWriter::new(Vec::new(), recipients).unwrap();
+        w.write_all(plain).unwrap();
+        w.finish().unwrap()
+    }
+
+    fn open(sealed: &[u8], identity: &Keys) -> Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        DecryptReader::new(sealed, identity)?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    /// Where the body of a file with one recipient starts
+    const BODY: usize = PARAMS_SIZE + 1 + STANZA_SIZE;
+
+    #[test]
+    fn round_trip_for_every_recipient() {
+        let (a, b) = (Keys::generate(), Keys::generate());
+        let plain = plaintext();
+        let sealed = seal(&plain, &[a.public_key(), b.public_key()]);
+        assert_eq!(open(&sealed, &a).unwrap(), plain);
+        assert_eq!(open(&sealed, &b).unwrap(), plain);
+
+        // the last chunk is sealed even when it is empty
+        let sealed = seal(&plain[..CHUNK_SIZE], &[a.public_key()]);
+        assert_eq!(sealed.len(), BODY + SEALED_CHUNK + TAG_SIZE);
+        assert_eq!(open(&sealed, &a).unwrap(), &plain[..CHUNK_SIZE]);
+        assert!(open(&seal(b"", &[a.public_key()]), &a).unwrap().is_empty());
+    }
+
+    #[test]
+    fn wrong_key_is_refused() {
+        let sealed = seal(&plaintext(), &[Keys::generate().public_key()]);
+        assert!(open(&sealed, &Keys::generate()).is_err());
+    }
+
+    #[test]
+    fn truncated_last_chunk_fails() {
+        let keys = Keys::generate();
+        let sealed = seal(&plaintext(), &[keys.public_key()]);
+        // inside the last chunk, and the last chunk cut off at a chunk boundary
+        let boundary = BODY + 3 * SEALED_CHUNK;
+        assert_eq!(sealed.len(), boundary + 100 + TAG_SIZE);
+        for len in [sealed.len() - 1, boundary] {
+            assert!(open(&sealed[..len], &keys).is_err());
+        }
+    }
+
+    #[test]
+    fn changed_ciphertext_fails() {
+        let keys = Keys::generate();
+        let sealed = seal(&plaintext(), &[keys.public_key()]);
+        for pos in [BODY, BODY + SEALED_CHUNK + 10, sealed.len() - 1] {
+            let mut changed = sealed.clone();
+            changed[pos] ^= 1;
+            assert!(open(&changed, &keys).is_err());
+        }
+    }
+
+    #[test]
+    fn reordered_chunks_fail() {
+        let keys = Keys::generate();
+        let sealed = seal(&plaintext(), &[keys.public_key()]);
+        let chunk = |n: usize| &sealed[BODY + n * SEALED_CHUNK..BODY + (n + 1) * SEALED_CHUNK];
+        // the first two swapped, and the second repeated in place of the third
+        for order in [[1, 0, 2], [0, 1, 1]] {
+            let mut changed = sealed[..BODY].to_vec();
+            for n in order {
+                changed.extend_from_slice(chunk(n));
+            }
+            changed.extend_from_slice(&sealed[BODY + 3 * SEALED_CHUNK..]);
+            assert!(open(&changed, &keys).is_err());
+        }
+    }
+
+    #[test]
+    fn changed_header_fails() {
+        let keys = Keys::generate();
+        let plain = plaintext();
+        let sealed = seal(&plain, &[keys.public_key()]);
+        // a chunk size reading the body in other chunks, which were sealed with another
+        let mut changed = sealed.clone();
+        changed[16..PARAMS_SIZE].copy_from_slice(&(2 * SEALED_CHUNK as u32).to_be_bytes());
+        assert!(open(&changed, &keys).is_err());
+        let mut changed = sealed.clone();
+        changed[14] = b'1';
+        assert!(open(&changed, &keys).is_err());
+
+        // the stanzas can be replaced, by rekey
+        let other = Keys::generate();
+        let (params, stanzas) = read_header(&mut sealed.as_slice()).unwrap();
+        let file_key = unwrap_key(&stanzas, &keys).unwrap();
+        let mut rekeyed = Vec::new();
+        write_header(&mut rekeyed, &params, &file_key, &[other.public_key()]).unwrap();
+        rekeyed.extend_from_slice(&sealed[BODY..]);
+        assert_eq!(open(&rekeyed, &other).unwrap(), plain);
+        assert!(open(&rekeyed, &keys).is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use crate::db::is_archive;
+use crate::layout::is_date_dir;
+use crate::naming::archive_day;
+use crate::scan::is_reserved_dir;
+use anyhow::{Result, anyhow};
+use chrono::NaiveDate;
+use std::path::{Path, PathBuf};
+
+/// Day given on the command line, `YYYY-MM-DD` or `YYYYMMDD`
+pub fn parse_day(s: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(s, "%Y%m%d"))
+        .map_err(|_| anyhow!("{} is not a date, use YYYY-MM-DD", s))
+}
+
+/// Archives of days in `from..=to` in `out_dir` and its partition directories, either layout, oldest first
+pub fn select_archives(
+    out_dir: &Path,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(out_dir.to_path_buf(), true)];
+    while let Some((d, top)) = dirs.pop() {
+        for entry in std::fs::read_dir(&d)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if (top && !name.starts_with('.') && !is_reserved_dir(&name)) || is_date_dir(&name)
+                {
+                    dirs.push((path, false));
+                }
+                continue;
+            }
+            if !is_archive(&path) {
+                continue;
+            }
+            let Some(day) = archive_day(&path) else {
+                continue;
+            };
+            if from.is_some_and(|f| day < f) || to.is_some_and(|t| day > t) {
+                continue;
+            }
+            files.push((day, path));
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, p)| p).collect())
+}
@@ -0,0 +1,131 @@
+use anyhow::{Result, anyhow, bail};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, OnceLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use url::Url;
+
+/// Max size of response headers
+const MAX_HEAD: usize = 64 * 1024;
+
+static TLS: LazyLock<TlsConnector> = LazyLock::new(|| {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+});
+
+/// `user_agent` of the config, set once at startup
+static USER_AGENT: OnceLock<String> = OnceLock::new();
+
+pub fn set_user_agent(user_agent: String) {
+    let _ = USER_AGENT.set(user_agent);
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Response to a [http_get] request
+pub struct FetchResponse {
+    pub status: u16,
+    pub body: Pin<Box<dyn AsyncRead + Send>>,
+}
+
+impl FetchResponse {
+    /// Read the whole body as a string
+    pub async fn text(mut self) -> Result<String> {
+        let mut ret = String::new();
+        self.body.read_to_string(&mut ret).await?;
+        Ok(ret)
+    }
+}
+
+/// Minimal HTTP/1.1 GET over plain TCP or TLS
+///
+/// Chunked responses are not supported, the server must send a `content-length`
+/// or close the connection after the body.
+pub async fn http_get(url: &Url, headers: &[(&str, String)]) -> Result<FetchResponse> {
+    let host = url.host_str().ok_or(anyhow!("URL has no host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or(anyhow!("URL has no port"))?;
+    let tcp = TcpStream::connect((host, port)).await?;
+    let mut stream: Box<dyn Stream> = match url.scheme() {
+        "http" => Box::new(tcp),
+        "https" => Box::new(
+            TLS.connect(ServerName::try_from(host.to_string())?, tcp)
+                .await?,
+        ),
+        s => bail!("Unsupported scheme {}", s),
+    };
+
+    let user_agent = USER_AGENT
+        .get()
+        .cloned()
+        .unwrap_or_else(|| format!("nostrhole/{}", env!("CARGO_PKG_VERSION")));
+    let mut req = format!(
+        "GET {} HTTP/1.1\r\nhost: {}\r\nconnection: close\r\nuser-agent: {}\r\n",
+        &url[url::Position::BeforePath..url::Position::AfterQuery],
+        host,
+        user_agent
+    );
+    for (k, v) in headers {
+        req.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    // read until end of headers
+    let mut buf = Vec::with_capacity(4096);
+    let head_len = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if buf.len() > MAX_HEAD {
+            bail!("Response headers too large");
+        }
+        let mut chunk = [0u8; 4096];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("Connection closed before response headers");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let mut parsed_headers = [httparse::EMPTY_HEADER; 64];
+    let mut rsp = httparse::Response::new(&mut parsed_headers);
+    rsp.parse(&buf[..head_len])?;
+    let status = rsp.code.ok_or(anyhow!("Response has no status"))?;
+    let headers: HashMap<String, String> = rsp
+        .headers
+        .iter()
+        .map(|h| {
+            (
+                h.name.to_lowercase(),
+                String::from_utf8_lossy(h.value).to_string(),
+            )
+        })
+        .collect();
+    if headers
+        .get("transfer-encoding")
+        .is_some_and(|v| v.contains("chunked"))
+    {
+        bail!("Chunked responses are not supported");
+    }
+
+    let rest = Cursor::new(buf.split_off(head_len)).chain(stream);
+    let body: Pin<Box<dyn AsyncRead + Send>> =
+        match headers.get("content-length").and_then(|v| v.parse().ok()) {
+            Some(len) => Box::pin(rest.take(len)),
+            None => Box::pin(rest),
+        };
+    Ok(FetchResponse { status, body })
+}
@@ -0,0 +1,133 @@
+use crate::kinds::KindSet;
+use hyper::body::{Body, Bytes, Frame};
+use log::debug;
+use nostr_sdk::prelude::JsonUtil;
+use nostr_sdk::{Event, Kind, PublicKey};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+
+/// Interval between SSE keepalive comments
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Messages buffered per SSE connection before it is dropped
+const CONNECTION_QUEUE: usize = 256;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct FirehoseSettings {
+    /// Stream newly saved events at `/events/stream`
+    pub enabled: Option<bool>,
+
+    /// Events buffered for slow consumers before they are disconnected
+    pub queue_size: Option<usize>,
+}
+
+/// Filter parsed from `?kinds=1,7&authors=<hex>,<npub>`
+#[derive(Default)]
+pub struct StreamFilter {
+    kinds: Option<HashSet<Kind>>,
+    authors: Option<HashSet<PublicKey>>,
+    /// Never streamed, the sensitive kinds without download_auth
+    hidden: Option<KindSet>,
+}
+
+impl StreamFilter {
+    pub fn parse(query: Option<&str>) -> Result<Self, String> {
+        let mut ret = Self::default();
+        for (k, v) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            let values = v.split(',').map(|s| s.trim()).filter(|s| !s.is_empty());
+            match k.as_ref() {
+                "kinds" => {
+                    ret.kinds = Some(
+                        values
+                            .map(|s| s.parse::<u16>().map(Kind::from))
+                            .collect::<Result<_, _>>()
+                            .map_err(|_| "Invalid kinds".to_string())?,
+                    )
+                }
+                "authors" => {
+                    ret.authors = Some(
+                        values
+                            .map(PublicKey::parse)
+                            .collect::<Result<_, _>>()
+                            .map_err(|_| "Invalid authors".to_string())?,
+                    )
+                }
+                _ => {}
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn with_hidden(mut self, kinds: Option<KindSet>) -> Self {
+        self.hidden = kinds;
+        self
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        self.kinds.as_ref().is_none_or(|k| k.contains(&event.kind))
+            && self
+                .authors
+                .as_ref()
+                .is_none_or(|a| a.contains(&event.pubkey))
+            && self.hidden.as_ref().is_none_or(|h| !h.contains(event.kind))
+    }
+}
+
+/// SSE response body fed from the firehose
+pub struct EventStream {
+    rx: mpsc::Receiver<Bytes>,
+}
+
+impl EventStream {
+    /// Forward matching events from the firehose until the client goes away or falls behind
+    pub fn new(mut firehose: broadcast::Receiver<Event>, filter: StreamFilter) -> Self {
+        let (tx, rx) = mpsc::channel(CONNECTION_QUEUE);
+        tokio::spawn(async move {
+            let mut keepalive = tokio::time::interval(KEEPALIVE);
+            loop {
+                let msg = tokio::select! {
+                    _ = keepalive.tick() => Bytes::from_static(b": keepalive\n\n"),
+                    e = firehose.recv() => match e {
+                        Ok(e) if filter.matches(&e) => {
+                            Bytes::from(format!("data: {}\n\n", e.as_json()))
+                        }
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(n)) => {
+                            debug!("SSE consumer lagged by {} events, disconnecting", n);
+                            break;
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                };
+                if tx.try_send(msg).is_err() {
+                    // full or closed, either way this consumer is gone
+                    break;
+                }
+            }
+        });
+        Self { rx }
+    }
+}
+
+impl From<mpsc::Receiver<Bytes>> for EventStream {
+    fn from(rx: mpsc::Receiver<Bytes>) -> Self {
+        Self { rx }
+    }
+}
+
+impl Body for EventStream {
+    type Data = Bytes;
+    type Error = String;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.rx.poll_recv(cx).map(|m| m.map(|b| Ok(Frame::data(b))))
+    }
+}
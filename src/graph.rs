@@ -0,0 +1,399 @@
+use crate::db::decode_archive_strict;
+use crate::jobs::JOB_BUFFER;
+use crate::replaceable::ReplaceableIndex;
+use crate::rocks;
+use crate::scan::scan_lines;
+use anyhow::{Result, anyhow, bail};
+use clap::ValueEnum;
+use nostr_sdk::EventId;
+use rocksdb::{DB, IteratorMode, WriteBatch};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Bytes of events held by the full scan before they are moved to the spill store
+const SPILL_BYTES: usize = 256 * 1024 * 1024;
+
+const CONTACTS: u16 = 3;
+const RELAY_LIST: u16 = 10002;
+
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq)]
+pub enum GraphFormat {
+    /// The newest kind 3 and kind 10002 event of each pubkey, one per line
+    #[default]
+    Jsonl,
+    /// `follower_pubkey,followed_pubkey,relay_hint` rows from the `p` tags of the newest
+    /// kind 3 event of each pubkey
+    Csv,
+}
+
+/// Counts of an [export]
+#[derive(Default)]
+pub struct GraphExport {
+    pub archives: usize,
+    pub contact_lists: u64,
+    pub relay_lists: u64,
+    /// Rows written with `--format csv`
+    pub edges: u64,
+    /// Versions in the replaceable index that no archive has
+    pub missing: u64,
+    pub skipped: u64,
+}
+
+#[derive(Deserialize)]
+struct KindOnly {
+    kind: u16,
+}
+
+#[derive(Deserialize)]
+struct GraphFields {
+    id: String,
+    pubkey: String,
+    created_at: u64,
+    kind: u16,
+    #[serde(default)]
+    tags: Vec<Vec<String>>,
+}
+
+/// Newest version of a (kind, pubkey) in the full scan
+struct Latest {
+    created_at: u64,
+    id: [u8; 32],
+    line: Vec<u8>,
+}
+
+impl Latest {
+    /// Newer version wins, ties on created_at go to the lowest id (NIP-01)
+    fn replaces(&self, other: &Latest) -> bool {
+        self.created_at > other.created_at
+            || (self.created_at == other.created_at && self.id < other.id)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(40 + self.line.len());
+        v.extend_from_slice(&self.created_at.to_be_bytes());
+        v.extend_from_slice(&self.id);
+        v.extend_from_slice(&self.line);
+        v
+    }
+
+    fn decode(v: &[u8]) -> Option<Self> {
+        Some(Self {
+            created_at: u64::from_be_bytes(v.get(..8)?.try_into().ok()?),
+            id: v.get(8..40)?.try_into().ok()?,
+            line: v[40..].to_vec(),
+        })
+    }
+}
+
+fn hex32(s: &str) -> Option<[u8; 32]> {
+    let mut ret = [0u8; 32];
+    hex::decode_to_slice(s, &mut ret).ok()?;
+    Some(ret)
+}
+
+fn key(kind: u16, pubkey: &[u8; 32]) -> Vec<u8> {
+    [&kind.to_be_bytes()[..], pubkey].concat()
+}
+
+/// Graph event of a line, None for other kinds and lines that aren't events
+fn parse(line: &[u8]) -> Option<Result<GraphFields, ()>> {
+    match serde_json::from_slice::<KindOnly>(line) {
+        Ok(k) if k.kind == CONTACTS || k.kind == RELAY_LIST => {}
+        Ok(_) => return None,
+        Err(_) => return Some(Err(())),
+    }
+    Some(serde_json::from_slice(line).map_err(|_| ()))
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+struct Output {
+    out: BufWriter<std::fs::File>,
+    format: GraphFormat,
+}
+
+impl Output {
+    fn write(&mut self, line: &[u8], e: &GraphFields, totals: &mut GraphExport) -> Result<()> {
+        if e.kind == CONTACTS {
+            totals.contact_lists += 1;
+        } else {
+            totals.relay_lists += 1;
+        }
+        match self.format {
+            GraphFormat::Jsonl => {
+                self.out.write_all(line)?;
+                self.out.write_all(b"\n")?;
+            }
+            GraphFormat::Csv if e.kind == CONTACTS => {
+                let follower = e.pubkey.to_ascii_lowercase();
+                for t in &e.tags {
+                    let (Some("p"), Some(followed)) = (t.first().map(String::as_str), t.get(1))
+                    else {
+                        continue;
+                    };
+                    if hex32(followed).is_none() {
+                        continue;
+                    }
+                    let hint = t.get(2).map(String::as_str).unwrap_or_default();
+                    writeln!(
+                        self.out,
+                        "{},{},{}",
+                        follower,
+                        followed.to_ascii_lowercase(),
+                        csv_field(hint.trim())
+                    )?;
+                    totals.edges += 1;
+                }
+            }
+            GraphFormat::Csv => {}
+        }
+        Ok(())
+    }
+}
+
+/// Write the newest kind 3 and kind 10002 event of every pubkey in `files` to `out`,
+/// printing progress per archive
+///
+/// With the replaceable index only the indexed versions are kept while reading and the
+/// scan ends once all are found; otherwise the newest version of each pubkey is kept,
+/// moved to a spill store next to `out` when they grow past [SPILL_BYTES]
+pub fn export(
+    files: &[PathBuf],
+    replaceable: Option<&ReplaceableIndex>,
+    out: &Path,
+    format: GraphFormat,
+) -> Result<GraphExport> {
+    if out.exists() {
+        bail!("{} exists", out.display());
+    }
+    let mut output = Output {
+        out: BufWriter::new(std::fs::File::create(out)?),
+        format,
+    };
+    if format == GraphFormat::Csv {
+        writeln!(output.out, "follower_pubkey,followed_pubkey,relay_hint")?;
+    }
+    let totals = match replaceable {
+        Some(index) => export_indexed(files, index, &mut output)?,
+        None => export_scan(files, out, &mut output)?,
+    };
+    output.out.flush()?;
+    Ok(totals)
+}
+
+fn open(path: &Path) -> Result<impl std::io::BufRead> {
+    decode_archive_strict(
+        path,
+        BufReader::with_capacity(JOB_BUFFER, std::fs::File::open(path)?),
+    )
+}
+
+fn export_indexed(
+    files: &[PathBuf],
+    index: &ReplaceableIndex,
+    output: &mut Output,
+) -> Result<GraphExport> {
+    let mut wanted: HashSet<EventId> = index
+        .iter()
+        .filter(|v| matches!(v.kind.as_u16(), CONTACTS | RELAY_LIST))
+        .map(|v| v.id)
+        .collect();
+    println!("Replaceable index has {} graph events", wanted.len());
+    let mut totals = GraphExport::default();
+    let mut failed = None;
+    for (i, path) in files.iter().enumerate() {
+        if wanted.is_empty() {
+            break;
+        }
+        let before = totals.contact_lists + totals.relay_lists;
+        let scan = scan_lines(path, open(path)?, |line| {
+            let e = match parse(line) {
+                None => return true,
+                Some(Err(_)) => return false,
+                Some(Ok(e)) => e,
+            };
+            if failed.is_some() {
+                return true;
+            }
+            if let Ok(id) = EventId::from_hex(&e.id)
+                && wanted.remove(&id)
+                && let Err(err) = output.write(line, &e, &mut totals)
+            {
+                failed = Some(err);
+            }
+            true
+        })?;
+        if let Some(e) = failed.take() {
+            return Err(e);
+        }
+        totals.archives += 1;
+        totals.skipped += scan.skipped;
+        println!(
+            "[{}/{}] {} {} graph events, {} left",
+            i + 1,
+            files.len(),
+            path.display(),
+            totals.contact_lists + totals.relay_lists - before,
+            wanted.len()
+        );
+    }
+    totals.missing = wanted.len() as u64;
+    Ok(totals)
+}
+
+/// Write a version kept by the full scan, only lines that parsed as graph events are kept
+fn write(output: &mut Output, line: &[u8], totals: &mut GraphExport) -> Result<()> {
+    let e: GraphFields = serde_json::from_slice(line)?;
+    output.write(line, &e, totals)
+}
+
+/// Newest versions of the full scan, in memory until they don't fit
+struct Versions {
+    held: HashMap<Vec<u8>, Latest>,
+    bytes: usize,
+    spill: Option<DB>,
+    spill_path: PathBuf,
+}
+
+impl Versions {
+    fn add(&mut self, key: Vec<u8>, v: Latest) -> Result<()> {
+        match self.held.get(&key) {
+            Some(old) if !v.replaces(old) => return Ok(()),
+            Some(old) => self.bytes -= old.line.len(),
+            None => {}
+        }
+        self.bytes += v.line.len();
+        self.held.insert(key, v);
+        if self.bytes > SPILL_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Move the held versions to the spill store, keeping the newer of each
+    fn flush(&mut self) -> Result<()> {
+        if self.held.is_empty() {
+            return Ok(());
+        }
+        let spill = match &mut self.spill {
+            Some(s) => s,
+            None => {
+                println!(
+                    "Moving graph events to {} to stay within {} MB",
+                    self.spill_path.display(),
+                    SPILL_BYTES / 1024 / 1024
+                );
+                self.spill.insert(rocks::open(&self.spill_path)?)
+            }
+        };
+        let mut batch = WriteBatch::default();
+        for (k, v) in self.held.drain() {
+            let stored = spill.get(&k).map_err(|e| anyhow!(e))?;
+            if stored
+                .as_deref()
+                .and_then(Latest::decode)
+                .is_none_or(|s| v.replaces(&s))
+            {
+                batch.put(&k, v.encode());
+            }
+        }
+        spill.write(batch).map_err(|e| anyhow!(e))?;
+        self.bytes = 0;
+        Ok(())
+    }
+}
+
+fn export_scan(files: &[PathBuf], out: &Path, output: &mut Output) -> Result<GraphExport> {
+    let mut spill_path = out.as_os_str().to_owned();
+    spill_path.push(".spill");
+    let mut versions = Versions {
+        held: HashMap::new(),
+        bytes: 0,
+        spill: None,
+        spill_path: PathBuf::from(spill_path),
+    };
+    if versions.spill_path.exists() {
+        std::fs::remove_dir_all(&versions.spill_path)?;
+    }
+    let ret = scan_all(files, &mut versions, output);
+    // the spill store is only needed until the versions are written
+    versions.spill = None;
+    if versions.spill_path.exists() {
+        std::fs::remove_dir_all(&versions.spill_path)?;
+    }
+    ret
+}
+
+fn scan_all(
+    files: &[PathBuf],
+    versions: &mut Versions,
+    output: &mut Output,
+) -> Result<GraphExport> {
+    let mut totals = GraphExport::default();
+    let mut failed = None;
+    for (i, path) in files.iter().enumerate() {
+        let mut found = 0;
+        let scan = scan_lines(path, open(path)?, |line| {
+            let e = match parse(line) {
+                None => return true,
+                Some(Err(_)) => return false,
+                Some(Ok(e)) => e,
+            };
+            let (Some(id), Some(pubkey)) = (hex32(&e.id), hex32(&e.pubkey)) else {
+                return false;
+            };
+            if failed.is_some() {
+                return true;
+            }
+            found += 1;
+            let v = Latest {
+                created_at: e.created_at,
+                id,
+                line: line.to_vec(),
+            };
+            if let Err(err) = versions.add(key(e.kind, &pubkey), v) {
+                failed = Some(err);
+            }
+            true
+        })?;
+        if let Some(e) = failed.take() {
+            return Err(e);
+        }
+        totals.archives += 1;
+        totals.skipped += scan.skipped;
+        println!(
+            "[{}/{}] {} {} graph events, {} MB held",
+            i + 1,
+            files.len(),
+            path.display(),
+            found,
+            versions.bytes / 1024 / 1024
+        );
+    }
+
+    if versions.spill.is_some() {
+        versions.flush()?;
+    }
+    if let Some(spill) = &versions.spill {
+        for kv in spill.iterator(IteratorMode::Start) {
+            let (_, v) = kv.map_err(|e| anyhow!(e))?;
+            let v = Latest::decode(&v).ok_or_else(|| anyhow!("Invalid spilled event"))?;
+            write(output, &v.line, &mut totals)?;
+        }
+    } else {
+        let mut held: Vec<_> = versions.held.drain().collect();
+        held.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, v) in held {
+            write(output, &v.line, &mut totals)?;
+        }
+    }
+    Ok(totals)
+}
@@ -1,28 +1,108 @@
+use crate::access::{AccessEntry, AccessLog};
+use crate::activity::{ActivityStats, DEFAULT_DAYS};
+use crate::auth::DownloadAuth;
+use crate::author_export::{ExportSlots, export_author};
+use crate::changes::ArchiveChanges;
+use crate::db::{ArchiveDatabase, REMOTE_ADDR, content_type, is_active, is_archive};
+use crate::deletions::Deletions;
+use crate::disk::DiskGuard;
+use crate::doctor::{Consistency, is_compressing};
+use crate::downloads::{DownloadCounts, DownloadRecord};
+use crate::encoding::{EncodedCache, Encoding, MIN_ENCODED_SIZE};
+use crate::encrypt::is_encrypted;
+use crate::firehose::{EventStream, StreamFilter};
+use crate::ingest::IngestHealth;
+use crate::kinds::KindSet;
+use crate::landing::{LandingPage, html_escape};
+use crate::layout::flat_name;
+use crate::limit::{ConnectionLimit, DownloadLimit, StreamGuard};
+use crate::metrics;
+use crate::mirror::FileEntry;
+use crate::peers::serve_authenticated;
+use crate::proxy::TrustedProxies;
+use crate::replaceable::export_latest;
+use crate::slice::{SliceSettings, plan, stream};
+use crate::stats::RelayStats;
+use crate::status::LiveStatus;
+use crate::summary::{read_summary, summary_path};
+use crate::throttle::{DownloadThrottle, Throttle};
 use base64::prelude::*;
-use http_body_util::Either;
+use chrono::Utc;
+use http_body_util::{Either, Full};
 use hyper::body::{Body, Bytes, Frame, Incoming};
-use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, UPGRADE};
+use hyper::header::{
+    ACCEPT, ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONNECTION, CONTENT_ENCODING,
+    CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, HOST, HeaderValue, IF_MODIFIED_SINCE,
+    LAST_MODIFIED, LOCATION, RANGE, RETRY_AFTER, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_VERSION, UPGRADE, VARY, WWW_AUTHENTICATE,
+};
+use hyper::http::response::Builder;
 use hyper::service::Service;
-use hyper::{Request, Response};
+use hyper::{Method, Request, Response, Version};
 use hyper_util::rt::TokioIo;
 use itertools::Itertools;
-use log::error;
-use nostr_archive_cursor::JsonFilesDatabase;
+use log::{debug, error, warn};
+use nostr_archive_cursor::ArchiveFile;
 use nostr_relay_builder::LocalRelay;
+use nostr_sdk::nips::nip11::RelayInformationDocument;
 use nostr_sdk::prelude::StreamExt;
+use nostr_sdk::prelude::{JsonUtil, NostrDatabase, RelayStatus, ToBech32};
+use nostr_sdk::{Client, Event, EventId, PublicKey};
 use sha1::Digest;
+use std::collections::HashMap;
 use std::future::Future;
+use std::io::SeekFrom;
 use std::net::SocketAddr;
+use std::path::Path;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
 use thousands::Separable;
 use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
+use tokio::sync::{broadcast, mpsc};
 use tokio_util::io::ReaderStream;
 
+#[derive(Clone)]
 pub(crate) struct HttpServer {
     relay: LocalRelay,
-    db: JsonFilesDatabase,
+    db: ArchiveDatabase,
+    /// Upstream relay pool, None when nothing is ingested
+    client: Option<Client>,
+    relay_stats: RelayStats,
     remote: SocketAddr,
+    proxies: TrustedProxies,
+    downloads: Option<DownloadLimit>,
+    /// Downloads per archive, None when not counted
+    counts: Option<DownloadCounts>,
+    /// Open websocket connections, counted even without caps
+    connections: ConnectionLimit,
+    throttle: DownloadThrottle,
+    access: Option<AccessLog>,
+    auth: Option<DownloadAuth>,
+    /// Tokens with a running `/api/export/`
+    exports: ExportSlots,
+    landing: LandingPage,
+    public_url: Option<String>,
+    firehose: Option<broadcast::Sender<Event>>,
+    /// Journal of file set changes for `/api/changes`
+    changes: Option<ArchiveChanges>,
+    /// Uncompressed bytes a `/slice` may read
+    max_slice_scan: u64,
+    activity: ActivityStats,
+    ingest: IngestHealth,
+    consistency: Consistency,
+    /// Only serve `/api/*`, for metrics listeners
+    api_only: bool,
+    disk: Option<DiskGuard>,
+    /// Encoded text bodies of recent responses
+    encoded: EncodedCache,
+    /// NIP-11 document, serialized once
+    info: Option<Arc<String>>,
+    /// Recent saves and running jobs for the landing page
+    status: LiveStatus,
+    /// Kinds never served, the sensitive kinds when they are archived without download_auth
+    hidden_kinds: Option<KindSet>,
 }
 
 /// Copied from https://github.com/snapview/tungstenite-rs/blob/c16778797b2eeb118aa064aa5b483f90c3989627/src/handshake/mod.rs#L112C1-L125C1
@@ -41,143 +121,1708 @@ pub fn derive_accept_key(request_key: &[u8]) -> String {
 }
 
 impl HttpServer {
-    pub fn new(relay: LocalRelay, db: JsonFilesDatabase, remote: SocketAddr) -> Self {
-        HttpServer { relay, db, remote }
+    pub fn new(
+        relay: LocalRelay,
+        db: ArchiveDatabase,
+        relay_stats: RelayStats,
+        proxies: TrustedProxies,
+    ) -> Self {
+        HttpServer {
+            relay,
+            activity: db.count_stats().cloned().unwrap_or_default(),
+            db,
+            client: None,
+            relay_stats,
+            remote: SocketAddr::from(([0, 0, 0, 0], 0)),
+            proxies,
+            downloads: None,
+            counts: None,
+            connections: ConnectionLimit::default(),
+            throttle: DownloadThrottle::default(),
+            access: None,
+            auth: None,
+            exports: ExportSlots::default(),
+            landing: LandingPage::default(),
+            public_url: None,
+            firehose: None,
+            changes: None,
+            max_slice_scan: SliceSettings::default().max_scan(),
+            ingest: IngestHealth::default(),
+            consistency: Consistency::default(),
+            api_only: false,
+            disk: None,
+            encoded: EncodedCache::default(),
+            info: None,
+            hidden_kinds: None,
+            status: LiveStatus::default(),
+        }
+    }
+
+    /// Answer NIP-11 requests to `/` with `info`
+    pub fn with_relay_info(mut self, info: RelayInformationDocument) -> Self {
+        self.info = Some(Arc::new(info.as_json()));
+        self
+    }
+
+    /// Leave events of `kinds` out of every response, archives that may hold them are
+    /// refused with 403
+    pub fn with_hidden_kinds(mut self, kinds: KindSet) -> Self {
+        self.hidden_kinds = Some(kinds);
+        self
+    }
+
+    /// Show recent saves and running jobs on the landing page
+    pub fn with_status(mut self, status: LiveStatus) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Report free space and ingestion pauses at `/api/health`
+    pub fn with_disk_guard(mut self, disk: DiskGuard) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    /// Report the upstream relays of the ingest client
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Copy of the server answering only `/api/*`
+    pub fn api_only(&self) -> Self {
+        Self {
+            api_only: true,
+            ..self.clone()
+        }
+    }
+
+    /// Report ingest supervisor state at `/api/health`
+    pub fn with_ingest_health(mut self, health: IngestHealth) -> Self {
+        self.ingest = health;
+        self
+    }
+
+    pub fn with_consistency(mut self, consistency: Consistency) -> Self {
+        self.consistency = consistency;
+        self
+    }
+
+    /// Serve newly saved events at `/events/stream`
+    pub fn with_firehose(mut self, firehose: broadcast::Sender<Event>) -> Self {
+        self.firehose = Some(firehose);
+        self
+    }
+
+    pub fn with_changes(mut self, changes: ArchiveChanges) -> Self {
+        self.changes = Some(changes);
+        self
+    }
+
+    pub fn with_slice(mut self, settings: &SliceSettings) -> Self {
+        self.max_slice_scan = settings.max_scan();
+        self
+    }
+
+    /// Base url used for absolute links, defaults to the request Host
+    pub fn with_public_url(mut self, url: String) -> Self {
+        self.public_url = Some(url);
+        self
+    }
+
+    /// Use a custom landing page template
+    pub fn with_landing_page(mut self, landing: LandingPage) -> Self {
+        self.landing = landing;
+        self
+    }
+
+    /// Require a token for downloads
+    pub fn with_auth(mut self, auth: DownloadAuth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Log every request to the access log
+    pub fn with_access_log(mut self, log: AccessLog) -> Self {
+        self.access = Some(log);
+        self
+    }
+
+    /// Limit download bandwidth
+    pub fn with_throttle(mut self, throttle: DownloadThrottle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Rate limit archive downloads per client IP
+    pub fn with_download_limit(mut self, limit: DownloadLimit) -> Self {
+        self.downloads = Some(limit);
+        self
+    }
+
+    /// Count downloads per archive
+    pub fn with_download_counts(mut self, counts: DownloadCounts) -> Self {
+        self.counts = Some(counts);
+        self
+    }
+
+    /// Cap open websocket connections per client IP and in total
+    pub fn with_connection_limit(mut self, limit: ConnectionLimit) -> Self {
+        self.connections = limit;
+        self
+    }
+
+    /// Copy of the server for a connection from `remote`
+    pub fn with_remote(&self, remote: SocketAddr) -> Self {
+        Self {
+            remote,
+            ..self.clone()
+        }
+    }
+}
+
+/// Archives listed in the Atom feed
+const FEED_ENTRIES: usize = 50;
+
+/// Characters of content shown on the html event page
+const EVENT_PREVIEW_CHARS: usize = 280;
+
+/// Months of archives shown per landing page
+const MONTHS_PER_PAGE: usize = 6;
+
+/// Archives listed as popular on the landing page
+const POPULAR_ARCHIVES: usize = 10;
+
+/// Generation of the archive file set with `track_changes`, bumped on every change
+const GENERATION_HEADER: &str = "x-archive-generation";
+
+/// Entry of `f` in `/api/files`
+async fn file_entry(
+    db: &ArchiveDatabase,
+    counts: Option<&DownloadCounts>,
+    f: &ArchiveFile,
+) -> FileEntry {
+    let sha256 = match db.checksum(f).await {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to hash {}: {}", f.path.display(), e);
+            None
+        }
+    };
+    let name = db.archive_name(&f.path);
+    FileEntry {
+        downloads: counts.map(|c| c.get(&flat_name(&name))),
+        encrypted: is_encrypted(&f.path),
+        name,
+        size: f.size,
+        timestamp: f.timestamp.timestamp(),
+        sha256,
+    }
+}
+
+/// Entry of the daily deletions file `f` in `/api/files`
+async fn deletions_entry(
+    db: &ArchiveDatabase,
+    deletions: &Deletions,
+    counts: Option<&DownloadCounts>,
+    f: &ArchiveFile,
+) -> FileEntry {
+    let sha256 = match deletions.checksum(f).await {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to hash {}: {}", f.path.display(), e);
+            None
+        }
+    };
+    let name = db.archive_name(&f.path);
+    FileEntry {
+        downloads: counts.map(|c| c.get(&flat_name(&name))),
+        encrypted: false,
+        name,
+        size: f.size,
+        timestamp: f.timestamp.timestamp(),
+        sha256,
     }
 }
 
+/// `/latest.jsonl.zst` style routes, also below a partition directory
+#[derive(Clone, Copy)]
+enum Latest {
+    /// Newest finalized archive with one of these extensions, any when empty
+    Archive(&'static [&'static str]),
+    /// `/api/files` entry of the newest finalized archive
+    Manifest,
+}
+
+impl Latest {
+    /// Directory and route of a request path
+    fn parse(path: &str) -> Option<(&str, Self)> {
+        let path = path.trim_start_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if dir.contains('/') {
+            return None;
+        }
+        let latest = match name {
+            "latest" => Self::Archive(&[]),
+            "latest.jsonl.zst" => Self::Archive(&["zst"]),
+            "latest.jsonl.gz" => Self::Archive(&["gz"]),
+            "latest-manifest.json" => Self::Manifest,
+            _ => return None,
+        };
+        Some((dir, latest))
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            Self::Archive(exts) => {
+                exts.is_empty()
+                    || path
+                        .extension()
+                        .and_then(|e| e.to_str())
+                        .is_some_and(|e| exts.contains(&e))
+            }
+            Self::Manifest => true,
+        }
+    }
+}
+
+/// Most downloaded of `files` by bytes served, as an html list
+fn popular_archives(
+    db: &ArchiveDatabase,
+    files: &[ArchiveFile],
+    counts: &DownloadCounts,
+) -> String {
+    // counts are kept by flat name, link where the archive is now
+    let names: HashMap<String, (String, u64)> = files
+        .iter()
+        .map(|f| {
+            let name = db.archive_name(&f.path);
+            (flat_name(&name), (name, f.size))
+        })
+        .collect();
+    let top = counts
+        .all()
+        .into_iter()
+        .filter(|(_, c)| c.bytes > 0)
+        .filter_map(|(name, c)| {
+            let (path, size) = names.get(&name)?;
+            Some((c.equivalent(*size), path))
+        })
+        .sorted_by(|a, b| b.0.total_cmp(&a.0))
+        .take(POPULAR_ARCHIVES)
+        .map(|(n, name)| {
+            format!(
+                "<li><a href=\"{}\">{}</a> ({:.1} downloads)</li>",
+                name,
+                html_escape(name),
+                n
+            )
+        })
+        .join("\n");
+    if top.is_empty() {
+        return String::new();
+    }
+    format!("<h3>Popular archives</h3>\n<ol>\n{}\n</ol>", top)
+}
+
+type HttpBody = Either<String, Either<ArchiveFileReader, EventStream>>;
+type HttpResponse = Response<HttpBody>;
+type HttpFuture = Pin<Box<dyn Future<Output = Result<HttpResponse, String>> + Send>>;
+
+/// Response as sent, text bodies may be replaced by their encoded bytes
+type EncodedResponse = Response<Either<HttpBody, Full<Bytes>>>;
+
+/// Encode a text `rsp` of at least [MIN_ENCODED_SIZE] with `encoding`
+fn encode_body(
+    rsp: HttpResponse,
+    encoding: Option<Encoding>,
+    cache: &EncodedCache,
+) -> EncodedResponse {
+    let (mut parts, body) = rsp.into_parts();
+    let text = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|t| t.starts_with("text/") || t.contains("json") || t.contains("xml"));
+    let Either::Left(s) = &body else {
+        return Response::from_parts(parts, Either::Left(body));
+    };
+    if parts.status != 200
+        || !text
+        || s.len() < MIN_ENCODED_SIZE
+        || parts.headers.contains_key(CONTENT_ENCODING)
+    {
+        return Response::from_parts(parts, Either::Left(body));
+    }
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    let Some(encoding) = encoding else {
+        return Response::from_parts(parts, Either::Left(body));
+    };
+    match cache.encode(s, encoding) {
+        Ok(b) => {
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Either::Right(Full::new(b)))
+        }
+        Err(e) => {
+            warn!("Failed to encode response: {}", e);
+            Response::from_parts(parts, Either::Left(body))
+        }
+    }
+}
+
+/// Host the request was sent to, HTTP/2 has it in the uri instead of a header
+fn request_host(req: &Request<Incoming>) -> Option<String> {
+    req.headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))
+        .map(|h| h.to_string())
+}
+
 impl Service<Request<Incoming>> for HttpServer {
-    type Response = Response<Either<String, ArchiveFileReader>>;
+    type Response = EncodedResponse;
     type Error = String;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    type Future = Pin<Box<dyn Future<Output = Result<EncodedResponse, String>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let remote = self.proxies.resolve(self.remote, req.headers());
+        debug!(ip:% = remote.ip(), method:% = req.method(), path = req.uri().path(); "Request");
+        let encoding = req
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|h| h.to_str().ok())
+            .and_then(Encoding::negotiate);
+        let entry = self
+            .access
+            .as_ref()
+            .map(|log| log.start(remote, req.method().as_str(), req.uri().path()));
+        let rsp = self.route(req, remote);
+        let cache = self.encoded.clone();
+        Box::pin(async move {
+            let rsp = match rsp.await {
+                Ok(r) => r,
+                Err(e) => {
+                    if let Some(mut entry) = entry {
+                        entry.set_status(500);
+                        entry.finish(0);
+                    }
+                    return Err(e);
+                }
+            };
+            let mut rsp = encode_body(rsp, encoding, &cache);
+            let Some(mut entry) = entry else {
+                return Ok(rsp);
+            };
+            entry.set_status(rsp.status().as_u16());
+            match rsp.body_mut() {
+                Either::Left(Either::Left(s)) => entry.finish(s.len() as u64),
+                Either::Left(Either::Right(Either::Left(r))) => r.access = Some(entry),
+                // open ended, bytes aren't counted
+                Either::Left(Either::Right(Either::Right(_))) => entry.finish(0),
+                Either::Right(b) => entry.finish(b.size_hint().exact().unwrap_or_default()),
+            }
+            Ok(rsp)
+        })
+    }
+}
+
+impl HttpServer {
+    fn route(&self, req: Request<Incoming>, remote: SocketAddr) -> HttpFuture {
         let base = Response::builder()
             .header("server", "nostr-relay-builder")
             .status(404);
 
+        if self.api_only
+            && (!(req.uri().path().starts_with("/api/")
+                || matches!(req.uri().path(), "/healthz" | "/metrics"))
+                || req.headers().contains_key(UPGRADE))
+        {
+            return Box::pin(async move { Ok(base.body(Either::Left(String::new())).unwrap()) });
+        }
+
+        // websockets over HTTP/2 (RFC 8441) aren't offered, clients have to connect again
+        if req.version() == Version::HTTP_2
+            && (req.method() == Method::CONNECT
+                || req.headers().contains_key(UPGRADE)
+                || req.headers().contains_key(SEC_WEBSOCKET_KEY)
+                || req.headers().contains_key(SEC_WEBSOCKET_VERSION))
+        {
+            return Box::pin(async move {
+                Ok(base
+                    .status(426)
+                    .body(Either::Left(
+                        "Websocket connections need HTTP/1.1, connect without h2 in ALPN"
+                            .to_string(),
+                    ))
+                    .unwrap())
+            });
+        }
+
         // check is upgrade
         if let (Some(c), Some(w)) = (
             req.headers().get("connection"),
             req.headers().get("upgrade"),
-        ) {
-            if c.to_str()
-                .map(|s| s.to_lowercase() == "upgrade")
+        ) && c
+            .to_str()
+            .map(|s| s.to_lowercase() == "upgrade")
+            .unwrap_or(false)
+            && w.to_str()
+                .map(|s| s.to_lowercase() == "websocket")
                 .unwrap_or(false)
-                && w.to_str()
-                    .map(|s| s.to_lowercase() == "websocket")
-                    .unwrap_or(false)
-            {
-                let key = req.headers().get("sec-websocket-key");
-                let derived = key.map(|k| derive_accept_key(k.as_bytes()));
-
-                let addr = self.remote;
-                let relay = self.relay.clone();
-                tokio::spawn(async move {
-                    match hyper::upgrade::on(req).await {
-                        Ok(upgraded) => {
-                            if let Err(e) =
-                                relay.take_connection(TokioIo::new(upgraded), addr).await
-                            {
-                                error!("{}", e);
-                            }
+        {
+            let key = req.headers().get("sec-websocket-key");
+            let derived = key.map(|k| derive_accept_key(k.as_bytes()));
+
+            let Some(guard) = self.connections.acquire(remote.ip()) else {
+                return Box::pin(async move {
+                    Ok(base
+                        .status(429)
+                        .header(RETRY_AFTER, "10")
+                        .body(Either::Left("Too many connections".to_string()))
+                        .unwrap())
+                });
+            };
+            let addr = remote;
+            let relay = self.relay.clone();
+            let authenticate = self.db.trusted_peers().is_some_and(|p| p.has_pubkeys());
+            tokio::spawn(async move {
+                // released when the task ends, also when the socket died without a close
+                let _guard = guard;
+                match hyper::upgrade::on(req).await {
+                    Ok(upgraded) => {
+                        let io = TokioIo::new(upgraded);
+                        let res = REMOTE_ADDR
+                            .scope(addr, async {
+                                if authenticate {
+                                    serve_authenticated(&relay, io, addr).await
+                                } else {
+                                    relay
+                                        .take_connection(io, addr)
+                                        .await
+                                        .map_err(|e| e.to_string())
+                                }
+                            })
+                            .await;
+                        if let Err(e) = res {
+                            error!(ip:% = addr.ip(); "{}", e);
                         }
-                        Err(e) => error!("{}", e),
                     }
+                    Err(e) => error!(ip:% = addr.ip(); "{}", e),
+                }
+            });
+            return Box::pin(async move {
+                Ok(base
+                    .status(101)
+                    .header(CONNECTION, "upgrade")
+                    .header(UPGRADE, "websocket")
+                    .header(SEC_WEBSOCKET_ACCEPT, derived.unwrap())
+                    .body(Either::Left(String::new()))
+                    .unwrap())
+            });
+        }
+
+        if let Some(auth) = &self.auth
+            && auth.is_protected(req.uri().path())
+            && !auth.is_authorized(&req)
+        {
+            return Box::pin(async move {
+                Ok(base
+                    .status(401)
+                    .header(WWW_AUTHENTICATE, "Bearer realm=\"nostrhole\"")
+                    .body(Either::Left(String::new()))
+                    .unwrap())
+            });
+        }
+
+        match req.uri().path() {
+            "/api/files" => self.file_list(base),
+            "/api/changes" => self.file_changes(base, req.uri().query()),
+            "/api/deletions" => self.deleted_ids(base, req.uri().query()),
+            "/slice" => self.slice(base, req.uri().query(), remote),
+            "/feed.xml" => {
+                let host = request_host(&req);
+                let since = req
+                    .headers()
+                    .get(IF_MODIFIED_SINCE)
+                    .and_then(|h| h.to_str().ok())
+                    .map(|h| h.to_string());
+                self.archive_feed(base, host, since)
+            }
+            path if Latest::parse(path).is_some() => {
+                let (dir, latest) = Latest::parse(path).unwrap();
+                let host = request_host(&req);
+                self.latest(base, dir.to_string(), latest, host)
+            }
+            "/api/replaceable" => self.replaceable_export(base),
+            "/api/relays" => self.relay_stats(base),
+            "/api/health" | "/healthz" => self.health(base),
+            "/api/metrics" | "/metrics" => Box::pin(async move {
+                Ok(base
+                    .status(200)
+                    .header("content-type", metrics::CONTENT_TYPE)
+                    .body(Either::Left(metrics::render()))
+                    .unwrap())
+            }),
+            "/api/stats" => self.activity_stats(base, req.uri().query()),
+            "/events/stream" => self.event_stream(base, req.uri().query()),
+            "/" if self.info.is_some()
+                && req
+                    .headers()
+                    .get(ACCEPT)
+                    .and_then(|h| h.to_str().ok())
+                    .is_some_and(|a| a.contains("application/nostr+json")) =>
+            {
+                self.relay_info(base)
+            }
+            "/" | "/index.html" => {
+                self.landing_page(base, req.uri().query().map(|q| q.to_string()))
+            }
+            path if path.starts_with("/api/export/") => {
+                let token = self.auth.as_ref().and_then(|a| a.token_index(&req));
+                let zstd = req
+                    .headers()
+                    .get(ACCEPT_ENCODING)
+                    .and_then(|h| h.to_str().ok())
+                    .is_some_and(|h| h.split(',').any(|e| e.trim().starts_with("zstd")));
+                self.author_export(base, &path[12..], token, zstd)
+            }
+            path if path.starts_with("/e/") => {
+                let accept = req
+                    .headers()
+                    .get(ACCEPT)
+                    .and_then(|h| h.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                self.event_lookup(base, &path[3..], &accept)
+            }
+            path => {
+                let range = req
+                    .headers()
+                    .get(RANGE)
+                    .and_then(|r| r.to_str().ok())
+                    .map(|r| r.to_string());
+                self.archive_file(base, path, range, remote)
+            }
+        }
+    }
+
+    /// Every archived event of a pubkey as jsonl, needs a download auth token and runs one
+    /// export per token at a time
+    fn author_export(
+        &self,
+        base: Builder,
+        author: &str,
+        token: Option<usize>,
+        zstd: bool,
+    ) -> HttpFuture {
+        let rsp = match (token, PublicKey::parse(author)) {
+            // exports are only served with download_auth
+            (None, _) => base.body(Either::Left(String::new())),
+            (Some(_), Err(_)) => base
+                .status(400)
+                .body(Either::Left("Invalid pubkey".to_string())),
+            (Some(t), Ok(pk)) => match self.exports.acquire(t) {
+                None => base
+                    .status(429)
+                    .header(RETRY_AFTER, "60")
+                    .body(Either::Left("An export is already running".to_string())),
+                Some(slot) => {
+                    let mut rsp = base
+                        .status(200)
+                        .header("content-type", "application/x-ndjson");
+                    if zstd {
+                        rsp = rsp.header(CONTENT_ENCODING, "zstd");
+                    }
+                    rsp.body(Either::Right(Either::Right(
+                        export_author(self.db.clone(), pk, zstd, slot).into(),
+                    )))
+                }
+            },
+        };
+        Box::pin(async move { Ok(rsp.unwrap()) })
+    }
+
+    /// Archived event by id as json, or a small html page when the client prefers html
+    fn event_lookup(&self, base: Builder, id: &str, accept: &str) -> HttpFuture {
+        let (id, html) = match id.strip_suffix(".json") {
+            Some(id) => (id, false),
+            None => (id, accept.contains("text/html")),
+        };
+        let content_type = if accept.contains("application/nostr+json") {
+            "application/nostr+json"
+        } else {
+            "application/json"
+        };
+        let id = match EventId::from_hex(id) {
+            Ok(parsed) if id.len() == 64 => parsed,
+            _ => {
+                return Box::pin(async move {
+                    Ok(base
+                        .status(400)
+                        .body(Either::Left("Invalid event id".to_string()))
+                        .unwrap())
                 });
+            }
+        };
+        let db = self.db.clone();
+        let hidden = self.hidden_kinds.clone();
+        Box::pin(async move {
+            let Some(event) = db
+                .event_by_id(&id)
+                .await
+                .map_err(|e| e.to_string())?
+                .filter(|e| hidden.as_ref().is_none_or(|h| !h.contains(e.kind)))
+            else {
+                return Ok(base.body(Either::Left(String::new())).unwrap());
+            };
+            let sources = match db.event_sources(&id) {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Failed to read sources of {}: {}", id, e);
+                    None
+                }
+            };
+            let seen = db.seen_info(&id);
+            if !html {
+                let mut res = base.status(200).header("content-type", content_type);
+                if let Some(s) = &sources {
+                    res = res.header("x-event-sources", s.join(", "));
+                }
+                if let Some(s) = &seen {
+                    res = res
+                        .header("x-event-last-seen", s.last_seen)
+                        .header("x-event-seen-count", s.count);
+                }
+                return Ok(res.body(Either::Left(event.as_json())).unwrap());
+            }
+            let mut seen_on = sources
+                .map(|s| format!("<div>seen on: {}</div>", html_escape(&s.join(", "))))
+                .unwrap_or_default();
+            if let Some(s) = &seen {
+                seen_on.push_str(&format!(
+                    "<div>delivered again: {} times, last {}</div>",
+                    s.count,
+                    chrono::DateTime::from_timestamp(s.last_seen as i64, 0)
+                        .map(|d| d.to_rfc3339())
+                        .unwrap_or_default()
+                ));
+            }
+            let preview: String = event.content.chars().take(EVENT_PREVIEW_CHARS).collect();
+            let page = format!(
+                "<!doctype html><html lang=\"en\"><head><title>{id}</title></head>\
+                 <body style=\"font-family: monospace\"><h3>{id}</h3>\
+                 <div>kind: {}</div><div>author: {}</div><div>created_at: {}</div>{}\
+                 <pre style=\"white-space: pre-wrap\">{}</pre><a href=\"/e/{id}.json\">json</a></body></html>",
+                event.kind,
+                event.pubkey.to_bech32().unwrap_or_default(),
+                chrono::DateTime::from_timestamp(event.created_at.as_secs() as i64, 0)
+                    .map(|d| d.to_rfc3339())
+                    .unwrap_or_default(),
+                seen_on,
+                html_escape(&preview),
+                id = event.id.to_hex(),
+            );
+            Ok(base
+                .status(200)
+                .header("content-type", "text/html")
+                .body(Either::Left(page))
+                .unwrap())
+        })
+    }
+
+    /// Stream an archive file if it exists, supports a single `bytes=` range
+    fn archive_file(
+        &self,
+        base: Builder,
+        path: &str,
+        range: Option<String>,
+        remote: SocketAddr,
+    ) -> HttpFuture {
+        let Ok(f) = self.db.get_file(path) else {
+            return Box::pin(async move { Ok(base.body(Either::Left(String::new())).unwrap()) });
+        };
+        if let Some(k) = &self.hidden_kinds
+            && is_archive(&f.path)
+            && self.db.may_hold_kinds(&f.path, k)
+        {
+            return Box::pin(async move {
+                Ok(base
+                    .status(403)
+                    .body(Either::Left(
+                        "Archive may hold private messages, downloads need download_auth"
+                            .to_string(),
+                    ))
+                    .unwrap())
+            });
+        }
+        let guard = match self.downloads.as_ref().map(|d| d.acquire(remote.ip())) {
+            Some(Ok(g)) => Some(g),
+            Some(Err(retry)) => {
                 return Box::pin(async move {
                     Ok(base
-                        .status(101)
-                        .header(CONNECTION, "upgrade")
-                        .header(UPGRADE, "websocket")
-                        .header(SEC_WEBSOCKET_ACCEPT, derived.unwrap())
+                        .status(429)
+                        .header(RETRY_AFTER, retry.as_secs().max(1).to_string())
                         .body(Either::Left(String::new()))
                         .unwrap())
                 });
             }
+            None => None,
+        };
+        // archives are served from the published view once they are in it, and not before
+        let open = match self
+            .db
+            .published()
+            .filter(|v| v.serves() && is_archive(&f.path))
+        {
+            Some(v) => match v.get_file(&self.db.archive_name(&f.path), &f) {
+                Ok(p) => p.path,
+                Err(_) => {
+                    return Box::pin(
+                        async move { Ok(base.body(Either::Left(String::new())).unwrap()) },
+                    );
+                }
+            },
+            None => f.path.clone(),
+        };
+        let throttle = self.throttle.start();
+        let counts = self.counts.clone();
+        let name = flat_name(&self.db.archive_name(&f.path));
+        Box::pin(async move {
+            let (start, end) = match range.as_deref().map(|r| parse_range(r, f.size)) {
+                Some(Some(r)) => r,
+                Some(None) => {
+                    return Ok(base
+                        .status(416)
+                        .header(CONTENT_RANGE, format!("bytes */{}", f.size))
+                        .body(Either::Left(String::new()))
+                        .unwrap());
+                }
+                None => (0, f.size.saturating_sub(1)),
+            };
+            let mut h = File::open(&open)
+                .await
+                .map_err(|_| "Failed to open file".to_owned())?;
+            if start > 0 {
+                h.seek(SeekFrom::Start(start))
+                    .await
+                    .map_err(|_| "Failed to seek file".to_owned())?;
+            }
+            let len = if f.size == 0 { 0 } else { end - start + 1 };
+            let count = counts.map(|c| DownloadRecord::new(c, name, range.is_some(), len));
+            let content_type = content_type(&f.path);
+            let mut rsp = base
+                .status(if range.is_some() { 206 } else { 200 })
+                .header("content-type", content_type)
+                .header("content-length", len.to_string())
+                .header(ACCEPT_RANGES, "bytes");
+            if range.is_some() {
+                rsp = rsp.header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, f.size));
+            }
+            Ok(rsp
+                .body(Either::Right(Either::Left(ArchiveFileReader {
+                    handle: ArchiveBytes::File(ReaderStream::new(h.take(len))),
+                    guard,
+                    throttle,
+                    sent: 0,
+                    access: None,
+                    count,
+                })))
+                .unwrap())
+        })
+    }
+
+    /// Atom feed of the newest finalized archives
+    fn archive_feed(
+        &self,
+        base: Builder,
+        host: Option<String>,
+        if_modified_since: Option<String>,
+    ) -> HttpFuture {
+        let db = self.db.clone();
+        let public_url = self.public_url.clone();
+        Box::pin(async move {
+            let base_url = public_url
+                .or(host.map(|h| format!("http://{}", h)))
+                .unwrap_or_default();
+            let base_url = base_url.trim_end_matches('/');
+            let files: Vec<ArchiveFile> = db
+                .list_archives()
+                .await
+                .map_err(|e| e.to_string())?
+                .iter()
+                .filter(|f| !is_active(&f.path))
+                .sorted_by(|a, b| b.timestamp.cmp(&a.timestamp))
+                .take(FEED_ENTRIES)
+                .cloned()
+                .collect();
+
+            let updated = files.iter().map(|f| f.created).max();
+            let last_modified = updated
+                .map(|u| u.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+                .unwrap_or_default();
+            if if_modified_since.is_some_and(|s| s == last_modified) {
+                return Ok(base
+                    .status(304)
+                    .header(LAST_MODIFIED, last_modified)
+                    .body(Either::Left(String::new()))
+                    .unwrap());
+            }
+
+            let mut entries = Vec::with_capacity(files.len());
+            for f in &files {
+                let name = db.archive_name(&f.path);
+                let url = html_escape(&format!("{}/{}", base_url, name));
+                let sha256 = db.checksum(f).await.ok().flatten().unwrap_or_default();
+                entries.push(format!(
+                    "<entry><id>{}</id><title>{}</title><updated>{}</updated>\
+                     <link rel=\"enclosure\" href=\"{}\" length=\"{}\" type=\"{}\"/>\
+                     <summary>{} bytes, sha256 {}</summary></entry>",
+                    url,
+                    html_escape(&name),
+                    f.timestamp.to_rfc3339(),
+                    url,
+                    f.size,
+                    content_type(&f.path),
+                    f.size,
+                    sha256
+                ));
+            }
+            let feed = format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                 <feed xmlns=\"http://www.w3.org/2005/Atom\"><id>{}/feed.xml</id><title>nostrhole archives</title>\
+                 <updated>{}</updated><link rel=\"self\" href=\"{}/feed.xml\"/>{}</feed>",
+                html_escape(base_url),
+                updated.map(|u| u.to_rfc3339()).unwrap_or_default(),
+                html_escape(base_url),
+                entries.join("")
+            );
+            let mut rsp = base
+                .status(200)
+                .header("content-type", "application/atom+xml");
+            if !last_modified.is_empty() {
+                rsp = rsp.header(LAST_MODIFIED, last_modified);
+            }
+            Ok(rsp.body(Either::Left(feed)).unwrap())
+        })
+    }
+
+    /// Archive files with size and checksum as json
+    fn file_list(&self, base: Builder) -> HttpFuture {
+        let db = self.db.clone();
+        let counts = self.counts.clone();
+        let base = match &self.changes {
+            Some(c) => base.header(GENERATION_HEADER, c.generation()),
+            None => base,
+        };
+        Box::pin(async move {
+            let mut files = Vec::new();
+            for f in db
+                .list_archives()
+                .await
+                .map_err(|e| e.to_string())?
+                .iter()
+                .sorted_by(|a, b| b.timestamp.cmp(&a.timestamp))
+            {
+                if let Some(v) = db.published().filter(|v| v.serves())
+                    && !v.contains(&db.archive_name(&f.path), f)
+                {
+                    continue;
+                }
+                files.push(file_entry(&db, counts.as_ref(), f).await);
+            }
+            if let Some(d) = db.deletions() {
+                for f in d.files().await.map_err(|e| e.to_string())? {
+                    files.push(deletions_entry(&db, d, counts.as_ref(), &f).await);
+                }
+            }
+            Ok(base
+                .status(200)
+                .header("content-type", "application/json")
+                .body(Either::Left(serde_json::to_string(&files).unwrap()))
+                .unwrap())
+        })
+    }
+
+    /// Finalized archive files added, replaced or deleted after `?since=` (unix time or
+    /// RFC 3339), 404 unless changes are tracked
+    fn file_changes(&self, base: Builder, query: Option<&str>) -> HttpFuture {
+        let Some(changes) = self.changes.clone() else {
+            return Box::pin(async move {
+                Ok(base.status(404).body(Either::Left(String::new())).unwrap())
+            });
+        };
+        let mut since = Ok(0);
+        for (k, v) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            if k == "since" {
+                since = parse_time(&v).ok_or(());
+            }
         }
+        let Ok(since) = since else {
+            return Box::pin(async move {
+                Ok(base
+                    .status(400)
+                    .body(Either::Left("Invalid since".to_string()))
+                    .unwrap())
+            });
+        };
+        Box::pin(async move {
+            // read before the listing, which covers at least this generation
+            let generation = changes.generation();
+            let files = tokio::task::spawn_blocking(move || changes.since(since))
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?;
+            let body = serde_json::json!({
+                "generation": generation,
+                "since": since,
+                "changes": files,
+            });
+            Ok(base
+                .status(200)
+                .header("content-type", "application/json")
+                .header(GENERATION_HEADER, generation)
+                .body(Either::Left(body.to_string()))
+                .unwrap())
+        })
+    }
 
-        // Check path is file path to serve file
-        let path = req.uri().path();
-        if path != "/" && path != "/index.html" {
-            if let Ok(f) = self.db.get_file(path) {
-                Box::pin(async move {
-                    File::open(f.path)
-                        .await
-                        .map(|h| {
-                            base.status(200)
-                                .header("content-type", "application/octet-stream")
-                                .header("content-length", f.size.to_string())
-                                .body(Either::Right(ArchiveFileReader {
-                                    handle: ReaderStream::new(h),
-                                }))
-                                .unwrap()
-                        })
-                        .map_err(|_| "Failed to open file".to_owned())
-                })
-            } else {
-                Box::pin(async move { Ok(base.body(Either::Left(String::new())).unwrap()) })
+    /// Event ids deleted by the kind 5 events saved on the day of `?since=` (unix time or
+    /// RFC 3339) and later, 404 unless deletions are tracked
+    fn deleted_ids(&self, base: Builder, query: Option<&str>) -> HttpFuture {
+        let Some(deletions) = self.db.deletions().cloned() else {
+            return Box::pin(async move {
+                Ok(base.status(404).body(Either::Left(String::new())).unwrap())
+            });
+        };
+        let mut since = Ok(0);
+        for (k, v) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            if k == "since" {
+                since = parse_time(&v).ok_or(());
             }
-        } else {
-            // serve landing page otherwise
-            let template = include_str!("./index.html");
-            let db = self.db.clone();
-            Box::pin(async move {
-                let files: Vec<(u64, String)> = db
-                    .list_files()
-                    .await
-                    .unwrap()
-                    .iter()
-                    .sorted_by(|a, b| b.timestamp.cmp(&a.timestamp))
-                    .map(|f| {
-                        let name = f.path.file_name().unwrap().to_str().unwrap();
-                        (f.size, String::from(name))
-                    })
-                    .collect();
+        }
+        let Some(since) = since
+            .ok()
+            .and_then(|s| chrono::DateTime::from_timestamp(s as i64, 0))
+        else {
+            return Box::pin(async move {
+                Ok(base
+                    .status(400)
+                    .body(Either::Left("Invalid since".to_string()))
+                    .unwrap())
+            });
+        };
+        Box::pin(async move {
+            let ids = deletions
+                .deleted_ids(since)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(base
+                .status(200)
+                .header("content-type", "application/json")
+                .body(Either::Left(serde_json::to_string(&ids).unwrap()))
+                .unwrap())
+        })
+    }
 
+    /// Events with `from <= created_at <= to` (unix time or RFC 3339) as ndjson, read from
+    /// the overlapping frames of seekable archives; counts as a download for the rate and
+    /// bandwidth limits and is refused with 413 when it would read more than `max_scan_mb`
+    fn slice(&self, base: Builder, query: Option<&str>, remote: SocketAddr) -> HttpFuture {
+        let (mut from, mut to) = (None, None);
+        for (k, v) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match k.as_ref() {
+                "from" => from = parse_time(&v),
+                "to" => to = parse_time(&v),
+                _ => {}
+            }
+        }
+        let (Some(from), Some(to)) = (from, to) else {
+            return Box::pin(async move {
+                Ok(base
+                    .status(400)
+                    .body(Either::Left("Set from and to".to_string()))
+                    .unwrap())
+            });
+        };
+        if from > to {
+            return Box::pin(async move {
                 Ok(base
+                    .status(400)
+                    .body(Either::Left("from is after to".to_string()))
+                    .unwrap())
+            });
+        }
+        let guard = match self.downloads.as_ref().map(|d| d.acquire(remote.ip())) {
+            Some(Ok(g)) => Some(g),
+            Some(Err(retry)) => {
+                return Box::pin(async move {
+                    Ok(base
+                        .status(429)
+                        .header(RETRY_AFTER, retry.as_secs().max(1).to_string())
+                        .body(Either::Left(String::new()))
+                        .unwrap())
+                });
+            }
+            None => None,
+        };
+        let throttle = self.throttle.start();
+        let (db, max) = (self.db.clone(), self.max_slice_scan);
+        let hidden = self.hidden_kinds.clone();
+        Box::pin(async move {
+            let plan = plan(&db, from, to).await.map_err(|e| e.to_string())?;
+            if plan.bytes > max {
+                return Ok(base
+                    .status(413)
+                    .body(Either::Left(format!(
+                        "The range would read {} MB of archives, more than the {} MB allowed",
+                        plan.bytes.div_ceil(1024 * 1024),
+                        max / 1024 / 1024
+                    )))
+                    .unwrap());
+            }
+            Ok(base
+                .status(200)
+                .header("content-type", "application/x-ndjson")
+                .body(Either::Right(Either::Left(ArchiveFileReader {
+                    handle: ArchiveBytes::Slice(stream(plan, from, to, hidden)),
+                    guard,
+                    throttle,
+                    sent: 0,
+                    access: None,
+                    count: None,
+                })))
+                .unwrap())
+        })
+    }
+
+    /// Redirect to the newest finalized archive of `dir`, or serve its `/api/files` entry
+    /// for the manifest; 404 with a json error until an archive was finalized
+    fn latest(
+        &self,
+        base: Builder,
+        dir: String,
+        latest: Latest,
+        host: Option<String>,
+    ) -> HttpFuture {
+        let db = self.db.clone();
+        let counts = self.counts.clone();
+        let public_url = self.public_url.clone();
+        Box::pin(async move {
+            let files = db.list_archives().await.map_err(|e| e.to_string())?;
+            let mut newest: Option<&ArchiveFile> = None;
+            for f in files
+                .iter()
+                .filter(|f| !is_active(&f.path) && latest.matches(&f.path))
+            {
+                let name = flat_name(&db.archive_name(&f.path));
+                if Path::new(&name).parent() != Some(Path::new(&dir))
+                    || db.is_pending(&f.path).await
+                {
+                    continue;
+                }
+                if newest.is_none_or(|n| (f.timestamp, &f.path) > (n.timestamp, &n.path)) {
+                    newest = Some(f);
+                }
+            }
+            let Some(f) = newest else {
+                return Ok(base
+                    .status(404)
+                    .header("content-type", "application/json")
+                    .body(Either::Left(
+                        serde_json::json!({ "error": "No finalized archive yet" }).to_string(),
+                    ))
+                    .unwrap());
+            };
+            let rsp = match latest {
+                Latest::Manifest => base
                     .status(200)
-                    .header("content-type", "text/html")
+                    .header("content-type", "application/json")
                     .body(Either::Left(
-                        template
-                            .replace(
-                                "%%_LINKS_%%",
-                                &files
-                                    .iter()
-                                    .map(|f| {
-                                        format!(
-                                            "<a href=\"{}\">{} ({:.2} MiB)</a>",
-                                            f.1,
-                                            f.1,
-                                            f.0 as f64 / 1024. / 1024.
-                                        )
-                                    })
-                                    .collect::<Vec<_>>()
-                                    .join("\n"),
-                            )
-                            .replace(
-                                "%%_TOTAL_EVENTS_%%",
-                                db.count_keys().separate_with_commas().as_str(),
-                            )
-                            .replace(
-                                "%%_TOTAL_SIZE_%%",
-                                &format!(
-                                    "{:.3} GiB",
-                                    files.iter().fold(0u64, |acc, v| acc + v.0) as f64
-                                        / 1024.0
-                                        / 1024.0
-                                        / 1024.0
-                                ),
+                        serde_json::to_string(&file_entry(&db, counts.as_ref(), f).await).unwrap(),
+                    )),
+                Latest::Archive(_) => {
+                    let base_url = public_url
+                        .or(host.map(|h| format!("http://{}", h)))
+                        .unwrap_or_default();
+                    base.status(302)
+                        .header(
+                            LOCATION,
+                            format!(
+                                "{}/{}",
+                                base_url.trim_end_matches('/'),
+                                db.archive_name(&f.path)
                             ),
-                    ))
+                        )
+                        .header(CACHE_CONTROL, "no-cache")
+                        .body(Either::Left(String::new()))
+                }
+            };
+            Ok(rsp.unwrap())
+        })
+    }
+
+    /// Latest version of each replaceable event as json lines
+    fn replaceable_export(&self, base: Builder) -> HttpFuture {
+        let rsp = match self.db.replaceable() {
+            None => base.body(Either::Left(String::new())),
+            Some(index) => base
+                .status(200)
+                .header("content-type", "application/x-ndjson")
+                .body(Either::Right(Either::Right(
+                    export_latest(index.clone(), self.hidden_kinds.clone()).into(),
+                ))),
+        };
+        Box::pin(async move { Ok(rsp.unwrap()) })
+    }
+
+    /// Per upstream relay ingestion counters as json
+    fn relay_stats(&self, base: Builder) -> HttpFuture {
+        let client = self.client.clone();
+        let stats = self.relay_stats.clone();
+        Box::pin(async move {
+            let relays = match &client {
+                Some(c) => stats.snapshot(c).await,
+                None => vec![],
+            };
+            Ok(base
+                .status(200)
+                .header("content-type", "application/json")
+                .body(Either::Left(serde_json::to_string(&relays).unwrap()))
+                .unwrap())
+        })
+    }
+
+    /// Ingest loop restarts and upstream connection counts
+    fn relay_info(&self, base: Builder) -> HttpFuture {
+        let info = self.info.clone().unwrap_or_default();
+        Box::pin(async move {
+            Ok(base
+                .status(200)
+                .header("content-type", "application/nostr+json")
+                .header("access-control-allow-origin", "*")
+                .body(Either::Left(info.to_string()))
+                .unwrap())
+        })
+    }
+
+    fn health(&self, base: Builder) -> HttpFuture {
+        let client = self.client.clone();
+        let ingest = self.ingest.snapshot();
+        let jobs = self.relay_stats.jobs();
+        let index_check = self.consistency.snapshot();
+        let limits = self.db.limits().map(|l| l.counts());
+        let disk = self.disk.as_ref().map(|d| d.snapshot());
+        let connections = self.connections.active();
+        let relay_writes = self.db.trusted_peers().map(|p| p.counts());
+        let dead_letters = self.db.dead_letters().map(|d| d.counts());
+        let invalid_ids = self.relay_stats.invalid_ids();
+        let db = self.db.clone();
+        Box::pin(async move {
+            let index_bytes = tokio::task::spawn_blocking(move || db.index_size())
+                .await
+                .map_err(|e| e.to_string())?;
+            let relays = match &client {
+                Some(c) => c.relays().await,
+                None => Default::default(),
+            };
+            let connected = relays
+                .values()
+                .filter(|r| r.status() == RelayStatus::Connected)
+                .count();
+            let body = serde_json::json!({
+                "ingest": ingest,
+                "ingest_jobs": (!jobs.is_empty()).then_some(jobs),
+                "relays": relays.len(),
+                "connected": connected,
+                "event_limits": limits,
+                "disk": disk,
+                "connections": connections,
+                "relay_writes": relay_writes,
+                "dead_letters": dead_letters,
+                "invalid_ids": invalid_ids,
+                "save_latency_p99_ms": metrics::SAVE_LATENCY.quantile(0.99).map(|s| s * 1000.),
+                "event_index_bytes": index_bytes,
+                "consistency": index_check.as_ref().map(|c| c.status),
+                "index_check": index_check,
+            });
+            Ok(base
+                .status(200)
+                .header("content-type", "application/json")
+                .body(Either::Left(body.to_string()))
+                .unwrap())
+        })
+    }
+
+    /// Event totals, per kind counts and a per-day series, `?days=90&kind=1`, with
+    /// `&author=<npub>` the events of a pubkey are counted from the author index
+    fn activity_stats(&self, base: Builder, query: Option<&str>) -> HttpFuture {
+        let (mut days, mut kind, mut author) = (Ok(DEFAULT_DAYS), Ok(None), Ok(None));
+        for (k, v) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match k.as_ref() {
+                "days" => days = v.parse::<u64>().map_err(|_| ()),
+                "kind" => kind = v.parse::<u16>().map(Some).map_err(|_| ()),
+                "author" => author = PublicKey::parse(&v).map(Some).map_err(|_| ()),
+                _ => {}
+            }
+        }
+        let (Ok(days), Ok(kind), Ok(author)) = (days, kind, author) else {
+            return Box::pin(async move {
+                Ok(base
+                    .status(400)
+                    .body(Either::Left("Invalid days, kind or author".to_string()))
                     .unwrap())
-            })
+            });
+        };
+        let db = self.db.clone();
+        let activity = self.activity.clone();
+        Box::pin(async move {
+            let mut stats = activity
+                .get(&db, days, kind)
+                .await
+                .map_err(|e| e.to_string())?;
+            if let Some(pk) = author {
+                let db = db.clone();
+                stats.author_events =
+                    tokio::task::spawn_blocking(move || db.events_by_author_count(&pk))
+                        .await
+                        .map_err(|e| e.to_string())?
+                        .map_err(|e| e.to_string())?;
+            }
+            Ok(base
+                .status(200)
+                .header("content-type", "application/json")
+                .body(Either::Left(serde_json::to_string(&stats).unwrap()))
+                .unwrap())
+        })
+    }
+
+    /// Newly saved events as Server-Sent Events, filtered by `?kinds=` and `?authors=`
+    fn event_stream(&self, base: Builder, query: Option<&str>) -> HttpFuture {
+        let rsp = match (&self.firehose, StreamFilter::parse(query)) {
+            (None, _) => base.status(404).body(Either::Left(String::new())),
+            (Some(_), Err(e)) => base.status(400).body(Either::Left(e)),
+            (Some(f), Ok(filter)) => base
+                .status(200)
+                .header("content-type", "text/event-stream")
+                .header("cache-control", "no-cache")
+                .body(Either::Right(Either::Right(EventStream::new(
+                    f.subscribe(),
+                    filter.with_hidden(self.hidden_kinds.clone()),
+                )))),
+        };
+        Box::pin(async move { Ok(rsp.unwrap()) })
+    }
+
+    fn landing_page(&self, base: Builder, query: Option<String>) -> HttpFuture {
+        let template = self.landing.template();
+        let db = self.db.clone();
+        let client = self.client.clone();
+        let stats = self.relay_stats.clone();
+        let activity = self.activity.clone();
+        let connections = self.connections.active();
+        let counts = self.counts.clone();
+        let status = self.status.clone();
+        Box::pin(async move {
+            let (mut page, mut year) = (0usize, None);
+            for (k, v) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+                match k.as_ref() {
+                    "page" => page = v.parse().unwrap_or(0),
+                    "year" => year = Some(v.to_string()),
+                    _ => {}
+                }
+            }
+
+            let files = db.list_archives().await.map_err(|e| e.to_string())?;
+            let total_size = files.iter().fold(0u64, |acc, f| acc + f.size);
+            let years: Vec<String> = files
+                .iter()
+                .map(|f| f.timestamp.format("%Y").to_string())
+                .unique()
+                .sorted_by(|a, b| b.cmp(a))
+                .collect();
+
+            // newest month first, each page renders at most MONTHS_PER_PAGE months
+            let months: Vec<(String, Vec<&ArchiveFile>)> = files
+                .iter()
+                .filter(|f| {
+                    year.as_ref()
+                        .is_none_or(|y| f.timestamp.format("%Y").to_string() == *y)
+                })
+                .sorted_by(|a, b| b.timestamp.cmp(&a.timestamp))
+                .chunk_by(|f| f.timestamp.format("%Y-%m").to_string())
+                .into_iter()
+                .map(|(m, g)| (m, g.collect()))
+                .collect();
+            let pages = months.len().div_ceil(MONTHS_PER_PAGE).max(1);
+            let page = page.min(pages - 1);
+
+            let mut magnets = HashMap::new();
+            let mut summaries = HashMap::new();
+            let mut authors = HashMap::new();
+            for f in months
+                .iter()
+                .skip(page * MONTHS_PER_PAGE)
+                .take(MONTHS_PER_PAGE)
+                .flat_map(|(_, files)| files)
+            {
+                if let Some(m) = db.magnet(f).await {
+                    magnets.insert(&f.path, m);
+                }
+                let summary = summary_path(&f.path);
+                if tokio::fs::try_exists(&summary).await.unwrap_or(false) {
+                    summaries.insert(&f.path, db.archive_name(&summary));
+                }
+                if let Some(s) = read_summary(&f.path).await {
+                    authors.insert(&f.path, s.authors);
+                }
+            }
+
+            let links = months
+                .iter()
+                .skip(page * MONTHS_PER_PAGE)
+                .take(MONTHS_PER_PAGE)
+                .enumerate()
+                .map(|(i, (month, files))| {
+                    format!(
+                        "<details{}><summary>{} ({} files, {})</summary>\n{}\n</details>",
+                        if i == 0 { " open" } else { "" },
+                        month,
+                        files.len(),
+                        format_size(files.iter().fold(0u64, |acc, f| acc + f.size)),
+                        files
+                            .iter()
+                            .map(|f| {
+                                let name = db.archive_name(&f.path);
+                                format!(
+                                    "<div><a href=\"{}\">{} ({:.2} MiB)</a>{}{}{}</div>",
+                                    name,
+                                    name,
+                                    f.size as f64 / 1024. / 1024.,
+                                    authors
+                                        .get(&f.path)
+                                        .map(|n| format!(" {} authors", n.separate_with_commas()))
+                                        .unwrap_or_default(),
+                                    summaries
+                                        .get(&f.path)
+                                        .map(|s| format!(" <a href=\"{}\">stats</a>", s))
+                                        .unwrap_or_default(),
+                                    magnets
+                                        .get(&f.path)
+                                        .map(|m| format!(
+                                            " <a href=\"{}\">magnet</a>",
+                                            html_escape(m)
+                                        ))
+                                        .unwrap_or_default()
+                                )
+                            })
+                            .join("\n")
+                    )
+                })
+                .join("\n");
+
+            let year_param = year
+                .as_ref()
+                .map(|y| format!("year={}&", y))
+                .unwrap_or_default();
+            let mut nav = vec!["<a href=\"/\">all</a>".to_string()];
+            nav.extend(
+                years
+                    .iter()
+                    .map(|y| format!("<a href=\"?year={}\">{}</a>", y, y)),
+            );
+            if page > 0 {
+                nav.push(format!(
+                    "<a href=\"?{}page={}\">newer</a>",
+                    year_param,
+                    page - 1
+                ));
+            }
+            if page + 1 < pages {
+                nav.push(format!(
+                    "<a href=\"?{}page={}\">older</a>",
+                    year_param,
+                    page + 1
+                ));
+            }
+
+            let relays = match &client {
+                Some(c) => stats.snapshot(c).await,
+                None => vec![],
+            };
+            // only count archives when the template wants the chart
+            let popular = match &counts {
+                Some(c) if template.contains("%%_POPULAR_%%") => popular_archives(&db, &files, c),
+                _ => String::new(),
+            };
+            let a = if template.contains("%%_CHART_DATA_%%") || template.contains("%%_ZAPS_%%") {
+                Some(
+                    activity
+                        .get(&db, DEFAULT_DAYS, None)
+                        .await
+                        .map_err(|e| e.to_string())?,
+                )
+            } else {
+                None
+            };
+            let chart = a
+                .as_ref()
+                .map(|a| serde_json::to_string(a).unwrap())
+                .unwrap_or_default();
+            let zaps = match a.as_ref().and_then(|a| a.zaps.as_ref()) {
+                Some(z) => format!(
+                    "{} zaps ({} sats) and {} reactions in the last {} days",
+                    z.zaps.separate_with_commas(),
+                    z.sats.separate_with_commas(),
+                    z.reactions.separate_with_commas(),
+                    DEFAULT_DAYS
+                ),
+                None => String::new(),
+            };
+            let deletions = match db.deletions() {
+                Some(d) if template.contains("%%_DELETIONS_%%") => {
+                    let files = d.files().await.map_err(|e| e.to_string())?;
+                    if files.is_empty() {
+                        String::new()
+                    } else {
+                        format!(
+                            "<h3>Deletions</h3>\n{}",
+                            files
+                                .iter()
+                                .map(|f| {
+                                    let name = db.archive_name(&f.path);
+                                    format!(
+                                        "<div><a href=\"{}\">{} ({:.2} KiB)</a></div>",
+                                        name,
+                                        name,
+                                        f.size as f64 / 1024.
+                                    )
+                                })
+                                .join("\n")
+                        )
+                    }
+                }
+                _ => String::new(),
+            };
+            let total_events = db.count_keys().map_err(|e| e.to_string())?;
+            let mut template = template;
+            for (k, v) in status_fields(&db, client.as_ref(), &status, &files).await {
+                template = template.replace(k, &v);
+            }
+
+            Ok(base
+                .status(200)
+                .header("content-type", "text/html")
+                .body(Either::Left(
+                    template
+                        .replace("%%_NAV_%%", &nav.join(" "))
+                        .replace("%%_LINKS_%%", &links)
+                        .replace("%%_DELETIONS_%%", &deletions)
+                        .replace(
+                            "%%_RELAYS_%%",
+                            &relays
+                                .iter()
+                                .map(|r| {
+                                    format!(
+                                        "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                                        r.url,
+                                        r.status,
+                                        r.new.separate_with_commas(),
+                                        r.duplicate.separate_with_commas(),
+                                        r.reconnects
+                                    )
+                                })
+                                .collect::<Vec<_>>()
+                                .join("\n"),
+                        )
+                        .replace("%%_CONNECTIONS_%%", &connections.to_string())
+                        .replace(
+                            "%%_UNIQUE_AUTHORS_%%",
+                            db.unique_authors()
+                                .unwrap_or_default()
+                                .separate_with_commas()
+                                .as_str(),
+                        )
+                        .replace(
+                            "%%_TOTAL_EVENTS_%%",
+                            total_events.separate_with_commas().as_str(),
+                        )
+                        .replace("%%_TOTAL_SIZE_%%", &format_size(total_size))
+                        .replace("%%_CHART_DATA_%%", &chart)
+                        .replace("%%_ZAPS_%%", &zaps)
+                        .replace("%%_POPULAR_%%", &popular),
+                ))
+                .unwrap())
+        })
+    }
+}
+
+/// Relay status placeholders of the landing page, upstream relays, recent saves, the
+/// files being written and running jobs
+async fn status_fields(
+    db: &ArchiveDatabase,
+    client: Option<&Client>,
+    status: &LiveStatus,
+    files: &[ArchiveFile],
+) -> [(&'static str, String); 5] {
+    let relays = match client {
+        Some(c) => c.relays().await,
+        None => Default::default(),
+    };
+    let upstream = if relays.is_empty() {
+        "ingestion disabled".to_string()
+    } else {
+        let connected = relays
+            .values()
+            .filter(|r| r.status() == RelayStatus::Connected)
+            .count();
+        format!(
+            "{} of {} upstream relays connected{}",
+            connected,
+            relays.len(),
+            if db.is_paused() {
+                ", paused: storage full"
+            } else {
+                ""
+            }
+        )
+    };
+    let last = status
+        .last_saved()
+        .and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0))
+        .map(|d| d.to_rfc3339())
+        .unwrap_or("none since startup".to_string());
+
+    // the writer's files of today, whatever file_name_template finalizes them as
+    let today = format!("events_{}.jsonl", Utc::now().format("%Y%m%d"));
+    let today_zst = format!("{}.zst", today);
+    let mut current = Vec::new();
+    for f in files.iter().filter(|f| {
+        f.path
+            .file_name()
+            .is_some_and(|n| n == today.as_str() || n == today_zst.as_str())
+    }) {
+        let size = tokio::fs::metadata(&f.path)
+            .await
+            .map_or(f.size, |m| m.len());
+        current.push(format!(
+            "{} ({})",
+            html_escape(&db.archive_name(&f.path)),
+            format_size(size)
+        ));
+    }
+    if current.is_empty() {
+        current.push("nothing written today".to_string());
+    }
+
+    let mut jobs = Vec::new();
+    let compressing: Vec<String> = files
+        .iter()
+        .filter(|f| is_compressing(&f.path))
+        .map(|f| html_escape(&db.archive_name(&f.path)))
+        .collect();
+    if !compressing.is_empty() {
+        jobs.push(format!("compressing {}", compressing.join(", ")));
+    }
+    match status.running_jobs() {
+        0 => {}
+        1 => jobs.push("1 archive job running".to_string()),
+        n => jobs.push(format!("{} archive jobs running", n)),
+    }
+    if jobs.is_empty() {
+        jobs.push("idle".to_string());
+    }
+    [
+        ("%%_UPSTREAM_%%", upstream),
+        (
+            "%%_EVENTS_LAST_HOUR_%%",
+            status.last_hour().separate_with_commas(),
+        ),
+        ("%%_LAST_EVENT_%%", last),
+        ("%%_CURRENT_FILE_%%", current.join(", ")),
+        ("%%_JOBS_%%", jobs.join("; ")),
+    ]
+}
+
+/// Human readable size in GiB, or MiB below 1 GiB
+fn format_size(size: u64) -> String {
+    if size >= 1024 * 1024 * 1024 {
+        format!("{:.3} GiB", size as f64 / 1024. / 1024. / 1024.)
+    } else {
+        format!("{:.2} MiB", size as f64 / 1024. / 1024.)
+    }
+}
+
+/// Parse a single `bytes=start-end` range header into an inclusive range
+///
+/// Returns None when the range is not satisfiable
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let last = size.checked_sub(1)?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let n: u64 = suffix.parse().ok()?;
+            (size.checked_sub(n.min(size))?, last)
+        }
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(last)),
+    };
+    if start > end {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
+/// Unix time or RFC 3339 of a query param
+fn parse_time(v: &str) -> Option<u64> {
+    v.parse::<u64>().ok().or_else(|| {
+        chrono::DateTime::parse_from_rfc3339(v)
+            .ok()
+            .map(|t| t.timestamp().max(0) as u64)
+    })
+}
+
+/// Body of a download, a range of an archive file or the lines of a slice
+pub enum ArchiveBytes {
+    File(ReaderStream<Take<File>>),
+    Slice(mpsc::Receiver<std::io::Result<Bytes>>),
+}
+
+impl ArchiveBytes {
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<std::io::Result<Bytes>>> {
+        match self {
+            ArchiveBytes::File(f) => f.poll_next_unpin(cx),
+            ArchiveBytes::Slice(rx) => rx.poll_recv(cx),
         }
     }
 }
 
 pub struct ArchiveFileReader {
-    pub handle: ReaderStream<File>,
+    pub handle: ArchiveBytes,
+    /// Download slot held for as long as the body is streaming
+    pub guard: Option<StreamGuard>,
+    pub throttle: Option<Throttle>,
+    /// Bytes sent so far
+    pub sent: u64,
+    pub access: Option<AccessEntry>,
+    pub count: Option<DownloadRecord>,
+}
+
+impl Drop for ArchiveFileReader {
+    fn drop(&mut self) {
+        // also logs aborted downloads with the bytes sent before the client went away
+        if let Some(a) = self.access.take() {
+            a.finish(self.sent);
+        }
+        if let Some(c) = self.count.take() {
+            c.record(self.sent);
+        }
+    }
 }
 
 impl Body for ArchiveFileReader {
@@ -188,11 +1833,249 @@ impl Body for ArchiveFileReader {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
-        match self.handle.poll_next_unpin(cx) {
-            Poll::Ready(Some(Ok(data))) => Poll::Ready(Some(Ok(Frame::data(data)))),
+        if let Some(t) = self.throttle.as_mut() {
+            ready!(t.poll_ready(cx));
+        }
+        match self.handle.poll_next(cx) {
+            Poll::Ready(Some(Ok(data))) => {
+                if let Some(t) = self.throttle.as_mut() {
+                    t.consume(data.len());
+                }
+                self.sent += data.len() as u64;
+                Poll::Ready(Some(Ok(Frame::data(data))))
+            }
             Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.to_string()))),
-            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Ready(None) => {
+                self.guard.take();
+                Poll::Ready(None)
+            }
             Poll::Pending => Poll::Pending,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::HttpSettings;
+    use crate::import::{ImportFormat, WriteMode, checkpoint_path, import};
+    use crate::index::IndexBackend;
+    use crate::store::EventStore;
+    use crate::test_util::{TempDir, event};
+    use crate::writer::WriterOptions;
+    use nostr_relay_builder::RelayBuilder;
+    use nostr_sdk::{EventBuilder, Keys};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Serve the archives of `dir` to one connection on a local port
+    async fn listen(dir: &Path, http2: bool) -> SocketAddr {
+        let db = ArchiveDatabase::new(
+            EventStore::open(
+                dir.to_path_buf(),
+                IndexBackend::default(),
+                WriterOptions::default(),
+            )
+            .unwrap(),
+            dir.to_path_buf(),
+        );
+        let relay = LocalRelay::new(RelayBuilder::default().database(db.clone()));
+        let server = HttpServer::new(relay, db, RelayStats::default(), TrustedProxies::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let http = HttpSettings {
+                http2: Some(http2),
+                ..Default::default()
+            };
+            let _ = crate::serve(socket, server, &http).await;
+        });
+        addr
+    }
+
+    /// Status and body of a GET over HTTP/1.0, the body ends with the connection
+    async fn get(addr: SocketAddr, path: &str) -> (u16, Vec<u8>) {
+        let mut s = TcpStream::connect(addr).await.unwrap();
+        s.write_all(format!("GET {} HTTP/1.0\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut rsp = Vec::new();
+        s.read_to_end(&mut rsp).await.unwrap();
+        let end = rsp.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = std::str::from_utf8(&rsp[9..12]).unwrap().parse().unwrap();
+        (status, rsp[end + 4..].to_vec())
+    }
+
+    /// HTTP/2 frame of type `kind` on `stream`
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut f = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        f.extend([kind, flags]);
+        f.extend(stream.to_be_bytes());
+        f.extend(payload);
+        f
+    }
+
+    /// `:status` of a response header block, the first field hyper encodes
+    fn status(block: &[u8]) -> u16 {
+        match block[0] {
+            // indexed from the static table
+            0x88 => 200,
+            0x8d => 404,
+            // literal value of the :status name, three huffman coded digits
+            0x08 | 0x48 => {
+                assert_eq!(block[1], 0x83);
+                let bits = u32::from_be_bytes([0, block[2], block[3], block[4]]);
+                let (mut pos, mut code) = (24, 0);
+                for _ in 0..3 {
+                    // 0-2 have 5 bit codes from 00000, 3-9 have 6 bit codes from 011001
+                    let digit = match (bits >> (pos - 5)) & 0x1f {
+                        d if d < 3 => {
+                            pos -= 5;
+                            d
+                        }
+                        _ => {
+                            pos -= 6;
+                            ((bits >> pos) & 0x3f) - 0b011001 + 3
+                        }
+                    };
+                    code = code * 10 + digit as u16;
+                }
+                code
+            }
+            b => panic!("Unexpected header field {:#x}", b),
+        }
+    }
+
+    /// Status and body of a request with `headers` over HTTP/2 with prior knowledge
+    async fn get_h2(addr: SocketAddr, headers: &[(&str, &str)]) -> (u16, Vec<u8>) {
+        let mut s = TcpStream::connect(addr).await.unwrap();
+        // literal fields without indexing, names and values not huffman coded
+        let mut fields = Vec::new();
+        for (name, value) in headers {
+            fields.push(0);
+            for x in [name, value] {
+                fields.push(x.len() as u8);
+                fields.extend(x.as_bytes());
+            }
+        }
+        let mut req = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        req.extend(frame(4, 0, 0, &[]));
+        // END_STREAM | END_HEADERS
+        req.extend(frame(1, 0x5, 1, &fields));
+        s.write_all(&req).await.unwrap();
+
+        let (mut block, mut body) = (Vec::new(), Vec::new());
+        loop {
+            let mut head = [0u8; 9];
+            s.read_exact(&mut head).await.unwrap();
+            let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+            let mut payload = vec![0u8; len];
+            s.read_exact(&mut payload).await.unwrap();
+            let (kind, flags) = (head[3], head[4]);
+            let stream = u32::from_be_bytes(head[5..].try_into().unwrap()) & 0x7fff_ffff;
+            match kind {
+                0 if stream == 1 => body.extend(payload),
+                1 if stream == 1 => block.extend(payload),
+                // acknowledge the server settings
+                4 if flags & 1 == 0 => s.write_all(&frame(4, 1, 0, &[])).await.unwrap(),
+                3 | 7 => panic!("Stream reset or connection closed: {:?}", payload),
+                _ => {}
+            }
+            if stream == 1 && matches!(kind, 0 | 1) && flags & 1 == 1 {
+                return (status(&block), body);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn archive_is_served_over_h2() {
+        let dir = TempDir::new();
+        let path = dir.path().join("events_20250101.jsonl");
+        let data = format!("{}\n{}\n", event(1, "a").as_json(), event(1, "b").as_json());
+        std::fs::write(&path, &data).unwrap();
+
+        let (status, body) = get_h2(
+            listen(dir.path(), true).await,
+            &[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":authority", "localhost"),
+                (":path", "/events_20250101.jsonl"),
+            ],
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body, data.into_bytes());
+    }
+
+    #[tokio::test]
+    async fn websocket_over_h2_is_refused() {
+        let dir = TempDir::new();
+        let (status, body) = get_h2(
+            listen(dir.path(), true).await,
+            &[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":authority", "localhost"),
+                (":path", "/"),
+                ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="),
+                ("sec-websocket-version", "13"),
+            ],
+        )
+        .await;
+        assert_eq!(status, 426);
+        assert!(String::from_utf8(body).unwrap().contains("HTTP/1.1"));
+    }
+
+    #[tokio::test]
+    async fn imported_lines_are_served_as_written() {
+        let e = EventBuilder::text_note("café \"quoted\"")
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        // key order, whitespace, an escape and a field outside NIP-01 that a canonical
+        // write normalizes
+        let line = format!(
+            "{{\"sig\": \"{}\", \"content\": \"caf\\u00e9 \\\"quoted\\\"\", \"tags\": [], \
+             \"kind\": 1, \"created_at\": {}, \"pubkey\": \"{}\", \"id\": \"{}\", \"extra\": true}}",
+            e.sig,
+            e.created_at.as_secs(),
+            e.pubkey.to_hex(),
+            e.id.to_hex()
+        );
+        for mode in [WriteMode::Verbatim, WriteMode::Canonical] {
+            let dir = TempDir::new();
+            let input = dir.path().join("input.jsonl");
+            std::fs::write(&input, format!("{}\n", line)).unwrap();
+            let out = dir.path().join("out");
+            std::fs::create_dir(&out).unwrap();
+            let c = import(
+                &out,
+                &input,
+                ImportFormat::Jsonl,
+                &checkpoint_path(&input),
+                mode,
+                IndexBackend::default(),
+            )
+            .unwrap();
+            assert_eq!(c.events, 1);
+            let name = std::fs::read_dir(&out)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .find(|n| n.ends_with(".jsonl.zst"))
+                .unwrap();
+
+            let (status, body) = get(listen(&out, false).await, &format!("/{}", name)).await;
+            assert_eq!(status, 200);
+            let served = zstd::decode_all(body.as_slice()).unwrap();
+            let written = match mode {
+                WriteMode::Verbatim => line.clone(),
+                WriteMode::Canonical => e.as_json(),
+            };
+            assert_eq!(served, format!("{}\n", written).into_bytes(), "{:?}", mode);
+            let parsed = Event::from_json(served.trim_ascii_end()).unwrap();
+            assert_eq!(parsed.id, e.id);
+            parsed.verify().unwrap();
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use crate::db::decode_archive_strict;
+use crate::index::{IndexBackend, open_index};
+use crate::jobs::JOB_BUFFER;
+use anyhow::{Result, bail};
+use chrono::DateTime;
+use clap::ValueEnum;
+use nostr_sdk::prelude::JsonUtil;
+use nostr_sdk::{Event, Timestamp};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Events between checkpoints, the index and checkpoint are written together
+pub const CHECKPOINT_EVENTS: u64 = 100_000;
+
+/// Day files kept open at once, all are closed when an input spans more days
+const MAX_OPEN: usize = 64;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(ValueEnum, Clone, Copy, Default, PartialEq)]
+pub enum ImportFormat {
+    /// One event per line
+    #[default]
+    Jsonl,
+    /// `strfry export` dumps, events may be wrapped (`["EVENT", ..]`, `{"event": ..}`),
+    /// carry extra fields or be followed by metadata on the line
+    Strfry,
+}
+
+/// How imported and merged events are written to the day files
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteMode {
+    /// Serialized again from the parsed event, key order, whitespace and escapes are
+    /// normalized and fields outside NIP-01 are dropped
+    #[default]
+    Canonical,
+    /// The line as it was read, once it parsed as a valid event; strfry lines wrapping the
+    /// event or followed by metadata are written canonically
+    Verbatim,
+}
+
+/// `import` of the config, used by `nostrhole import` and `nostrhole merge`
+#[derive(Deserialize, Clone, Default)]
+pub struct ImportSettings {
+    /// Write events as they were read (verbatim) or serialized again (canonical, default)
+    pub write_mode: Option<WriteMode>,
+}
+
+impl ImportSettings {
+    pub fn write_mode(&self) -> WriteMode {
+        self.write_mode.unwrap_or_default()
+    }
+}
+
+/// Progress of an import, saved next to the input so it can be resumed
+#[derive(Serialize, Deserialize, Default)]
+pub struct Checkpoint {
+    /// Bytes of the (decompressed) input already imported
+    pub offset: u64,
+    /// Suffix of the day files written by this import, `events_YYYYMMDD.<id>.jsonl`
+    pub id: String,
+    pub events: u64,
+    pub duplicates: u64,
+    pub errors: u64,
+    /// The whole input was read and the day files compressed
+    pub done: bool,
+}
+
+impl Checkpoint {
+    fn load(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(b) => Ok(Some(serde_json::from_slice(&b)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Default checkpoint of an input, `<input>.checkpoint`
+pub fn checkpoint_path(input: &Path) -> PathBuf {
+    let name = input
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    input.with_file_name(format!("{}.checkpoint", name))
+}
+
+/// The event object of a strfry export line
+fn unwrap_strfry(line: &[u8]) -> Option<Value> {
+    // anything after the first json value on the line is metadata
+    let value = serde_json::Deserializer::from_slice(line)
+        .into_iter::<Value>()
+        .next()?
+        .ok()?;
+    let mut obj = match value {
+        Value::Array(mut a) if a.first().and_then(|v| v.as_str()) == Some("EVENT") => a.pop()?,
+        Value::Object(mut o) if o.contains_key("event") => o.remove("event")?,
+        v => v,
+    };
+    let map = obj.as_object_mut()?;
+    map.retain(|k, _| {
+        matches!(
+            k.as_str(),
+            "id" | "pubkey" | "created_at" | "kind" | "tags" | "content" | "sig"
+        )
+    });
+    Some(obj)
+}
+
+/// The bytes of `line` to write for `ev` parsed from it, None to serialize the event again
+///
+/// They are only kept when the line is the event on its own, its id and signature were
+/// checked by [parse]
+pub fn verbatim<'a>(
+    mode: WriteMode,
+    format: ImportFormat,
+    line: &'a [u8],
+    ev: &Event,
+) -> Option<&'a [u8]> {
+    if mode != WriteMode::Verbatim {
+        return None;
+    }
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    match format {
+        ImportFormat::Jsonl => Some(line),
+        ImportFormat::Strfry => Event::from_json(line)
+            .ok()
+            .filter(|e| e.id == ev.id)
+            .map(|_| line),
+    }
+}
+
+pub fn parse(format: ImportFormat, line: &[u8]) -> Option<Event> {
+    let ev = match format {
+        ImportFormat::Jsonl => Event::from_json(line).ok()?,
+        ImportFormat::Strfry => serde_json::from_value(unwrap_strfry(line)?).ok()?,
+    };
+    ev.verify().is_ok().then_some(ev)
+}
+
+/// Day files of an import, appended to until the import is done
+pub struct DayFiles {
+    out_dir: PathBuf,
+    id: String,
+    open: HashMap<String, BufWriter<File>>,
+}
+
+impl DayFiles {
+    /// Day files `events_YYYYMMDD.<id>.jsonl` in `out_dir`
+    pub fn new(out_dir: &Path, id: String) -> Self {
+        Self {
+            out_dir: out_dir.to_path_buf(),
+            id,
+            open: HashMap::new(),
+        }
+    }
+
+    fn path(&self, day: &str) -> PathBuf {
+        self.out_dir
+            .join(format!("events_{}.{}.jsonl", day, self.id))
+    }
+
+    /// Append `ev` to the file of its day, as `raw` when given
+    pub fn write(&mut self, ev: &Event, raw: Option<&[u8]>) -> Result<()> {
+        let day = DateTime::from_timestamp(ev.created_at.as_secs() as i64, 0)
+            .unwrap_or_default()
+            .format("%Y%m%d")
+            .to_string();
+        if !self.open.contains_key(&day) && self.open.len() >= MAX_OPEN {
+            self.flush()?;
+            self.open.clear();
+        }
+        let out = match self.open.get_mut(&day) {
+            Some(o) => o,
+            None => {
+                let f = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.path(&day))?;
+                self.open
+                    .entry(day)
+                    .or_insert(BufWriter::with_capacity(64 * 1024, f))
+            }
+        };
+        match raw {
+            Some(r) => out.write_all(r)?,
+            None => out.write_all(ev.as_json().as_bytes())?,
+        }
+        out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        for out in self.open.values_mut() {
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Compress every day file of this import to `.jsonl.zst`
+    pub fn compress(&mut self) -> Result<usize> {
+        self.flush()?;
+        self.open.clear();
+        let suffix = format!(".{}.jsonl", self.id);
+        let mut n = 0;
+        for entry in std::fs::read_dir(&self.out_dir)? {
+            let path = entry?.path();
+            if !path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.ends_with(&suffix))
+            {
+                continue;
+            }
+            let dst = path.with_extension("jsonl.zst");
+            let tmp = path.with_extension("jsonl.zst.tmp");
+            let mut out = BufWriter::with_capacity(JOB_BUFFER, File::create(&tmp)?);
+            zstd::stream::copy_encode(
+                BufReader::with_capacity(JOB_BUFFER, File::open(&path)?),
+                &mut out,
+                0,
+            )?;
+            out.into_inner()?.sync_all()?;
+            std::fs::rename(&tmp, &dst)?;
+            std::fs::remove_file(&path)?;
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+/// Import the events of `input` into dated archives in `out_dir`, skipping events already in
+/// the index; progress is printed to stderr and saved to `checkpoint` so a re-run resumes
+///
+/// Events are written to `events_YYYYMMDD.import-<start time>.jsonl` by created_at next to the
+/// other archives and compressed with zstd at the end. The index and checkpoint are updated
+/// after the files are flushed, an interrupted import can't lose events but may write a
+/// few duplicates of the last batch when resumed
+pub fn import(
+    out_dir: &Path,
+    input: &Path,
+    format: ImportFormat,
+    checkpoint: &Path,
+    mode: WriteMode,
+    backend: IndexBackend,
+) -> Result<Checkpoint> {
+    let mut state = match Checkpoint::load(checkpoint)? {
+        Some(c) if c.done => {
+            bail!(
+                "{} was already imported, remove {} to import it again",
+                input.display(),
+                checkpoint.display()
+            )
+        }
+        Some(c) => {
+            eprintln!("Resuming {} at byte {}", input.display(), c.offset);
+            c
+        }
+        None => Checkpoint {
+            id: format!("import-{}", Timestamp::now().as_secs()),
+            ..Default::default()
+        },
+    };
+    let index = open_index(out_dir, backend)?;
+
+    let mut file = File::open(input)?;
+    let size = file.metadata()?.len();
+    let plain = !matches!(
+        input.extension().and_then(|e| e.to_str()),
+        Some("gz" | "zst" | "zstd" | "bz2")
+    );
+    if plain {
+        file.seek(SeekFrom::Start(state.offset))?;
+    }
+    let mut reader = decode_archive_strict(input, BufReader::with_capacity(JOB_BUFFER, file))?;
+    if !plain {
+        // compressed inputs are resumed by decompressing up to the offset
+        std::io::copy(
+            &mut Read::take(&mut reader, state.offset),
+            &mut std::io::sink(),
+        )?;
+    }
+
+    let mut files = DayFiles::new(out_dir, state.id.clone());
+    // ids are claimed before their event is written and committed once the files are
+    // flushed, a resumed import writes the uncommitted ones again
+    let mut batch = index.batch()?;
+    let mut claimed = 0;
+    let mut offset = state.offset;
+    let mut line = Vec::new();
+    let started = Instant::now();
+    let start_events = state.events;
+    let mut printed = Instant::now();
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if n == 0 {
+            break;
+        }
+        offset += n as u64;
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        if !content.iter().all(u8::is_ascii_whitespace) {
+            match parse(format, content) {
+                Some(ev) => {
+                    if batch.claim(ev.id, ev.created_at)? {
+                        files.write(&ev, verbatim(mode, format, content, &ev))?;
+                        claimed += 1;
+                        state.events += 1;
+                    } else {
+                        state.duplicates += 1;
+                    }
+                }
+                None => state.errors += 1,
+            }
+        }
+        if claimed >= CHECKPOINT_EVENTS {
+            files.flush()?;
+            batch.commit()?;
+            batch = index.batch()?;
+            claimed = 0;
+            state.offset = offset;
+            state.save(checkpoint)?;
+        }
+        if printed.elapsed() >= PROGRESS_INTERVAL {
+            printed = Instant::now();
+            let rate =
+                (state.events - start_events) as f64 / started.elapsed().as_secs_f64().max(1.0);
+            eprint!(
+                "\r{} MB{} {} events ({:.0}/s), {} duplicates, {} errors   ",
+                offset / 1_000_000,
+                if plain {
+                    format!(" ({}%)", offset * 100 / size.max(1))
+                } else {
+                    String::new()
+                },
+                state.events,
+                rate,
+                state.duplicates,
+                state.errors
+            );
+        }
+    }
+    files.flush()?;
+    batch.commit()?;
+    state.offset = offset;
+    state.save(checkpoint)?;
+    eprintln!();
+
+    let n = files.compress()?;
+    eprintln!("Compressed {} day files", n);
+    state.done = true;
+    state.save(checkpoint)?;
+    Ok(state)
+}
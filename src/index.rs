@@ -0,0 +1,778 @@
+use crate::rocks;
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use log::info;
+use nostr_archive_cursor::IndexDb;
+use nostr_sdk::{EventId, Timestamp};
+use redb::{
+    Database, Durability, ReadableDatabase, ReadableTable, ReadableTableMetadata, WriteTransaction,
+};
+use rocksdb::{
+    DB, ErrorKind, IteratorMode, OptimisticTransactionDB, Transaction, WriteBatchWithTransaction,
+};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Event id to created_at, seconds
+const EVENTS: redb::TableDefinition<[u8; 32], u64> = redb::TableDefinition::new("events");
+
+/// Single inserts into a redb index are committed without fsync, every this many is
+/// committed durably
+const DURABLE_EVERY: u64 = 1000;
+
+/// Directory of a redb index, the archive directories only hold archives and their
+/// sidecar files
+pub const REDB_DIR: &str = "index_redb";
+
+/// Entries inserted at once by [migrate] and [bench]
+const COPY_BATCH: usize = 10_000;
+
+/// [RocksIndex::count] before the entries were counted
+const UNCOUNTED: u64 = u64::MAX;
+
+/// Store holding the event id index of a directory
+#[derive(Deserialize, ValueEnum, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexBackend {
+    /// `index/`, the format of nostr-archive-cursor
+    #[default]
+    Rocksdb,
+    /// `index_redb/index.redb`, a single file with bounded memory use
+    Redb,
+}
+
+impl IndexBackend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IndexBackend::Rocksdb => "rocksdb",
+            IndexBackend::Redb => "redb",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct IndexSettings {
+    /// rocksdb (default) or redb, switch with `migrate-index`
+    pub backend: Option<IndexBackend>,
+}
+
+/// Event ids of the archives of a directory and when the events were created
+pub trait EventIndex: Send + Sync {
+    fn contains(&self, id: &EventId) -> Result<bool>;
+
+    /// Add `id` unless it is indexed, returns false when it is; of concurrent inserts of
+    /// the same id only one returns true
+    fn insert(&self, id: EventId, created_at: Timestamp) -> Result<bool>;
+
+    /// Start claiming ids in one transaction, see [IndexBatch]
+    fn batch(&self) -> Result<Box<dyn IndexBatch + '_>>;
+
+    /// Add entries without checking for them, when rebuilding
+    fn insert_batch(&self, items: Vec<(EventId, Timestamp)>) -> Result<()>;
+
+    fn remove(&self, ids: &[EventId]) -> Result<()>;
+
+    /// Delete every entry, returns the number deleted
+    fn clear(&self) -> Result<u64>;
+
+    /// Ids and created_at of the events created in `since..=until`
+    fn list_ids(&self, since: u64, until: u64) -> Result<Vec<(EventId, Timestamp)>>;
+
+    /// Entries in the index
+    fn count_keys(&self) -> Result<u64>;
+
+    fn is_index_empty(&self) -> Result<bool>;
+
+    /// Make what was inserted so far durable
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Prepare for a rebuild inserting every event
+    fn setup_for_reindex(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Ids claimed in one transaction, the index only holds them once committed and dropping
+/// the batch discards them
+pub trait IndexBatch {
+    /// Add `id` unless the index or the batch holds it, returns false when one does
+    fn claim(&mut self, id: EventId, created_at: Timestamp) -> Result<bool>;
+
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+/// Where the index of `backend` is kept in `dir`
+pub fn index_path(dir: &Path, backend: IndexBackend) -> PathBuf {
+    match backend {
+        IndexBackend::Rocksdb => dir.join("index"),
+        IndexBackend::Redb => dir.join(REDB_DIR).join("index.redb"),
+    }
+}
+
+/// Open or create the index of `dir`
+pub fn open_index(dir: &Path, backend: IndexBackend) -> Result<Arc<dyn EventIndex>> {
+    open_path(&index_path(dir, backend), backend)
+}
+
+/// Open or create the index at `path`, eg. one moved aside
+pub fn open_path(path: &Path, backend: IndexBackend) -> Result<Arc<dyn EventIndex>> {
+    Ok(match backend {
+        IndexBackend::Rocksdb => Arc::new(RocksIndex::open(path)?),
+        IndexBackend::Redb => Arc::new(RedbIndex::open(path)?),
+    })
+}
+
+/// Event id index in rocksdb, in the format of nostr-archive-cursor: the id is the key and
+/// created_at the little endian value
+pub struct RocksIndex {
+    /// Only None while it is reopened by [EventIndex::setup_for_reindex]
+    db: Option<OptimisticTransactionDB>,
+    /// Entries, [UNCOUNTED] until they are counted the first time
+    count: AtomicU64,
+}
+
+impl RocksIndex {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            db: Some(OptimisticTransactionDB::open(&rocks::options(), path)?),
+            count: AtomicU64::new(UNCOUNTED),
+        })
+    }
+
+    fn db(&self) -> &OptimisticTransactionDB {
+        self.db.as_ref().expect("index is open")
+    }
+
+    /// Count `n` new entries, when they were counted already
+    fn add_count(&self, n: u64) {
+        let _ = self
+            .count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+                (c != UNCOUNTED).then(|| c + n)
+            });
+    }
+
+    /// Call `f` with the id and created_at of every entry
+    fn for_each(&self, mut f: impl FnMut(EventId, u64)) -> Result<()> {
+        for x in self.db().iterator(IteratorMode::Start) {
+            let (k, v) = x?;
+            let Ok(id) = EventId::from_slice(&k) else {
+                continue;
+            };
+            let created_at = <[u8; 8]>::try_from(&v[..])
+                .map(u64::from_le_bytes)
+                .unwrap_or_default();
+            f(id, created_at);
+        }
+        Ok(())
+    }
+}
+
+/// Claims of a [RocksIndex], commit fails when one of the ids was inserted meanwhile
+struct RocksBatch<'a> {
+    index: &'a RocksIndex,
+    txn: Transaction<'a, OptimisticTransactionDB>,
+    claimed: u64,
+}
+
+impl<'a> RocksBatch<'a> {
+    fn new(index: &'a RocksIndex) -> Self {
+        Self {
+            index,
+            txn: index.db().transaction(),
+            claimed: 0,
+        }
+    }
+}
+
+impl IndexBatch for RocksBatch<'_> {
+    fn claim(&mut self, id: EventId, created_at: Timestamp) -> Result<bool> {
+        if self
+            .txn
+            .get_pinned_for_update(id.as_bytes(), true)?
+            .is_some()
+        {
+            return Ok(false);
+        }
+        self.txn
+            .put(id.as_bytes(), created_at.as_secs().to_le_bytes())?;
+        self.claimed += 1;
+        Ok(true)
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        self.txn.commit()?;
+        self.index.add_count(self.claimed);
+        Ok(())
+    }
+}
+
+impl EventIndex for RocksIndex {
+    fn contains(&self, id: &EventId) -> Result<bool> {
+        Ok(self.db().get_pinned(id.as_bytes())?.is_some())
+    }
+
+    fn insert(&self, id: EventId, created_at: Timestamp) -> Result<bool> {
+        loop {
+            let mut batch = RocksBatch::new(self);
+            if !batch.claim(id, created_at)? {
+                return Ok(false);
+            }
+            match batch.txn.commit() {
+                Ok(()) => {
+                    self.add_count(1);
+                    return Ok(true);
+                }
+                // another insert of the id committed first, it is found when read again
+                Err(e) if matches!(e.kind(), ErrorKind::Busy | ErrorKind::TryAgain) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn batch(&self) -> Result<Box<dyn IndexBatch + '_>> {
+        Ok(Box::new(RocksBatch::new(self)))
+    }
+
+    fn insert_batch(&self, items: Vec<(EventId, Timestamp)>) -> Result<()> {
+        let mut batch = WriteBatchWithTransaction::<true>::default();
+        for (id, created_at) in items {
+            batch.put(id.as_bytes(), created_at.as_secs().to_le_bytes());
+        }
+        self.db().write(batch)?;
+        // some may have been indexed already
+        self.count.store(UNCOUNTED, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn remove(&self, ids: &[EventId]) -> Result<()> {
+        let mut batch = WriteBatchWithTransaction::<true>::default();
+        for id in ids {
+            batch.delete(id.as_bytes());
+        }
+        self.db().write(batch)?;
+        self.count.store(UNCOUNTED, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<u64> {
+        let mut n = 0;
+        let mut batch = WriteBatchWithTransaction::<true>::default();
+        for x in self.db().iterator(IteratorMode::Start) {
+            let (k, _) = x?;
+            batch.delete(&k);
+            n += 1;
+            if batch.len() >= COPY_BATCH {
+                self.db().write(std::mem::take(&mut batch))?;
+            }
+        }
+        self.db().write(batch)?;
+        self.count.store(UNCOUNTED, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn list_ids(&self, since: u64, until: u64) -> Result<Vec<(EventId, Timestamp)>> {
+        let mut ids = Vec::new();
+        self.for_each(|id, created_at| {
+            if (since..=until).contains(&created_at) {
+                ids.push((id, Timestamp::from_secs(created_at)));
+            }
+        })?;
+        Ok(ids)
+    }
+
+    fn count_keys(&self) -> Result<u64> {
+        let n = self.count.load(Ordering::Relaxed);
+        if n != UNCOUNTED {
+            return Ok(n);
+        }
+        let mut n = 0;
+        self.for_each(|_, _| n += 1)?;
+        self.count.store(n, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn is_index_empty(&self) -> Result<bool> {
+        Ok(self
+            .db()
+            .iterator(IteratorMode::Start)
+            .next()
+            .transpose()?
+            .is_none())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(self.db().flush()?)
+    }
+
+    fn setup_for_reindex(&mut self) -> Result<()> {
+        let path = self.db().path().to_path_buf();
+        // closed before it is opened again
+        self.db.take();
+        let mut opts = IndexDb::get_bulk_load_options();
+        rocks::use_cache(&mut opts);
+        self.db = Some(OptimisticTransactionDB::open(&opts, path)?);
+        self.count.store(UNCOUNTED, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Event id index in a single redb file
+pub struct RedbIndex {
+    db: Database,
+    /// Inserts since the last durable commit
+    pending: AtomicU64,
+}
+
+impl RedbIndex {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let db = Database::create(path)?;
+        let txn = db.begin_write()?;
+        txn.open_table(EVENTS)?;
+        txn.commit()?;
+        Ok(Self {
+            db,
+            pending: AtomicU64::new(0),
+        })
+    }
+
+    fn write(&self, durable: bool, items: &[(EventId, Timestamp)]) -> Result<()> {
+        let mut txn = self.db.begin_write()?;
+        if !durable {
+            txn.set_durability(Durability::None)?;
+        }
+        {
+            let mut table = txn.open_table(EVENTS)?;
+            for (id, created_at) in items {
+                table.insert(id.to_bytes(), created_at.as_secs())?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Call `f` with every entry
+    fn for_each(&self, mut f: impl FnMut([u8; 32], u64) -> Result<()>) -> Result<()> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(EVENTS)?;
+        for x in table.iter()? {
+            let (k, v) = x?;
+            f(k.value(), v.value())?;
+        }
+        Ok(())
+    }
+
+    fn remove_keys(&self, ids: &[[u8; 32]]) -> Result<()> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(EVENTS)?;
+            for id in ids {
+                table.remove(id)?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+/// Claims of a [RedbIndex] in a write transaction, other writers wait for it
+struct RedbBatch {
+    txn: WriteTransaction,
+}
+
+impl IndexBatch for RedbBatch {
+    fn claim(&mut self, id: EventId, created_at: Timestamp) -> Result<bool> {
+        let mut table = self.txn.open_table(EVENTS)?;
+        if table.get(id.to_bytes())?.is_some() {
+            return Ok(false);
+        }
+        table.insert(id.to_bytes(), created_at.as_secs())?;
+        Ok(true)
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        Ok(self.txn.commit()?)
+    }
+}
+
+impl EventIndex for RedbIndex {
+    fn contains(&self, id: &EventId) -> Result<bool> {
+        let txn = self.db.begin_read()?;
+        Ok(txn.open_table(EVENTS)?.get(id.to_bytes())?.is_some())
+    }
+
+    fn insert(&self, id: EventId, created_at: Timestamp) -> Result<bool> {
+        let durable = self.pending.fetch_add(1, Ordering::Relaxed) + 1 >= DURABLE_EVERY;
+        let mut txn = self.db.begin_write()?;
+        if !durable {
+            txn.set_durability(Durability::None)?;
+        }
+        let mut batch = RedbBatch { txn };
+        let inserted = batch.claim(id, created_at)?;
+        batch.txn.commit()?;
+        if durable {
+            self.pending.store(0, Ordering::Relaxed);
+        }
+        Ok(inserted)
+    }
+
+    fn batch(&self) -> Result<Box<dyn IndexBatch + '_>> {
+        Ok(Box::new(RedbBatch {
+            txn: self.db.begin_write()?,
+        }))
+    }
+
+    fn insert_batch(&self, items: Vec<(EventId, Timestamp)>) -> Result<()> {
+        self.write(true, &items)?;
+        self.pending.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn remove(&self, ids: &[EventId]) -> Result<()> {
+        let ids: Vec<[u8; 32]> = ids.iter().map(|id| id.to_bytes()).collect();
+        self.remove_keys(&ids)
+    }
+
+    fn clear(&self) -> Result<u64> {
+        let txn = self.db.begin_write()?;
+        let n = txn.open_table(EVENTS)?.len()?;
+        txn.delete_table(EVENTS)?;
+        txn.open_table(EVENTS)?;
+        txn.commit()?;
+        Ok(n)
+    }
+
+    fn list_ids(&self, since: u64, until: u64) -> Result<Vec<(EventId, Timestamp)>> {
+        let mut ids = Vec::new();
+        self.for_each(|k, v| {
+            if (since..=until).contains(&v) {
+                ids.push((EventId::from_byte_array(k), Timestamp::from_secs(v)));
+            }
+            Ok(())
+        })?;
+        Ok(ids)
+    }
+
+    fn count_keys(&self) -> Result<u64> {
+        let txn = self.db.begin_read()?;
+        Ok(txn.open_table(EVENTS)?.len()?)
+    }
+
+    fn is_index_empty(&self) -> Result<bool> {
+        Ok(self.count_keys()? == 0)
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.pending.swap(0, Ordering::Relaxed) > 0 {
+            self.write(true, &[])?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RedbIndex {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Event id index opened directly for maintenance, before the archive database is opened
+pub enum RawIndex {
+    Rocksdb(DB),
+    Redb(RedbIndex),
+}
+
+impl RawIndex {
+    pub fn open(path: &Path, backend: IndexBackend) -> Result<Self> {
+        Ok(match backend {
+            IndexBackend::Rocksdb => RawIndex::Rocksdb(rocks::open(path)?),
+            IndexBackend::Redb => RawIndex::Redb(RedbIndex::open(path)?),
+        })
+    }
+
+    /// Call `f` with the id and created_at of every entry
+    pub fn for_each(&self, mut f: impl FnMut([u8; 32], u64) -> Result<()>) -> Result<()> {
+        match self {
+            RawIndex::Rocksdb(db) => {
+                for x in db.iterator(IteratorMode::Start) {
+                    let (k, v) = x.map_err(|e| anyhow!(e))?;
+                    let Ok(id) = <[u8; 32]>::try_from(&k[..]) else {
+                        continue;
+                    };
+                    // created_at, little endian
+                    let created_at = <[u8; 8]>::try_from(&v[..])
+                        .map(u64::from_le_bytes)
+                        .unwrap_or_default();
+                    f(id, created_at)?;
+                }
+                Ok(())
+            }
+            RawIndex::Redb(db) => db.for_each(f),
+        }
+    }
+}
+
+/// `out_dir` and its partition directories that hold an event id index of `backend`
+pub fn indexed_dirs(out_dir: &Path, backend: IndexBackend) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![out_dir.to_path_buf()];
+    for entry in std::fs::read_dir(out_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs
+        .into_iter()
+        .filter(|d| index_path(d, backend).exists())
+        .collect())
+}
+
+/// Indexes copied by [migrate]
+pub struct Migrated {
+    pub indexes: usize,
+    pub entries: u64,
+}
+
+/// Copy the event id indexes of `out_dir` and its partitions from `from` to `to`, the
+/// copies must hold as many entries as the originals; the originals are kept
+pub fn migrate(out_dir: &Path, from: IndexBackend, to: IndexBackend) -> Result<Migrated> {
+    if from == to {
+        return Err(anyhow!("The index is already stored in {}", to.name()));
+    }
+    let mut migrated = Migrated {
+        indexes: 0,
+        entries: 0,
+    };
+    for dir in indexed_dirs(out_dir, from)? {
+        let (src, dst) = (index_path(&dir, from), index_path(&dir, to));
+        if dst.exists() {
+            return Err(anyhow!(
+                "{} already exists, remove it to migrate again",
+                dst.display()
+            ));
+        }
+        let source = RawIndex::open(&src, from)?;
+        let target = open_index(&dir, to)?;
+        let mut batch = Vec::with_capacity(COPY_BATCH);
+        let mut copied = 0;
+        source.for_each(|id, created_at| {
+            batch.push((
+                EventId::from_byte_array(id),
+                Timestamp::from_secs(created_at),
+            ));
+            copied += 1;
+            if batch.len() >= COPY_BATCH {
+                target.insert_batch(std::mem::take(&mut batch))?;
+            }
+            Ok(())
+        })?;
+        target.insert_batch(batch)?;
+        target.flush()?;
+        let count = target.count_keys()?;
+        if count != copied {
+            return Err(anyhow!(
+                "{} holds {} entries after copying {} from {}",
+                dst.display(),
+                count,
+                copied,
+                src.display()
+            ));
+        }
+        info!(
+            "Copied {} entries from {} to {}",
+            copied,
+            src.display(),
+            dst.display()
+        );
+        migrated.indexes += 1;
+        migrated.entries += copied;
+    }
+    Ok(migrated)
+}
+
+/// check_id rate of an index
+pub struct BenchResult {
+    pub backend: IndexBackend,
+    pub insert_secs: f64,
+    /// Lookups per second, half of the ids are in the index
+    pub lookups: f64,
+    /// Looked up ids reported as indexed, and how many are
+    pub found: u64,
+    pub present: u64,
+}
+
+/// Fill a new index of `backend` in `dir` with `ids` synthetic ids, then look up as many
+/// ids, half of them missing
+pub fn bench(dir: &Path, backend: IndexBackend, ids: u64) -> Result<BenchResult> {
+    std::fs::create_dir_all(dir)?;
+    let path = index_path(dir, backend);
+    if path.exists() {
+        return Err(anyhow!("{} already exists", path.display()));
+    }
+    let id = |n: u64| {
+        let mut b = [0u8; 32];
+        // spread over the key space like the hashes event ids are
+        b[..8].copy_from_slice(&n.wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes());
+        b[8..16].copy_from_slice(&n.to_le_bytes());
+        EventId::from_byte_array(b)
+    };
+    let index = open_index(dir, backend)?;
+    let start = Instant::now();
+    let mut batch = Vec::with_capacity(COPY_BATCH);
+    for n in 0..ids {
+        batch.push((id(n * 2), Timestamp::from_secs(n)));
+        if batch.len() >= COPY_BATCH {
+            index.insert_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    index.insert_batch(batch)?;
+    let insert_secs = start.elapsed().as_secs_f64();
+
+    let start = Instant::now();
+    let mut found = 0;
+    for n in 0..ids {
+        if index.contains(&id(n))? {
+            found += 1;
+        }
+    }
+    let secs = start.elapsed().as_secs_f64();
+    Ok(BenchResult {
+        backend,
+        insert_secs,
+        lookups: ids as f64 / secs.max(f64::EPSILON),
+        found,
+        present: ids.div_ceil(2),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn ids(n: u8) -> Vec<(EventId, Timestamp)> {
+        (0..n)
+            .map(|i| {
+                (
+                    EventId::from_byte_array([i; 32]),
+                    Timestamp::from_secs(i as u64 * 10),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn redb_index_keeps_entries() {
+        let dir = TempDir::new();
+        {
+            let index = open_index(dir.path(), IndexBackend::Redb).unwrap();
+            assert!(index.is_index_empty().unwrap());
+            index.insert_batch(ids(5)).unwrap();
+            assert!(
+                index
+                    .insert(EventId::from_byte_array([9; 32]), Timestamp::from_secs(90))
+                    .unwrap()
+            );
+        }
+        // the single insert isn't committed durably, closing the index makes it so
+        let index = open_index(dir.path(), IndexBackend::Redb).unwrap();
+        assert_eq!(index.count_keys().unwrap(), 6);
+        assert!(index.contains(&EventId::from_byte_array([9; 32])).unwrap());
+        assert!(!index.contains(&EventId::from_byte_array([7; 32])).unwrap());
+        assert_eq!(index.list_ids(10, 30).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn inserts_and_batches_only_claim_new_ids() {
+        for backend in [IndexBackend::Rocksdb, IndexBackend::Redb] {
+            let dir = TempDir::new();
+            let index = open_index(dir.path(), backend).unwrap();
+            let (id, at) = ids(1)[0];
+            assert!(index.insert(id, at).unwrap(), "{:?}", backend);
+            assert!(!index.insert(id, at).unwrap(), "{:?}", backend);
+
+            let mut batch = index.batch().unwrap();
+            assert!(!batch.claim(id, at).unwrap());
+            let (other, at) = ids(2)[1];
+            assert!(batch.claim(other, at).unwrap());
+            assert!(!batch.claim(other, at).unwrap());
+            // not indexed until committed
+            drop(batch);
+            assert!(!index.contains(&other).unwrap());
+
+            let mut batch = index.batch().unwrap();
+            assert!(batch.claim(other, at).unwrap());
+            batch.commit().unwrap();
+            assert!(index.contains(&other).unwrap());
+            assert_eq!(index.count_keys().unwrap(), 2, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn concurrent_inserts_claim_an_id_once() {
+        for backend in [IndexBackend::Rocksdb, IndexBackend::Redb] {
+            let dir = TempDir::new();
+            let index = open_index(dir.path(), backend).unwrap();
+            let (id, at) = ids(1)[0];
+            let start = std::sync::Barrier::new(16);
+            let won = std::thread::scope(|s| {
+                let tasks: Vec<_> = (0..16)
+                    .map(|_| {
+                        s.spawn(|| {
+                            start.wait();
+                            index.insert(id, at).unwrap()
+                        })
+                    })
+                    .collect();
+                tasks
+                    .into_iter()
+                    .map(|t| t.join().unwrap())
+                    .filter(|c| *c)
+                    .count()
+            });
+            assert_eq!(won, 1, "{:?}", backend);
+            assert_eq!(index.count_keys().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn migrate_copies_every_index() {
+        let dir = TempDir::new();
+        std::fs::create_dir(dir.path().join("kind-1")).unwrap();
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        for (d, n) in [
+            (dir.path().to_path_buf(), 5),
+            (dir.path().join("kind-1"), 3),
+        ] {
+            open_index(&d, IndexBackend::Redb)
+                .unwrap()
+                .insert_batch(ids(n))
+                .unwrap();
+        }
+
+        let m = migrate(dir.path(), IndexBackend::Redb, IndexBackend::Rocksdb).unwrap();
+        assert_eq!((m.indexes, m.entries), (2, 8));
+        assert!(index_path(&dir.path().join("kind-1"), IndexBackend::Rocksdb).is_dir());
+        assert!(!index_path(&dir.path().join("empty"), IndexBackend::Rocksdb).exists());
+        // the copies exist now
+        assert!(migrate(dir.path(), IndexBackend::Redb, IndexBackend::Rocksdb).is_err());
+        assert!(migrate(dir.path(), IndexBackend::Redb, IndexBackend::Redb).is_err());
+    }
+
+    #[test]
+    fn bench_finds_the_inserted_half() {
+        let dir = TempDir::new();
+        let r = bench(dir.path(), IndexBackend::Redb, 10_001).unwrap();
+        assert_eq!((r.found, r.present), (5001, 5001));
+        assert!(r.lookups > 0.0);
+        assert!(bench(dir.path(), IndexBackend::Redb, 10).is_err());
+    }
+}
@@ -0,0 +1,518 @@
+use crate::db::ArchiveDatabase;
+use crate::ingest_jobs::job_name;
+use crate::stats::{NoticeKind, RelayStats};
+use anyhow::{Result, anyhow};
+use log::{debug, error, info, warn};
+use nostr_sdk::prelude::{JsonUtil, NostrDatabase, RelayStatus, SubscribeOptions};
+use nostr_sdk::{
+    Client, Event, Filter, Kind, RelayMessage, RelayPoolNotification, RelayUrl, SingleLetterTag,
+    SubscriptionId, Timestamp,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// Longest wait between restarts of the ingest loop
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Wait before opening a subscription a relay closed again
+const CLOSED_RETRY: Duration = Duration::from_secs(30);
+
+/// How often the filters of the open ingest subscriptions are copied, relays forget the
+/// filters of subscriptions they closed
+const FILTER_REFRESH: Duration = Duration::from_secs(60);
+
+/// How often relays are checked for delivering events outside their subscriptions
+const NOISY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Events a relay delivers before its share of out of scope events is judged
+const NOISY_MIN_RECEIVED: u64 = 1000;
+
+/// CLOSED reasons a retry won't fix, by their machine readable prefix
+const FINAL_CLOSED: [&str; 5] = [
+    "invalid:",
+    "unsupported:",
+    "blocked:",
+    "restricted:",
+    "auth-required:",
+];
+
+#[derive(Deserialize, Clone, Default)]
+pub struct IngestSettings {
+    /// Resubscribe after this many seconds without events while relays are connected,
+    /// default 900, 0 disables
+    pub silence_timeout_secs: Option<u64>,
+
+    /// Events requested per subscription when it opens, default 100, 0 for no limit clause
+    pub initial_limit: Option<usize>,
+
+    /// Kinds per subscription when filtering by kind, default 20
+    pub kinds_per_filter: Option<usize>,
+
+    /// Received events waiting to be saved, default 4096; once full the loop waits for
+    /// the writer instead of dropping events
+    pub queue_size: Option<usize>,
+
+    /// Warn about relays when more than this share of the events they deliver is outside
+    /// the kinds, authors or tags they were asked for, default 0.5
+    pub noisy_relay_ratio: Option<f64>,
+
+    /// Disconnect such relays instead of only warning about them
+    pub auto_drop_noisy_relays: Option<bool>,
+}
+
+impl IngestSettings {
+    pub fn queue_size(&self) -> usize {
+        self.queue_size.unwrap_or(4096).max(1)
+    }
+
+    /// Limit clause for newly opened subscriptions
+    pub fn initial_limit(&self) -> Option<usize> {
+        match self.initial_limit.unwrap_or(100) {
+            0 => None,
+            l => Some(l),
+        }
+    }
+
+    pub fn noisy_relay_ratio(&self) -> f64 {
+        self.noisy_relay_ratio
+            .filter(|r| *r > 0.0 && *r <= 1.0)
+            .unwrap_or(0.5)
+    }
+
+    /// Ingest filters, `kinds` split into groups so relays capping results per
+    /// filter still deliver every kind
+    pub fn filters(&self, kinds: Option<&[Kind]>) -> Vec<Filter> {
+        let with_limit = |f: Filter| match self.initial_limit() {
+            Some(l) => f.limit(l),
+            None => f,
+        };
+        match kinds {
+            Some(k) if !k.is_empty() => k
+                .chunks(self.kinds_per_filter.unwrap_or(20).max(1))
+                .map(|c| with_limit(Filter::new().kinds(c.iter().copied())))
+                .collect(),
+            _ => vec![with_limit(Filter::new())],
+        }
+    }
+}
+
+/// Each of `filters` once per tag group, relays match any of a group's values
+pub fn with_tags(filters: Vec<Filter>, tags: &[(SingleLetterTag, Vec<String>)]) -> Vec<Filter> {
+    if tags.is_empty() {
+        return filters;
+    }
+    filters
+        .iter()
+        .flat_map(|f| {
+            tags.iter()
+                .map(|(t, v)| f.clone().custom_tags(*t, v.iter().cloned()))
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct HealthInner {
+    restarts: AtomicU64,
+    resubscribes: AtomicU64,
+    /// Notifications dropped because the loop lagged behind
+    lagged: AtomicU64,
+    /// Events in the save queue
+    queued: AtomicU64,
+    queue_size: AtomicU64,
+    /// Times the loop waited for the writer because the save queue was full
+    queue_full: AtomicU64,
+    last_event: AtomicU64,
+    last_restart: Mutex<Option<Restart>>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Restart {
+    pub at: u64,
+    pub reason: String,
+}
+
+/// Supervisor counters for the ingest loop
+#[derive(Clone, Default)]
+pub struct IngestHealth(Arc<HealthInner>);
+
+#[derive(Serialize)]
+pub struct IngestInfo {
+    pub restarts: u64,
+    pub resubscribes: u64,
+    pub lagged: u64,
+    pub queued: u64,
+    pub queue_size: u64,
+    pub queue_full: u64,
+    pub last_event: Option<u64>,
+    pub last_restart: Option<Restart>,
+}
+
+impl IngestHealth {
+    pub fn snapshot(&self) -> IngestInfo {
+        let last_event = self.0.last_event.load(Ordering::Relaxed);
+        IngestInfo {
+            restarts: self.0.restarts.load(Ordering::Relaxed),
+            resubscribes: self.0.resubscribes.load(Ordering::Relaxed),
+            lagged: self.0.lagged.load(Ordering::Relaxed),
+            queued: self.0.queued.load(Ordering::Relaxed),
+            queue_size: self.0.queue_size.load(Ordering::Relaxed),
+            queue_full: self.0.queue_full.load(Ordering::Relaxed),
+            last_event: (last_event > 0).then_some(last_event),
+            last_restart: self.0.last_restart.lock().unwrap().clone(),
+        }
+    }
+
+    fn restarted(&self, reason: String) {
+        self.0.restarts.fetch_add(1, Ordering::Relaxed);
+        *self.0.last_restart.lock().unwrap() = Some(Restart {
+            at: Timestamp::now().as_secs(),
+            reason,
+        });
+    }
+}
+
+/// Save events from the client pool, restarting the loop with backoff when it
+/// exits or panics
+///
+/// Events go through a bounded queue to a writer task so slow saves don't hold up the
+/// notification receiver; events already queued by a stopped loop are still saved
+///
+/// `filters` are subscribed once at startup, empty when subscriptions are managed elsewhere
+pub async fn run_ingest(
+    client: Client,
+    db: ArchiveDatabase,
+    filters: Vec<Filter>,
+    stats: RelayStats,
+    health: IngestHealth,
+    settings: IngestSettings,
+) -> Result<()> {
+    // subscribe to notifications before the subscription so no events are missed
+    let rx = client.notifications();
+    subscribe(&client, &stats, filters, 0).await;
+    let silence = match settings.silence_timeout_secs.unwrap_or(900) {
+        0 => None,
+        s => Some(Duration::from_secs(s)),
+    };
+
+    let queue_size = settings.queue_size();
+    health
+        .0
+        .queue_size
+        .store(queue_size as u64, Ordering::Relaxed);
+
+    let mut rx = Some(rx);
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let started = Instant::now();
+        let (tx, queue) = mpsc::channel(queue_size);
+        tokio::spawn(write(db.clone(), queue, health.clone()));
+        let task = tokio::spawn(ingest(
+            client.clone(),
+            tx,
+            rx.take().unwrap_or_else(|| client.notifications()),
+            stats.clone(),
+            health.clone(),
+            silence,
+        ));
+        let reason = match task.await {
+            Ok(Ok(())) => "exited".to_string(),
+            Ok(Err(e)) => e.to_string(),
+            Err(e) if e.is_panic() => "panicked".to_string(),
+            Err(e) => e.to_string(),
+        };
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = Duration::from_secs(1);
+        }
+        error!(
+            "Ingest loop stopped ({}), restarting in {:?}",
+            reason, backoff
+        );
+        health.restarted(reason);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Save queued events until every sender is gone and the queue is empty
+async fn write(
+    db: ArchiveDatabase,
+    mut queue: mpsc::Receiver<(RelayUrl, Box<Event>)>,
+    health: IngestHealth,
+) {
+    while let Some((relay_url, event)) = queue.recv().await {
+        health.0.queued.fetch_sub(1, Ordering::Relaxed);
+        if let Err(e) = db.save_event(&event).await {
+            error!(relay:% = relay_url, id:% = event.id; "Failed to save event: {}", e);
+        }
+    }
+}
+
+async fn ingest(
+    client: Client,
+    queue: mpsc::Sender<(RelayUrl, Box<Event>)>,
+    mut rx: tokio::sync::broadcast::Receiver<RelayPoolNotification>,
+    stats: RelayStats,
+    health: IngestHealth,
+    silence: Option<Duration>,
+) -> Result<()> {
+    let mut filters = HashMap::new();
+    let mut refreshed: Option<Instant> = None;
+    loop {
+        let next = match silence {
+            Some(s) => match tokio::time::timeout(s, rx.recv()).await {
+                Ok(n) => n,
+                Err(_) => {
+                    resubscribe(&client, &health).await;
+                    continue;
+                }
+            },
+            None => rx.recv().await,
+        };
+        match next {
+            Ok(RelayPoolNotification::Event {
+                relay_url,
+                subscription_id,
+                event,
+            }) => {
+                health
+                    .0
+                    .last_event
+                    .store(Timestamp::now().as_secs(), Ordering::Relaxed);
+                stats.record_new(&relay_url, &subscription_id);
+                debug!(relay:% = relay_url, id:% = event.id; "Received event");
+                health.0.queued.fetch_add(1, Ordering::Relaxed);
+                match queue.try_send((relay_url, event)) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(item)) => {
+                        health.0.queue_full.fetch_add(1, Ordering::Relaxed);
+                        debug!("Save queue full, waiting for the writer");
+                        if queue.send(item).await.is_err() {
+                            health.0.queued.fetch_sub(1, Ordering::Relaxed);
+                            return Err(anyhow!("event writer stopped"));
+                        }
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        health.0.queued.fetch_sub(1, Ordering::Relaxed);
+                        return Err(anyhow!("event writer stopped"));
+                    }
+                }
+                if refreshed.is_none_or(|r| r.elapsed() > FILTER_REFRESH) {
+                    refresh_filters(&client, &mut filters).await;
+                    refreshed = Some(Instant::now());
+                }
+            }
+            Ok(RelayPoolNotification::Message { relay_url, message }) => {
+                relay_message(&client, &stats, &health, &filters, relay_url, message).await;
+            }
+            Ok(RelayPoolNotification::Shutdown) => return Err(anyhow!("client shutdown")),
+            Err(RecvError::Lagged(n)) => {
+                health.0.lagged.fetch_add(n, Ordering::Relaxed);
+                warn!("Ingest lagged, dropped {} notifications", n);
+            }
+            Err(RecvError::Closed) => return Err(anyhow!("notification channel closed")),
+        }
+    }
+}
+
+fn is_ingest(id: &SubscriptionId) -> bool {
+    id.as_str().starts_with("ingest-") || job_name(id).is_some()
+}
+
+/// Copy the filters of the ingest subscriptions, kept until the id is reused
+async fn refresh_filters(client: &Client, filters: &mut HashMap<SubscriptionId, Vec<Filter>>) {
+    for (id, relays) in client.subscriptions().await {
+        if is_ingest(&id)
+            && let Some(f) = relays.into_values().next()
+        {
+            filters.insert(id, f);
+        }
+    }
+}
+
+/// Log and count NOTICE, CLOSED and failed OK messages, ingest subscriptions a relay
+/// closed are opened again after [CLOSED_RETRY] unless the reason is final
+async fn relay_message(
+    client: &Client,
+    stats: &RelayStats,
+    health: &IngestHealth,
+    filters: &HashMap<SubscriptionId, Vec<Filter>>,
+    relay_url: RelayUrl,
+    message: RelayMessage<'static>,
+) {
+    match message {
+        RelayMessage::Notice(m) => {
+            warn!(relay:% = relay_url; "NOTICE: {}", m);
+            stats.record_notice(&relay_url, NoticeKind::Notice, m.into_owned());
+        }
+        RelayMessage::Closed {
+            subscription_id,
+            message,
+        } => {
+            warn!(relay:% = relay_url; "CLOSED {}: {}", subscription_id, message);
+            stats.record_notice(
+                &relay_url,
+                NoticeKind::Closed,
+                format!("{}: {}", subscription_id, message),
+            );
+            // subscriptions of removed ingest jobs stay closed
+            if !is_ingest(&subscription_id)
+                || job_name(&subscription_id).is_some() && !stats.is_job(&subscription_id)
+            {
+                return;
+            }
+            if FINAL_CLOSED.iter().any(|p| message.starts_with(p)) {
+                warn!(relay:% = relay_url; "Not reopening {}", subscription_id);
+                return;
+            }
+            // other relays still hold the filters of the same subscription
+            let id = subscription_id.into_owned();
+            let Some(f) = client
+                .subscription(&id)
+                .await
+                .into_values()
+                .next()
+                .or_else(|| filters.get(&id).cloned())
+            else {
+                warn!(relay:% = relay_url; "Filters of {} unknown, not reopening", id);
+                return;
+            };
+            tokio::spawn(reopen(client.clone(), relay_url, id, f, health.clone()));
+        }
+        RelayMessage::Ok {
+            event_id,
+            status: false,
+            message,
+        } => {
+            warn!(relay:% = relay_url, id:% = event_id; "Event refused: {}", message);
+            stats.record_notice(
+                &relay_url,
+                NoticeKind::Ok,
+                format!("{}: {}", event_id, message),
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Open a subscription closed by `relay_url` again, replacing it when it was already
+/// reopened
+async fn reopen(
+    client: Client,
+    relay_url: RelayUrl,
+    id: SubscriptionId,
+    filters: Vec<Filter>,
+    health: IngestHealth,
+) {
+    tokio::time::sleep(CLOSED_RETRY).await;
+    let Ok(relay) = client.relay(&relay_url).await else {
+        return;
+    };
+    if relay.status() != RelayStatus::Connected {
+        return;
+    }
+    match relay
+        .subscribe_with_id(id.clone(), filters, SubscribeOptions::default())
+        .await
+    {
+        Ok(_) => {
+            info!(relay:% = relay_url; "Reopened subscription {}", id);
+            health.0.resubscribes.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(e) => warn!(relay:% = relay_url; "Failed to reopen {}: {}", id, e),
+    }
+}
+
+/// Open `ingest-N` subscriptions for `filters`, replacing ones with the same id
+/// and closing the rest of the `previous` subscriptions
+///
+/// Returns the number of subscriptions now open
+pub async fn subscribe(
+    client: &Client,
+    stats: &RelayStats,
+    filters: Vec<Filter>,
+    previous: usize,
+) -> usize {
+    let count = filters.len();
+    for (i, f) in filters.into_iter().enumerate() {
+        let id = SubscriptionId::new(format!("ingest-{}", i));
+        info!("Subscribing {} {}", id, f.as_json());
+        stats.track_filter(&id, &f);
+        // fails without relays yet, the subscription is still kept for discovered relays
+        if let Err(e) = client.subscribe_with_id(id, f, None).await {
+            warn!("Failed to subscribe: {}", e);
+        }
+    }
+    for i in count..previous {
+        let id = SubscriptionId::new(format!("ingest-{}", i));
+        stats.untrack_filter(&id);
+        client.unsubscribe(&id).await;
+    }
+    count
+}
+
+/// Close and re-issue every subscription on connected relays, some relays
+/// silently drop long running subscriptions
+async fn resubscribe(client: &Client, health: &IngestHealth) {
+    let mut count = 0;
+    for (url, relay) in client.relays().await {
+        if relay.status() != RelayStatus::Connected {
+            continue;
+        }
+        for (id, filters) in relay.subscriptions().await {
+            if let Err(e) = relay.unsubscribe(&id).await {
+                warn!("Failed to close subscription {} on {}: {}", id, url, e);
+            }
+            match relay
+                .subscribe_with_id(id.clone(), filters, SubscribeOptions::default())
+                .await
+            {
+                Ok(_) => count += 1,
+                Err(e) => warn!("Failed to resubscribe {} on {}: {}", id, url, e),
+            }
+        }
+    }
+    if count > 0 {
+        info!("No events received, resubscribed {} subscriptions", count);
+        health.0.resubscribes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Warn once about each relay whose share of out of scope events exceeds
+/// `noisy_relay_ratio`, disconnecting it with `auto_drop_noisy_relays`
+pub async fn run_noisy_relays(client: Client, stats: RelayStats, settings: IngestSettings) {
+    let max_ratio = settings.noisy_relay_ratio();
+    let drop = settings.auto_drop_noisy_relays.unwrap_or(false);
+    let mut noisy = HashSet::new();
+    loop {
+        tokio::time::sleep(NOISY_INTERVAL).await;
+        for r in stats.snapshot(&client).await {
+            if r.received < NOISY_MIN_RECEIVED || noisy.contains(&r.url) {
+                continue;
+            }
+            let ratio = r.out_of_scope as f64 / r.received as f64;
+            if ratio <= max_ratio {
+                continue;
+            }
+            let share = format!(
+                "{:.0}% of {} events were outside the subscriptions",
+                ratio * 100.0,
+                r.received
+            );
+            if drop {
+                warn!(relay:% = r.url; "{}, disconnecting (auto_drop_noisy_relays)", share);
+                if let Err(e) = client.disconnect_relay(&r.url).await {
+                    warn!(relay:% = r.url; "Failed to disconnect: {}", e);
+                }
+            } else {
+                warn!(relay:% = r.url; "{}, consider removing it from relays", share);
+            }
+            noisy.insert(r.url);
+        }
+    }
+}
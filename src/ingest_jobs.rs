@@ -0,0 +1,354 @@
+use crate::ingest::{IngestSettings, with_tags};
+use crate::kinds::{KindEntry, KindSet, MAX_FILTER_KINDS};
+use crate::policy::{TagMatch, tag_groups};
+use crate::sensitive::SensitiveKinds;
+use crate::stats::RelayStats;
+use anyhow::{Result, anyhow, bail};
+use config::Config;
+use log::{info, warn};
+use nostr_sdk::prelude::JsonUtil;
+use nostr_sdk::{Client, Filter, Kind, PublicKey, RelayUrl, SubscriptionId, Timestamp};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Authors per subscription filter of a job
+const AUTHORS_PER_FILTER: usize = 500;
+
+/// Subscription ids of ingest jobs are `job-<name>-<n>`
+const PREFIX: &str = "job-";
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct IngestJobSettings {
+    /// Letters, digits, `-` and `_`, used in the job's subscription ids and counters
+    pub name: String,
+
+    /// Kinds of the job, default the archived `kinds`
+    pub kinds: Option<Vec<KindEntry>>,
+
+    /// Only events of these pubkeys (npub or hex)
+    pub authors: Option<Vec<String>>,
+
+    /// Only events with any of these tag values
+    pub tags: Option<Vec<TagMatch>>,
+
+    /// Subscribe on these of the configured relays only, default all of them
+    pub relays: Option<Vec<String>>,
+
+    /// Hours of history fetched when the job starts or changes
+    pub backfill_hours: Option<u64>,
+
+    /// Events requested per subscription when it opens, default ingest.initial_limit
+    pub initial_limit: Option<usize>,
+}
+
+/// Settings of the archive every job is narrowed to
+#[derive(Clone)]
+pub struct JobDefaults {
+    pub ingest: IngestSettings,
+    /// Archived kinds, None for all
+    pub kinds: Option<KindSet>,
+    /// Upstream kinds filter of the archived kinds
+    pub filter_kinds: Option<Vec<Kind>>,
+    pub sensitive: SensitiveKinds,
+    /// The configured relays
+    pub relays: Vec<String>,
+}
+
+/// Subscriptions of a job
+struct Job {
+    filters: Vec<Filter>,
+    /// Empty for every relay
+    relays: Vec<RelayUrl>,
+}
+
+/// Name of the ingest job a subscription belongs to
+pub fn job_name(id: &SubscriptionId) -> Option<&str> {
+    id.as_str()
+        .strip_prefix(PREFIX)
+        .and_then(|s| s.rsplit_once('-'))
+        .map(|(name, _)| name)
+        .filter(|n| !n.is_empty())
+}
+
+fn subscription_id(name: &str, i: usize) -> SubscriptionId {
+    SubscriptionId::new(format!("{}{}-{}", PREFIX, name, i))
+}
+
+impl IngestJobSettings {
+    fn build(&self, defaults: &JobDefaults) -> Result<Job> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("name: only letters, digits, - and _ are allowed");
+        }
+
+        let kinds = match &self.kinds {
+            Some(k) => Some(KindSet::parse(k)?.filter_kinds().ok_or(anyhow!(
+                "kinds: more than {} kinds can't be sent to relays",
+                MAX_FILTER_KINDS
+            ))?),
+            None => defaults.filter_kinds.clone(),
+        }
+        .map(|k| {
+            defaults
+                .sensitive
+                .filter(k)
+                .into_iter()
+                .filter(|k| defaults.kinds.as_ref().is_none_or(|a| a.contains(*k)))
+                .collect::<Vec<Kind>>()
+        });
+        if kinds.as_ref().is_some_and(|k| k.is_empty()) {
+            bail!("kinds: none of the kinds are archived");
+        }
+
+        let authors = self
+            .authors
+            .as_ref()
+            .map(|a| {
+                a.iter()
+                    .map(|p| PublicKey::parse(p).map_err(|e| anyhow!("authors: {}: {}", p, e)))
+                    .collect::<Result<Vec<PublicKey>>>()
+            })
+            .transpose()?;
+        if authors.as_ref().is_some_and(|a| a.is_empty()) {
+            bail!("authors: the list is empty");
+        }
+
+        let tags = tag_groups(self.tags.as_deref().unwrap_or_default())
+            .map_err(|e| anyhow!("tags: {}", e))?;
+
+        let configured: HashSet<RelayUrl> = defaults
+            .relays
+            .iter()
+            .filter_map(|r| RelayUrl::parse(r).ok())
+            .collect();
+        let relays = self
+            .relays
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .map(|r| {
+                let url = RelayUrl::parse(r).map_err(|e| anyhow!("relays: {}: {}", r, e))?;
+                if !configured.contains(&url) {
+                    bail!("relays: {} is not one of the configured relays", r);
+                }
+                Ok(url)
+            })
+            .collect::<Result<Vec<RelayUrl>>>()?;
+
+        let settings = IngestSettings {
+            initial_limit: self.initial_limit.or(defaults.ingest.initial_limit),
+            ..defaults.ingest.clone()
+        };
+        let mut filters = settings.filters(kinds.as_deref());
+        if let Some(authors) = authors {
+            filters = filters
+                .iter()
+                .flat_map(|f| {
+                    authors
+                        .chunks(AUTHORS_PER_FILTER)
+                        .map(|c| f.clone().authors(c.iter().copied()))
+                })
+                .collect();
+        }
+        Ok(Job {
+            filters: with_tags(filters, &tags),
+            relays,
+        })
+    }
+}
+
+/// Check the ingest jobs of the config, names must be unique
+pub fn validate(jobs: &[IngestJobSettings], defaults: &JobDefaults) -> Result<()> {
+    let mut names = HashSet::new();
+    for j in jobs {
+        if !names.insert(&j.name) {
+            bail!("ingest_jobs: {} is listed twice", j.name);
+        }
+        j.build(defaults)
+            .map_err(|e| anyhow!("ingest_jobs: {}: {}", j.name, e))?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct JobsConfig {
+    ingest_jobs: Option<Vec<IngestJobSettings>>,
+}
+
+/// Open one set of subscriptions per ingest job, the `ingest_jobs` of `config` are read
+/// again on SIGHUP; added jobs start, removed ones are closed and changed ones
+/// resubscribed while unchanged jobs keep their subscriptions
+pub async fn run_ingest_jobs(
+    client: Client,
+    stats: RelayStats,
+    jobs: Vec<IngestJobSettings>,
+    defaults: JobDefaults,
+    config: PathBuf,
+) -> Result<()> {
+    let mut running = HashMap::new();
+    apply(&client, &stats, &defaults, &mut running, jobs).await;
+
+    let mut hup = signal(SignalKind::hangup())?;
+    while hup.recv().await.is_some() {
+        let jobs = Config::builder()
+            .add_source(config::File::from(config.clone()))
+            .build()
+            .and_then(|c| c.try_deserialize::<JobsConfig>());
+        match jobs {
+            Ok(c) => {
+                info!("Reloading ingest_jobs from {}", config.display());
+                apply(
+                    &client,
+                    &stats,
+                    &defaults,
+                    &mut running,
+                    c.ingest_jobs.unwrap_or_default(),
+                )
+                .await
+            }
+            Err(e) => warn!(
+                "Failed to reload ingest_jobs from {}: {}",
+                config.display(),
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Settings and subscription count of the running jobs by name
+type Running = HashMap<String, (IngestJobSettings, usize)>;
+
+async fn apply(
+    client: &Client,
+    stats: &RelayStats,
+    defaults: &JobDefaults,
+    running: &mut Running,
+    jobs: Vec<IngestJobSettings>,
+) {
+    let mut wanted: HashMap<String, IngestJobSettings> = HashMap::new();
+    for j in jobs {
+        if wanted.contains_key(&j.name) {
+            warn!("Ingest job {} is listed twice, using the first", j.name);
+            continue;
+        }
+        wanted.insert(j.name.clone(), j);
+    }
+
+    let removed: Vec<String> = running
+        .keys()
+        .filter(|n| !wanted.contains_key(*n))
+        .cloned()
+        .collect();
+    for name in removed {
+        if let Some((_, count)) = running.remove(&name) {
+            close(client, stats, &name, count).await;
+            stats.untrack_job(&name);
+            info!("Ingest job {} removed", name);
+        }
+    }
+
+    for (name, settings) in wanted {
+        let previous = running.get(&name);
+        if previous.is_some_and(|(s, _)| *s == settings) {
+            continue;
+        }
+        let job = match settings.build(defaults) {
+            Ok(j) => j,
+            Err(e) => {
+                warn!(
+                    "Ingest job {}: {}, {}",
+                    name,
+                    e,
+                    if previous.is_some() {
+                        "keeping the previous settings"
+                    } else {
+                        "not started"
+                    }
+                );
+                continue;
+            }
+        };
+        // the relays of a changed job may differ, close everything it had open
+        if let Some((_, count)) = previous {
+            close(client, stats, &name, *count).await;
+        }
+        stats.track_job(&name);
+        let count = open(client, stats, &name, &job).await;
+        info!(
+            "Ingest job {}: {} subscriptions on {}",
+            name,
+            count,
+            if job.relays.is_empty() {
+                "every relay".to_string()
+            } else {
+                format!("{} relays", job.relays.len())
+            }
+        );
+        if let Some(h) = settings.backfill_hours {
+            tokio::spawn(backfill(client.clone(), name.clone(), job, h));
+        }
+        running.insert(name, (settings, count));
+    }
+}
+
+/// Open the subscriptions of `job`, returns their number
+async fn open(client: &Client, stats: &RelayStats, name: &str, job: &Job) -> usize {
+    for (i, f) in job.filters.iter().enumerate() {
+        let id = subscription_id(name, i);
+        info!("Subscribing {} {}", id, f.as_json());
+        stats.track_filter(&id, f);
+        let r = if job.relays.is_empty() {
+            // kept for discovered relays when no relay is connected yet
+            client.subscribe_with_id(id, f.clone(), None).await
+        } else {
+            client
+                .subscribe_with_id_to(job.relays.clone(), id, f.clone(), None)
+                .await
+        };
+        if let Err(e) = r {
+            warn!("Ingest job {}: failed to subscribe: {}", name, e);
+        }
+    }
+    job.filters.len()
+}
+
+async fn close(client: &Client, stats: &RelayStats, name: &str, count: usize) {
+    for i in 0..count {
+        let id = subscription_id(name, i);
+        stats.untrack_filter(&id);
+        client.unsubscribe(&id).await;
+    }
+}
+
+/// Fetch the last `hours` of the job's events, they are saved by the client
+async fn backfill(client: Client, name: String, job: Job, hours: u64) {
+    let since = Timestamp::now() - hours * 60 * 60;
+    let mut count = 0;
+    for f in job.filters {
+        // the limit of the live subscription would cut the backfill short
+        let mut f = f.since(since);
+        f.limit = None;
+        let r = if job.relays.is_empty() {
+            client.fetch_events(f, FETCH_TIMEOUT).await
+        } else {
+            client
+                .fetch_events_from(job.relays.clone(), f, FETCH_TIMEOUT)
+                .await
+        };
+        match r {
+            Ok(events) => count += events.len(),
+            Err(e) => warn!("Ingest job {}: backfill failed: {}", name, e),
+        }
+    }
+    info!("Ingest job {}: backfilled {} events", name, count);
+}
@@ -0,0 +1,345 @@
+use anyhow::Result;
+use log::{debug, info};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, oneshot};
+
+/// Read and write buffer of archive jobs
+pub const JOB_BUFFER: usize = 1024 * 1024;
+
+/// Nice value of the threads running archive jobs, on Linux it also lowers their IO
+/// priority unless an IO class was set
+const JOB_NICE: i32 = 10;
+
+/// How often a running job logs its progress
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Messages queued per scan thread before it waits for the writer
+const SCAN_QUEUE: usize = 4;
+
+/// `scan_threads` of the config, set once at startup
+static SCAN_THREADS: OnceLock<usize> = OnceLock::new();
+
+pub fn set_scan_threads(threads: usize) {
+    let _ = SCAN_THREADS.set(threads.max(1));
+}
+
+/// Threads reading archives in [scan_files], the number of CPUs unless configured
+pub fn scan_threads() -> usize {
+    SCAN_THREADS
+        .get()
+        .copied()
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+/// Limits how many archive jobs (compressions, seekable rewrites, conversions, summaries)
+/// read and write whole archives at once, so they don't compete with ingestion for the disk
+#[derive(Clone)]
+pub struct ArchiveJobs {
+    slots: Arc<Semaphore>,
+    /// Jobs holding a slot
+    running: Arc<AtomicUsize>,
+}
+
+impl ArchiveJobs {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(concurrency.max(1))),
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Run a blocking job once a slot is free, on its own thread at a lowered priority
+    pub async fn run<T, F>(&self, job: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let _permit = self.slots.acquire().await?;
+        let _running = Running::start(&self.running);
+        let (tx, rx) = oneshot::channel();
+        std::thread::Builder::new()
+            .name("archive-job".to_string())
+            .spawn(move || {
+                lower_priority();
+                let _ = tx.send(job());
+            })?;
+        rx.await?
+    }
+}
+
+/// Lower the priority of the calling thread, the nice value is per thread on Linux
+#[cfg(target_os = "linux")]
+fn lower_priority() {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, JOB_NICE) } != 0 {
+        debug!(
+            "Failed to lower the priority of an archive job: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_priority() {}
+
+/// Counts a job as running until dropped
+struct Running(Arc<AtomicUsize>);
+
+impl Running {
+    fn start(running: &Arc<AtomicUsize>) -> Self {
+        running.fetch_add(1, Ordering::Relaxed);
+        Self(running.clone())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Open the input of a job, `action` and the percentage read are logged every
+/// [PROGRESS_INTERVAL]
+pub fn open_input(path: &Path, action: &str) -> Result<BufReader<Progress<File>>> {
+    let f = File::open(path)?;
+    let total = f.metadata()?.len();
+    Ok(BufReader::with_capacity(
+        JOB_BUFFER,
+        Progress {
+            inner: f,
+            label: format!("{} {}", action, path.display()),
+            read: 0,
+            total,
+            logged: Instant::now(),
+        },
+    ))
+}
+
+pub struct Progress<R> {
+    inner: R,
+    label: String,
+    read: u64,
+    total: u64,
+    logged: Instant,
+}
+
+impl<R: Read> Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.logged.elapsed() >= PROGRESS_INTERVAL {
+            self.logged = Instant::now();
+            info!("{}: {}%", self.label, self.read * 100 / self.total.max(1));
+        }
+        Ok(n)
+    }
+}
+
+/// Sent by a scan thread to the writer of [scan_files]
+pub enum Scanned<B, T> {
+    /// Part of an archive, eg. a batch of rows
+    Batch(B),
+    /// The archive was read
+    Done(T),
+}
+
+/// Archives read by [scan_files] so far, with the time left estimated from their size
+pub struct ScanProgress {
+    pub done: usize,
+    pub total: usize,
+    pub eta: Option<Duration>,
+}
+
+impl Display for ScanProgress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}/{}", self.done, self.total)?;
+        if let Some(eta) = self.eta.filter(|_| self.done < self.total) {
+            let s = eta.as_secs();
+            match s {
+                0..3600 => write!(f, ", ETA {}m{:02}s", s / 60, s % 60)?,
+                _ => write!(f, ", ETA {}h{:02}m", s / 3600, s % 3600 / 60)?,
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+/// Read `files` on [scan_threads] threads and hand what they find to `write`, one
+/// message at a time on the calling thread
+///
+/// `scan` reads one archive, streaming its batches with the function it is given, which
+/// returns false once the writer stopped; its result is sent last as [Scanned::Done].
+/// Queues are bounded, a slow writer holds up the scan threads. The first error of
+/// `write` stops the scan and is returned
+pub fn scan_files<B: Send, T: Send>(
+    files: &[PathBuf],
+    scan: impl Fn(&Path, &mut dyn FnMut(B) -> bool) -> T + Sync,
+    write: impl FnMut(&Path, Scanned<B, T>, &ScanProgress) -> Result<()>,
+) -> Result<()> {
+    scan_files_on(scan_threads(), files, scan, write)
+}
+
+fn scan_files_on<B: Send, T: Send>(
+    threads: usize,
+    files: &[PathBuf],
+    scan: impl Fn(&Path, &mut dyn FnMut(B) -> bool) -> T + Sync,
+    mut write: impl FnMut(&Path, Scanned<B, T>, &ScanProgress) -> Result<()>,
+) -> Result<()> {
+    let sizes: Vec<u64> = files
+        .iter()
+        .map(|f| std::fs::metadata(f).map_or(0, |m| m.len()))
+        .collect();
+    let total_bytes: u64 = sizes.iter().sum();
+    let threads = threads.min(files.len());
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = sync_channel::<(usize, Scanned<B, T>)>(threads * SCAN_QUEUE);
+    let started = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            let tx = tx.clone();
+            let (next, scan) = (&next, &scan);
+            s.spawn(move || {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = files.get(i) else {
+                        break;
+                    };
+                    let mut send = |b| tx.send((i, Scanned::Batch(b))).is_ok();
+                    let done = scan(path, &mut send);
+                    if tx.send((i, Scanned::Done(done))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let mut progress = ScanProgress {
+            done: 0,
+            total: files.len(),
+            eta: None,
+        };
+        let mut read = 0u64;
+        for (i, msg) in rx {
+            if let Scanned::Done(_) = &msg {
+                progress.done += 1;
+                read += sizes[i];
+                if read > 0 {
+                    let left = total_bytes.saturating_sub(read) as u128;
+                    let nanos = started.elapsed().as_nanos() * left / read as u128;
+                    progress.eta = Some(Duration::from_nanos(nanos as u64));
+                }
+            }
+            // dropping the receiver stops the scan threads at their next send
+            write(&files[i], msg, &progress)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::decode_archive_strict;
+    use crate::scan::scan_lines;
+    use crate::test_util::{TempDir, event};
+    use nostr_sdk::{Event, EventId, JsonUtil};
+    use std::collections::HashSet;
+    use std::io::Write;
+
+    /// Events and ids of `files` read on `threads` threads, the way rebuilds count them
+    fn count(threads: usize, files: &[PathBuf]) -> (u64, HashSet<EventId>) {
+        let (mut events, mut ids) = (0, HashSet::new());
+        scan_files_on(
+            threads,
+            files,
+            |path, send| {
+                let input =
+                    decode_archive_strict(path, BufReader::new(File::open(path).unwrap())).unwrap();
+                let mut batch = Vec::new();
+                let scan = scan_lines(path, input, |l| match Event::from_json(l) {
+                    Ok(e) => {
+                        batch.push(e.id);
+                        if batch.len() >= 16 {
+                            send(std::mem::take(&mut batch));
+                        }
+                        true
+                    }
+                    Err(_) => false,
+                })
+                .unwrap();
+                send(batch);
+                scan.events
+            },
+            |_, msg, _| {
+                match msg {
+                    Scanned::Batch(b) => ids.extend(b),
+                    Scanned::Done(n) => events += n,
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+        (events, ids)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn jobs_run_at_a_lower_priority() {
+        let nice = || unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+        let base = nice();
+        let job = ArchiveJobs::new(1).run(move || Ok(nice())).await.unwrap();
+        assert_eq!(job, (base + JOB_NICE).min(19));
+        // the thread that started the job keeps its priority
+        assert_eq!(nice(), base);
+    }
+
+    #[test]
+    fn parallel_scan_counts_like_one_thread() {
+        let dir = TempDir::new();
+        let mut files = Vec::new();
+        let mut written = 0;
+        for day in 0..12u64 {
+            let mut data = Vec::new();
+            for i in 0..day * 37 + 5 {
+                writeln!(data, "{}", event(1, &format!("{} {}", day, i)).as_json()).unwrap();
+                written += 1;
+            }
+            // a damaged line, skipped by every reader
+            data.extend_from_slice(b"{not an event}\n");
+            let path = if day % 2 == 0 {
+                let path = dir
+                    .path()
+                    .join(format!("events_202601{:02}.jsonl", day + 1));
+                std::fs::write(&path, data).unwrap();
+                path
+            } else {
+                let path = dir
+                    .path()
+                    .join(format!("events_202601{:02}.jsonl.zst", day + 1));
+                std::fs::write(&path, zstd::encode_all(data.as_slice(), 0).unwrap()).unwrap();
+                path
+            };
+            files.push(path);
+        }
+
+        let (single, single_ids) = count(1, &files);
+        assert_eq!(single, written);
+        assert_eq!(single_ids.len() as u64, written);
+        for threads in [2, 8, 32] {
+            let (events, ids) = count(threads, &files);
+            assert_eq!(events, single, "{} threads", threads);
+            assert_eq!(ids, single_ids, "{} threads", threads);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::{Result, anyhow, bail};
+use nostr_sdk::Kind;
+use serde::Deserialize;
+use std::ops::RangeInclusive;
+
+/// Ranges covering more kinds than this are not sent to upstream relays
+pub const MAX_FILTER_KINDS: usize = 1000;
+
+/// Entry in a `kinds` list, a single kind, a range `"30000-39999"` or `"all"`
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum KindEntry {
+    Kind(u16),
+    Spec(String),
+}
+
+/// Set of kinds stored as sorted, non-overlapping inclusive ranges
+#[derive(Clone, Debug, PartialEq)]
+pub struct KindSet {
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl KindSet {
+    pub fn all() -> Self {
+        Self {
+            ranges: vec![0..=u16::MAX],
+        }
+    }
+
+    pub fn parse(entries: &[KindEntry]) -> Result<Self> {
+        let mut ranges = Vec::with_capacity(entries.len());
+        for e in entries {
+            let r = match e {
+                KindEntry::Kind(k) => *k..=*k,
+                KindEntry::Spec(s) if s.trim() == "all" => return Ok(Self::all()),
+                KindEntry::Spec(s) => match s.split_once('-') {
+                    Some((a, b)) => {
+                        let (a, b): (u16, u16) = a
+                            .trim()
+                            .parse()
+                            .and_then(|a| Ok((a, b.trim().parse()?)))
+                            .map_err(|_| anyhow!("Invalid kind range {}", s))?;
+                        if a > b {
+                            bail!("Invalid kind range {}, start is after end", s);
+                        }
+                        a..=b
+                    }
+                    None => {
+                        let k = s
+                            .trim()
+                            .parse()
+                            .map_err(|_| anyhow!("Invalid kind {}", s))?;
+                        k..=k
+                    }
+                },
+            };
+            ranges.push(r);
+        }
+
+        // merge overlapping and adjacent ranges
+        ranges.sort_by_key(|r| *r.start());
+        let mut merged: Vec<RangeInclusive<u16>> = Vec::with_capacity(ranges.len());
+        for r in ranges {
+            match merged.last_mut() {
+                Some(last) if *r.start() <= last.end().saturating_add(1) => {
+                    *last = *last.start()..=(*last.end()).max(*r.end());
+                }
+                _ => merged.push(r),
+            }
+        }
+        Ok(Self { ranges: merged })
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.ranges == [0..=u16::MAX]
+    }
+
+    pub fn contains(&self, kind: Kind) -> bool {
+        let k = kind.as_u16();
+        self.ranges
+            .binary_search_by(|r| {
+                if *r.end() < k {
+                    std::cmp::Ordering::Less
+                } else if *r.start() > k {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Does any kind of `other` fall in this set
+    pub fn intersects(&self, other: &KindSet) -> bool {
+        self.ranges.iter().any(|a| {
+            other
+                .ranges
+                .iter()
+                .any(|b| a.start() <= b.end() && b.start() <= a.end())
+        })
+    }
+
+    /// Kinds of this set not in `other`
+    pub fn difference(&self, other: &KindSet) -> KindSet {
+        let mut ranges = Vec::with_capacity(self.ranges.len());
+        for r in &self.ranges {
+            let mut start = *r.start() as u32;
+            let end = *r.end() as u32;
+            for o in &other.ranges {
+                let (os, oe) = (*o.start() as u32, *o.end() as u32);
+                if oe < start || os > end {
+                    continue;
+                }
+                if os > start {
+                    ranges.push(start as u16..=(os - 1) as u16);
+                }
+                start = oe + 1;
+            }
+            if start <= end {
+                ranges.push(start as u16..=end as u16);
+            }
+        }
+        KindSet { ranges }
+    }
+
+    /// Explicit kinds for an upstream filter, None when the set is too large to list
+    pub fn filter_kinds(&self) -> Option<Vec<Kind>> {
+        let count: usize = self.ranges.iter().map(|r| r.len()).sum();
+        if count > MAX_FILTER_KINDS {
+            return None;
+        }
+        Some(
+            self.ranges
+                .iter()
+                .flat_map(|r| r.clone().map(Kind::from))
+                .collect(),
+        )
+    }
+}
+
+impl std::fmt::Display for KindSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_all() {
+            return write!(f, "all");
+        }
+        for (i, r) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            if r.start() == r.end() {
+                write!(f, "{}", r.start())?;
+            } else {
+                write!(f, "{}-{}", r.start(), r.end())?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use log::{error, info};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{SignalKind, signal};
+
+/// Template compiled into the binary
+const DEFAULT_TEMPLATE: &str = include_str!("./index.html");
+
+#[derive(Deserialize, Clone, Default)]
+pub struct LandingPageSettings {
+    /// Load the landing page template from this file instead of the built-in one
+    pub template_path: Option<PathBuf>,
+
+    /// Relay name, `%%_RELAY_NAME_%%`
+    pub name: Option<String>,
+
+    /// Relay description, `%%_DESCRIPTION_%%`
+    pub description: Option<String>,
+
+    /// Operator pubkey, `%%_PUBKEY_%%`
+    pub pubkey: Option<String>,
+}
+
+/// Landing page template and the static values filled into it
+#[derive(Clone)]
+pub struct LandingPage {
+    template: Arc<RwLock<String>>,
+    fields: Arc<Vec<(&'static str, String)>>,
+}
+
+impl Default for LandingPage {
+    fn default() -> Self {
+        Self {
+            template: Arc::new(RwLock::new(DEFAULT_TEMPLATE.to_string())),
+            fields: Arc::new(Vec::new()),
+        }
+    }
+}
+
+impl LandingPage {
+    /// Load the template, a custom template is re-read on SIGHUP
+    pub fn new(settings: LandingPageSettings, kinds: String) -> std::io::Result<Self> {
+        let ret = Self {
+            template: Arc::new(RwLock::new(match &settings.template_path {
+                Some(p) => Self::load(p),
+                None => DEFAULT_TEMPLATE.to_string(),
+            })),
+            fields: Arc::new(vec![
+                (
+                    "%%_RELAY_NAME_%%",
+                    html_escape(settings.name.as_deref().unwrap_or("nostrhole")),
+                ),
+                (
+                    "%%_DESCRIPTION_%%",
+                    html_escape(settings.description.as_deref().unwrap_or_default()),
+                ),
+                (
+                    "%%_PUBKEY_%%",
+                    html_escape(settings.pubkey.as_deref().unwrap_or_default()),
+                ),
+                ("%%_KINDS_%%", html_escape(&kinds)),
+            ]),
+        };
+        if let Some(path) = settings.template_path {
+            let template = ret.template.clone();
+            let mut hup = signal(SignalKind::hangup())?;
+            tokio::spawn(async move {
+                while hup.recv().await.is_some() {
+                    *template.write().unwrap() = Self::load(&path);
+                    info!("Reloaded landing page template");
+                }
+            });
+        }
+        Ok(ret)
+    }
+
+    fn load(path: &PathBuf) -> String {
+        match std::fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => {
+                error!(
+                    "Failed to load landing page template {}, using built-in: {}",
+                    path.display(),
+                    e
+                );
+                DEFAULT_TEMPLATE.to_string()
+            }
+        }
+    }
+
+    /// Template with the static fields filled in
+    pub fn template(&self) -> String {
+        let mut ret = self.template.read().unwrap().clone();
+        for (k, v) in self.fields.iter() {
+            ret = ret.replace(k, v);
+        }
+        ret
+    }
+}
+
+/// Escape text for html and xml
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,333 @@
+use crate::bloom::bloom_path;
+use crate::db::{ArchiveDatabase, is_active, is_archive, sidecar_path};
+use crate::encrypt::ENCRYPTED_EXT;
+use crate::naming::{archive_day, template};
+use crate::rollup::rollup_path;
+use crate::scan::is_reserved_dir;
+use crate::seekable::frames_path;
+use crate::summary::summary_path;
+use crate::torrent::TorrentMaker;
+use anyhow::{Result, bail};
+use chrono::{Datelike, Days, NaiveDate, Utc};
+use itertools::Itertools;
+use log::{error, info};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often finalized archives are checked for their place in the nested layout
+const LAYOUT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where archives are kept in out_dir and each partition directory
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// Every archive in the top directory
+    #[default]
+    Flat,
+    /// Finalized archives in a `YYYY/MM` directory of their day, eg.
+    /// `2025/01/events_20250101.jsonl.zst`; the active file stays in the top directory
+    Nested,
+}
+
+impl Layout {
+    /// Where the archive at `path` belongs, archives already in a `YYYY/MM` directory
+    /// stay there; archives named by the writer get their `file_name_template` name
+    pub fn place(&self, path: &Path) -> PathBuf {
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str()))
+        else {
+            return path.to_path_buf();
+        };
+        let name = template()
+            .and_then(|t| t.rename(name, t.partition_dir(dir).as_deref()))
+            .unwrap_or(name.to_string());
+        match (self, archive_day(path)) {
+            (Self::Nested, Some(day)) if !is_nested(path) => dir.join(nested_dir(day)).join(name),
+            _ => dir.join(name),
+        }
+    }
+
+    /// Finalized archive that [run_layout] will move or rename, nothing is published for
+    /// it until then
+    pub fn awaits_move(&self, path: &Path) -> bool {
+        (*self == Self::Nested || template().is_some())
+            && is_final(path)
+            && self.place(path) != path
+    }
+}
+
+/// `YYYY/MM` directory of the archives of `day`
+pub fn nested_dir(day: NaiveDate) -> PathBuf {
+    PathBuf::from(format!("{:04}", day.year())).join(format!("{:02}", day.month()))
+}
+
+/// Year or month directory of the nested layout
+pub fn is_date_dir(name: &str) -> bool {
+    matches!(name.len(), 2 | 4) && name.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn is_year(name: &str) -> bool {
+    name.len() == 4 && is_date_dir(name)
+}
+
+fn is_month(name: &str) -> bool {
+    name.len() == 2 && is_date_dir(name)
+}
+
+/// Is the archive at `path` in a `YYYY/MM` directory
+pub fn is_nested(path: &Path) -> bool {
+    let name = |p: Option<&Path>| {
+        p.and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .map(str::to_string)
+            .unwrap_or_default()
+    };
+    let month = path.parent();
+    is_month(&name(month)) && is_year(&name(month.and_then(|m| m.parent())))
+}
+
+/// Archive path relative to out_dir without its `YYYY/MM` directory and with the writer's
+/// name of templated names, so download counts, the author index and attestations name an
+/// archive the same in both layouts and whatever `file_name_template` is used
+pub fn flat_name(rel: &str) -> String {
+    let mut parts: Vec<&str> = rel.split('/').collect();
+    let n = parts.len();
+    if n >= 3 && is_year(parts[n - 3]) && is_month(parts[n - 2]) {
+        parts.drain(n - 3..n - 1);
+    }
+    if let (Some(t), Some((name, dirs))) = (template(), parts.split_last())
+        && let Some(writer) = t.writer(name, dirs.first().copied())
+    {
+        return dirs.iter().copied().chain([writer.as_str()]).join("/");
+    }
+    parts.join("/")
+}
+
+/// The writer won't touch the archive again, plain jsonl a day after the writer could
+/// have rotated it and compressed archives once their jsonl is gone
+fn is_final(path: &Path) -> bool {
+    if !is_archive(path) || is_active(path) {
+        return false;
+    }
+    let Some(day) = archive_day(path) else {
+        return false;
+    };
+    if path.extension().and_then(|e| e.to_str()) == Some("jsonl") {
+        return day < Utc::now().date_naive() - Days::new(1);
+    }
+    !path.with_extension("").exists()
+}
+
+/// Archives in `dir` and its `YYYY/MM` directories, partition directories aren't read
+pub fn archive_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    files_in(dir, is_archive)
+}
+
+/// Files of `dir` and its `YYYY/MM` directories matching `keep`
+fn files_in(dir: &Path, keep: impl Fn(&Path) -> bool) -> Result<Vec<PathBuf>> {
+    let mut ret = Vec::new();
+    let mut dirs = vec![(dir.to_path_buf(), 0)];
+    while let Some((d, depth)) = dirs.pop() {
+        for entry in std::fs::read_dir(&d)? {
+            let entry = entry?;
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if entry.file_type()?.is_dir() {
+                if (depth == 0 && is_year(&name)) || (depth == 1 && is_month(&name)) {
+                    dirs.push((path, depth + 1));
+                }
+            } else if keep(&path) {
+                ret.push(path);
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Archives in the `YYYY/MM` directories of `out_dir` and its partition directories
+pub fn nested_archives(out_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut ret = Vec::new();
+    for d in top_dirs(out_dir)? {
+        ret.extend(archive_paths(&d)?.into_iter().filter(|p| is_nested(p)));
+    }
+    Ok(ret)
+}
+
+/// `out_dir` and the directories in it that may be partitions
+fn top_dirs(out_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut ret = vec![out_dir.to_path_buf()];
+    for entry in std::fs::read_dir(out_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_dir()
+            && !name.starts_with('.')
+            && name != "index"
+            && !is_reserved_dir(&name)
+            && !is_date_dir(&name)
+        {
+            ret.push(entry.path());
+        }
+    }
+    Ok(ret)
+}
+
+/// Rename zstd archives of `out_dir` and its partition directories still named
+/// `.jsonl.zstd`, as the writer named them with `write_compressed` before, to
+/// `.jsonl.zst`; returns the number renamed
+pub fn rename_zstd_archives(out_dir: &Path) -> Result<usize> {
+    let old = |p: &Path| {
+        p.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+            let n = n.strip_suffix(&format!(".{}", ENCRYPTED_EXT)).unwrap_or(n);
+            n.ends_with(".jsonl.zstd")
+        })
+    };
+    let mut renamed = 0;
+    for d in top_dirs(out_dir)? {
+        for path in files_in(&d, old)? {
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let dst = path.with_file_name(name.replacen(".jsonl.zstd", ".jsonl.zst", 1));
+            move_archive(&path, &dst)?;
+            info!("Renamed {} to {}", path.display(), dst.display());
+            renamed += 1;
+        }
+    }
+    Ok(renamed)
+}
+
+/// Move a finalized archive and its sidecars to `dst`, sidecars first so an interrupted
+/// move is finished by the next one; the torrent is removed and built again with the
+/// new url
+fn move_archive(path: &Path, dst: &Path) -> Result<()> {
+    if dst.exists() {
+        bail!("{} already exists", dst.display());
+    }
+    let Some(dir) = dst.parent() else {
+        bail!("Invalid destination {}", dst.display());
+    };
+    std::fs::create_dir_all(dir)?;
+    let sidecars = |p: &Path| {
+        [
+            sidecar_path(p, "attestation"),
+            summary_path(p),
+            rollup_path(p),
+            bloom_path(p),
+            frames_path(p),
+        ]
+    };
+    for (from, to) in sidecars(path).into_iter().zip(sidecars(dst)) {
+        if from.exists() {
+            std::fs::rename(&from, &to)?;
+        }
+    }
+    // the checksum names the file for `sha256sum -c`
+    let (checksum, name) = (sidecar_path(path, "sha256"), dst.file_name());
+    if let (Ok(s), Some(name)) = (std::fs::read_to_string(&checksum), name) {
+        let hash = s.split_whitespace().next().unwrap_or_default();
+        std::fs::write(
+            sidecar_path(dst, "sha256"),
+            format!("{}  {}\n", hash, name.to_string_lossy()),
+        )?;
+        std::fs::remove_file(&checksum)?;
+    }
+    let torrent = TorrentMaker::path_for(path);
+    if torrent.exists() {
+        std::fs::remove_file(&torrent)?;
+    }
+    std::fs::rename(path, dst)?;
+    Ok(())
+}
+
+/// Periodically move finalized archives into their `YYYY/MM` directory, once they are
+/// rewritten in their final format
+pub async fn run_layout(db: ArchiveDatabase) -> Result<()> {
+    let layout = db.layout();
+    loop {
+        match db.list_archives().await {
+            Ok(files) => {
+                let mut moved = 0;
+                for f in files.iter() {
+                    if !layout.awaits_move(&f.path) || db.awaits_rewrite(&f.path).await {
+                        continue;
+                    }
+                    let (src, dst) = (f.path.clone(), layout.place(&f.path));
+                    let to = dst.clone();
+                    match tokio::task::spawn_blocking(move || move_archive(&src, &to)).await? {
+                        Ok(()) => {
+                            info!("Moved {} to {}", f.path.display(), db.archive_name(&dst));
+                            moved += 1;
+                        }
+                        Err(e) => error!("Failed to move {}: {}", f.path.display(), e),
+                    }
+                }
+                if moved > 0 {
+                    db.invalidate_archives().await;
+                }
+            }
+            Err(e) => error!("Failed to list archives: {}", e),
+        }
+        tokio::time::sleep(LAYOUT_INTERVAL).await;
+    }
+}
+
+/// Move the finalized archives of `out_dir` and its partition directories to where
+/// `layout` and `file_name_template` place them; returns the number moved and the number
+/// that failed
+pub fn migrate(out_dir: &Path, layout: Layout) -> Result<(usize, usize)> {
+    let (mut moved, mut failed) = (0, 0);
+    for d in top_dirs(out_dir)? {
+        for entry in std::fs::read_dir(&d)? {
+            let path = entry?.path();
+            if !path.is_file() || !layout.awaits_move(&path) {
+                continue;
+            }
+            let dst = layout.place(&path);
+            let rel = |p: &Path| p.strip_prefix(out_dir).unwrap_or(p).display().to_string();
+            match move_archive(&path, &dst) {
+                Ok(()) => {
+                    println!("{} -> {}", rel(&path), rel(&dst));
+                    moved += 1;
+                }
+                Err(e) => {
+                    println!("{} failed: {}", rel(&path), e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    Ok((moved, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn zstd_archives_are_renamed_with_their_sidecars() {
+        let dir = TempDir::new();
+        let part = dir.path().join("kind-1");
+        std::fs::create_dir(&part).unwrap();
+        let old = dir.path().join("events_20250101.jsonl.zstd");
+        std::fs::write(&old, b"data").unwrap();
+        std::fs::write(
+            sidecar_path(&old, "sha256"),
+            "abc  events_20250101.jsonl.zstd\n",
+        )
+        .unwrap();
+        std::fs::write(part.join("events_20250102.jsonl.zstd.enc"), b"data").unwrap();
+
+        assert_eq!(rename_zstd_archives(dir.path()).unwrap(), 2);
+        let new = dir.path().join("events_20250101.jsonl.zst");
+        assert!(!old.exists() && new.exists());
+        assert_eq!(
+            std::fs::read_to_string(sidecar_path(&new, "sha256")).unwrap(),
+            "abc  events_20250101.jsonl.zst\n"
+        );
+        assert!(part.join("events_20250102.jsonl.zst.enc").exists());
+        assert_eq!(rename_zstd_archives(dir.path()).unwrap(), 0);
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::{Result, bail};
+use dashmap::DashMap;
+use nostr_relay_builder::RelayBuilder;
+use nostr_relay_builder::builder::RateLimit;
+use nostr_sdk::nips::nip11::Limitation;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Clone, Default)]
+pub struct DownloadSettings {
+    /// Archive downloads allowed per IP per minute
+    pub requests_per_minute: Option<u32>,
+
+    /// Concurrent downloads allowed per IP
+    pub max_streams: Option<u32>,
+
+    /// Bandwidth of a single download (kbit/s), 0 is unlimited
+    pub per_conn_kbps: Option<u64>,
+
+    /// Bandwidth shared by all downloads (Mbit/s), 0 is unlimited
+    pub global_mbps: Option<u64>,
+
+    /// Count downloads per archive, shown in /api/files and on the landing page
+    pub count: Option<bool>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct RelayLimitSettings {
+    /// Subscriptions open at once per connection, default 20
+    pub max_reqs: Option<usize>,
+
+    /// Events accepted per connection per minute, default 100000
+    pub notes_per_minute: Option<u32>,
+
+    /// Events accepted per minute from a `trusted_peers` connection, default 10000000
+    pub peer_notes_per_minute: Option<u32>,
+
+    /// Longest subscription id, default 250
+    pub max_subid_length: Option<usize>,
+
+    /// Filter limits above this are lowered to it, default unlimited
+    pub max_filter_limit: Option<usize>,
+
+    /// Limit of filters without one, default 500
+    pub default_filter_limit: Option<usize>,
+}
+
+impl RelayLimitSettings {
+    /// Reject zero values, which would make the relay refuse every request
+    pub fn validate(&self) -> Result<()> {
+        for (name, v) in [
+            ("max_reqs", self.max_reqs),
+            (
+                "notes_per_minute",
+                self.notes_per_minute.map(|n| n as usize),
+            ),
+            (
+                "peer_notes_per_minute",
+                self.peer_notes_per_minute.map(|n| n as usize),
+            ),
+            ("max_subid_length", self.max_subid_length),
+            ("max_filter_limit", self.max_filter_limit),
+            ("default_filter_limit", self.default_filter_limit),
+        ] {
+            if v == Some(0) {
+                bail!("limits.{} must be above 0", name);
+            }
+        }
+        if let (Some(max), Some(default)) = (self.max_filter_limit, self.default_filter_limit)
+            && default > max
+        {
+            bail!("limits.default_filter_limit is above limits.max_filter_limit");
+        }
+        Ok(())
+    }
+
+    fn max_reqs(&self) -> usize {
+        self.max_reqs.unwrap_or(20)
+    }
+
+    fn max_subid_length(&self) -> usize {
+        self.max_subid_length.unwrap_or(250)
+    }
+
+    pub fn notes_per_minute(&self) -> u32 {
+        self.notes_per_minute.unwrap_or(100_000)
+    }
+
+    /// Enforce the limits on the relay, with `peers` the relay allows the peer rate and
+    /// public connections are limited by the write policy
+    pub fn apply(&self, builder: RelayBuilder, peers: bool) -> RelayBuilder {
+        let notes_per_minute = if peers {
+            self.peer_notes_per_minute
+                .unwrap_or(10_000_000)
+                .max(self.notes_per_minute())
+        } else {
+            self.notes_per_minute()
+        };
+        let mut builder = builder
+            .rate_limit(RateLimit {
+                max_reqs: self.max_reqs(),
+                notes_per_minute,
+            })
+            .max_subid_length(self.max_subid_length())
+            .default_filter_limit(self.default_filter_limit.unwrap_or(500));
+        if let Some(max) = self.max_filter_limit {
+            builder = builder.max_filter_limit(max);
+        }
+        builder
+    }
+
+    /// NIP-11 `limitation` advertising the limits [Self::apply] enforces
+    pub fn limitation(&self) -> Limitation {
+        let clamp = |v: usize| i32::try_from(v).unwrap_or(i32::MAX);
+        Limitation {
+            max_subscriptions: Some(clamp(self.max_reqs())),
+            max_limit: self.max_filter_limit.map(clamp),
+            max_subid_length: Some(clamp(self.max_subid_length())),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ConnectionSettings {
+    /// Open websocket connections allowed per IP, default unlimited
+    pub max_per_ip: Option<u32>,
+
+    /// Open websocket connections allowed in total, default unlimited
+    pub max_total: Option<u64>,
+}
+
+/// Open websocket connections per client IP and in total, 0 caps are unlimited
+#[derive(Clone, Default)]
+pub struct ConnectionLimit {
+    max_per_ip: u32,
+    max_total: u64,
+    per_ip: Arc<DashMap<IpAddr, u32>>,
+    total: Arc<AtomicU64>,
+}
+
+/// Open connection, released when dropped with the task serving it, however it ended
+pub struct ConnectionGuard {
+    ip: IpAddr,
+    per_ip: Arc<DashMap<IpAddr, u32>>,
+    total: Arc<AtomicU64>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.total.fetch_sub(1, Ordering::Relaxed);
+        self.per_ip.remove_if_mut(&self.ip, |_, n| {
+            *n = n.saturating_sub(1);
+            *n == 0
+        });
+    }
+}
+
+impl ConnectionLimit {
+    pub fn new(settings: &ConnectionSettings) -> Self {
+        Self {
+            max_per_ip: settings.max_per_ip.unwrap_or(0),
+            max_total: settings.max_total.unwrap_or(0),
+            ..Default::default()
+        }
+    }
+
+    /// Open connections
+    pub fn active(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Count a new connection from `ip`, None when a cap is reached
+    pub fn acquire(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut n = self.per_ip.entry(ip).or_insert(0);
+        let admitted = (self.max_per_ip == 0 || *n < self.max_per_ip)
+            && self
+                .total
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |t| {
+                    (self.max_total == 0 || t < self.max_total).then_some(t + 1)
+                })
+                .is_ok();
+        if !admitted {
+            drop(n);
+            self.per_ip.remove_if(&ip, |_, n| *n == 0);
+            return None;
+        }
+        *n += 1;
+        Some(ConnectionGuard {
+            ip,
+            per_ip: self.per_ip.clone(),
+            total: self.total.clone(),
+        })
+    }
+}
+
+/// Fixed window request counter keyed by client IP
+#[derive(Clone)]
+pub struct IpRateLimit {
+    max: u32,
+    window: Duration,
+    counters: Arc<DashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl IpRateLimit {
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            counters: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Count a request from `ip`, returns false if it is over the limit
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut entry = self.counters.entry(ip).or_insert((now, 0));
+        if now.duration_since(entry.0) > self.window {
+            *entry = (now, 0);
+        }
+        if entry.1 >= self.max {
+            false
+        } else {
+            entry.1 += 1;
+            true
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    streams: u32,
+}
+
+/// Per IP token bucket for downloads plus a cap on concurrent streams
+#[derive(Clone)]
+pub struct DownloadLimit {
+    per_minute: u32,
+    max_streams: u32,
+    buckets: Arc<DashMap<IpAddr, Bucket>>,
+}
+
+/// Active download, releases the stream slot when dropped
+pub struct StreamGuard {
+    ip: IpAddr,
+    buckets: Arc<DashMap<IpAddr, Bucket>>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Some(mut b) = self.buckets.get_mut(&self.ip) {
+            b.streams = b.streams.saturating_sub(1);
+        }
+    }
+}
+
+impl DownloadLimit {
+    pub fn new(per_minute: u32, max_streams: u32) -> Self {
+        let ret = Self {
+            per_minute,
+            max_streams,
+            buckets: Arc::new(DashMap::new()),
+        };
+        // drop idle entries, a full bucket with no streams is the same as no entry
+        let buckets = Arc::downgrade(&ret.buckets);
+        let refill = Duration::from_secs(60);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(refill).await;
+                let Some(b) = buckets.upgrade() else {
+                    break;
+                };
+                b.retain(|_, v| v.streams > 0 || v.updated.elapsed() < refill);
+            }
+        });
+        ret
+    }
+
+    /// Take a token and a stream slot for `ip`, or the time to wait before retrying
+    pub fn acquire(&self, ip: IpAddr) -> Result<StreamGuard, Duration> {
+        let now = Instant::now();
+        let max = self.per_minute as f64;
+        let mut b = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: max,
+            updated: now,
+            streams: 0,
+        });
+        b.tokens = (b.tokens + now.duration_since(b.updated).as_secs_f64() * max / 60.0).min(max);
+        b.updated = now;
+        if b.streams >= self.max_streams {
+            return Err(Duration::from_secs(1));
+        }
+        if b.tokens < 1.0 {
+            return Err(Duration::from_secs_f64(
+                (1.0 - b.tokens) * 60.0 / max.max(1.0),
+            ));
+        }
+        b.tokens -= 1.0;
+        b.streams += 1;
+        Ok(StreamGuard {
+            ip,
+            buckets: self.buckets.clone(),
+        })
+    }
+}
@@ -0,0 +1,95 @@
+use anyhow::{Result, anyhow, bail};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+
+/// Address reported for unix socket peers, the reverse proxy in front should
+/// be configured as a trusted proxy to forward the real client address
+pub const UNIX_PEER: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Bind `ip:port`, `[ipv6]:port` or `unix:/path/to.sock`
+    ///
+    /// IPv6 sockets are bound v6 only so `0.0.0.0` and `[::]` can be used together,
+    /// an existing unix socket file is replaced
+    pub fn bind(spec: &str, unix_mode: Option<u32>) -> Result<Self> {
+        if let Some(path) = spec.strip_prefix("unix:") {
+            let path = PathBuf::from(path);
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            let listener = UnixListener::bind(&path)?;
+            if let Some(mode) = unix_mode {
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+            }
+            return Ok(Listener::Unix(listener, path));
+        }
+
+        let addr: SocketAddr = spec
+            .parse()
+            .map_err(|e| anyhow!("Invalid listen address {}: {}", spec, e))?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+    }
+
+    /// Check a listen address without binding it, the directory of a unix socket must exist
+    pub fn check(spec: &str) -> Result<()> {
+        if let Some(path) = spec.strip_prefix("unix:") {
+            let dir = Path::new(path)
+                .parent()
+                .filter(|d| !d.as_os_str().is_empty());
+            if dir.is_some_and(|d| !d.is_dir()) {
+                bail!("Invalid listen address {}: directory doesn't exist", spec);
+            }
+            return Ok(());
+        }
+        spec.parse::<SocketAddr>()
+            .map_err(|e| anyhow!("Invalid listen address {}: {}", spec, e))?;
+        Ok(())
+    }
+
+    pub async fn accept(&self) -> std::io::Result<(Box<dyn Io>, SocketAddr)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (s, addr) = l.accept().await?;
+                Ok((Box::new(s), addr))
+            }
+            Listener::Unix(l, _) => {
+                let (s, _) = l.accept().await?;
+                Ok((Box::new(s), UNIX_PEER))
+            }
+        }
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(l) => match l.local_addr() {
+                Ok(a) => write!(f, "{}", a),
+                Err(_) => write!(f, "tcp"),
+            },
+            Listener::Unix(_, p) => write!(f, "unix:{}", p.display()),
+        }
+    }
+}
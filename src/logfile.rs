@@ -0,0 +1,75 @@
+use log::{error, warn};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{Receiver, Sender, channel};
+
+/// Max queued lines before new ones are dropped
+const QUEUE_SIZE: usize = 10_000;
+
+/// Append only JSON lines file, written from a background task
+#[derive(Clone, Debug)]
+pub struct JsonLog {
+    name: &'static str,
+    tx: Sender<String>,
+}
+
+impl JsonLog {
+    /// Start the writer task, the log is rotated to `<path>.1` once it reaches `max_size` bytes
+    pub fn spawn(name: &'static str, path: PathBuf, max_size: u64) -> Self {
+        let (tx, rx) = channel(QUEUE_SIZE);
+        tokio::spawn(async move {
+            if let Err(e) = write_loop(rx, path, max_size).await {
+                error!("{} writer failed: {}", name, e);
+            }
+        });
+        Self { name, tx }
+    }
+
+    /// Queue an entry, never waits on the writer
+    pub fn write<T: Serialize>(&self, entry: &T) {
+        let line = serde_json::to_string(entry).unwrap();
+        if self.tx.try_send(line).is_err() {
+            warn!("{} queue full, dropping entry", self.name);
+        }
+    }
+}
+
+async fn open(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+async fn write_loop(mut rx: Receiver<String>, path: PathBuf, max_size: u64) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let rotated = PathBuf::from(format!("{}.1", path.display()));
+    let mut size = tokio::fs::metadata(&path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+    let mut out = BufWriter::new(open(&path).await?);
+    while let Some(line) = rx.recv().await {
+        let mut next = Some(line);
+        while let Some(line) = next {
+            out.write_all(line.as_bytes()).await?;
+            out.write_all(b"\n").await?;
+            size += line.len() as u64 + 1;
+            next = rx.try_recv().ok();
+        }
+        out.flush().await?;
+
+        if size >= max_size {
+            drop(out);
+            tokio::fs::rename(&path, &rotated).await?;
+            out = BufWriter::new(open(&path).await?);
+            size = 0;
+        }
+    }
+    Ok(())
+}
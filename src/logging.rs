@@ -0,0 +1,173 @@
+use anyhow::Result;
+use env_logger::{Builder, Target};
+use log::kv::{Error, Key, Value, VisitSource};
+use serde::Deserialize;
+use serde_json::{Map, json};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, key-values of the record as fields
+    Json,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct LogSettings {
+    /// Line format, default text
+    pub format: Option<LogFormat>,
+
+    /// Levels per target, eg. `info,nostrhole::db=debug,nostr_relay_builder=warn`,
+    /// RUST_LOG replaces it when set. nostr-sdk and the relay builder log through
+    /// `tracing`, their events arrive as records of their module path
+    pub filter: Option<String>,
+
+    /// Also write lines to this file, rotated to `<file>.1` at `file_max_mb`
+    pub file: Option<PathBuf>,
+
+    /// Size of the log file before it is rotated (MB), default 64
+    pub file_max_mb: Option<u64>,
+}
+
+/// Log file rotated once it grows past `max_size`
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    size: u64,
+    file: File,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            size: file.metadata()?.len(),
+            file,
+        })
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        std::fs::rename(&self.path, format!("{}.1", self.path.display()))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Writes each record to stderr and the log file, a failing file never drops the stderr line
+struct Tee(RotatingFile);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        std::io::stderr().write_all(buf)?;
+        let f = &mut self.0;
+        // a record is written in one call, files are rotated between records
+        if f.size >= f.max_size && f.rotate().is_err() {
+            return Ok(buf.len());
+        }
+        if f.file.write_all(buf).is_ok() {
+            f.size += buf.len() as u64;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()?;
+        self.0.file.flush()
+    }
+}
+
+/// Collects the key-values of a record as JSON fields
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        self.0.insert(key.to_string(), value.to_string().into());
+        Ok(())
+    }
+}
+
+/// Install the global logger, call once before anything logs
+pub fn init(settings: &LogSettings) -> Result<()> {
+    let mut builder = match std::env::var("RUST_LOG") {
+        Ok(_) => Builder::from_default_env(),
+        Err(_) => {
+            let mut b = Builder::new();
+            if let Some(f) = &settings.filter {
+                b.parse_filters(f);
+            }
+            b
+        }
+    };
+    if settings.format.unwrap_or_default() == LogFormat::Json {
+        builder.format(|buf, record| {
+            let mut line = Map::new();
+            line.insert("ts".to_string(), buf.timestamp().to_string().into());
+            line.insert("level".to_string(), record.level().as_str().into());
+            line.insert("target".to_string(), record.target().into());
+            line.insert("msg".to_string(), record.args().to_string().into());
+            let _ = record.key_values().visit(&mut Fields(&mut line));
+            writeln!(buf, "{}", json!(line))
+        });
+    }
+    if let Some(path) = &settings.file {
+        let file = RotatingFile::open(path, settings.file_max_mb.unwrap_or(64) * 1024 * 1024)?;
+        builder.target(Target::Pipe(Box::new(Tee(file))));
+    }
+    builder.try_init()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::sync::Mutex;
+
+    /// Targets and messages of the records logged
+    static RECORDS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+    struct Capture;
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Info
+        }
+
+        fn log(&self, record: &Record) {
+            RECORDS
+                .lock()
+                .unwrap()
+                .push((record.target().to_string(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn tracing_events_reach_the_logger() {
+        log::set_logger(&Capture).unwrap();
+        log::set_max_level(LevelFilter::Info);
+        // what nostr-sdk and the relay builder log with
+        tracing::warn!(target: "nostr_relay_builder::local", "relay message");
+        tracing::debug!(target: "nostr_relay_builder::local", "filtered out");
+
+        let records = RECORDS.lock().unwrap();
+        assert!(records.contains(&(
+            "nostr_relay_builder::local".to_string(),
+            "relay message".to_string()
+        )));
+        assert!(!records.iter().any(|(_, m)| m == "filtered out"));
+    }
+}
@@ -1,149 +1,1764 @@
+use crate::access::AccessLog;
+use crate::activity::ActivityStats;
+use crate::announce::{AnnounceSettings, SelfDescription, relay_url, run_announce};
+use crate::attest::{run_attest, verify};
+use crate::audit::AuditLog;
+use crate::auth::{DownloadAuth, DownloadAuthSettings};
+use crate::authors::AuthorIndex;
+use crate::bloom::{BloomFilters, BloomSettings, run_blooms};
+use crate::changes::{ArchiveChanges, run_changes};
+use crate::compression::{
+    CompressionFormat, CompressionSettings, Recompress, compress_leftovers, recover_compression,
+    run_recompress,
+};
+use crate::conn::{HttpSettings, IdleIo};
+use crate::db::ArchiveDatabase;
+use crate::deadletter::{DeadLetterSettings, DeadLetters};
+use crate::deletions::{Deletions, Tombstones};
+use crate::discover::{DiscoverSettings, run_discover};
+use crate::disk::{DiskGuard, DiskGuardSettings, run_disk_guard};
+use crate::doctor::{Consistency, doctor, run_consistency};
+use crate::downloads::DownloadCounts;
+use crate::durability::{Durability, Fsync};
+use crate::encrypt::{Encryption, EncryptionSettings, run_encrypt};
+use crate::export::{parse_day, select_archives};
+use crate::firehose::FirehoseSettings;
+use crate::graph::GraphFormat;
 use crate::http::HttpServer;
-use crate::policy::{EphemeralPolicy, KindPolicy, NoQuery};
-use anyhow::Result;
-use clap::Parser;
+use crate::import::{ImportFormat, ImportSettings, WriteMode, checkpoint_path};
+use crate::index::{IndexBackend, IndexSettings};
+use crate::ingest::{IngestHealth, IngestSettings, run_ingest, run_noisy_relays, with_tags};
+use crate::ingest_jobs::{IngestJobSettings, JobDefaults, run_ingest_jobs};
+use crate::jobs::ArchiveJobs;
+use crate::kinds::{KindEntry, KindSet};
+use crate::landing::{LandingPage, LandingPageSettings};
+use crate::layout::{Layout, run_layout};
+use crate::limit::{
+    ConnectionLimit, ConnectionSettings, DownloadLimit, DownloadSettings, IpRateLimit,
+    RelayLimitSettings,
+};
+use crate::listen::Listener;
+use crate::logging::LogSettings;
+use crate::mirror::{MirrorSettings, REINDEX_MARKER, run_mirror};
+use crate::naming::FileNameTemplate;
+use crate::offsets::EventOffsets;
+use crate::partition::{PartitionSettings, Partitions};
+use crate::peers::{PeerPolicy, TrustedPeers};
+use crate::policy::{
+    AuthorAllowPolicy, LiveEphemeralPolicy, NoQuery, PolicyChain, PolicyConfig, QueryMode,
+    QueryPolicySettings, QueryWindowPolicy, ReadOnlyPolicy, SensitiveKindPolicy, StorageFullPolicy,
+    ingest_tags,
+};
+use crate::protected::ProtectedAdmit;
+use crate::proxy::TrustedProxies;
+use crate::publish::Publisher;
+use crate::published::{PublishedView, PublishedViewSettings, run_published};
+use crate::replaceable::ReplaceableIndex;
+use crate::rocks::RocksdbSettings;
+use crate::rollup::run_rollups;
+use crate::sanity::{EventLimitSettings, EventLimits};
+use crate::scope::{AuthorScope, IngestScopeSettings, ScopedAdmit, run_scope};
+use crate::seekable::{Seekable, SeekableSettings, run_seekable};
+use crate::seen::EventSeen;
+use crate::sensitive::SensitiveKinds;
+use crate::sketch::run_sketch;
+use crate::slice::SliceSettings;
+use crate::sources::{EventSources, SourceAdmit};
+use crate::stats::RelayStats;
+use crate::status::LiveStatus;
+use crate::summary::run_summaries;
+use crate::sync::{NegentropySettings, SyncSettings, run_sync};
+use crate::throttle::DownloadThrottle;
+use crate::times::TimeIndex;
+use crate::tls::{ReloadableTls, TlsSettings};
+use crate::torrent::{TorrentMaker, TorrentSettings, run_torrents};
+use crate::upstream::{Upstream, UpstreamSettings};
+use crate::wot::{WotPolicy, WotSet, WotSettings, run_wot};
+use crate::writer::{WriterOptions, WriterSettings};
+use anyhow::{Result, bail};
+use chrono::{NaiveDate, NaiveTime};
+use clap::{Parser, Subcommand};
 use config::Config;
 use hyper::server::conn::http1;
-use hyper_util::rt::TokioIo;
-use log::{error, info};
-use nostr_archive_cursor::JsonFilesDatabase;
-use nostr_relay_builder::builder::RateLimit;
-use nostr_relay_builder::prelude::Kind;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use log::{debug, error, info, warn};
 use nostr_relay_builder::{LocalRelay, RelayBuilder};
-use nostr_sdk::prelude::NostrDatabase;
-use nostr_sdk::{Client, Filter, RelayPoolNotification};
+use nostr_sdk::nips::nip11::RelayInformationDocument;
+use nostr_sdk::{Filter, Keys, PublicKey, Timestamp};
 use serde::Deserialize;
-use std::net::SocketAddr;
 use std::path::PathBuf;
-use tokio::net::TcpListener;
-use tokio::sync::broadcast::error::RecvError;
-use tokio::task::JoinHandle;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
 
+mod access;
+mod activity;
+mod announce;
+mod attest;
+mod audit;
+mod auth;
+mod author_export;
+mod authors;
+mod bloom;
+mod changes;
+mod check;
+mod compression;
+mod conn;
+mod content_dedup;
+mod db;
+mod deadletter;
+mod deletions;
+mod discover;
+mod disk;
+mod doctor;
+mod downloads;
+mod durability;
+mod encoding;
+mod encrypt;
+mod export;
+mod fetch;
+mod firehose;
+mod graph;
 mod http;
+mod import;
+mod index;
+mod ingest;
+mod ingest_jobs;
+mod jobs;
+mod kinds;
+mod landing;
+mod layout;
+mod limit;
+mod listen;
+mod logfile;
+mod logging;
+mod merge;
+mod metrics;
+mod mirror;
+mod naming;
+mod offsets;
+mod parquet_export;
+mod partition;
+mod peers;
 mod policy;
+mod protected;
+mod proxy;
+mod prune;
+mod publish;
+mod published;
+mod replaceable;
+mod rocks;
+mod rollup;
+mod sanity;
+mod scan;
+mod scope;
+mod seekable;
+mod seen;
+mod sensitive;
+mod sketch;
+mod slice;
+mod sources;
+mod sqlite;
+mod stats;
+mod status;
+mod store;
+mod summary;
+mod sync;
+#[cfg(test)]
+mod test_util;
+mod throttle;
+mod times;
+mod tls;
+mod torrent;
+mod upstream;
+mod wot;
+mod writer;
 
 #[derive(Parser)]
 #[command(version, about)]
 struct Args {
     /// Define path for config file
     pub config: Option<PathBuf>,
+
+    /// File holding a secret key (nsec or hex) to read encrypted archives with
+    #[arg(long, global = true)]
+    pub identity: Option<PathBuf>,
+
+    /// Validate the config, print the effective settings and exit, same as `check`
+    #[arg(long)]
+    pub config_check: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the config without opening the archives or binding sockets, print the
+    /// effective settings and exit with 1 if anything is invalid
+    Check,
+    /// Import events from a jsonl dump (plain or compressed) into dated archives, skipping
+    /// events already archived; re-running resumes from the checkpoint
+    Import {
+        /// File to import
+        input: PathBuf,
+
+        #[arg(long, value_enum, default_value_t)]
+        format: ImportFormat,
+
+        /// Progress file, defaults to `<input>.checkpoint`
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+    },
+    /// Copy the events another data directory has and this one doesn't
+    Merge {
+        /// Data directory to read, it isn't modified
+        #[arg(long)]
+        from: PathBuf,
+
+        /// Data directory to merge into, defaults to `out_dir`
+        #[arg(long)]
+        into: Option<PathBuf>,
+
+        /// Only report how many new events each source archive has
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Check local archives against attestations published by the relay key
+    Attest {
+        /// Re-hash local archives and compare them with the fetched attestations
+        #[arg(long, required = true)]
+        verify: bool,
+
+        /// Attestation author, defaults to the public key of `relay_secret_key`
+        #[arg(long)]
+        pubkey: Option<String>,
+    },
+    /// Rebuild the event index from the archives and exit, exits with 1 if lines were skipped
+    Index {
+        /// Re-read every archive, malformed lines are copied to `quarantine/`
+        #[arg(long, required = true)]
+        rebuild: bool,
+    },
+    /// Delete index entries of events created before a day, eg. after deleting old archives
+    PruneIndex {
+        /// First day to keep, YYYY-MM-DD
+        #[arg(long, value_parser = parse_day)]
+        before: NaiveDate,
+    },
+    /// Copy the event id indexes to another backend and check the copies hold every entry,
+    /// then set `index.backend` to it; run while nostrhole is stopped
+    MigrateIndex {
+        #[arg(long, value_enum)]
+        to: IndexBackend,
+    },
+    /// Measure event id lookups of each index backend on synthetic ids
+    BenchIndex {
+        /// Ids inserted before the lookups
+        #[arg(long, default_value_t = 10_000_000)]
+        ids: u64,
+
+        /// Directory for the indexes, it is removed afterwards
+        #[arg(long)]
+        dir: PathBuf,
+    },
+    /// Load archives into a SQLite database with `events` and `tags` tables
+    ExportSqlite {
+        /// First day to export, YYYY-MM-DD
+        #[arg(long, value_parser = parse_day)]
+        from: Option<NaiveDate>,
+
+        /// Last day to export, YYYY-MM-DD
+        #[arg(long, value_parser = parse_day)]
+        to: Option<NaiveDate>,
+
+        /// SQLite database to write
+        #[arg(long)]
+        out: PathBuf,
+
+        /// Add to an existing database, events already in it are skipped
+        #[arg(long)]
+        append: bool,
+
+        /// Leave out events deleted by their author, needs `track_deletions`
+        #[arg(long)]
+        apply_deletions: bool,
+    },
+    /// Write archives as zstd compressed Parquet files, `events_YYYYMMDD.parquet` per archive
+    ExportParquet {
+        /// First day to export, YYYY-MM-DD
+        #[arg(long, value_parser = parse_day)]
+        from: Option<NaiveDate>,
+
+        /// Last day to export, YYYY-MM-DD
+        #[arg(long, value_parser = parse_day)]
+        to: Option<NaiveDate>,
+
+        /// Directory to write to, partitioned archives keep their subdirectory
+        #[arg(long)]
+        out: PathBuf,
+
+        /// Split archives into `events_YYYYMMDD.0001.parquet`, .. of this many million rows
+        #[arg(long, value_name = "MILLIONS")]
+        rows_per_file: Option<u64>,
+
+        /// Leave out events deleted by their author, needs `track_deletions`
+        #[arg(long)]
+        apply_deletions: bool,
+    },
+    /// Write the newest contact list (kind 3) and relay list (kind 10002) of every pubkey,
+    /// using the replaceable index with `track_replaceable`; run while nostrhole is stopped
+    ExportGraph {
+        /// File to write
+        #[arg(long)]
+        out: PathBuf,
+
+        #[arg(long, value_enum, default_value_t)]
+        format: GraphFormat,
+    },
+    /// Write `events_YYYYMMDD.bloom` filters for finalized archives that have none
+    Bloom {
+        /// Build filters for every finalized archive missing an up to date one
+        #[arg(long, required = true)]
+        rebuild: bool,
+    },
+    /// Write `events_YYYYMMDD.stats.json` summaries for archives that have none, exits with 1
+    /// if lines were skipped
+    Stats {
+        /// Summarize every finalized archive missing an up to date summary
+        #[arg(long, required_unless_present = "rollup")]
+        rebuild: bool,
+
+        /// Write `events_YYYYMMDD.zaps.json` reaction and zap rollups for finalized archives
+        /// missing an up to date one
+        #[arg(long)]
+        rollup: bool,
+    },
+    /// Wrap the file keys of encrypted archives for the current `encryption.recipients`,
+    /// needs `--identity`; only headers are rewritten, the event data isn't re-encrypted
+    Rekey,
+    /// Move finalized archives into `YYYY/MM` directories for `layout: nested` and rename
+    /// them by `file_name_template`, with their checksums, summaries and bloom filters; run
+    /// while nostrhole is stopped
+    MigrateLayout,
+    /// Save the events in `quarantine/deadletter.jsonl` again, keeping the ones that fail;
+    /// run while nostrhole is stopped
+    RetryDeadletter,
+    /// Re-read every archive and compare it with its summary, checksum and the event index,
+    /// printing what to run to fix it; exits with 1 if anything needs attention
+    Doctor {
+        /// Also check the archives against the attestations published by the relay key
+        #[arg(long)]
+        attestations: bool,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum RunMode {
+    /// Ingest, archive and serve
+    #[default]
+    Full,
+    /// Serve the existing archives read-only, nothing is ingested
+    Serve,
+    /// Ingest and archive without binding `listen`
+    Archive,
 }
 
 #[derive(Deserialize)]
 struct Settings {
-    /// Listen address for relay ip:port
+    /// full (default), serve (read-only, no ingestion) or archive (no listeners)
+    pub mode: Option<RunMode>,
+
+    /// Listen address for relay ip:port, replaced by `listen`
     pub listen_relay: Option<String>,
 
+    /// Listen addresses, ip:port, [ipv6]:port or unix:/path/to.sock
+    pub listen: Option<Vec<String>>,
+
+    /// Extra listen addresses answering only `/api/*`, the only listeners in `archive` mode
+    pub metrics_listen: Option<Vec<String>>,
+
+    /// Permissions of unix sockets (octal)
+    pub unix_socket_mode: Option<String>,
+
     /// Nostr relays to ingest events from
     pub relays: Option<Vec<String>>,
 
-    /// Nostr kinds to accept
-    pub kinds: Option<Vec<u32>>,
+    /// Proxy and timeouts for connections to `relays`
+    #[serde(flatten)]
+    pub upstream: UpstreamSettings,
+
+    /// Nostr kinds to accept, single kinds, ranges "30000-39999" or "all"
+    pub kinds: Option<Vec<KindEntry>>,
+
+    /// Kinds treated like the private message kinds 4, 13, 14, 15, 1059 and 1060, which
+    /// are never archived unless `allow_sensitive_kinds` is set
+    pub sensitive_kinds: Option<Vec<KindEntry>>,
+
+    /// Archive the sensitive kinds; without download_auth they are still left out of
+    /// downloads, `/slice`, `/e/` and `/events/stream`
+    pub allow_sensitive_kinds: Option<bool>,
 
     /// Path to save data
     pub out_dir: Option<PathBuf>,
+
+    /// Write archives into a subdirectory per kind or per named kind range
+    pub partition_by_kind: Option<PartitionSettings>,
+
+    /// Only keep the newest version of replaceable / addressable events
+    pub track_replaceable: Option<bool>,
+
+    /// Keep archiving events after their NIP-40 expiration
+    pub keep_expired: Option<bool>,
+
+    /// Archive ephemeral events (kinds 20000-29999) from upstream relays, default true;
+    /// otherwise they are only sent to the firehose
+    pub archive_ephemeral: Option<bool>,
+
+    /// Archive NIP-70 protected events from upstream relays, default false
+    pub archive_protected: Option<bool>,
+
+    /// Format of finalized archives
+    pub compression: Option<CompressionSettings>,
+
+    /// Archive compressions, rewrites, conversions and summaries running at once, default 1
+    pub archive_jobs: Option<usize>,
+
+    /// Threads reading archives for index rebuilds, `stats`, `attest --verify` and exports,
+    /// default the number of CPUs
+    pub scan_threads: Option<usize>,
+
+    /// Rewrite finalized zstd archives as seekable frames
+    pub seekable: Option<SeekableSettings>,
+
+    /// Scan budget of `/slice` time range downloads
+    pub slice: Option<SliceSettings>,
+
+    /// Tag and content size limits, events over them are dropped or quarantined
+    pub event_limits: Option<EventLimitSettings>,
+
+    /// When the active archive is synced to disk: none (default), interval or every_event
+    pub durability: Option<Durability>,
+
+    /// Seconds between syncs with `durability: interval`, default 5
+    pub durability_interval_secs: Option<u64>,
+
+    /// Also sync after this many events with `durability: interval`
+    pub durability_interval_events: Option<u64>,
+
+    /// Buffering of the active archive
+    pub writer: Option<WriterSettings>,
+
+    /// Write the active archive through zstd to `events_YYYYMMDD.jsonl.zst`, no compression
+    /// at rotation
+    pub write_compressed: Option<bool>,
+
+    /// Pause ingestion before the out_dir filesystem fills
+    pub disk_guard: Option<DiskGuardSettings>,
+
+    /// Keep events that failed to save for a later retry
+    pub dead_letter: Option<DeadLetterSettings>,
+
+    /// Also write received deletions (kind 5) to `deletions/deletions_YYYYMMDD.jsonl`
+    pub track_deletions: Option<bool>,
+
+    /// Index where each event is written so it can be fetched at `/e/<id>`
+    pub event_lookup: Option<bool>,
+
+    /// Record which upstream relays delivered each event, shown at `/e/<id>`
+    pub track_sources: Option<bool>,
+
+    /// Record when archived events are delivered again and how often, shown at `/e/<id>`
+    /// and in /api/stats
+    pub track_seen: Option<bool>,
+
+    /// Journal finalized archive files as they are added, replaced or deleted, listed at
+    /// `/api/changes?since=` for incremental mirrors
+    pub track_changes: Option<bool>,
+
+    /// Index which archives hold the events of each pubkey, used by author exports and stats
+    pub index_authors: Option<bool>,
+
+    /// Bloom filters of the ids and pubkeys in finalized archives, to skip them in lookups
+    pub bloom: Option<BloomSettings>,
+
+    /// Keep hardlinks of the finalized, verified archives in `published/` under stable
+    /// names, for read-only NFS or SFTP exports
+    pub published_view: Option<PublishedViewSettings>,
+
+    /// Roll up the reactions and zaps of finalized archives, shown in /api/stats and on
+    /// the landing page
+    pub zap_rollups: Option<bool>,
+
+    /// Move an event index that fails to open aside and rebuild it instead of exiting
+    pub auto_recover_index: Option<bool>,
+
+    /// Store of the event id index
+    pub index: Option<IndexSettings>,
+
+    /// Recompute the id of every event delivered by upstream relays and reject mismatches,
+    /// default true. Relay writes are always checked by the relay builder
+    pub verify_ids: Option<bool>,
+
+    /// Memory of the rocksdb stores: the event id index and the lookup indexes
+    pub rocksdb: Option<RocksdbSettings>,
+
+    /// Let NostrDatabase::wipe clear the event id and lookup indexes, it fails otherwise
+    pub allow_wipe: Option<bool>,
+
+    /// Supervision of the ingest loop
+    pub ingest: Option<IngestSettings>,
+
+    /// Negentropy sync with upstream relays
+    pub sync: Option<SyncSettings>,
+
+    /// Negentropy reconciliation for websocket peers
+    pub negentropy: Option<NegentropySettings>,
+
+    /// Answer NIP-45 COUNT for the filters the indexes can count, on by default
+    pub count: Option<bool>,
+
+    /// Pull archive files from another hole instance
+    pub mirror: Option<MirrorSettings>,
+
+    /// Which REQ filters the relay answers
+    pub query_policy: Option<QueryPolicySettings>,
+
+    /// Write policies applied in order, defaults to ephemeral, expiration and kinds
+    pub policies: Option<Vec<PolicyConfig>>,
+
+    /// Log rejected events to this file
+    pub audit_log: Option<PathBuf>,
+
+    /// Rotate the audit log after this many MB
+    pub audit_log_max_mb: Option<u64>,
+
+    /// Relays (IP or NIP-42 authenticated pubkey) whose websocket writes skip the write
+    /// policies, for replication
+    pub trusted_peers: Option<Vec<String>>,
+
+    /// Reverse proxies (CIDR) whose X-Forwarded-For / X-Real-IP headers are trusted
+    pub trusted_proxies: Option<Vec<String>>,
+
+    /// Serve https / wss directly, certificate is reloaded on SIGHUP
+    pub tls: Option<TlsSettings>,
+
+    /// Request and bandwidth limits for archive downloads
+    pub download: Option<DownloadSettings>,
+
+    /// Subscription, filter and event rate limits of the relay, also advertised in NIP-11
+    pub limits: Option<RelayLimitSettings>,
+
+    /// Timeouts and header limits of http connections
+    pub http: Option<HttpSettings>,
+
+    /// Caps on open websocket connections
+    pub connections: Option<ConnectionSettings>,
+
+    /// Log format, levels and file
+    pub log: Option<LogSettings>,
+
+    /// Log HTTP requests to this file
+    pub access_log: Option<PathBuf>,
+
+    /// Rotate the access log after this many MB
+    pub access_log_max_mb: Option<u64>,
+
+    /// Require a bearer token to download archives
+    pub download_auth: Option<DownloadAuthSettings>,
+
+    /// Landing page template and branding
+    pub landing_page: Option<LandingPageSettings>,
+
+    /// Public base url of this instance, used for absolute links
+    pub public_url: Option<String>,
+
+    /// Stream newly saved events over Server-Sent Events
+    pub firehose: Option<FirehoseSettings>,
+
+    /// Generate torrents for finalized archives
+    pub torrent: Option<TorrentSettings>,
+
+    /// Only ingest events from the follows of a pubkey
+    pub ingest_scope: Option<IngestScopeSettings>,
+
+    /// Named ingest subscriptions with their own kinds, authors, tags and relays,
+    /// replacing the default subscription; reloaded on SIGHUP
+    pub ingest_jobs: Option<Vec<IngestJobSettings>>,
+
+    /// Only accept relay writes from the follow graph of a pubkey
+    pub wot: Option<WotSettings>,
+
+    /// Also ingest from relays discovered from a pubkey's contacts
+    pub discover_relays: Option<DiscoverSettings>,
+
+    /// Relay key (nsec or hex) used to sign daily archive attestations
+    pub relay_secret_key: Option<String>,
+
+    /// Publish NIP-66 discovery events describing this relay, signed by `relay_secret_key`
+    pub announce: Option<AnnounceSettings>,
+
+    /// Encrypt finalized archives at rest, they are read back with `--identity`
+    pub encryption: Option<EncryptionSettings>,
+    /// Keep finalized archives in out_dir (flat, default) or `YYYY/MM` directories (nested)
+    pub layout: Option<Layout>,
+
+    /// Name of finalized archives from `{date}`, `{instance}`, `{partition}` and `{part}`,
+    /// eg. `{instance}_events_{date}`; default is the writer's `events_{date}`
+    pub file_name_template: Option<String>,
+
+    /// `{instance}` of file_name_template
+    pub instance_name: Option<String>,
+
+    /// Only canonical, events from relays and clients are always serialized again;
+    /// verbatim is set with import.write_mode
+    pub write_mode: Option<WriteMode>,
+
+    /// Imports and merges
+    pub import: Option<ImportSettings>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
-
     let args = Args::parse();
 
+    let config_path = args.config.unwrap_or(PathBuf::from("config.yaml"));
     let config: Settings = Config::builder()
-        .add_source(config::File::from(
-            args.config.unwrap_or(PathBuf::from("config.yaml")),
-        ))
+        .add_source(config::File::from(config_path.clone()))
         .build()?
         .try_deserialize()?;
+    if args.config_check || matches!(args.command, Some(Command::Check)) {
+        config.validate()?;
+        println!("{} is valid", config_path.display());
+        print!("{}", config.summary());
+        return Ok(());
+    }
+    logging::init(&config.log.clone().unwrap_or_default())?;
+    config.validate()?;
+    if let Some(p) = &args.identity {
+        encrypt::set_identity(p)?;
+    }
+
+    let out_dir = config.out_dir();
+    let listen = config.listen_addrs();
+    let policies = config.effective_policies();
+    if let Some(t) = &config.file_name_template {
+        naming::set_template(FileNameTemplate::parse(
+            t,
+            config.instance_name.as_deref(),
+            &out_dir,
+        )?);
+    }
+    if let Some(n) = config.scan_threads {
+        jobs::set_scan_threads(n);
+    }
+    rocks::configure(config.rocksdb.clone().unwrap_or_default());
+    // the writer named `write_compressed` archives `.jsonl.zstd` before, unlike the
+    // `.jsonl.zst` archives compressed at rotation
+    if config.mode.unwrap_or_default() != RunMode::Serve && out_dir.exists() {
+        let dir = out_dir.clone();
+        tokio::task::spawn_blocking(move || layout::rename_zstd_archives(&dir)).await??;
+    }
+    let relay_keys = config
+        .relay_secret_key
+        .as_deref()
+        .map(Keys::parse)
+        .transpose()?;
+
+    let upstream = Upstream::new(&config.upstream)?;
+    if let Some(ua) = &config.upstream.user_agent {
+        fetch::set_user_agent(ua.clone());
+    }
+    let relays = config.relays.unwrap_or_default();
+    let limits = config.limits.clone().unwrap_or_default();
+    let peers = config
+        .trusted_peers
+        .as_deref()
+        .map(TrustedPeers::parse)
+        .transpose()?;
+
+    if let Some(Command::Rekey) = args.command {
+        let Some(e) = &config.encryption else {
+            bail!("Set encryption.recipients to rekey archives");
+        };
+        let (n, failed) = encrypt::rekey(&out_dir, e.recipients()?).await?;
+        println!("Rekeyed {} archives, {} failed", n, failed);
+        std::process::exit(if failed == 0 { 0 } else { 1 });
+    }
+    if let Some(Command::MigrateLayout) = args.command {
+        let (n, failed) = layout::migrate(&out_dir, config.layout.unwrap_or_default())?;
+        println!("Moved {} archives, {} failed", n, failed);
+        std::process::exit(if failed == 0 { 0 } else { 1 });
+    }
+    if let Some(Command::Stats { rebuild, rollup }) = args.command {
+        let mut failed = 0;
+        if rebuild {
+            let (n, damaged) = summary::rebuild(&out_dir).await?;
+            println!(
+                "Wrote {} summaries, {} archives with skipped lines",
+                n, damaged
+            );
+            failed += damaged;
+        }
+        if rollup {
+            let (n, f) = rollup::rebuild(&out_dir).await?;
+            println!("Wrote {} rollups, {} failed", n, f);
+            failed += f;
+        }
+        std::process::exit(if failed == 0 { 0 } else { 1 });
+    }
+    let write_mode = config.import.clone().unwrap_or_default().write_mode();
+    let bloom = config.bloom.unwrap_or_default();
+    if let Some(Command::Bloom { .. }) = args.command {
+        let (n, failed) = bloom::rebuild(&out_dir, &bloom).await?;
+        println!("Wrote {} bloom filters, {} failed", n, failed);
+        std::process::exit(if failed == 0 { 0 } else { 1 });
+    }
+    let backend = config
+        .index
+        .as_ref()
+        .and_then(|i| i.backend)
+        .unwrap_or_default();
+    if let Some(Command::MigrateIndex { to }) = args.command {
+        let dir = out_dir.clone();
+        let m = tokio::task::spawn_blocking(move || index::migrate(&dir, backend, to)).await??;
+        println!(
+            "Copied {} entries of {} indexes to {}, set index.backend: {} and remove the {} \
+             indexes",
+            m.entries,
+            m.indexes,
+            to.name(),
+            to.name(),
+            backend.name()
+        );
+        return Ok(());
+    }
+    if let Some(Command::BenchIndex { ids, dir }) = &args.command {
+        if dir.exists() {
+            bail!("{} exists, pick a new directory", dir.display());
+        }
+        let (ids, d) = (*ids, dir.clone());
+        let results = tokio::task::spawn_blocking(move || {
+            [IndexBackend::Rocksdb, IndexBackend::Redb]
+                .map(|b| index::bench(&d.join(b.name()), b, ids))
+                .into_iter()
+                .collect::<Result<Vec<_>>>()
+        })
+        .await?;
+        let _ = std::fs::remove_dir_all(dir);
+        for r in results? {
+            println!(
+                "{}: {} ids inserted in {:.1}s, {:.0} lookups/s, {} of {} indexed ids found",
+                r.backend.name(),
+                ids,
+                r.insert_secs,
+                r.lookups,
+                r.found,
+                r.present
+            );
+        }
+        return Ok(());
+    }
+    if let Some(Command::Import {
+        input,
+        format,
+        checkpoint,
+    }) = &args.command
+    {
+        let checkpoint = checkpoint.clone().unwrap_or_else(|| checkpoint_path(input));
+        let (dir, input, format) = (out_dir.clone(), input.clone(), *format);
+        let c = tokio::task::spawn_blocking(move || {
+            import::import(&dir, &input, format, &checkpoint, write_mode, backend)
+        })
+        .await??;
+        println!(
+            "Imported {} events, {} duplicates, {} errors",
+            c.events, c.duplicates, c.errors
+        );
+        return Ok(());
+    }
+    if let Some(Command::Merge {
+        from,
+        into,
+        dry_run,
+    }) = &args.command
+    {
+        let into = into.clone().unwrap_or_else(|| out_dir.clone());
+        let (from, dry_run) = (from.clone(), *dry_run);
+        let reports = tokio::task::spawn_blocking(move || {
+            merge::merge(&from, &into, dry_run, write_mode, backend)
+        })
+        .await??;
+        for r in &reports {
+            println!(
+                "{}: {} new, {} duplicates, {} errors",
+                r.path.display(),
+                r.events,
+                r.duplicates,
+                r.errors
+            );
+        }
+        println!(
+            "{} {} new events from {} archives, {} duplicates, {} errors",
+            if dry_run { "Would merge" } else { "Merged" },
+            reports.iter().map(|r| r.events).sum::<u64>(),
+            reports.len(),
+            reports.iter().map(|r| r.duplicates).sum::<u64>(),
+            reports.iter().map(|r| r.errors).sum::<u64>()
+        );
+        return Ok(());
+    }
+    if let Some(Command::ExportSqlite {
+        from,
+        to,
+        out,
+        append,
+        apply_deletions,
+    }) = &args.command
+    {
+        let files = select_archives(&out_dir, *from, *to)?;
+        let tombstones = apply_deletions
+            .then(|| Tombstones::load(&out_dir))
+            .transpose()?;
+        let (out, append) = (out.clone(), *append);
+        let t = tokio::task::spawn_blocking(move || {
+            sqlite::export(&files, &out, append, tombstones.as_ref())
+        })
+        .await??;
+        println!(
+            "Exported {} events from {} files, {} already present, {} deleted, {} lines skipped",
+            t.events, t.files, t.duplicates, t.deleted, t.skipped
+        );
+        return Ok(());
+    }
+    if let Some(Command::ExportParquet {
+        from,
+        to,
+        out,
+        rows_per_file,
+        apply_deletions,
+    }) = &args.command
+    {
+        let files = select_archives(&out_dir, *from, *to)?;
+        let tombstones = apply_deletions
+            .then(|| Tombstones::load(&out_dir))
+            .transpose()?;
+        let (src, out, max_rows) = (
+            out_dir.clone(),
+            out.clone(),
+            rows_per_file.map(|m| m * 1_000_000),
+        );
+        let t = tokio::task::spawn_blocking(move || {
+            parquet_export::export(&src, &files, &out, max_rows, tombstones.as_ref())
+        })
+        .await??;
+        println!(
+            "Exported {} rows from {} archives into {} files, {} deleted, {} lines skipped",
+            t.rows, t.archives, t.files, t.deleted, t.skipped
+        );
+        return Ok(());
+    }
+    if let Some(Command::ExportGraph { out, format }) = &args.command {
+        let files = select_archives(&out_dir, None, None)?;
+        let index_dir = out_dir.join("replaceable");
+        let index = if config.track_replaceable.unwrap_or(false) && index_dir.exists() {
+            Some(ReplaceableIndex::open(&index_dir)?)
+        } else {
+            println!("No replaceable index, reading every archive");
+            None
+        };
+        let (out, format) = (out.clone(), *format);
+        let t = tokio::task::spawn_blocking(move || {
+            graph::export(&files, index.as_ref(), &out, format)
+        })
+        .await??;
+        println!(
+            "Exported {} contact lists and {} relay lists from {} archives, {} follow edges, \
+             {} lines skipped",
+            t.contact_lists, t.relay_lists, t.archives, t.edges, t.skipped
+        );
+        if t.missing > 0 {
+            println!(
+                "{} versions in the replaceable index weren't found in the archives",
+                t.missing
+            );
+        }
+        return Ok(());
+    }
+    if let Some(Command::Attest { pubkey, .. }) = args.command {
+        let author = match (pubkey, &relay_keys) {
+            (Some(p), _) => PublicKey::parse(&p)?,
+            (None, Some(k)) => k.public_key(),
+            (None, None) => bail!("Set relay_secret_key or pass --pubkey"),
+        };
+        let ok = verify(&out_dir, &upstream, &relays, author).await?;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    let unix_mode = config
+        .unix_socket_mode
+        .map(|m| u32::from_str_radix(&m, 8))
+        .transpose()?;
 
-    let out_dir = config.out_dir.unwrap_or(PathBuf::from("./data"));
-    let addr: SocketAddr = config
-        .listen_relay
-        .map(|a| a.parse())
-        .unwrap_or(Ok(SocketAddr::from(([0, 0, 0, 0], 8001))))?;
+    let proxies = TrustedProxies::parse(&config.trusted_proxies.unwrap_or_default())?;
 
-    let mut db = JsonFilesDatabase::new(out_dir.clone())?;
+    let format = config.compression.unwrap_or_default().format();
+    // compressions interrupted by the last shutdown, before the archives are indexed
+    let leftovers = if config.mode.unwrap_or_default() != RunMode::Serve
+        && matches!(args.command, None | Some(Command::Index { .. }))
+        && out_dir.exists()
+    {
+        let dir = out_dir.clone();
+        tokio::task::spawn_blocking(move || recover_compression(&dir, format)).await??
+    } else {
+        Vec::new()
+    };
+
+    let recover = config.auto_recover_index.unwrap_or(false);
+    let jobs = ArchiveJobs::new(config.archive_jobs.unwrap_or(1));
+    let options = WriterOptions {
+        write_compressed: config.write_compressed.unwrap_or(false),
+        jobs: jobs.clone(),
+        ..WriterOptions::new(
+            &config.writer.unwrap_or_default(),
+            format,
+            Fsync::new(
+                config.durability.unwrap_or_default(),
+                Duration::from_secs(config.durability_interval_secs.unwrap_or(5)),
+                config.durability_interval_events,
+            ),
+        )
+    };
+    let (mut db, moved) = scan::open_database(&out_dir, recover, backend, options.clone())?;
+    let authors = config
+        .index_authors
+        .unwrap_or(false)
+        .then(|| AuthorIndex::open(&out_dir.join("authors"), out_dir.clone()))
+        .transpose()?;
+    // enabled on existing archives, they are all indexed again
+    let authors_missing = authors.as_ref().is_some_and(|a| a.is_empty());
+    let times = TimeIndex::open(&out_dir.join("times"))?;
 
     // rebuild index if needed
-    if db.is_index_empty() && !db.list_files().await?.is_empty() {
+    let rebuild = matches!(args.command, Some(Command::Index { .. }));
+    let mirrored = out_dir.join(REINDEX_MARKER).exists();
+    let reindex = rebuild || mirrored || authors_missing;
+    let mut damaged = 0;
+    let root_rebuilt = if rebuild {
+        info!("Rebuilding index....");
+        times.clear()?;
+        damaged += scan::rebuild_index(&mut db, &out_dir, authors.as_ref(), &times)?;
+        true
+    } else if let Some(m) = &moved {
+        info!("Broken index was moved to {}, rebuilding....", m.display());
+        damaged += scan::rebuild_index(&mut db, &out_dir, authors.as_ref(), &times)?;
+        true
+    } else if db.is_index_empty()? && !db.list_files().await?.is_empty() {
         info!("Index is empty, rebuilding....");
-        db.rebuild_index()?;
+        damaged += scan::rebuild_index(&mut db, &out_dir, authors.as_ref(), &times)?;
+        true
+    } else if mirrored {
+        info!("New archive files were mirrored, rebuilding index....");
+        damaged += scan::rebuild_index(&mut db, &out_dir, authors.as_ref(), &times)?;
+        true
+    } else if authors_missing && !db.list_files().await?.is_empty() {
+        info!("Author index is empty, rebuilding....");
+        damaged += scan::rebuild_index(&mut db, &out_dir, authors.as_ref(), &times)?;
+        true
+    } else {
+        false
+    };
+    if let Some(m) = &moved {
+        scan::compare_recovered(&db, m);
     }
-
-    let client = Client::builder().database(db.clone()).build();
-    if let Some(r) = config.relays {
-        for r in &r {
-            client.add_relay(r).await?;
+    let partitions = match config.partition_by_kind {
+        Some(p) => Partitions::new(p, out_dir.clone(), backend, options)?,
+        None => None,
+    };
+    if let Some(p) = &partitions {
+        p.open(reindex, &mut damaged, authors.as_ref(), &times, recover)
+            .await?;
+    }
+    if mirrored {
+        std::fs::remove_file(out_dir.join(REINDEX_MARKER))?;
+    }
+    if !times.is_complete() {
+        let mut empty = db.is_index_empty()?;
+        for d in partitions.iter().flat_map(|p| p.all()) {
+            empty &= d.is_index_empty()?;
         }
-        client.connect().await;
+        // a partition is only rebuilt with the rest on reindex, otherwise when it is empty
+        if (root_rebuilt && (reindex || partitions.is_none())) || empty {
+            times.set_complete()?;
+        } else {
+            warn!(
+                "Time index is incomplete, run `nostrhole index --rebuild` to build it; until then \
+                 time ranges read the whole event index"
+            );
+        }
+    }
+    if rebuild {
+        println!("Rebuilt index, {} files with skipped lines", damaged);
+        std::process::exit(if damaged == 0 { 0 } else { 1 });
+    }
 
-        let mut filter_base = Filter::default();
-        if let Some(k) = &config.kinds {
-            filter_base = filter_base.kinds(k.iter().map(|v| Kind::Custom(*v as u16)))
+    let kinds = config.kinds.as_deref().map(KindSet::parse).transpose()?;
+    let sensitive = SensitiveKinds::new(
+        config.sensitive_kinds.as_deref().unwrap_or_default(),
+        config.allow_sensitive_kinds.unwrap_or(false),
+    )?;
+    if let (Some(k), Some(b)) = (&kinds, sensitive.blocked())
+        && !k.is_all()
+        && k.intersects(b)
+    {
+        warn!(
+            "kinds: the sensitive kinds ({}) are not archived, set allow_sensitive_kinds to archive them",
+            b
+        );
+    }
+    let filter_kinds = kinds
+        .as_ref()
+        .and_then(|k| k.filter_kinds())
+        .map(|k| sensitive.filter(k));
+
+    let sketch = times.sketch().clone();
+    let mut db = ArchiveDatabase::new(db, out_dir.clone())
+        .with_times(times)
+        .with_layout(config.layout.unwrap_or_default());
+    if let Some(p) = partitions {
+        db = db.with_partitions(p);
+    }
+    let rocksdb = rocks::settings();
+    info!(
+        "Event index holds {} ids in {} MB, rocksdb stores share a {} MiB cache with {} MiB \
+         memtables",
+        db.count_keys()?,
+        db.index_size() / 1_000_000,
+        rocksdb.cache_bytes() / 1024 / 1024,
+        rocksdb.write_buffer_bytes() / 1024 / 1024
+    );
+    if let Some(Command::Doctor { attestations }) = args.command {
+        let author = match (attestations, &relay_keys) {
+            (false, _) => None,
+            (true, Some(k)) => Some(k.public_key()),
+            (true, None) => bail!("Set relay_secret_key to check attestations"),
+        };
+        let ok = doctor(&db, &upstream, &relays, author).await?;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if let Some(k) = &kinds
+        && !k.is_all()
+        && filter_kinds.is_none()
+    {
+        // relays are sent no kinds filter, drop the kinds we don't want locally
+        db = db.with_kinds(k.clone());
+    }
+    if let Some(b) = sensitive.blocked() {
+        db = db.with_blocked_kinds(b.clone());
+    }
+    if config.track_replaceable.unwrap_or(false) {
+        db = db.with_replaceable(ReplaceableIndex::open(&out_dir.join("replaceable"))?);
+    }
+    if config.allow_wipe.unwrap_or(false) {
+        db = db.with_allow_wipe();
+    }
+    if let Some(a) = authors {
+        let (entries, size) = a.size();
+        info!(
+            "Author index holds ~{} entries in {} MB",
+            entries,
+            size / 1_000_000
+        );
+        db = db.with_authors(a);
+    }
+    if config.event_lookup.unwrap_or(false) {
+        db = db.with_offsets(EventOffsets::open(
+            &out_dir.join("offsets"),
+            out_dir.clone(),
+        )?);
+    }
+    let sources = config
+        .track_sources
+        .unwrap_or(false)
+        .then(|| EventSources::open(&out_dir.join("sources")))
+        .transpose()?;
+    if let Some(s) = &sources {
+        db = db.with_sources(s.clone());
+    }
+    if config.track_seen.unwrap_or(false) {
+        db = db.with_seen(EventSeen::open(&out_dir.join("seen"))?);
+    }
+    let negentropy = config.negentropy.unwrap_or_default();
+    if negentropy.enabled.unwrap_or(false) {
+        db = db.with_negentropy(IpRateLimit::new(
+            negentropy.max_per_hour.unwrap_or(6),
+            Duration::from_secs(60 * 60),
+        ));
+    }
+    if config.count.unwrap_or(true) {
+        db = db.with_count(ActivityStats::default());
+    }
+    let keep_expired = config.keep_expired.unwrap_or(false);
+    if !keep_expired {
+        db = db.with_reject_expired();
+    }
+    if !config.archive_ephemeral.unwrap_or(true) {
+        db = db.with_skip_ephemeral();
+    }
+    if let Some(limits) = config.event_limits {
+        db = db.with_limits(EventLimits::new(limits, out_dir.clone()));
+    }
+
+    let disk = config.disk_guard.map(|s| (DiskGuard::default(), s));
+    if let Some((guard, _)) = &disk {
+        db = db.with_disk_guard(guard.clone());
+    }
+    if let Some(p) = &peers {
+        db = db.with_trusted_peers(p.clone());
+    }
+    let dead_letter = config.dead_letter.unwrap_or_default();
+    let retry_dead_letters = matches!(args.command, Some(Command::RetryDeadletter));
+    if dead_letter.enabled.unwrap_or(true) || retry_dead_letters {
+        db = db.with_dead_letters(DeadLetters::new(&out_dir));
+    }
+    if config.track_deletions.unwrap_or(false) {
+        db = db.with_deletions(Deletions::new(&out_dir));
+    }
+    let published = config
+        .published_view
+        .as_ref()
+        .filter(|p| p.enabled.unwrap_or(false))
+        .map(|p| PublishedView::new(&out_dir, p));
+    if let Some(p) = &published {
+        db = db.with_published(p.clone());
+    }
+    if let Some(Command::PruneIndex { before }) = &args.command {
+        let before =
+            Timestamp::from_secs(before.and_time(NaiveTime::MIN).and_utc().timestamp() as u64);
+        let pruned = db.prune_ids_older_than(before).await?;
+        db.flush().await?;
+        println!("Pruned {} index entries, {} kept", pruned, db.count_keys()?);
+        return Ok(());
+    }
+    if let (true, Some(d)) = (retry_dead_letters, db.dead_letters()) {
+        let r = d.retry(&db).await?;
+        db.flush().await?;
+        println!(
+            "Saved {} events, {} rejected, {} failed again, {} invalid{}",
+            r.saved,
+            r.rejected,
+            r.failed,
+            r.invalid,
+            if r.paused {
+                ", paused by the disk guard"
+            } else {
+                ""
+            }
+        );
+        std::process::exit(if r.failed == 0 && !r.paused { 0 } else { 1 });
+    }
+
+    let status = LiveStatus::default().with_jobs(jobs.clone());
+    db = db.with_status(status.clone());
+    if !leftovers.is_empty() {
+        info!(
+            "Compressing {} archives left plain by the last run",
+            leftovers.len()
+        );
+        tokio::spawn(compress_leftovers(
+            db.clone(),
+            jobs.clone(),
+            leftovers,
+            format,
+        ));
+    }
+    let rollups = config.zap_rollups.unwrap_or(false);
+    let seekable = config.seekable.unwrap_or_default();
+    if format != CompressionFormat::Zstd {
+        let r = Recompress::new(format, jobs.clone());
+        db = db.with_recompress(r.clone());
+        tokio::spawn(run_recompress(db.clone(), r));
+    } else if seekable.enabled.unwrap_or(true) {
+        let s = Seekable::new(&seekable, jobs.clone());
+        db = db.with_seekable(s.clone());
+        tokio::spawn(run_seekable(db.clone(), s));
+    }
+
+    if bloom.enabled.unwrap_or(false) {
+        db = db.with_blooms(BloomFilters::default());
+        tokio::spawn(run_blooms(db.clone(), bloom, jobs.clone()));
+    }
+    if let Some(e) = &config.encryption {
+        let mut enc = Encryption::new(
+            e.recipients()?,
+            jobs.clone(),
+            db.blooms().is_some(),
+            format == CompressionFormat::None,
+        );
+        if rollups {
+            enc = enc.with_rollups();
         }
+        db = db.with_encryption(enc.clone());
+        tokio::spawn(run_encrypt(db.clone(), enc));
+    }
+    if db.layout() == Layout::Nested || naming::template().is_some() {
+        tokio::spawn(run_layout(db.clone()));
+    }
+    if rollups {
+        tokio::spawn(run_rollups(db.clone(), jobs.clone()));
+    }
+    tokio::spawn(run_summaries(db.clone(), jobs));
+    tokio::spawn(run_sketch(sketch.clone()));
 
-        // spawn main ingester
-        let client_sub = client.clone();
-        let db_sub = db.clone();
-        let filter_sub = filter_base.clone();
-        let _: JoinHandle<Result<()>> = tokio::spawn(async move {
-            let mut rx = client_sub.notifications();
-            client_sub.subscribe(filter_sub.limit(100), None).await?;
-            loop {
-                match rx.recv().await {
-                    Ok(e) => match e {
-                        RelayPoolNotification::Event { event, .. } => {
-                            if let Err(e) = db_sub.save_event(&event).await {
-                                error!("Failed to save event: {}", e);
-                            }
-                        }
-                        RelayPoolNotification::Message { .. } => {}
-                        RelayPoolNotification::Shutdown => {}
-                    },
-                    Err(e) => {
-                        error!("Client error notification: {}", e);
-                        if matches!(e, RecvError::Closed) {
-                            break;
-                        }
-                    }
-                }
+    let firehose = config.firehose.unwrap_or_default();
+    let live = if firehose.enabled.unwrap_or(false) {
+        let (tx, _) = broadcast::channel(firehose.queue_size.unwrap_or(1024));
+        db = db.with_live(tx.clone());
+        Some(tx)
+    } else {
+        None
+    };
+
+    let torrent = config.torrent.unwrap_or_default();
+    if torrent.enabled.unwrap_or(false) {
+        db = db.with_torrents(TorrentMaker::new(torrent, config.public_url.clone())?);
+        tokio::spawn(run_torrents(db.clone()));
+    }
+
+    if let Some(mirror) = config.mirror {
+        tokio::spawn(run_mirror(db.clone(), mirror));
+    }
+    let changes = config
+        .track_changes
+        .unwrap_or(false)
+        .then(|| ArchiveChanges::open(&out_dir.join("changes")))
+        .transpose()?;
+    if let Some(c) = &changes {
+        tokio::spawn(run_changes(db.clone(), c.clone()));
+    }
+
+    if dead_letter.retry_on_start.unwrap_or(false)
+        && let Some(d) = db.dead_letters().cloned()
+    {
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = d.retry(&db).await {
+                warn!("Failed to retry dead-lettered events: {}", e);
             }
-            error!("Read loop exited!");
-            Ok(())
         });
     }
 
-    let mut builder = RelayBuilder::default()
-        .database(db.clone())
-        .query_policy(NoQuery)
-        .write_policy(EphemeralPolicy)
-        .rate_limit(RateLimit {
-            max_reqs: 20,
-            notes_per_minute: 100_000,
-        });
-    if let Some(k) = &config.kinds {
-        builder = builder.write_policy(KindPolicy::new(
-            k.iter().map(|k| Kind::Custom(*k as u16)).collect(),
+    let consistency = Consistency::default();
+    tokio::spawn(run_consistency(db.clone(), consistency.clone()));
+
+    let relay_stats = RelayStats::default().with_verify_ids(config.verify_ids.unwrap_or(true));
+    let ingest_health = IngestHealth::default();
+    let scope = config.ingest_scope.as_ref().map(|_| AuthorScope::default());
+    let mode = config.mode.unwrap_or_default();
+    if mode == RunMode::Serve && !relays.is_empty() {
+        warn!("mode: serve, relays are not ingested from");
+    }
+    let client = upstream.client_builder().database(db.clone());
+    let protected = config.archive_protected.unwrap_or(false);
+    let client = match (&scope, &sources) {
+        (Some(s), Some(src)) => client.admit_policy(SourceAdmit::new(
+            ProtectedAdmit::new(ScopedAdmit::new(relay_stats.clone(), s.clone()), protected),
+            src.clone(),
+        )),
+        (Some(s), None) => client.admit_policy(ProtectedAdmit::new(
+            ScopedAdmit::new(relay_stats.clone(), s.clone()),
+            protected,
+        )),
+        (None, Some(src)) => client.admit_policy(SourceAdmit::new(
+            ProtectedAdmit::new(relay_stats.clone(), protected),
+            src.clone(),
+        )),
+        (None, None) => client.admit_policy(ProtectedAdmit::new(relay_stats.clone(), protected)),
+    };
+    let client = (mode != RunMode::Serve).then(|| client.build());
+    if let Some(client) = &client
+        && (!relays.is_empty() || config.discover_relays.is_some())
+    {
+        for r in &relays {
+            info!("Relay {}: {}", r, upstream.describe(r)?);
+            upstream.add_relay(client, r).await?;
+        }
+        upstream.connect(client).await;
+
+        let mut filter_base = Filter::default();
+        if let Some(k) = &filter_kinds {
+            filter_base = filter_base.kinds(k.iter().copied())
+        }
+
+        let ingest = config.ingest.unwrap_or_default();
+
+        let sync = config.sync.unwrap_or_default();
+        if sync.enabled.unwrap_or(false) {
+            tokio::spawn(run_sync(client.clone(), filter_base.clone(), sync));
+        }
+
+        if let Some(discover) = config.discover_relays {
+            tokio::spawn(run_discover(
+                client.clone(),
+                upstream.clone(),
+                discover,
+                relays.clone(),
+            ));
+        }
+
+        if let (Some(scope), Some(settings)) = (&scope, config.ingest_scope) {
+            tokio::spawn(run_scope(
+                client.clone(),
+                upstream.clone(),
+                filter_base.clone(),
+                ingest.initial_limit(),
+                ScopedAdmit::new(relay_stats.clone(), scope.clone()),
+                settings,
+                relays.clone(),
+            ));
+        }
+
+        if let Some(jobs) = &config.ingest_jobs {
+            let defaults = JobDefaults {
+                ingest: ingest.clone(),
+                kinds: kinds.clone(),
+                filter_kinds: filter_kinds.clone(),
+                sensitive: sensitive.clone(),
+                relays: relays.clone(),
+            };
+            tokio::spawn(run_ingest_jobs(
+                client.clone(),
+                relay_stats.clone(),
+                jobs.clone(),
+                defaults,
+                config_path.clone(),
+            ));
+        }
+
+        tokio::spawn(run_noisy_relays(
+            client.clone(),
+            relay_stats.clone(),
+            ingest.clone(),
+        ));
+
+        // scoped ingestion is subscribed by run_scope, jobs by run_ingest_jobs
+        tokio::spawn(run_ingest(
+            client.clone(),
+            db.clone(),
+            if scope.is_none() && config.ingest_jobs.is_none() {
+                with_tags(
+                    ingest.filters(filter_kinds.as_deref()),
+                    &config
+                        .policies
+                        .as_deref()
+                        .map(ingest_tags)
+                        .unwrap_or_default(),
+                )
+            } else {
+                vec![]
+            },
+            relay_stats.clone(),
+            ingest_health.clone(),
+            ingest,
+        ));
+    }
+
+    if let Some((guard, settings)) = &disk {
+        tokio::spawn(run_disk_guard(
+            guard.clone(),
+            out_dir.clone(),
+            client.clone(),
+            settings.clone(),
         ));
     }
+
+    if let (Some(keys), Some(client)) = (&relay_keys, &client) {
+        let publisher = Publisher::new(client.clone(), &upstream, keys.clone(), &[]).await?;
+        tokio::spawn(run_attest(db.clone(), publisher));
+    }
+    if let Some(p) = published {
+        tokio::spawn(run_published(db.clone(), p));
+    }
+
+    let landing = config.landing_page.unwrap_or_default();
+    let announce = config.announce.unwrap_or_default();
+    if announce.enabled.unwrap_or(false) {
+        match (&relay_keys, &client, &config.public_url) {
+            (Some(keys), Some(client), Some(url)) => {
+                let info = SelfDescription {
+                    url: relay_url(url)?,
+                    name: landing.name.clone().unwrap_or("nostrhole".to_string()),
+                    description: landing.description.clone().unwrap_or_default(),
+                    kinds: kinds.clone(),
+                };
+                let publisher = Publisher::new(
+                    client.clone(),
+                    &upstream,
+                    keys.clone(),
+                    announce.relays.as_deref().unwrap_or_default(),
+                )
+                .await?;
+                tokio::spawn(run_announce(db.clone(), publisher, info, announce));
+            }
+            (_, None, _) => warn!("announce: nothing is published in serve mode"),
+            _ => warn!("announce: needs relay_secret_key and public_url, not announcing"),
+        }
+    }
+
+    let mut chain = if mode == RunMode::Serve {
+        PolicyChain::default().with_policy("read_only", Box::new(ReadOnlyPolicy))
+    } else {
+        PolicyChain::from_config(&policies)?
+    };
+    // peers only get the kinds filter, plus what protects the relay itself
+    let mut peer_chain = if mode == RunMode::Serve {
+        PolicyChain::default().with_policy("read_only", Box::new(ReadOnlyPolicy))
+    } else {
+        match &config.kinds {
+            Some(k) => PolicyChain::from_config(&[PolicyConfig::Kinds { kinds: k.clone() }])?,
+            None => PolicyChain::default(),
+        }
+    };
+    let audit = config
+        .audit_log
+        .map(|path| AuditLog::spawn(path, config.audit_log_max_mb.unwrap_or(64) * 1024 * 1024));
+    if let Some(a) = &audit {
+        chain = chain.with_audit(a.clone());
+        peer_chain = peer_chain.with_audit(a.clone());
+    }
+    if let Some(b) = sensitive.blocked() {
+        chain = chain.with_policy("sensitive_kinds", Box::new(SensitiveKindPolicy(b.clone())));
+        peer_chain =
+            peer_chain.with_policy("sensitive_kinds", Box::new(SensitiveKindPolicy(b.clone())));
+    }
+    if let Some((guard, _)) = &disk {
+        chain = chain.with_policy("disk_guard", Box::new(StorageFullPolicy(guard.clone())));
+        peer_chain =
+            peer_chain.with_policy("disk_guard", Box::new(StorageFullPolicy(guard.clone())));
+    }
+    if let Some(s) = &scope {
+        chain = chain.with_policy("ingest_scope", Box::new(AuthorAllowPolicy::new(s.clone())));
+    }
+    if let Some(settings) = config.wot {
+        let set = WotSet::default();
+        chain = chain.with_policy("wot", Box::new(WotPolicy::new(set.clone(), &settings)?));
+        if relays.is_empty() {
+            warn!(
+                "wot: no relays to fetch contact lists from, only allow_unknown_kinds are accepted"
+            );
+        } else {
+            tokio::spawn(run_wot(upstream.clone(), relays.clone(), set, settings));
+        }
+    }
+    // last, events it forwards were admitted by every other policy
+    if live.is_some() {
+        chain = chain.with_policy("live_ephemeral", Box::new(LiveEphemeralPolicy(db.clone())));
+        peer_chain =
+            peer_chain.with_policy("live_ephemeral", Box::new(LiveEphemeralPolicy(db.clone())));
+    }
+    let mut builder = RelayBuilder::default().database(db.clone());
+    builder = match &peers {
+        Some(p) => builder.write_policy(PeerPolicy::new(
+            p.clone(),
+            peer_chain,
+            chain,
+            limits.notes_per_minute(),
+        )),
+        None => builder.write_policy(chain),
+    };
+    builder = limits.apply(builder, peers.is_some());
+    let query_policy = config.query_policy.unwrap_or_default();
+    builder = match query_policy.mode.unwrap_or_default() {
+        QueryMode::None => builder.query_policy(NoQuery),
+        QueryMode::Window => builder.query_policy(QueryWindowPolicy::new(&query_policy)),
+    };
     let relay = LocalRelay::new(builder);
 
-    let listener = TcpListener::bind(&addr).await?;
-    info!("Listening on {}", &addr);
+    let http = config.http.unwrap_or_default();
+    let tls = config
+        .tls
+        .map(|t| ReloadableTls::new(t, http.http2()))
+        .transpose()?;
+
+    // NIP-42 and NIP-70 are always handled by the relay, the others depend on the config
+    let mut supported_nips = vec![1, 11, 42, 70];
+    if !keep_expired {
+        supported_nips.push(40);
+    }
+    if config.count.unwrap_or(true) {
+        supported_nips.push(45);
+    }
+    if negentropy.enabled.unwrap_or(false) {
+        supported_nips.push(77);
+    }
+    supported_nips.sort_unstable();
+    let info = RelayInformationDocument {
+        name: Some(landing.name.clone().unwrap_or("nostrhole".to_string())),
+        description: landing.description.clone(),
+        pubkey: landing.pubkey.clone(),
+        supported_nips: Some(supported_nips),
+        software: Some(env!("CARGO_PKG_NAME").to_string()),
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        limitation: Some(limits.limitation()),
+        ..Default::default()
+    };
+    let mut server = HttpServer::new(relay, db.clone(), relay_stats, proxies)
+        .with_relay_info(info)
+        .with_ingest_health(ingest_health)
+        .with_status(status)
+        .with_consistency(consistency)
+        .with_landing_page(LandingPage::new(
+            landing,
+            kinds.map(|k| k.to_string()).unwrap_or("all".to_string()),
+        )?);
+    if let Some(c) = &client {
+        server = server.with_client(c.clone());
+    }
+    if let Some((guard, _)) = disk {
+        server = server.with_disk_guard(guard);
+    }
+    if let Some(c) = changes {
+        server = server.with_changes(c);
+    }
+    if let Some(s) = &config.slice {
+        server = server.with_slice(s);
+    }
+    if let Some(url) = config.public_url {
+        server = server.with_public_url(url);
+    }
+    if let Some(live) = live {
+        server = server.with_firehose(live);
+    }
+    if let Some(path) = config.access_log {
+        server = server.with_access_log(AccessLog::spawn(
+            path,
+            config.access_log_max_mb.unwrap_or(64) * 1024 * 1024,
+        ));
+    }
+    if let Some(k) = sensitive.hidden(config.download_auth.is_some()) {
+        warn!(
+            "allow_sensitive_kinds: download_auth isn't set, archives that may hold kinds {} can't be downloaded",
+            k
+        );
+        server = server.with_hidden_kinds(k.clone());
+    }
+    if let Some(auth) = config.download_auth {
+        server = server.with_auth(DownloadAuth::new(auth));
+    }
+    let download = config.download.unwrap_or_default();
+    if download.requests_per_minute.is_some() || download.max_streams.is_some() {
+        server = server.with_download_limit(DownloadLimit::new(
+            download.requests_per_minute.unwrap_or(10),
+            download.max_streams.unwrap_or(2),
+        ));
+    }
+    if let Some(c) = &config.connections {
+        server = server.with_connection_limit(ConnectionLimit::new(c));
+    }
+    if download.count.unwrap_or(false) {
+        server = server.with_download_counts(DownloadCounts::open(&out_dir.join("downloads"))?);
+    }
+    server = server.with_throttle(DownloadThrottle::new(
+        download.per_conn_kbps.unwrap_or(0) * 1000 / 8,
+        download.global_mbps.unwrap_or(0) * 1_000_000 / 8,
+    ));
+    let mut listeners = JoinSet::new();
+    if mode != RunMode::Archive {
+        for spec in &listen {
+            let listener = Listener::bind(spec, unix_mode)?;
+            info!(
+                "Listening on {}{}",
+                listener,
+                if tls.is_some() { " (tls)" } else { "" }
+            );
+            listeners.spawn(accept_loop(
+                listener,
+                server.clone(),
+                tls.clone(),
+                http.clone(),
+            ));
+        }
+    }
+    for spec in config.metrics_listen.iter().flatten() {
+        let listener = Listener::bind(spec, unix_mode)?;
+        info!("Serving /api on {}", listener);
+        listeners.spawn(accept_loop(listener, server.api_only(), None, http.clone()));
+    }
+    tokio::select! {
+        r = async {
+            while let Some(r) = listeners.join_next().await {
+                r??;
+            }
+            anyhow::Ok(())
+        }, if !listeners.is_empty() => r?,
+        r = shutdown_signal() => {
+            r?;
+            info!("Shutting down");
+        }
+    }
+    if let Some(c) = client {
+        c.shutdown().await;
+    }
+    if let Err(e) = db.flush().await {
+        warn!("Failed to write the archives: {}", e);
+    }
+    if let Err(e) = sketch.save() {
+        warn!("Failed to save unique author sketch: {}", e);
+    }
+    Ok(())
+}
+
+/// Resolves on SIGINT or SIGTERM
+async fn shutdown_signal() -> Result<()> {
+    let mut term = signal(SignalKind::terminate())?;
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r?,
+        _ = term.recv() => {}
+    }
+    Ok(())
+}
+
+async fn accept_loop(
+    listener: Listener,
+    server: HttpServer,
+    tls: Option<ReloadableTls>,
+    http: HttpSettings,
+) -> Result<()> {
     loop {
         let (socket, addr) = listener.accept().await?;
 
-        let io = TokioIo::new(socket);
-        let server = HttpServer::new(relay.clone(), db.clone(), addr);
+        let server = server.with_remote(addr);
+        let acceptor = tls.as_ref().map(|t| t.acceptor());
+        let http = http.clone();
         tokio::spawn(async move {
-            if let Err(e) = http1::Builder::new()
-                .serve_connection(io, server)
-                .with_upgrades()
-                .await
-            {
+            let res = match acceptor {
+                // a client that stalls the handshake is held to the same idle timeout
+                Some(acceptor) => {
+                    match tokio::time::timeout(http.idle_timeout(), acceptor.accept(socket)).await {
+                        Ok(Ok(stream)) => serve(stream, server, &http).await,
+                        Ok(Err(e)) => {
+                            debug!("TLS handshake failed with {}: {}", addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("TLS handshake with {} timed out", addr);
+                            return;
+                        }
+                    }
+                }
+                None => serve(socket, server, &http).await,
+            };
+            if let Err(e) = res {
                 error!("Failed to handle request: {}", e);
             }
         });
     }
 }
+
+/// Serve http on `io` until it is closed, upgraded, or idle for longer than the idle timeout
+///
+/// HTTP/2 is detected by its preface, websocket upgrades are only possible over HTTP/1.1
+async fn serve<I>(
+    io: I,
+    server: HttpServer,
+    http: &HttpSettings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (io, activity) = IdleIo::new(io);
+    if !http.http2() {
+        let mut builder = http1::Builder::new();
+        builder
+            .timer(TokioTimer::new())
+            .header_read_timeout(http.header_timeout());
+        if let Some(n) = http.max_headers {
+            builder.max_headers(n);
+        }
+        // the upgraded websocket is handed off when the connection future resolves
+        return tokio::select! {
+            r = builder.serve_connection(TokioIo::new(io), server).with_upgrades() => Ok(r?),
+            _ = activity.idle(http.idle_timeout()) => Ok(()),
+        };
+    }
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(http.header_timeout());
+    if let Some(n) = http.max_headers {
+        builder.http1().max_headers(n);
+    }
+    builder.http2().timer(TokioTimer::new());
+    tokio::select! {
+        r = builder.serve_connection_with_upgrades(TokioIo::new(io), server) => r,
+        _ = activity.idle(http.idle_timeout()) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::EventStore;
+    use crate::test_util::TempDir;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn slow_writing_client_is_disconnected() {
+        let dir = TempDir::new();
+        let db = ArchiveDatabase::new(
+            EventStore::open(
+                dir.path().to_path_buf(),
+                IndexBackend::default(),
+                WriterOptions::default(),
+            )
+            .unwrap(),
+            dir.path().to_path_buf(),
+        );
+        let relay = LocalRelay::new(RelayBuilder::default().database(db.clone()));
+        let server = HttpServer::new(relay, db, RelayStats::default(), TrustedProxies::default());
+        for http2 in [false, true] {
+            let http = HttpSettings {
+                header_timeout_secs: Some(1),
+                http2: Some(http2),
+                ..Default::default()
+            };
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = server.clone();
+            tokio::spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let _ = serve(socket, server, &http).await;
+            });
+
+            let started = Instant::now();
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Slow: ")
+                .await
+                .unwrap();
+            // a byte every 100ms is never idle, but the headers never end
+            let mut buf = [0u8; 1024];
+            loop {
+                assert!(
+                    started.elapsed() < Duration::from_secs(5),
+                    "http2: {}",
+                    http2
+                );
+                if client.write_all(b"x").await.is_err() {
+                    break;
+                }
+                let read = tokio::time::timeout(Duration::from_millis(100), client.read(&mut buf));
+                if let Ok(Ok(0) | Err(_)) = read.await {
+                    break;
+                }
+            }
+            assert!(started.elapsed() >= Duration::from_secs(1));
+        }
+    }
+}
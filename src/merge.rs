@@ -0,0 +1,136 @@
+use crate::db::decode_archive_strict;
+use crate::import::{CHECKPOINT_EVENTS, DayFiles, ImportFormat, WriteMode, parse, verbatim};
+use crate::index::{IndexBackend, index_path, open_index};
+use crate::jobs::JOB_BUFFER;
+use crate::layout::{self, is_date_dir};
+use crate::mirror::REINDEX_MARKER;
+use crate::scan::is_reserved_dir;
+use anyhow::{Result, bail};
+use nostr_sdk::Timestamp;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Events of one source archive
+pub struct SourceReport {
+    pub path: PathBuf,
+    /// Not in the destination or an earlier source, written unless it is a dry run
+    pub events: u64,
+    pub duplicates: u64,
+    pub errors: u64,
+}
+
+/// Archives of `dir` and its partition directories, either layout, sorted
+fn source_archives(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut ret = layout::archive_paths(dir)?;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_dir()
+            && name != "index"
+            && !is_reserved_dir(&name)
+            && !name.starts_with('.')
+            && !is_date_dir(&name)
+        {
+            ret.extend(layout::archive_paths(&entry.path())?);
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Copy the events of the archives in `from` that `into` doesn't have yet into
+/// `events_YYYYMMDD.merge-<start time>.jsonl` day files by created_at, compressed with zstd
+/// at the end; `from` is only read
+///
+/// Events are checked against the index of `into` and every event merged before it, so no
+/// event is written twice. The next start rebuilds the indexes to pick up the new files.
+/// An interrupted merge can be run again, events of the last batch may then be written twice
+///
+/// With `dry_run` nothing is written, the report counts what each source would add
+pub fn merge(
+    from: &Path,
+    into: &Path,
+    dry_run: bool,
+    mode: WriteMode,
+    backend: IndexBackend,
+) -> Result<Vec<SourceReport>> {
+    if !from.is_dir() {
+        bail!("{} is not a directory", from.display());
+    }
+    if from.canonicalize()? == into.canonicalize()? {
+        bail!("Can't merge {} into itself", from.display());
+    }
+    if std::fs::read_dir(into)?
+        .flatten()
+        .any(|e| e.file_name() != "index" && index_path(&e.path(), backend).exists())
+    {
+        bail!(
+            "{} is partitioned by kind, merging into partitions is not supported",
+            into.display()
+        );
+    }
+
+    let index = open_index(into, backend)?;
+    let mut files = DayFiles::new(into, format!("merge-{}", Timestamp::now().as_secs()));
+    // committed once the files are flushed, like an import
+    let mut batch = index.batch()?;
+    let mut claimed = 0;
+    let mut reports = Vec::new();
+    let mut line = Vec::new();
+    for path in source_archives(from)? {
+        let mut report = SourceReport {
+            path: path.clone(),
+            events: 0,
+            duplicates: 0,
+            errors: 0,
+        };
+        let mut reader = decode_archive_strict(
+            &path,
+            BufReader::with_capacity(JOB_BUFFER, File::open(&path)?),
+        )?;
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            let content = line.strip_suffix(b"\n").unwrap_or(&line);
+            if content.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let Some(ev) = parse(ImportFormat::Jsonl, content) else {
+                report.errors += 1;
+                continue;
+            };
+            if !batch.claim(ev.id, ev.created_at)? {
+                report.duplicates += 1;
+                continue;
+            }
+            report.events += 1;
+            if dry_run {
+                continue;
+            }
+            files.write(&ev, verbatim(mode, ImportFormat::Jsonl, content, &ev))?;
+            claimed += 1;
+            if claimed >= CHECKPOINT_EVENTS {
+                files.flush()?;
+                batch.commit()?;
+                batch = index.batch()?;
+                claimed = 0;
+            }
+        }
+        reports.push(report);
+    }
+    if dry_run {
+        return Ok(reports);
+    }
+    files.flush()?;
+    batch.commit()?;
+    let n = files.compress()?;
+    eprintln!("Compressed {} day files", n);
+    if reports.iter().any(|r| r.events > 0) {
+        std::fs::write(into.join(REINDEX_MARKER), b"")?;
+    }
+    Ok(reports)
+}
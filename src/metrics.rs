@@ -0,0 +1,194 @@
+use nostr_sdk::Event;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Content type of [render]
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Compressions of smaller archives aren't recorded, fixed costs dominate their rate
+const MIN_COMPRESSED_BYTES: u64 = 1024 * 1024;
+
+/// Serialized size of saved events in bytes, 256 B to 1 MiB
+pub static EVENT_SIZE: Histogram<13> = Histogram::new(
+    [
+        256,
+        512,
+        1024,
+        2 * 1024,
+        4 * 1024,
+        8 * 1024,
+        16 * 1024,
+        32 * 1024,
+        64 * 1024,
+        128 * 1024,
+        256 * 1024,
+        512 * 1024,
+        1024 * 1024,
+    ],
+    1,
+);
+
+/// Buckets of the latencies in microseconds, 50 µs to 5 s
+const LATENCY_BOUNDS: [u64; 16] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000,
+];
+
+/// Microseconds from the start of a save to the event being archived
+pub static SAVE_LATENCY: Histogram<16> = Histogram::new(LATENCY_BOUNDS, 1_000_000);
+
+/// Microseconds of the archive write of a save, the event index insert and file append
+pub static WRITE_LATENCY: Histogram<16> = Histogram::new(LATENCY_BOUNDS, 1_000_000);
+
+/// Milliseconds per GB of uncompressed input of the archive compressions nostrhole runs
+pub static COMPRESSION_RATE: Histogram<10> = Histogram::new(
+    [
+        1_000, 2_000, 5_000, 10_000, 20_000, 30_000, 60_000, 120_000, 300_000, 600_000,
+    ],
+    1_000,
+);
+
+/// Buckets over fixed upper bounds, recorded with atomic adds only
+pub struct Histogram<const N: usize> {
+    /// Upper bound of each bucket in the recorded unit
+    bounds: [u64; N],
+    /// Values per bucket, not cumulative
+    counts: [AtomicU64; N],
+    /// Values over the last bound
+    over: AtomicU64,
+    sum: AtomicU64,
+    /// Recorded units per exposed unit, eg. 1000000 for microseconds exposed as seconds
+    scale: u64,
+}
+
+impl<const N: usize> Histogram<N> {
+    pub const fn new(bounds: [u64; N], scale: u64) -> Self {
+        Self {
+            bounds,
+            counts: [const { AtomicU64::new(0) }; N],
+            over: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            scale,
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        let i = self.bounds.partition_point(|b| *b < value);
+        match self.counts.get(i) {
+            Some(c) => c.fetch_add(1, Ordering::Relaxed),
+            None => self.over.fetch_add(1, Ordering::Relaxed),
+        };
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn record_duration(&self, d: Duration) {
+        self.record(d.as_micros().min(u64::MAX as u128) as u64);
+    }
+
+    /// Upper bound of the bucket holding quantile `q` in the exposed unit, infinite when
+    /// it is over the last bound; None before anything is recorded
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let counts = self.counts.each_ref().map(|c| c.load(Ordering::Relaxed));
+        let total = counts.iter().sum::<u64>() + self.over.load(Ordering::Relaxed);
+        if total == 0 {
+            return None;
+        }
+        let rank = (q * total as f64).ceil() as u64;
+        let mut seen = 0;
+        for (b, c) in self.bounds.iter().zip(counts) {
+            seen += c;
+            if seen >= rank {
+                return Some(*b as f64 / self.scale as f64);
+            }
+        }
+        Some(f64::INFINITY)
+    }
+
+    /// OpenMetrics lines of the histogram `name`
+    fn write(&self, out: &mut String, name: &str, unit: &str, help: &str) {
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let _ = writeln!(out, "# UNIT {} {}", name, unit);
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let mut total = 0;
+        for (b, c) in self.bounds.iter().zip(&self.counts) {
+            total += c.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{:?}\"}} {}",
+                name,
+                *b as f64 / self.scale as f64,
+                total
+            );
+        }
+        total += self.over.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, total);
+        let _ = writeln!(
+            out,
+            "{}_sum {:?}",
+            name,
+            self.sum.load(Ordering::Relaxed) as f64 / self.scale as f64
+        );
+        let _ = writeln!(out, "{}_count {}", name, total);
+    }
+}
+
+/// Counts the bytes written to it
+struct ByteCount(u64);
+
+impl std::io::Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Record the serialized size of a saved event, without building its json
+pub fn record_event_size(event: &Event) {
+    let mut n = ByteCount(0);
+    if serde_json::to_writer(&mut n, event).is_ok() {
+        EVENT_SIZE.record(n.0);
+    }
+}
+
+/// Record a compression of `bytes` uncompressed bytes that took `elapsed`
+pub fn record_compression(bytes: u64, elapsed: Duration) {
+    if bytes >= MIN_COMPRESSED_BYTES {
+        COMPRESSION_RATE.record((elapsed.as_millis() as u64).saturating_mul(1_000_000_000) / bytes);
+    }
+}
+
+/// Every histogram in the OpenMetrics text format
+pub fn render() -> String {
+    let mut out = String::new();
+    EVENT_SIZE.write(
+        &mut out,
+        "nostrhole_event_size_bytes",
+        "bytes",
+        "Serialized size of saved events.",
+    );
+    SAVE_LATENCY.write(
+        &mut out,
+        "nostrhole_save_latency_seconds",
+        "seconds",
+        "Time from the start of a save to the event being archived.",
+    );
+    WRITE_LATENCY.write(
+        &mut out,
+        "nostrhole_write_latency_seconds",
+        "seconds",
+        "Time of the event index insert and archive append of a save.",
+    );
+    COMPRESSION_RATE.write(
+        &mut out,
+        "nostrhole_compression_seconds_per_gigabyte",
+        "seconds_per_gigabyte",
+        "Archive compression time per GB of uncompressed input.",
+    );
+    out.push_str("# EOF\n");
+    out
+}
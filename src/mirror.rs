@@ -0,0 +1,217 @@
+use crate::db::{ArchiveDatabase, is_archive, sha256_file, sidecar_path};
+use crate::downloads::DownloadCount;
+use crate::fetch::http_get;
+use crate::layout::flat_name;
+use crate::scan::is_reserved_dir;
+use anyhow::{Result, bail};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use url::Url;
+
+/// Marker file asking for an index rebuild on next startup
+pub const REINDEX_MARKER: &str = ".reindex";
+
+#[derive(Deserialize, Clone)]
+pub struct MirrorSettings {
+    /// Base url of the hole instance to mirror
+    pub upstream: String,
+
+    /// Minutes between checking upstream for new files
+    pub interval_minutes: Option<u64>,
+
+    /// Bearer token if upstream requires download auth
+    pub token: Option<String>,
+}
+
+/// Archive file entry as served by `/api/files`
+#[derive(Serialize, Deserialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: u64,
+    pub timestamp: i64,
+    pub sha256: Option<String>,
+    /// Only served when downloads are counted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<DownloadCount>,
+    /// Encrypted to the recipients of the serving instance, it is mirrored as it is
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+}
+
+/// Periodically download missing or changed archive files from another hole instance
+pub async fn run_mirror(db: ArchiveDatabase, settings: MirrorSettings) -> Result<()> {
+    let mut upstream = Url::parse(&settings.upstream)?;
+    if !upstream.path().ends_with('/') {
+        upstream.set_path(&format!("{}/", upstream.path()));
+    }
+    let interval = Duration::from_secs(settings.interval_minutes.unwrap_or(60) * 60);
+    let auth: Vec<(&str, String)> = settings
+        .token
+        .iter()
+        .map(|t| ("authorization", format!("Bearer {}", t)))
+        .collect();
+    loop {
+        match mirror_files(&db, &upstream, &auth).await {
+            Ok(0) => {}
+            Ok(n) => {
+                info!(
+                    "Mirrored {} new archive files, index will be rebuilt on restart",
+                    n
+                );
+                tokio::fs::write(db.out_dir().join(REINDEX_MARKER), b"").await?;
+                db.invalidate_archives().await;
+            }
+            Err(e) => error!("Mirror from {} failed: {}", upstream, e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// List upstream files, falling back to scraping links from the landing page
+async fn list_upstream(upstream: &Url, auth: &[(&str, String)]) -> Result<Vec<FileEntry>> {
+    let rsp = http_get(&upstream.join("api/files")?, auth).await?;
+    if rsp.status == 200
+        && let Ok(files) = serde_json::from_str::<Vec<FileEntry>>(&rsp.text().await?)
+    {
+        return Ok(files);
+    }
+
+    warn!("{} has no /api/files, scraping landing page", upstream);
+    let rsp = http_get(upstream, auth).await?;
+    if rsp.status != 200 {
+        bail!("Landing page returned {}", rsp.status);
+    }
+    Ok(rsp
+        .text()
+        .await?
+        .split("href=\"")
+        .skip(1)
+        .filter_map(|s| s.split('"').next())
+        .map(|name| FileEntry {
+            name: name.to_string(),
+            size: 0,
+            timestamp: 0,
+            sha256: None,
+            downloads: None,
+            encrypted: name.ends_with(".enc"),
+        })
+        .collect())
+}
+
+/// Returns the number of files downloaded
+async fn mirror_files(
+    db: &ArchiveDatabase,
+    upstream: &Url,
+    auth: &[(&str, String)],
+) -> Result<usize> {
+    let partial_dir = db.out_dir().join(".mirror");
+    tokio::fs::create_dir_all(&partial_dir).await?;
+
+    let mut downloaded = 0;
+    for file in list_upstream(upstream, auth).await? {
+        // only plain archive names or `partition/name`, either in a `YYYY/MM` directory,
+        // never anything that could escape out_dir or a magnet link
+        let flat = flat_name(&file.name);
+        if file.name.contains(['\\', ':', '?'])
+            || flat.split('/').count() > if db.is_partitioned() { 2 } else { 1 }
+            || file
+                .name
+                .split('/')
+                .any(|p| p.is_empty() || p.starts_with('.'))
+            || !is_archive(Path::new(&file.name))
+            || file.name.split('/').next().is_some_and(is_reserved_dir)
+        {
+            continue;
+        }
+        // without a checksum we can't tell if the file is complete, skip the active file
+        if file.sha256.is_none() && file.name.ends_with(".jsonl") {
+            continue;
+        }
+
+        // stored where the local layout keeps it, whatever layout upstream uses
+        let local = db.layout().place(&db.out_dir().join(&flat));
+        if let Ok(meta) = tokio::fs::metadata(&local).await {
+            let Some(sha256) = &file.sha256 else {
+                continue;
+            };
+            if meta.len() > file.size {
+                warn!(
+                    "Local {} is larger than upstream ({} > {}), not overwriting",
+                    file.name,
+                    meta.len(),
+                    file.size
+                );
+                continue;
+            }
+            if meta.len() == file.size
+                && let Ok(f) = db.get_file(&format!("/{}", db.archive_name(&local)))
+                && db.checksum(&f).await?.as_ref() == Some(sha256)
+            {
+                continue;
+            }
+        }
+
+        if let Err(e) = download(upstream, auth, &file, &partial_dir, &local).await {
+            warn!("Failed to mirror {}: {}", file.name, e);
+        } else {
+            info!("Mirrored {}", file.name);
+            downloaded += 1;
+        }
+    }
+    Ok(downloaded)
+}
+
+/// Download a file into the partial dir, resuming if possible, then move it into place
+async fn download(
+    upstream: &Url,
+    auth: &[(&str, String)],
+    file: &FileEntry,
+    partial_dir: &Path,
+    dst: &Path,
+) -> Result<()> {
+    let partial = partial_dir.join(format!("{}.part", file.name.replace('/', "_")));
+    let mut start = tokio::fs::metadata(&partial)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+    if file.size > 0 && start >= file.size {
+        start = 0;
+    }
+
+    let mut headers = auth.to_vec();
+    if start > 0 {
+        headers.push(("range", format!("bytes={}-", start)));
+    }
+    let mut rsp = http_get(&upstream.join(&file.name)?, &headers).await?;
+    let mut out = match rsp.status {
+        206 => OpenOptions::new().append(true).open(&partial).await?,
+        200 => tokio::fs::File::create(&partial).await?,
+        s => bail!("Upstream returned {}", s),
+    };
+    tokio::io::copy(&mut rsp.body, &mut out).await?;
+    out.sync_all().await?;
+    drop(out);
+
+    let actual = sha256_file(partial.clone()).await?;
+    if let Some(expected) = &file.sha256
+        && actual != *expected
+    {
+        tokio::fs::remove_file(&partial).await?;
+        bail!("Checksum mismatch {} != {}", actual, expected);
+    }
+    if let Some(parent) = dst.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::rename(&partial, dst).await?;
+    // mirrored archives keep the upstream bytes, the sidecar also stops them being rewritten
+    let name = dst.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    tokio::fs::write(
+        sidecar_path(dst, "sha256"),
+        format!("{}  {}\n", actual, name),
+    )
+    .await?;
+    Ok(())
+}
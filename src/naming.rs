@@ -0,0 +1,276 @@
+use crate::layout::is_date_dir;
+use anyhow::{Result, bail};
+use chrono::NaiveDate;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Name prefix of the archives the writer creates, `events_YYYYMMDD.jsonl`
+const WRITER_PREFIX: &str = "events_";
+
+/// `{partition}` of archives outside a partition directory
+const NO_PARTITION: &str = "all";
+
+/// `{part}` of the writer's own archive of a day, imports and merges have their id
+const MAIN_PART: &str = "0";
+
+static TEMPLATE: OnceLock<FileNameTemplate> = OnceLock::new();
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Date,
+    Instance,
+    Partition,
+    Part,
+}
+
+/// Names of finalized archives from `file_name_template`, eg. `{instance}_events_{date}`
+/// names `events_20250101.jsonl.zst` of instance `eu1` `eu1_events_20250101.jsonl.zst`
+#[derive(Debug)]
+pub struct FileNameTemplate {
+    segments: Vec<Segment>,
+    instance: String,
+    /// Archives in its directories are in a partition
+    out_dir: PathBuf,
+}
+
+/// Archive named by the writer, `events_YYYYMMDD[.<part>].<rest>`
+struct WriterName<'a> {
+    day: &'a str,
+    part: Option<&'a str>,
+    /// Extensions from `jsonl` on
+    rest: &'a str,
+}
+
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    if s.len() != 8 || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    NaiveDate::parse_from_str(s, "%Y%m%d").ok()
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+fn writer_name(name: &str) -> Option<WriterName<'_>> {
+    let name = name.strip_prefix(WRITER_PREFIX)?;
+    let day = name.get(..8)?;
+    parse_date(day)?;
+    let rest = name[8..].strip_prefix('.')?;
+    match rest.split_once('.') {
+        Some((part, _)) if part != "jsonl" => Some(WriterName {
+            day,
+            part: Some(part),
+            rest: &rest[part.len() + 1..],
+        }),
+        _ => Some(WriterName {
+            day,
+            part: None,
+            rest,
+        }),
+    }
+}
+
+impl FileNameTemplate {
+    /// Placeholders are `{date}` (YYYYMMDD, required), `{instance}`, `{partition}` and
+    /// `{part}`, the rest may only use letters, digits, `-` and `_`
+    pub fn parse(template: &str, instance: Option<&str>, out_dir: &Path) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('{') {
+                let Some((name, after)) = after.split_once('}') else {
+                    bail!("file_name_template: unclosed {{ in {}", template);
+                };
+                let s = match name {
+                    "date" => Segment::Date,
+                    "instance" => Segment::Instance,
+                    "partition" => Segment::Partition,
+                    "part" => Segment::Part,
+                    _ => bail!("file_name_template: unknown placeholder {{{}}}", name),
+                };
+                if segments.contains(&s) {
+                    bail!("file_name_template: {{{}}} is used twice", name);
+                }
+                segments.push(s);
+                rest = after;
+            } else {
+                let end = rest.find('{').unwrap_or(rest.len());
+                let literal = &rest[..end];
+                if !literal.chars().all(is_name_char) {
+                    bail!(
+                        "file_name_template: {} may only use letters, digits, - and _ besides placeholders",
+                        template
+                    );
+                }
+                segments.push(Segment::Literal(literal.to_string()));
+                rest = &rest[end..];
+            }
+        }
+        if !segments.contains(&Segment::Date) {
+            bail!("file_name_template: {{date}} is required");
+        }
+        let instance = match instance {
+            Some(i) if !i.is_empty() && i.chars().all(is_name_char) => i.to_string(),
+            Some(i) => bail!("instance_name: {} may only use letters, digits, - and _", i),
+            None if segments.contains(&Segment::Instance) => {
+                bail!("file_name_template: {{instance}} needs instance_name")
+            }
+            None => String::new(),
+        };
+        Ok(Self {
+            segments,
+            instance,
+            out_dir: out_dir.to_path_buf(),
+        })
+    }
+
+    fn has_part(&self) -> bool {
+        self.segments.contains(&Segment::Part)
+    }
+
+    /// Stem of the archive of `day` (YYYYMMDD)
+    fn stem(&self, day: &str, partition: Option<&str>, part: Option<&str>) -> String {
+        self.segments
+            .iter()
+            .map(|s| match s {
+                Segment::Literal(l) => l.as_str(),
+                Segment::Date => day,
+                Segment::Instance => &self.instance,
+                Segment::Partition => partition.unwrap_or(NO_PARTITION),
+                Segment::Part => part.unwrap_or(MAIN_PART),
+            })
+            .collect()
+    }
+
+    /// Name of the writer's archive of `day` (YYYYMMDD) with extensions `rest`
+    pub fn file_name(&self, day: &str, partition: Option<&str>, rest: &str) -> String {
+        format!("{}.{}", self.stem(day, partition, None), rest)
+    }
+
+    /// Templated name of an archive named by the writer, None for other names
+    pub fn rename(&self, name: &str, partition: Option<&str>) -> Option<String> {
+        let w = writer_name(name)?;
+        let mut ret = self.stem(w.day, partition, w.part);
+        if let (false, Some(part)) = (self.has_part(), w.part) {
+            ret.push('.');
+            ret.push_str(part);
+        }
+        ret.push('.');
+        ret.push_str(w.rest);
+        Some(ret)
+    }
+
+    /// Writer's name of an archive named by the template, None for other names
+    pub fn writer(&self, name: &str, partition: Option<&str>) -> Option<String> {
+        let (stem, rest) = name.split_once('.')?;
+        let (part, rest) = match rest.split_once('.') {
+            Some((p, r)) if !self.has_part() && p != "jsonl" => (Some(p), r),
+            _ => (None, rest),
+        };
+        let mut found = Found::default();
+        if !self.matches(stem, 0, partition, &mut found) {
+            return None;
+        }
+        let part = part.or(found.part.filter(|p| *p != MAIN_PART));
+        Some(match part {
+            Some(p) => format!("{}{}.{}.{}", WRITER_PREFIX, found.day?, p, rest),
+            None => format!("{}{}.{}", WRITER_PREFIX, found.day?, rest),
+        })
+    }
+
+    /// Partition of the archives in `dir`, or in the `YYYY/MM` directories of `dir`;
+    /// None for out_dir
+    pub fn partition_dir(&self, dir: &Path) -> Option<String> {
+        let rel = dir.strip_prefix(&self.out_dir).ok()?;
+        let parts: Vec<&str> = rel.iter().filter_map(|c| c.to_str()).collect();
+        match parts[..] {
+            [p] | [p, _, _] if !is_date_dir(p) => Some(p.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Match `s` against the segments from `i` on, filling in the date and part
+    fn matches<'a>(
+        &self,
+        s: &'a str,
+        i: usize,
+        partition: Option<&str>,
+        found: &mut Found<'a>,
+    ) -> bool {
+        let Some(segment) = self.segments.get(i) else {
+            return s.is_empty();
+        };
+        let fixed = match segment {
+            Segment::Literal(l) => Some(l.as_str()),
+            Segment::Instance => Some(self.instance.as_str()),
+            Segment::Partition => Some(partition.unwrap_or(NO_PARTITION)),
+            Segment::Date => {
+                let Some(day) = s.get(..8).filter(|d| parse_date(d).is_some()) else {
+                    return false;
+                };
+                found.day = Some(day);
+                return self.matches(&s[8..], i + 1, partition, found);
+            }
+            Segment::Part => None,
+        };
+        if let Some(f) = fixed {
+            return s
+                .strip_prefix(f)
+                .is_some_and(|r| self.matches(r, i + 1, partition, found));
+        }
+        // part ids are letters, digits and -, the shortest that lets the rest match
+        for end in 1..=s.len() {
+            if !s[..end]
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                break;
+            }
+            if self.matches(&s[end..], i + 1, partition, found) {
+                found.part = Some(&s[..end]);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[derive(Default)]
+struct Found<'a> {
+    day: Option<&'a str>,
+    part: Option<&'a str>,
+}
+
+/// Use `template` for the names of finalized archives
+pub fn set_template(template: FileNameTemplate) {
+    let _ = TEMPLATE.set(template);
+}
+
+pub fn template() -> Option<&'static FileNameTemplate> {
+    TEMPLATE.get()
+}
+
+/// Day of an archive named by the writer or the template; names of an earlier template
+/// are dated by the first YYYYMMDD in their stem
+pub fn archive_day(path: &Path) -> Option<NaiveDate> {
+    let name = path.file_name()?.to_str()?;
+    if let Some(w) = writer_name(name) {
+        return parse_date(w.day);
+    }
+    if let Some(t) = template()
+        && let Some(w) = t.writer(name, t.partition_dir(path.parent()?).as_deref())
+    {
+        return writer_name(&w).and_then(|w| parse_date(w.day));
+    }
+    let stem = name.split('.').next()?;
+    let chars: Vec<(usize, char)> = stem.char_indices().collect();
+    chars.windows(8).find_map(|w| {
+        let (start, end) = (w[0].0, w[7].0 + 1);
+        let alone = |c: Option<char>| c.is_none_or(|c| !c.is_ascii_digit());
+        (alone(stem[..start].chars().next_back()) && alone(stem[end..].chars().next()))
+            .then(|| parse_date(&stem[start..end]))
+            .flatten()
+    })
+}
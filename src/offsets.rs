@@ -0,0 +1,125 @@
+use crate::db::open_archive;
+use crate::layout::nested_dir;
+use crate::naming::template;
+use crate::prune;
+use crate::rocks;
+use crate::seekable;
+use crate::writer::Written;
+use anyhow::{Result, anyhow};
+use chrono::NaiveDate;
+use log::warn;
+use nostr_sdk::prelude::JsonUtil;
+use nostr_sdk::{Event, EventId};
+use rocksdb::DB;
+use std::io::{BufRead, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Furthest into a compressed archive a lookup will decompress
+const MAX_COMPRESSED_SKIP: u64 = 256 * 1024 * 1024;
+
+/// Index of event id -> archive day + byte offset of its line, followed by the
+/// partition directory when archives are partitioned
+#[derive(Clone)]
+pub struct EventOffsets {
+    database: Arc<DB>,
+    /// Directory the archives are written to
+    dir: PathBuf,
+}
+
+impl EventOffsets {
+    pub fn open(path: &Path, dir: PathBuf) -> Result<Self> {
+        let db = rocks::open(path)?;
+        Ok(Self {
+            database: Arc::new(db),
+            dir,
+        })
+    }
+
+    /// Forget every recorded offset
+    pub fn clear(&self) -> Result<u64> {
+        prune::clear(&self.database)
+    }
+
+    /// Forget the offsets of `ids`
+    pub fn remove(&self, ids: &[[u8; 32]]) -> Result<()> {
+        prune::remove_ids(&self.database, ids)
+    }
+
+    /// Record where the writer put `event`
+    pub fn record(&self, event: &Event, partition: Option<&str>, written: Written) {
+        let mut value = Vec::with_capacity(12);
+        value.extend_from_slice(&written.day.to_be_bytes());
+        value.extend_from_slice(&written.offset.to_be_bytes());
+        value.extend_from_slice(partition.unwrap_or_default().as_bytes());
+        if let Err(e) = self.database.put(event.id.as_bytes(), value) {
+            warn!("Failed to record event offset: {}", e);
+        }
+    }
+
+    /// Read an event from the archives, None when it was never indexed
+    ///
+    /// Blocking, compressed archives are decompressed from the frame holding the event
+    /// when seekable, otherwise from the start
+    pub fn read(&self, id: &EventId) -> Result<Option<Event>> {
+        let Some(v) = self.database.get(id.as_bytes()).map_err(|e| anyhow!(e))? else {
+            return Ok(None);
+        };
+        if v.len() < 12 {
+            return Ok(None);
+        }
+        let day = u32::from_be_bytes(v[..4].try_into()?);
+        let offset = u64::from_be_bytes(v[4..12].try_into()?);
+        let partition = Some(std::str::from_utf8(&v[12..])?).filter(|p| !p.is_empty());
+        let dir = match partition {
+            None => self.dir.clone(),
+            Some(p) => self.dir.join(p),
+        };
+
+        // finalized archives may have been renamed by file_name_template
+        let names: Vec<String> = [format!("events_{}.jsonl", day)]
+            .into_iter()
+            .chain(template().map(|t| t.file_name(&day.to_string(), partition, "jsonl")))
+            .collect();
+        // finalized archives may have been moved into the nested layout
+        let dirs: Vec<PathBuf> = NaiveDate::parse_from_str(&day.to_string(), "%Y%m%d")
+            .ok()
+            .map(|d| dir.join(nested_dir(d)))
+            .into_iter()
+            .chain([dir])
+            .collect();
+        let plain = dirs
+            .iter()
+            .flat_map(|d| names.iter().map(|n| d.join(n)))
+            .find(|p| p.exists());
+        let mut reader = if let Some(plain) = plain {
+            let mut f = std::fs::File::open(&plain)?;
+            f.seek(SeekFrom::Start(offset))?;
+            Box::new(std::io::BufReader::new(f)) as Box<dyn BufRead + Send>
+        } else {
+            let Some(path) = dirs
+                .iter()
+                .flat_map(|d| names.iter().map(move |n| (d, n)))
+                .flat_map(|(d, n)| ["zst", "gz", "bz2"].map(|ext| d.join(format!("{}.{}", n, ext))))
+                .find(|p| p.exists())
+            else {
+                return Ok(None);
+            };
+            match seekable::open_at(&path, offset)? {
+                Some(r) => r,
+                None if offset > MAX_COMPRESSED_SKIP => return Ok(None),
+                None => {
+                    let mut r = open_archive(&path)?;
+                    std::io::copy(&mut r.by_ref().take(offset), &mut std::io::sink())?;
+                    r
+                }
+            }
+        };
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        Ok(Event::from_json(line.trim_end())
+            .ok()
+            .filter(|e| e.id == *id))
+    }
+}
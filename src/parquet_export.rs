@@ -0,0 +1,296 @@
+use crate::db::decode_archive_strict;
+use crate::deletions::Tombstones;
+use crate::jobs::{JOB_BUFFER, Scanned, scan_files};
+use crate::scan::scan_lines;
+use anyhow::{Result, anyhow, bail};
+use arrow::array::{ArrayRef, Int32Builder, ListBuilder, StringBuilder, TimestampSecondBuilder};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use nostr_sdk::Event;
+use nostr_sdk::prelude::JsonUtil;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Rows buffered before they are written as a record batch
+const BATCH_ROWS: usize = 65_536;
+
+/// Rows per row group, bounds what the writer holds in memory
+const ROW_GROUP_ROWS: usize = 256 * 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Events written by [export]
+#[derive(Default)]
+pub struct ParquetExport {
+    pub archives: usize,
+    /// Parquet files written
+    pub files: usize,
+    pub rows: u64,
+    pub skipped: u64,
+    /// Left out by `tombstones`
+    pub deleted: u64,
+}
+
+fn schema() -> SchemaRef {
+    let string_list = DataType::List(Arc::new(Field::new("item", DataType::Utf8, true)));
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("pubkey", DataType::Utf8, false),
+        Field::new(
+            "created_at",
+            DataType::Timestamp(TimeUnit::Second, Some("UTC".into())),
+            false,
+        ),
+        Field::new("kind", DataType::Int32, false),
+        Field::new("content", DataType::Utf8, false),
+        Field::new("sig", DataType::Utf8, false),
+        Field::new(
+            "tags",
+            DataType::List(Arc::new(Field::new("item", string_list, true))),
+            false,
+        ),
+    ]))
+}
+
+/// Columns of the rows not yet written
+struct Columns {
+    id: StringBuilder,
+    pubkey: StringBuilder,
+    created_at: TimestampSecondBuilder,
+    kind: Int32Builder,
+    content: StringBuilder,
+    sig: StringBuilder,
+    tags: ListBuilder<ListBuilder<StringBuilder>>,
+    rows: usize,
+}
+
+impl Columns {
+    fn new() -> Self {
+        Self {
+            id: StringBuilder::with_capacity(BATCH_ROWS, BATCH_ROWS * 64),
+            pubkey: StringBuilder::with_capacity(BATCH_ROWS, BATCH_ROWS * 64),
+            created_at: TimestampSecondBuilder::with_capacity(BATCH_ROWS).with_timezone("UTC"),
+            kind: Int32Builder::with_capacity(BATCH_ROWS),
+            content: StringBuilder::new(),
+            sig: StringBuilder::with_capacity(BATCH_ROWS, BATCH_ROWS * 128),
+            tags: ListBuilder::new(ListBuilder::new(StringBuilder::new())),
+            rows: 0,
+        }
+    }
+
+    fn push(&mut self, ev: &Event) {
+        self.id.append_value(ev.id.to_hex());
+        self.pubkey.append_value(ev.pubkey.to_hex());
+        self.created_at.append_value(ev.created_at.as_secs() as i64);
+        self.kind.append_value(ev.kind.as_u16() as i32);
+        self.content.append_value(&ev.content);
+        self.sig.append_value(ev.sig.to_string());
+        for tag in ev.tags.iter() {
+            let values = self.tags.values();
+            for v in tag.as_slice() {
+                values.values().append_value(v);
+            }
+            values.append(true);
+        }
+        self.tags.append(true);
+        self.rows += 1;
+    }
+
+    /// Take the buffered rows as a record batch
+    fn finish(&mut self, schema: &SchemaRef) -> Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.id.finish()),
+            Arc::new(self.pubkey.finish()),
+            Arc::new(self.created_at.finish()),
+            Arc::new(self.kind.finish()),
+            Arc::new(self.content.finish()),
+            Arc::new(self.sig.finish()),
+            Arc::new(self.tags.finish()),
+        ];
+        self.rows = 0;
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+/// Parquet files written for one archive, split every `max_rows` when set
+struct Parts {
+    schema: SchemaRef,
+    /// Output path without the `.parquet` extension, eg. `out/kind-1/events_20250101`
+    base: PathBuf,
+    max_rows: Option<u64>,
+    writer: Option<ArrowWriter<File>>,
+    /// Rows in the open part
+    rows: u64,
+    written: Vec<PathBuf>,
+}
+
+impl Parts {
+    fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        let writer = match &mut self.writer {
+            Some(w) => w,
+            None => {
+                let path = match self.max_rows {
+                    Some(_) => self
+                        .base
+                        .with_extension(format!("{:04}.parquet", self.written.len() + 1)),
+                    None => self.base.with_extension("parquet"),
+                };
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(ZstdLevel::try_new(ZSTD_LEVEL)?))
+                    .set_max_row_group_size(ROW_GROUP_ROWS)
+                    .build();
+                let w =
+                    ArrowWriter::try_new(File::create(&path)?, self.schema.clone(), Some(props))?;
+                self.written.push(path);
+                self.rows = 0;
+                self.writer.insert(w)
+            }
+        };
+        writer.write(batch)?;
+        self.rows += batch.num_rows() as u64;
+        if self.max_rows.is_some_and(|m| self.rows >= m) {
+            self.close()?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if let Some(w) = self.writer.take() {
+            w.close()?;
+        }
+        Ok(())
+    }
+}
+
+/// Output path of `archive` in `out` without extension, keeping the partition directory
+/// and the date of the archive name
+fn output_base(out_dir: &Path, archive: &Path, out: &Path) -> Result<PathBuf> {
+    let rel = archive.strip_prefix(out_dir).unwrap_or(archive);
+    let name = rel
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or(anyhow!("Invalid archive name {}", archive.display()))?;
+    let stem = name.split(".jsonl").next().unwrap_or(name);
+    Ok(out.join(rel).with_file_name(stem))
+}
+
+/// Write the events of `files` (archives in `out_dir`) as zstd compressed Parquet files in
+/// `out`, one per archive or per `max_rows` rows; the rows of each file are checked against
+/// the events read from its archive; events deleted by their author are left out with
+/// `tombstones`
+///
+/// Archives are converted in parallel by [scan_files], each into its own files
+pub fn export(
+    out_dir: &Path,
+    files: &[PathBuf],
+    out: &Path,
+    max_rows: Option<u64>,
+    tombstones: Option<&Tombstones>,
+) -> Result<ParquetExport> {
+    let schema = schema();
+    let mut totals = ParquetExport::default();
+    scan_files::<(), _>(
+        files,
+        |path, _| export_file(out_dir, path, out, &schema, max_rows, tombstones),
+        |path, msg, progress| {
+            let Scanned::Done(res) = msg else {
+                return Ok(());
+            };
+            let (rows, written, skipped, deleted) = res?;
+            println!(
+                "{} {} {} rows in {} files, {} lines skipped",
+                progress,
+                path.display(),
+                rows,
+                written,
+                skipped
+            );
+            totals.archives += 1;
+            totals.files += written;
+            totals.rows += rows;
+            totals.skipped += skipped;
+            totals.deleted += deleted;
+            Ok(())
+        },
+    )?;
+    Ok(totals)
+}
+
+/// Write one archive, returns the rows, the number of Parquet files, the lines skipped and
+/// the deleted events left out
+fn export_file(
+    out_dir: &Path,
+    path: &Path,
+    out: &Path,
+    schema: &SchemaRef,
+    max_rows: Option<u64>,
+    tombstones: Option<&Tombstones>,
+) -> Result<(u64, usize, u64, u64)> {
+    let base = output_base(out_dir, path, out)?;
+    if let Some(dir) = base.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut parts = Parts {
+        schema: schema.clone(),
+        base,
+        max_rows: max_rows.filter(|m| *m > 0),
+        writer: None,
+        rows: 0,
+        written: Vec::new(),
+    };
+    let input = decode_archive_strict(
+        path,
+        BufReader::with_capacity(JOB_BUFFER, File::open(path)?),
+    )?;
+    let mut columns = Columns::new();
+    let mut failed = None;
+    let mut deleted = 0;
+    let scan = scan_lines(path, input, |line| {
+        let Ok(ev) = Event::from_json(line) else {
+            return false;
+        };
+        if failed.is_some() {
+            return true;
+        }
+        if tombstones.is_some_and(|t| t.is_deleted(&ev)) {
+            deleted += 1;
+            return true;
+        }
+        columns.push(&ev);
+        if columns.rows >= BATCH_ROWS
+            && let Err(e) = columns.finish(schema).and_then(|b| parts.write(&b))
+        {
+            failed = Some(e);
+        }
+        true
+    })?;
+    if let Some(e) = failed {
+        return Err(e);
+    }
+    if columns.rows > 0 {
+        parts.write(&columns.finish(schema)?)?;
+    }
+    parts.close()?;
+
+    let mut rows = 0;
+    for p in &parts.written {
+        let reader = SerializedFileReader::new(File::open(p)?)?;
+        rows += reader.metadata().file_metadata().num_rows() as u64;
+    }
+    if rows + deleted != scan.events {
+        bail!(
+            "{}: wrote {} rows and left out {} deleted for {} events",
+            path.display(),
+            rows,
+            deleted,
+            scan.events
+        );
+    }
+    Ok((rows, parts.written.len(), scan.skipped, deleted))
+}
@@ -0,0 +1,197 @@
+use crate::authors::AuthorIndex;
+use crate::index::{IndexBackend, REDB_DIR};
+use crate::kinds::{KindEntry, KindSet};
+use crate::layout::is_date_dir;
+use crate::scan::{self, is_reserved_dir};
+use crate::store::EventStore;
+use crate::times::TimeIndex;
+use crate::writer::WriterOptions;
+use anyhow::{Result, anyhow, bail};
+use dashmap::DashMap;
+use log::info;
+use nostr_sdk::Kind;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Partition for kinds not listed in a named mapping
+pub const MISC_PARTITION: &str = "misc";
+
+/// `partition_by_kind: true` for a `kind-<n>` directory per kind, or a list of
+/// named kind ranges
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum PartitionSettings {
+    PerKind(bool),
+    Named(Vec<PartitionEntry>),
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PartitionEntry {
+    /// Directory name under `out_dir`
+    pub name: String,
+    pub kinds: Vec<KindEntry>,
+}
+
+impl PartitionSettings {
+    /// Check the partition names and kinds
+    pub fn validate(&self) -> Result<()> {
+        if let PartitionSettings::Named(entries) = self {
+            parse_named(entries)?;
+        }
+        Ok(())
+    }
+}
+
+/// Named partitions and their kinds
+fn parse_named(entries: &[PartitionEntry]) -> Result<Vec<(String, KindSet)>> {
+    let mut named = Vec::with_capacity(entries.len());
+    for e in entries {
+        if e.name.is_empty()
+            || e.name == "index"
+            || e.name == REDB_DIR
+            || is_reserved_dir(&e.name)
+            || e.name.starts_with('.')
+            || is_date_dir(&e.name)
+            || e.name.contains(['/', '\\', ':', '?'])
+        {
+            bail!("Invalid partition name {}", e.name);
+        }
+        named.push((e.name.clone(), KindSet::parse(&e.kinds)?));
+    }
+    Ok(named)
+}
+
+/// Archives split into subdirectories of `out_dir` by kind, each written,
+/// rotated and compressed by its own [EventStore]
+#[derive(Clone)]
+pub struct Partitions {
+    out_dir: PathBuf,
+    /// Named partitions checked in order, None for one partition per kind
+    named: Option<Arc<Vec<(String, KindSet)>>>,
+    dbs: Arc<DashMap<String, EventStore>>,
+    backend: IndexBackend,
+    options: WriterOptions,
+}
+
+impl Partitions {
+    /// None when partitioning is disabled
+    pub fn new(
+        settings: PartitionSettings,
+        out_dir: PathBuf,
+        backend: IndexBackend,
+        options: WriterOptions,
+    ) -> Result<Option<Self>> {
+        let named = match settings {
+            PartitionSettings::PerKind(false) => return Ok(None),
+            PartitionSettings::PerKind(true) => None,
+            PartitionSettings::Named(entries) => Some(Arc::new(parse_named(&entries)?)),
+        };
+        Ok(Some(Self {
+            out_dir,
+            named,
+            dbs: Arc::new(DashMap::new()),
+            backend,
+            options,
+        }))
+    }
+
+    /// Open existing partitions, rebuilding indexes that are empty or when `reindex` is set,
+    /// files with skipped lines are added to `damaged`; `authors` is rebuilt with them and
+    /// broken indexes are moved aside and rebuilt with `recover`
+    pub async fn open(
+        &self,
+        reindex: bool,
+        damaged: &mut u64,
+        authors: Option<&AuthorIndex>,
+        times: &TimeIndex,
+        recover: bool,
+    ) -> Result<()> {
+        let mut dir = tokio::fs::read_dir(&self.out_dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let Some(name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+                continue;
+            };
+            if !entry.file_type().await?.is_dir() || !self.is_partition(&name) {
+                continue;
+            }
+            let (mut db, moved) =
+                scan::open_database(&entry.path(), recover, self.backend, self.options.clone())?;
+            if reindex
+                || moved.is_some()
+                || (db.is_index_empty()? && !db.list_files().await?.is_empty())
+            {
+                info!("Rebuilding index of partition {}....", name);
+                *damaged += scan::rebuild_index(&mut db, &entry.path(), authors, times)?;
+            }
+            if let Some(m) = moved {
+                scan::compare_recovered(&db, &m);
+            }
+            self.dbs.insert(name, db);
+        }
+        Ok(())
+    }
+
+    fn is_partition(&self, name: &str) -> bool {
+        match &self.named {
+            Some(n) => name == MISC_PARTITION || n.iter().any(|(p, _)| p == name),
+            None => name
+                .strip_prefix("kind-")
+                .is_some_and(|k| k.parse::<u16>().is_ok()),
+        }
+    }
+
+    /// Partition directory name for a kind
+    pub fn name_for(&self, kind: Kind) -> String {
+        match &self.named {
+            Some(n) => n
+                .iter()
+                .find(|(_, k)| k.contains(kind))
+                .map(|(p, _)| p.clone())
+                .unwrap_or(MISC_PARTITION.to_string()),
+            None => format!("kind-{}", kind.as_u16()),
+        }
+    }
+
+    /// Can partition `name` hold events of `kinds`, true for unknown names
+    pub fn may_hold(&self, name: &str, kinds: &KindSet) -> bool {
+        match &self.named {
+            // misc gets the kinds no named partition lists
+            Some(n) if name == MISC_PARTITION => !n
+                .iter()
+                .fold(kinds.clone(), |rest, (_, k)| rest.difference(k))
+                .is_empty(),
+            Some(n) => n
+                .iter()
+                .find(|(p, _)| p == name)
+                .is_none_or(|(_, k)| k.intersects(kinds)),
+            None => name
+                .strip_prefix("kind-")
+                .and_then(|k| k.parse::<u16>().ok())
+                .is_none_or(|k| kinds.contains(Kind::from(k))),
+        }
+    }
+
+    /// Database writing events of `kind`, created on first use
+    pub fn get(&self, kind: Kind) -> Result<(String, EventStore)> {
+        let name = self.name_for(kind);
+        let db = self
+            .dbs
+            .entry(name.clone())
+            .or_try_insert_with(|| {
+                EventStore::open(self.out_dir.join(&name), self.backend, self.options.clone())
+            })
+            .map_err(|e| anyhow!("Failed to open partition {}: {}", name, e))?
+            .clone();
+        Ok((name, db))
+    }
+
+    pub fn all(&self) -> Vec<EventStore> {
+        self.dbs.iter().map(|e| e.value().clone()).collect()
+    }
+
+    /// Directory names of the opened partitions
+    pub fn names(&self) -> Vec<String> {
+        self.dbs.iter().map(|e| e.key().clone()).collect()
+    }
+}
@@ -0,0 +1,414 @@
+use crate::policy::PolicyChain;
+use anyhow::{Result, bail};
+use dashmap::DashMap;
+use futures_util::{SinkExt, StreamExt};
+use log::debug;
+use nostr_relay_builder::LocalRelay;
+use nostr_relay_builder::prelude::{
+    ClientMessage, MachineReadablePrefix, PolicyResult, RelayMessage, WritePolicy,
+};
+use nostr_sdk::prelude::BoxedFuture;
+use nostr_sdk::{Event, JsonUtil, Kind, PublicKey, SubscriptionId, Timestamp};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+/// Connections counted by the public event limit before ones outside the window are dropped
+const MAX_TRACKED: usize = 10_000;
+
+/// Bytes in flight between the authenticating proxy and the relay
+const PROXY_BUFFER: usize = 64 * 1024;
+
+/// Seconds an AUTH event's created_at may be off, same as the relay's own NIP-42 check
+const AUTH_MAX_AGE: u64 = 120;
+
+tokio::task_local! {
+    /// Pubkey the websocket connection authenticated as (NIP-42), set by [serve_authenticated]
+    static AUTHENTICATED: Arc<OnceLock<PublicKey>>;
+}
+
+/// Events saved from websocket clients
+#[derive(Serialize)]
+pub struct RelayWrites {
+    pub peer: u64,
+    pub public: u64,
+}
+
+/// Relays allowed to push everything they have over the websocket, by IP or by the pubkey
+/// their connection authenticated as with NIP-42
+#[derive(Clone, Debug, Default)]
+pub struct TrustedPeers {
+    ips: Arc<HashSet<IpAddr>>,
+    pubkeys: Arc<HashSet<PublicKey>>,
+    peer: Arc<AtomicU64>,
+    public: Arc<AtomicU64>,
+}
+
+impl TrustedPeers {
+    pub fn parse(peers: &[String]) -> Result<Self> {
+        let mut ips = HashSet::new();
+        let mut pubkeys = HashSet::new();
+        for p in peers {
+            if let Ok(ip) = p.parse::<IpAddr>() {
+                ips.insert(ip);
+            } else if let Ok(pk) = PublicKey::parse(p) {
+                pubkeys.insert(pk);
+            } else {
+                bail!("trusted_peers: {} is not an IP address or pubkey", p);
+            }
+        }
+        Ok(Self {
+            ips: Arc::new(ips),
+            pubkeys: Arc::new(pubkeys),
+            ..Default::default()
+        })
+    }
+
+    /// Are peers given by pubkey, websockets then go through [serve_authenticated]
+    pub fn has_pubkeys(&self) -> bool {
+        !self.pubkeys.is_empty()
+    }
+
+    /// Is the connection from `ip`, or authenticated as, a trusted peer
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers on a dual stack listener show up as mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            _ => ip,
+        };
+        self.ips.contains(&ip)
+            || AUTHENTICATED
+                .try_with(|a| a.get().is_some_and(|pk| self.pubkeys.contains(pk)))
+                .unwrap_or(false)
+    }
+
+    /// Count an event saved from a websocket client
+    pub fn record(&self, ip: IpAddr) {
+        let counter = if self.contains(ip) {
+            &self.peer
+        } else {
+            &self.public
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> RelayWrites {
+        RelayWrites {
+            peer: self.peer.load(Ordering::Relaxed),
+            public: self.public.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sends events of trusted peers through their own chain and everyone else's through the
+/// configured policies
+///
+/// The relay's own event limit is raised to the peer limit, so public connections are
+/// held to `notes_per_minute` here
+#[derive(Debug)]
+pub struct PeerPolicy {
+    peers: TrustedPeers,
+    peer: PolicyChain,
+    public: PolicyChain,
+    notes_per_minute: u32,
+    counters: DashMap<SocketAddr, (Instant, u32)>,
+}
+
+impl PeerPolicy {
+    pub fn new(
+        peers: TrustedPeers,
+        peer: PolicyChain,
+        public: PolicyChain,
+        notes_per_minute: u32,
+    ) -> Self {
+        Self {
+            peers,
+            peer,
+            public,
+            notes_per_minute,
+            counters: DashMap::new(),
+        }
+    }
+
+    /// Count an event of a public connection, returns false if it is over the limit
+    fn check(&self, addr: SocketAddr) -> bool {
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+        if self.counters.len() > MAX_TRACKED {
+            self.counters
+                .retain(|_, (start, _)| now.duration_since(*start) <= window);
+        }
+        let mut entry = self.counters.entry(addr).or_insert((now, 0));
+        if now.duration_since(entry.0) > window {
+            *entry = (now, 0);
+        }
+        if entry.1 >= self.notes_per_minute {
+            false
+        } else {
+            entry.1 += 1;
+            true
+        }
+    }
+}
+
+impl WritePolicy for PeerPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        if self.peers.contains(addr.ip()) {
+            return self.peer.admit_event(event, addr);
+        }
+        if !self.check(*addr) {
+            return Box::pin(async move {
+                PolicyResult::Reject("rate-limited: too many events".to_string())
+            });
+        }
+        self.public.admit_event(event, addr)
+    }
+}
+
+/// Is `event` a valid NIP-42 answer to `challenge`
+fn is_auth_for(event: &Event, challenge: &str) -> bool {
+    event.kind == Kind::Authentication
+        && event.tags.challenge() == Some(challenge)
+        && Timestamp::now()
+            .as_secs()
+            .abs_diff(event.created_at.as_secs())
+            <= AUTH_MAX_AGE
+        && event.verify().is_ok()
+}
+
+/// Hand the websocket `client` to `relay` through a proxy that sends a NIP-42 challenge
+/// on connect and answers it, so write policies can recognize peers by pubkey
+///
+/// The relay never sees the answer to this challenge, AUTH for challenges of its own
+/// (eg. for NIP-70 protected events) is passed through
+pub async fn serve_authenticated<S>(
+    relay: &LocalRelay,
+    client: S,
+    addr: SocketAddr,
+) -> Result<(), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (upstream, downstream) = tokio::io::duplex(PROXY_BUFFER);
+    let authenticated = Arc::new(OnceLock::new());
+    let proxy = async {
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Server, None).await;
+        let mut relay_ws = WebSocketStream::from_raw_socket(upstream, Role::Client, None).await;
+        let challenge = SubscriptionId::generate().to_string();
+        let msg = RelayMessage::Auth {
+            challenge: Cow::Borrowed(&challenge),
+        };
+        if client.send(Message::text(msg.as_json())).await.is_err() {
+            return;
+        }
+        loop {
+            tokio::select! {
+                m = client.next() => {
+                    let m = match m {
+                        Some(Ok(m @ (Message::Text(_) | Message::Binary(_) | Message::Close(_)))) => m,
+                        Some(Ok(_)) => continue,
+                        _ => break,
+                    };
+                    if let Message::Text(json) = &m
+                        && json.contains("AUTH")
+                        && let Ok(ClientMessage::Auth(event)) = ClientMessage::from_json(json.as_bytes())
+                        && event.tags.challenge() == Some(challenge.as_str())
+                    {
+                        let ok = is_auth_for(&event, &challenge)
+                            && authenticated.set(event.pubkey).is_ok();
+                        if ok {
+                            debug!("{} authenticated as {}", addr, event.pubkey);
+                        }
+                        let msg = RelayMessage::Ok {
+                            event_id: event.id,
+                            status: ok,
+                            message: Cow::Owned(if ok {
+                                String::new()
+                            } else {
+                                format!("{}: invalid auth", MachineReadablePrefix::AuthRequired)
+                            }),
+                        };
+                        if client.send(Message::text(msg.as_json())).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    let close = matches!(m, Message::Close(_));
+                    if relay_ws.send(m).await.is_err() || close {
+                        break;
+                    }
+                }
+                m = relay_ws.next() => {
+                    let m = match m {
+                        Some(Ok(m @ (Message::Text(_) | Message::Binary(_) | Message::Close(_)))) => m,
+                        Some(Ok(_)) => continue,
+                        _ => break,
+                    };
+                    let close = matches!(m, Message::Close(_));
+                    if client.send(m).await.is_err() || close {
+                        break;
+                    }
+                }
+            }
+        }
+        let _ = client.close(None).await;
+    };
+    // the relay's error isn't Send, so it is turned into a string before join! holds it
+    let served = async {
+        relay
+            .take_connection(downstream, addr)
+            .await
+            .map_err(|e| e.to_string())
+    };
+    let (res, ()) = AUTHENTICATED
+        .scope(authenticated.clone(), async { tokio::join!(served, proxy) })
+        .await;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::ReadOnlyPolicy;
+    use nostr_relay_builder::RelayBuilder;
+    use nostr_sdk::{EventBuilder, Keys, RelayUrl, ToBech32};
+
+    type Client = WebSocketStream<tokio::io::DuplexStream>;
+
+    /// Relay that accepts peer events and rejects everyone else's, connected to a client
+    async fn connect(peers: TrustedPeers) -> Client {
+        let policy = PeerPolicy::new(
+            peers,
+            PolicyChain::default(),
+            PolicyChain::default().with_policy("read_only", Box::new(ReadOnlyPolicy)),
+            100,
+        );
+        let relay = LocalRelay::new(RelayBuilder::default().write_policy(policy));
+        let (client, server) = tokio::io::duplex(PROXY_BUFFER);
+        tokio::spawn(async move {
+            let _ = serve_authenticated(&relay, server, "192.0.2.1:4000".parse().unwrap()).await;
+        });
+        WebSocketStream::from_raw_socket(client, Role::Client, None).await
+    }
+
+    async fn recv(ws: &mut Client) -> RelayMessage<'static> {
+        loop {
+            if let Message::Text(t) = ws.next().await.unwrap().unwrap() {
+                return RelayMessage::from_json(t.as_bytes()).unwrap();
+            }
+        }
+    }
+
+    async fn send(ws: &mut Client, msg: ClientMessage<'_>) -> (bool, String) {
+        ws.send(Message::text(msg.as_json())).await.unwrap();
+        match recv(ws).await {
+            RelayMessage::Ok {
+                status, message, ..
+            } => (status, message.into_owned()),
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+
+    async fn challenge(ws: &mut Client) -> String {
+        match recv(ws).await {
+            RelayMessage::Auth { challenge } => challenge.into_owned(),
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+
+    fn auth(keys: &Keys, challenge: &str) -> ClientMessage<'static> {
+        let url = RelayUrl::parse("ws://localhost").unwrap();
+        ClientMessage::auth(
+            EventBuilder::auth(challenge, url)
+                .sign_with_keys(keys)
+                .unwrap(),
+        )
+    }
+
+    fn note() -> ClientMessage<'static> {
+        ClientMessage::event(
+            EventBuilder::text_note("hi")
+                .sign_with_keys(&Keys::generate())
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn parse_ips_and_pubkeys() {
+        let pk = Keys::generate().public_key();
+        let peers = TrustedPeers::parse(&["10.0.0.2".to_string(), pk.to_hex()]).unwrap();
+        assert!(peers.has_pubkeys());
+        assert!(peers.contains("10.0.0.2".parse().unwrap()));
+        assert!(peers.contains("::ffff:10.0.0.2".parse().unwrap()));
+        assert!(!peers.contains("10.0.0.3".parse().unwrap()));
+        assert!(TrustedPeers::parse(&["not a peer".to_string()]).is_err());
+        assert!(
+            !TrustedPeers::parse(&["10.0.0.2".to_string()])
+                .unwrap()
+                .has_pubkeys()
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticated_peer_skips_public_chain() {
+        let keys = Keys::generate();
+        let peers = TrustedPeers::parse(&[keys.public_key().to_bech32().unwrap()]).unwrap();
+        let mut ws = connect(peers).await;
+        let c = challenge(&mut ws).await;
+
+        let (ok, message) = send(&mut ws, note()).await;
+        assert!(!ok, "unauthenticated writes go through the public chain");
+        assert!(message.contains("read-only"), "{}", message);
+
+        let (ok, _) = send(&mut ws, auth(&keys, &c)).await;
+        assert!(ok);
+        let (ok, message) = send(&mut ws, note()).await;
+        assert!(ok, "{}", message);
+    }
+
+    #[tokio::test]
+    async fn unknown_pubkey_stays_public() {
+        let peers = TrustedPeers::parse(&[Keys::generate().public_key().to_hex()]).unwrap();
+        let mut ws = connect(peers).await;
+        let c = challenge(&mut ws).await;
+
+        let (ok, _) = send(&mut ws, auth(&Keys::generate(), &c)).await;
+        assert!(ok);
+        let (ok, message) = send(&mut ws, note()).await;
+        assert!(!ok);
+        assert!(message.contains("read-only"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn invalid_auth_is_refused() {
+        let keys = Keys::generate();
+        let peers = TrustedPeers::parse(&[keys.public_key().to_hex()]).unwrap();
+        let mut ws = connect(peers).await;
+        let c = challenge(&mut ws).await;
+
+        // signed for another challenge, handed to the relay which never issued it
+        let (ok, _) = send(&mut ws, auth(&keys, "other")).await;
+        assert!(!ok);
+        // wrong kind for our challenge
+        let event = EventBuilder::new(Kind::TextNote, "")
+            .tag(nostr_sdk::Tag::parse(["challenge", c.as_str()]).unwrap())
+            .sign_with_keys(&keys)
+            .unwrap();
+        let (ok, _) = send(&mut ws, ClientMessage::auth(event)).await;
+        assert!(!ok);
+        let (ok, _) = send(&mut ws, note()).await;
+        assert!(!ok);
+    }
+}
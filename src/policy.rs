@@ -1,8 +1,46 @@
+use crate::audit::AuditLog;
+use crate::content_dedup::{ContentDedupPolicy, ContentDedupSettings};
+use crate::db::ArchiveDatabase;
+use crate::disk::DiskGuard;
+use crate::kinds::{KindEntry, KindSet};
+use crate::scope::AuthorScope;
+use anyhow::{Result, bail};
+use itertools::Itertools;
 use nostr_relay_builder::prelude::{PolicyResult, QueryPolicy, WritePolicy};
-use nostr_sdk::prelude::BoxedFuture;
-use nostr_sdk::{Event, Filter, Kind};
+use nostr_sdk::prelude::{BoxedFuture, JsonUtil};
+use nostr_sdk::{Event, Filter, PublicKey, SingleLetterTag, Timestamp};
+use serde::Deserialize;
 use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Max number of ids in a single id lookup
+pub const MAX_ID_LOOKUP: usize = 20;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryMode {
+    /// Reject all queries
+    #[default]
+    None,
+    /// Allow recent and id queries, see [QueryWindowPolicy]
+    Window,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct QueryPolicySettings {
+    pub mode: Option<QueryMode>,
+
+    /// Oldest `since` accepted (hours)
+    pub max_age_hours: Option<u64>,
+
+    /// Largest `limit` accepted
+    pub max_limit: Option<usize>,
+
+    /// Accept filters asking for specific event ids
+    pub allow_id_lookups: Option<bool>,
+}
 
 #[derive(Debug)]
 pub struct NoQuery;
@@ -13,11 +51,62 @@ impl QueryPolicy for NoQuery {
     }
 }
 
+/// Only accept cheap queries, small id lookups or a recent window with a limit
+#[derive(Debug)]
+pub struct QueryWindowPolicy {
+    pub max_age: Duration,
+    pub max_limit: usize,
+    pub allow_id_lookups: bool,
+}
+
+impl QueryWindowPolicy {
+    pub fn new(settings: &QueryPolicySettings) -> Self {
+        Self {
+            max_age: Duration::from_secs(settings.max_age_hours.unwrap_or(24) * 60 * 60),
+            max_limit: settings.max_limit.unwrap_or(500),
+            allow_id_lookups: settings.allow_id_lookups.unwrap_or(true),
+        }
+    }
+
+    fn check(&self, query: &Filter) -> PolicyResult {
+        if let Some(ids) = &query.ids
+            && !ids.is_empty()
+        {
+            return if !self.allow_id_lookups {
+                PolicyResult::Reject("blocked: id lookups not allowed".to_string())
+            } else if ids.len() > MAX_ID_LOOKUP {
+                PolicyResult::Reject(format!("blocked: at most {} ids per query", MAX_ID_LOOKUP))
+            } else {
+                PolicyResult::Accept
+            };
+        }
+
+        let oldest = Timestamp::now() - self.max_age.as_secs();
+        match (query.since, query.limit) {
+            (Some(since), Some(limit)) if since >= oldest && limit <= self.max_limit => {
+                PolicyResult::Accept
+            }
+            _ => PolicyResult::Reject(format!(
+                "blocked: queries need since within the last {}h and limit <= {}",
+                self.max_age.as_secs() / 3600,
+                self.max_limit
+            )),
+        }
+    }
+}
+
+impl QueryPolicy for QueryWindowPolicy {
+    fn admit_query(&self, query: &Filter, _addr: &SocketAddr) -> BoxedFuture<'_, PolicyResult> {
+        let res = self.check(query);
+        Box::pin(async move { res })
+    }
+}
+
 #[derive(Debug)]
-pub struct KindPolicy(HashSet<Kind>);
+pub struct KindPolicy(KindSet);
 
 impl KindPolicy {
-    pub fn new(kinds: HashSet<Kind>) -> Self {
+    pub fn new(kinds: KindSet) -> Self {
         Self(kinds)
     }
 }
@@ -29,7 +118,7 @@ impl WritePolicy for KindPolicy {
         _addr: &SocketAddr,
     ) -> BoxedFuture<'a, PolicyResult> {
         Box::pin(async move {
-            if self.0.contains(&event.kind) {
+            if self.0.contains(event.kind) {
                 PolicyResult::Accept
             } else {
                 PolicyResult::Reject("Kind not accepted".to_string())
@@ -38,6 +127,26 @@ impl WritePolicy for KindPolicy {
     }
 }
 
+/// Rejects the sensitive kinds unless `allow_sensitive_kinds` is set
+#[derive(Debug)]
+pub struct SensitiveKindPolicy(pub KindSet);
+
+impl WritePolicy for SensitiveKindPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        Box::pin(async move {
+            if self.0.contains(event.kind) {
+                PolicyResult::Reject("Private message kinds are not archived".to_string())
+            } else {
+                PolicyResult::Accept
+            }
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct EphemeralPolicy;
 impl WritePolicy for EphemeralPolicy {
@@ -55,3 +164,442 @@ impl WritePolicy for EphemeralPolicy {
         }
     }
 }
+
+/// Sends ephemeral events admitted by the policies before it to the firehose, the relay
+/// only broadcasts them to its websocket subscriptions and never saves them
+#[derive(Debug)]
+pub struct LiveEphemeralPolicy(pub ArchiveDatabase);
+impl WritePolicy for LiveEphemeralPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        if event.kind.is_ephemeral() {
+            self.0.publish_live(event);
+        }
+        Box::pin(async move { PolicyResult::Accept })
+    }
+}
+
+/// Rejects every write, for `mode: serve`
+#[derive(Debug)]
+pub struct ReadOnlyPolicy;
+impl WritePolicy for ReadOnlyPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        _event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        Box::pin(async move { PolicyResult::Reject("Archive is read-only".to_string()) })
+    }
+}
+
+/// Rejects writes while ingestion is paused by the disk guard
+#[derive(Debug)]
+pub struct StorageFullPolicy(pub DiskGuard);
+impl WritePolicy for StorageFullPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        _event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        let paused = self.0.is_paused();
+        Box::pin(async move {
+            if paused {
+                PolicyResult::Reject("storage full".to_string())
+            } else {
+                PolicyResult::Accept
+            }
+        })
+    }
+}
+
+/// Allowed clock skew (seconds) when checking NIP-40 expiration
+pub const EXPIRATION_SKEW: u64 = 60;
+
+#[derive(Debug)]
+pub struct ExpirationPolicy;
+
+impl ExpirationPolicy {
+    /// Is the event past its expiration, allowing for some clock skew
+    pub fn is_expired(event: &Event) -> bool {
+        event.is_expired_at(&(Timestamp::now() - EXPIRATION_SKEW))
+    }
+}
+
+impl WritePolicy for ExpirationPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        Box::pin(async move {
+            if Self::is_expired(event) {
+                PolicyResult::Reject("Event is expired".to_string())
+            } else {
+                PolicyResult::Accept
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct PubkeyAllowPolicy(HashSet<PublicKey>);
+
+impl WritePolicy for PubkeyAllowPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        Box::pin(async move {
+            if self.0.contains(&event.pubkey) {
+                PolicyResult::Accept
+            } else {
+                PolicyResult::Reject("Pubkey not allowed".to_string())
+            }
+        })
+    }
+}
+
+/// Only accept authors in the ingest scope, follows the refreshed follow list
+#[derive(Debug)]
+pub struct AuthorAllowPolicy(AuthorScope);
+
+impl AuthorAllowPolicy {
+    pub fn new(scope: AuthorScope) -> Self {
+        Self(scope)
+    }
+}
+
+impl WritePolicy for AuthorAllowPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        Box::pin(async move {
+            if self.0.contains(&event.pubkey) {
+                PolicyResult::Accept
+            } else {
+                PolicyResult::Reject("Author not followed".to_string())
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct PowPolicy(u8);
+
+impl WritePolicy for PowPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        Box::pin(async move {
+            if event.check_pow(self.0) {
+                PolicyResult::Accept
+            } else {
+                PolicyResult::Reject(format!("pow: difficulty {} required", self.0))
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct MaxSizePolicy(usize);
+
+impl WritePolicy for MaxSizePolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        Box::pin(async move {
+            if event.as_json().len() <= self.0 {
+                PolicyResult::Accept
+            } else {
+                PolicyResult::Reject(format!("Event larger than {} bytes", self.0))
+            }
+        })
+    }
+}
+
+/// Reject events with `created_at` too far in the past or future
+#[derive(Debug)]
+pub struct TimestampPolicy {
+    max_past: Option<u64>,
+    max_future: Option<u64>,
+}
+
+impl WritePolicy for TimestampPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        Box::pin(async move {
+            let now = Timestamp::now();
+            if let Some(p) = self.max_past
+                && event.created_at < now - p
+            {
+                PolicyResult::Reject("Event created_at too old".to_string())
+            } else if let Some(f) = self.max_future
+                && event.created_at > now + f
+            {
+                PolicyResult::Reject("Event created_at too far in the future".to_string())
+            } else {
+                PolicyResult::Accept
+            }
+        })
+    }
+}
+
+/// Tag name and the values matched by a `tags` policy rule
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct TagMatch {
+    pub tag: String,
+    pub values: Vec<String>,
+}
+
+/// Accept or reject events by their tags, `t` values are compared case-insensitively
+#[derive(Debug, Default)]
+pub struct TagPolicy {
+    /// At least one of these pairs is required
+    any: Vec<(String, String)>,
+    /// Every one of these pairs is required
+    all: Vec<(String, String)>,
+    /// Events with any of these pairs are rejected
+    deny: Vec<(String, String)>,
+}
+
+impl TagPolicy {
+    fn pairs(rules: &Option<Vec<TagMatch>>) -> Vec<(String, String)> {
+        rules
+            .iter()
+            .flatten()
+            .flat_map(|m| {
+                m.values
+                    .iter()
+                    .map(|v| (m.tag.clone(), Self::normalize(&m.tag, v)))
+            })
+            .collect()
+    }
+
+    fn normalize(tag: &str, value: &str) -> String {
+        if tag == "t" {
+            value.to_lowercase()
+        } else {
+            value.to_string()
+        }
+    }
+
+    fn check(&self, event: &Event) -> PolicyResult {
+        let tags: HashSet<(String, String)> = event
+            .tags
+            .iter()
+            .filter_map(|t| match t.as_slice() {
+                [name, value, ..] => Some((name.clone(), Self::normalize(name, value))),
+                _ => None,
+            })
+            .collect();
+        let show = |(t, v): &(String, String)| format!("{}={}", t, v);
+        if let Some(p) = self.deny.iter().find(|p| tags.contains(*p)) {
+            return PolicyResult::Reject(format!("deny: {}", show(p)));
+        }
+        if !self.any.is_empty() && !self.any.iter().any(|p| tags.contains(p)) {
+            return PolicyResult::Reject(format!(
+                "any: none of {}",
+                self.any.iter().map(show).join(", ")
+            ));
+        }
+        if let Some(p) = self.all.iter().find(|p| !tags.contains(*p)) {
+            return PolicyResult::Reject(format!("all: missing {}", show(p)));
+        }
+        PolicyResult::Accept
+    }
+}
+
+impl WritePolicy for TagPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        let res = self.check(event);
+        Box::pin(async move { res })
+    }
+}
+
+/// Tag filters narrowing the ingest subscriptions to the `any` rules of a `tags` policy,
+/// one group per tag name; empty when a rule can't be expressed as a relay filter
+pub fn ingest_tags(policies: &[PolicyConfig]) -> Vec<(SingleLetterTag, Vec<String>)> {
+    policies
+        .iter()
+        .find_map(|p| match p {
+            PolicyConfig::Tags { any: Some(any), .. } if !any.is_empty() => Some(any),
+            _ => None,
+        })
+        .and_then(|any| tag_groups(any).ok())
+        .unwrap_or_default()
+}
+
+/// Relay tag filters matching any of `matches`, one group per tag name
+pub fn tag_groups(matches: &[TagMatch]) -> Result<Vec<(SingleLetterTag, Vec<String>)>> {
+    let mut groups: Vec<(SingleLetterTag, Vec<String>)> = Vec::new();
+    for m in matches {
+        let Ok(tag) = SingleLetterTag::from_str(&m.tag) else {
+            bail!("{} is not a single letter tag", m.tag);
+        };
+        let values = m.values.iter().flat_map(|v| {
+            // relays compare exactly, hashtags are usually lowercase
+            if m.tag == "t" {
+                vec![v.clone(), v.to_lowercase()]
+            } else {
+                vec![v.clone()]
+            }
+        });
+        match groups.iter_mut().find(|(t, _)| *t == tag) {
+            Some((_, v)) => v.extend(values),
+            None => groups.push((tag, values.collect())),
+        }
+    }
+    for (_, v) in groups.iter_mut() {
+        v.sort();
+        v.dedup();
+    }
+    Ok(groups)
+}
+
+/// Write policy entry in the `policies` config section
+#[derive(Deserialize, Clone)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub enum PolicyConfig {
+    Ephemeral,
+    Expiration,
+    Kinds {
+        kinds: Vec<KindEntry>,
+    },
+    AllowPubkeys {
+        pubkeys: Vec<String>,
+    },
+    MinPow {
+        difficulty: u8,
+    },
+    MaxSize {
+        bytes: usize,
+    },
+    Timestamp {
+        /// Max seconds in the past
+        max_past: Option<u64>,
+        /// Max seconds in the future
+        max_future: Option<u64>,
+    },
+    ContentDedup(ContentDedupSettings),
+    Tags {
+        any: Option<Vec<TagMatch>>,
+        all: Option<Vec<TagMatch>>,
+        deny: Option<Vec<TagMatch>>,
+    },
+}
+
+impl PolicyConfig {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PolicyConfig::Ephemeral => "ephemeral",
+            PolicyConfig::Expiration => "expiration",
+            PolicyConfig::Kinds { .. } => "kinds",
+            PolicyConfig::AllowPubkeys { .. } => "allow_pubkeys",
+            PolicyConfig::MinPow { .. } => "min_pow",
+            PolicyConfig::MaxSize { .. } => "max_size",
+            PolicyConfig::Timestamp { .. } => "timestamp",
+            PolicyConfig::ContentDedup(_) => "content_dedup",
+            PolicyConfig::Tags { .. } => "tags",
+        }
+    }
+
+    fn build(&self) -> Result<Box<dyn WritePolicy>> {
+        Ok(match self {
+            PolicyConfig::Ephemeral => Box::new(EphemeralPolicy),
+            PolicyConfig::Expiration => Box::new(ExpirationPolicy),
+            PolicyConfig::Kinds { kinds } => Box::new(KindPolicy::new(KindSet::parse(kinds)?)),
+            PolicyConfig::AllowPubkeys { pubkeys } => Box::new(PubkeyAllowPolicy(
+                pubkeys
+                    .iter()
+                    .map(|p| PublicKey::parse(p))
+                    .collect::<Result<_, _>>()?,
+            )),
+            PolicyConfig::MinPow { difficulty } => Box::new(PowPolicy(*difficulty)),
+            PolicyConfig::MaxSize { bytes } => Box::new(MaxSizePolicy(*bytes)),
+            PolicyConfig::Timestamp {
+                max_past,
+                max_future,
+            } => Box::new(TimestampPolicy {
+                max_past: *max_past,
+                max_future: *max_future,
+            }),
+            PolicyConfig::ContentDedup(s) => Box::new(ContentDedupPolicy::new(s)?),
+            PolicyConfig::Tags { any, all, deny } => Box::new(TagPolicy {
+                any: TagPolicy::pairs(any),
+                all: TagPolicy::pairs(all),
+                deny: TagPolicy::pairs(deny),
+            }),
+        })
+    }
+}
+
+/// Ordered list of write policies, the first rejection wins
+#[derive(Debug, Default)]
+pub struct PolicyChain {
+    policies: Vec<(&'static str, Box<dyn WritePolicy>)>,
+    audit: Option<AuditLog>,
+}
+
+impl PolicyChain {
+    pub fn from_config(policies: &[PolicyConfig]) -> Result<Self> {
+        let mut ret = Self::default();
+        for p in policies {
+            ret.policies.push((
+                p.name(),
+                p.build()
+                    .map_err(|e| anyhow::anyhow!("Invalid policy {}: {}", p.name(), e))?,
+            ));
+        }
+        Ok(ret)
+    }
+
+    /// Append a policy not built from the `policies` config
+    pub fn with_policy(mut self, name: &'static str, policy: Box<dyn WritePolicy>) -> Self {
+        self.policies.push((name, policy));
+        self
+    }
+
+    /// Record every rejection in the audit log
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+}
+
+impl WritePolicy for PolicyChain {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        Box::pin(async move {
+            for (name, policy) in &self.policies {
+                if let PolicyResult::Reject(reason) = policy.admit_event(event, addr).await {
+                    let reason = format!("{}: {}", name, reason);
+                    if let Some(a) = &self.audit {
+                        a.rejected(event, addr, &reason);
+                    }
+                    return PolicyResult::Reject(reason);
+                }
+            }
+            PolicyResult::Accept
+        })
+    }
+}
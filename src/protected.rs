@@ -0,0 +1,48 @@
+use nostr_sdk::prelude::{AdmitPolicy, AdmitStatus, BoxedFuture, PolicyError};
+use nostr_sdk::{Event, RelayUrl, SubscriptionId};
+use std::fmt::{Debug, Formatter};
+
+/// Drops NIP-70 protected events (`["-"]` tag) admitted by `inner` unless `archive` is
+/// set, they are meant to stay on the relays their author published them to
+///
+/// Only applies to events from upstream relays, protected events published to the relay
+/// itself need NIP-42 auth as their author
+pub struct ProtectedAdmit<P> {
+    inner: P,
+    archive: bool,
+}
+
+impl<P> ProtectedAdmit<P> {
+    pub fn new(inner: P, archive: bool) -> Self {
+        Self { inner, archive }
+    }
+}
+
+impl<P: Debug> Debug for ProtectedAdmit<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProtectedAdmit")
+            .field("inner", &self.inner)
+            .field("archive", &self.archive)
+            .finish()
+    }
+}
+
+impl<P: AdmitPolicy> AdmitPolicy for ProtectedAdmit<P> {
+    fn admit_event<'a>(
+        &'a self,
+        relay_url: &'a RelayUrl,
+        subscription_id: &'a SubscriptionId,
+        event: &'a Event,
+    ) -> BoxedFuture<'a, Result<AdmitStatus, PolicyError>> {
+        Box::pin(async move {
+            let status = self
+                .inner
+                .admit_event(relay_url, subscription_id, event)
+                .await?;
+            if matches!(status, AdmitStatus::Success) && !self.archive && event.is_protected() {
+                return Ok(AdmitStatus::rejected("restricted: protected event"));
+            }
+            Ok(status)
+        })
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::{Result, anyhow};
+use hyper::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// IP network in CIDR notation, a bare address is a single host
+#[derive(Clone, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a.trim().parse::<IpAddr>()?, Some(p.trim().parse::<u8>()?)),
+            None => (s.trim().parse::<IpAddr>()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err(anyhow!("Invalid prefix length in {}", s));
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Reverse proxies allowed to set `X-Forwarded-For` / `X-Real-IP`
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Vec<Cidr>);
+
+impl TrustedProxies {
+    pub fn parse(cidrs: &[String]) -> Result<Self> {
+        Ok(Self(
+            cidrs
+                .iter()
+                .map(|c| Cidr::parse(c).map_err(|e| anyhow!("Invalid trusted proxy {}: {}", c, e)))
+                .collect::<Result<_>>()?,
+        ))
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|c| c.contains(ip))
+    }
+
+    /// Client address of a request, forwarding headers are only used when the
+    /// peer is a trusted proxy and fall back to the peer address if malformed
+    pub fn resolve(&self, peer: SocketAddr, headers: &HeaderMap) -> SocketAddr {
+        if !self.is_trusted(peer.ip()) {
+            return peer;
+        }
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| {
+                // rightmost hop that isn't one of our proxies
+                let mut ret = None;
+                for hop in h.rsplit(',') {
+                    let ip = hop.trim().parse::<IpAddr>().ok()?;
+                    ret = Some(ip);
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                ret
+            });
+        let real_ip = || {
+            headers
+                .get("x-real-ip")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.trim().parse::<IpAddr>().ok())
+        };
+        match forwarded.or_else(real_ip) {
+            Some(ip) => SocketAddr::new(ip.to_canonical(), peer.port()),
+            None => peer,
+        }
+    }
+}
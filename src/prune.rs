@@ -0,0 +1,40 @@
+use anyhow::{Result, anyhow};
+use rocksdb::{DB, IteratorMode, WriteBatch};
+
+/// Keys deleted per write batch
+pub const PRUNE_BATCH: usize = 10_000;
+
+/// Delete every key of `db` in batches, returns the number deleted
+pub fn clear(db: &DB) -> Result<u64> {
+    delete_where(db, |_, _| true)
+}
+
+/// Delete the keys of `db` for which `f(key, value)` is true in batches, returns the
+/// number deleted
+pub fn delete_where(db: &DB, mut f: impl FnMut(&[u8], &[u8]) -> bool) -> Result<u64> {
+    let mut batch = WriteBatch::default();
+    let mut n = 0;
+    for x in db.iterator(IteratorMode::Start) {
+        let (k, v) = x.map_err(|e| anyhow!(e))?;
+        if !f(&k, &v) {
+            continue;
+        }
+        batch.delete(&k);
+        n += 1;
+        if batch.len() >= PRUNE_BATCH {
+            db.write(std::mem::take(&mut batch))
+                .map_err(|e| anyhow!(e))?;
+        }
+    }
+    db.write(batch).map_err(|e| anyhow!(e))?;
+    Ok(n)
+}
+
+/// Delete the keys `ids` from `db`
+pub fn remove_ids(db: &DB, ids: &[[u8; 32]]) -> Result<()> {
+    let mut batch = WriteBatch::default();
+    for id in ids {
+        batch.delete(id);
+    }
+    db.write(batch).map_err(|e| anyhow!(e))
+}
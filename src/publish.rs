@@ -0,0 +1,67 @@
+use crate::upstream::Upstream;
+use anyhow::Result;
+use log::warn;
+use nostr_sdk::{Client, Event, EventBuilder, Keys, RelayUrl};
+
+/// Signs events with the relay key and sends them to a set of relays through the
+/// ingest client, failures are logged and never stop the caller
+#[derive(Clone)]
+pub struct Publisher {
+    client: Client,
+    keys: Keys,
+    /// Empty sends to every relay of the client
+    relays: Vec<RelayUrl>,
+}
+
+impl Publisher {
+    /// Publish to `relays`, added to the client as write-only so nothing is ingested
+    /// from them, or to the client's relays when empty
+    pub async fn new(
+        client: Client,
+        upstream: &Upstream,
+        keys: Keys,
+        relays: &[String],
+    ) -> Result<Self> {
+        let mut urls = Vec::with_capacity(relays.len());
+        for r in relays {
+            let url = RelayUrl::parse(r)?;
+            if upstream.add_write_relay(&client, r).await? {
+                client.connect_relay(&url).await?;
+            }
+            urls.push(url);
+        }
+        Ok(Self {
+            client,
+            keys,
+            relays: urls,
+        })
+    }
+
+    pub fn sign(&self, builder: EventBuilder) -> Result<Event> {
+        Ok(builder.sign_with_keys(&self.keys)?)
+    }
+
+    /// Send `ev`, returns whether any relay accepted it
+    pub async fn send(&self, ev: &Event) -> bool {
+        let res = if self.relays.is_empty() {
+            if self.client.relays().await.is_empty() {
+                return false;
+            }
+            self.client.send_event(ev).await
+        } else {
+            self.client.send_event_to(&self.relays, ev).await
+        };
+        match res {
+            Ok(out) => {
+                for (url, e) in &out.failed {
+                    warn!("Relay {} rejected {} (kind {}): {}", url, ev.id, ev.kind, e);
+                }
+                !out.success.is_empty()
+            }
+            Err(e) => {
+                warn!("Failed to publish {} (kind {}): {}", ev.id, ev.kind, e);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::db::{ArchiveDatabase, decode_archive_strict, is_active};
+use crate::encrypt::is_encrypted;
+use crate::scan::PUBLISHED_DIR;
+use anyhow::{Result, bail};
+use log::{error, info, warn};
+use nostr_archive_cursor::ArchiveFile;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// How often the view is brought in line with the finalized archives
+const PUBLISHED_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Prefix of links and copies not yet renamed into place
+const TMP_PREFIX: &str = ".tmp-";
+
+#[derive(Deserialize, Clone, Default)]
+pub struct PublishedViewSettings {
+    pub enabled: Option<bool>,
+    /// Only list and serve archives that are in the view, default false
+    pub serve: Option<bool>,
+}
+
+/// `published/` directory holding a hardlink (or a copy where linking fails) of every
+/// finalized and verified archive under its archive name, so it can be exported read-only
+/// over NFS or SFTP without readers seeing the active file or half written archives
+#[derive(Clone)]
+pub struct PublishedView {
+    dir: PathBuf,
+    serve: bool,
+    /// Notified when archives were added or replaced, eg. after a compression
+    changed: Arc<Notify>,
+}
+
+impl PublishedView {
+    pub fn new(out_dir: &Path, settings: &PublishedViewSettings) -> Self {
+        Self {
+            dir: out_dir.join(PUBLISHED_DIR),
+            serve: settings.serve.unwrap_or(false),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Update the view now instead of at the next interval
+    pub fn wake(&self) {
+        self.changed.notify_one();
+    }
+
+    /// Are archives listed and served from the view
+    pub fn serves(&self) -> bool {
+        self.serve
+    }
+
+    /// Path of the archive `name` in the view
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// The published copy of `file` named `name`, failing until it was published
+    pub fn get_file(&self, name: &str, file: &ArchiveFile) -> Result<ArchiveFile> {
+        let path = self.path(name);
+        let meta = std::fs::metadata(&path)?;
+        if meta.len() != file.size {
+            bail!("{} is not published yet", name);
+        }
+        Ok(ArchiveFile {
+            path,
+            size: meta.len(),
+            created: file.created,
+            timestamp: file.timestamp,
+        })
+    }
+
+    /// Is the archive `name` in the view
+    pub fn contains(&self, name: &str, file: &ArchiveFile) -> bool {
+        self.get_file(name, file).is_ok()
+    }
+}
+
+/// Fail unless the archive at `path` decodes to the end, encrypted archives are only
+/// checked by their checksum
+fn verify(path: &Path) -> Result<()> {
+    if is_encrypted(path) {
+        return Ok(());
+    }
+    let mut input = decode_archive_strict(path, std::fs::File::open(path)?)?;
+    std::io::copy(&mut input, &mut std::io::sink())?;
+    Ok(())
+}
+
+/// Link `source` to `target` through a temporary name, copying it where hardlinks
+/// aren't possible, so readers only ever see the complete file
+fn place(source: &Path, target: &Path) -> Result<()> {
+    let Some(dir) = target.parent() else {
+        bail!("Invalid target {}", target.display());
+    };
+    std::fs::create_dir_all(dir)?;
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dir.join(format!("{}{}", TMP_PREFIX, name));
+    let _ = std::fs::remove_file(&tmp);
+    if let Err(e) = std::fs::hard_link(source, &tmp) {
+        warn!(
+            "Copying {} to the published view, hardlink failed: {}",
+            source.display(),
+            e
+        );
+        std::fs::copy(source, &tmp)?;
+    }
+    std::fs::rename(&tmp, target)?;
+    Ok(())
+}
+
+/// Remove the files of `dir` that aren't listed in `keep`, and directories left empty
+fn remove_stale(dir: &Path, keep: &HashSet<PathBuf>) -> Result<u64> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            removed += remove_stale(&path, keep)?;
+            if std::fs::read_dir(&path)?.next().is_none() {
+                std::fs::remove_dir(&path)?;
+            }
+        } else if !keep.contains(&path) {
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Bring the view in line with the archives: publish finalized ones once they are checksummed
+/// and decode fully, drop entries whose archive was deleted, replaced or moved
+async fn sync(db: &ArchiveDatabase, view: &PublishedView) -> Result<()> {
+    let mut keep = HashSet::new();
+    for f in db.list_archives().await?.iter() {
+        let name = db.archive_name(&f.path);
+        if view.contains(&name, f) {
+            keep.insert(view.path(&name));
+            continue;
+        }
+        if is_active(&f.path) || db.is_pending(&f.path).await || db.awaits_encryption(&f.path) {
+            continue;
+        }
+        let res = async {
+            // the checksum is only written for archives that won't be rewritten anymore
+            if db.checksum(f).await?.is_none() {
+                return Ok(false);
+            }
+            let (source, target) = (f.path.clone(), view.path(&name));
+            tokio::task::spawn_blocking(move || {
+                verify(&source)?;
+                place(&source, &target)
+            })
+            .await??;
+            anyhow::Ok(true)
+        }
+        .await;
+        match res {
+            Ok(true) => {
+                info!("Published {}", name);
+                keep.insert(view.path(&name));
+            }
+            Ok(false) => {}
+            Err(e) => error!("Failed to publish {}: {}", name, e),
+        }
+    }
+
+    let dir = view.dir.clone();
+    let removed = tokio::task::spawn_blocking(move || {
+        if !dir.exists() {
+            return Ok(0);
+        }
+        remove_stale(&dir, &keep)
+    })
+    .await??;
+    if removed > 0 {
+        info!("Removed {} files from the published view", removed);
+    }
+    Ok(())
+}
+
+/// Publish finalized archives into the view and remove those that are gone, whenever
+/// the archives changed and at least every [PUBLISHED_INTERVAL]
+pub async fn run_published(db: ArchiveDatabase, view: PublishedView) -> Result<()> {
+    loop {
+        if let Err(e) = sync(&db, &view).await {
+            error!("Failed to update the published view: {}", e);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(PUBLISHED_INTERVAL) => {}
+            _ = view.changed.notified() => {}
+        }
+    }
+}
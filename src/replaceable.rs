@@ -0,0 +1,191 @@
+use crate::kinds::KindSet;
+use crate::prune;
+use crate::rocks;
+use anyhow::{Result, anyhow};
+use hyper::body::Bytes;
+use log::warn;
+use nostr_sdk::{Event, EventId, Kind, PublicKey, Timestamp};
+use rocksdb::{DB, IteratorMode};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Chunks buffered per export before the index walk waits for the client
+const EXPORT_QUEUE: usize = 16;
+
+/// Bytes collected before a chunk is sent
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Latest known version of a replaceable or addressable event
+#[derive(Debug, Clone)]
+pub struct LatestVersion {
+    pub kind: Kind,
+    pub pubkey: PublicKey,
+    /// `d` tag for addressable events
+    pub identifier: Option<String>,
+    pub created_at: Timestamp,
+    pub id: EventId,
+}
+
+/// Index of (kind, pubkey[, d-tag]) -> newest created_at + id
+#[derive(Clone)]
+pub struct ReplaceableIndex {
+    database: Arc<DB>,
+    /// Serializes check-and-set so two versions can't race each other
+    lock: Arc<Mutex<()>>,
+}
+
+impl ReplaceableIndex {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = rocks::open(path)?;
+        Ok(Self {
+            database: Arc::new(db),
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Forget every tracked version
+    pub fn clear(&self) -> Result<u64> {
+        let _guard = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Replaceable index lock poisoned"))?;
+        prune::clear(&self.database)
+    }
+
+    /// Forget the versions created before `before`, returns the number deleted
+    pub fn prune(&self, before: Timestamp) -> Result<u64> {
+        let _guard = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Replaceable index lock poisoned"))?;
+        prune::delete_where(&self.database, |_, v| {
+            v.get(..8)
+                .and_then(|b| b.try_into().ok())
+                .map(u64::from_be_bytes)
+                .is_some_and(|t| t < before.as_secs())
+        })
+    }
+
+    /// Is this event tracked by the index
+    pub fn is_tracked(event: &Event) -> bool {
+        event.kind.is_replaceable() || event.kind.is_addressable()
+    }
+
+    fn key(event: &Event) -> Vec<u8> {
+        let mut key = Vec::with_capacity(34);
+        key.extend_from_slice(&event.kind.as_u16().to_be_bytes());
+        key.extend_from_slice(&event.pubkey.to_bytes());
+        if event.kind.is_addressable() {
+            key.extend_from_slice(event.tags.identifier().unwrap_or_default().as_bytes());
+        }
+        key
+    }
+
+    fn value(event: &Event) -> [u8; 40] {
+        let mut value = [0u8; 40];
+        value[..8].copy_from_slice(&event.created_at.as_secs().to_be_bytes());
+        value[8..].copy_from_slice(event.id.as_bytes());
+        value
+    }
+
+    /// Returns true if a newer version than `event` is already stored
+    ///
+    /// Ties on created_at are broken by the lowest id (NIP-01)
+    pub fn is_replaced(&self, event: &Event) -> Result<bool> {
+        let stored = self
+            .database
+            .get(Self::key(event))
+            .map_err(|e| anyhow!(e))?;
+        Ok(match stored {
+            Some(v) if v.len() == 40 => {
+                let stored_at = u64::from_be_bytes(v[..8].try_into()?);
+                let created_at = event.created_at.as_secs();
+                stored_at > created_at
+                    || (stored_at == created_at && v[8..] < event.id.as_bytes()[..])
+            }
+            _ => false,
+        })
+    }
+
+    /// Store `event` as the latest version unless a newer one is already known
+    ///
+    /// Returns false when the event is stale
+    pub fn update(&self, event: &Event) -> Result<bool> {
+        let _guard = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Replaceable index lock poisoned"))?;
+        if self.is_replaced(event)? {
+            return Ok(false);
+        }
+        self.database
+            .put(Self::key(event), Self::value(event))
+            .map_err(|e| anyhow!(e))?;
+        Ok(true)
+    }
+
+    /// Iterate the latest version of every tracked (kind, pubkey[, d-tag])
+    pub fn iter(&self) -> impl Iterator<Item = LatestVersion> + '_ {
+        self.database.iterator(IteratorMode::Start).filter_map(|x| {
+            let (k, v) = x.ok()?;
+            if k.len() < 34 || v.len() != 40 {
+                return None;
+            }
+            let kind = Kind::from_u16(u16::from_be_bytes(k[..2].try_into().ok()?));
+            Some(LatestVersion {
+                kind,
+                pubkey: PublicKey::from_slice(&k[2..34]).ok()?,
+                identifier: if kind.is_addressable() {
+                    Some(String::from_utf8_lossy(&k[34..]).into_owned())
+                } else {
+                    None
+                },
+                created_at: Timestamp::from_secs(u64::from_be_bytes(v[..8].try_into().ok()?)),
+                id: EventId::from_slice(&v[8..]).ok()?,
+            })
+        })
+    }
+}
+
+/// Stream the latest version of every tracked event as json lines into the returned
+/// channel, skipping `hidden` kinds; the index is walked on a blocking thread
+pub fn export_latest(index: ReplaceableIndex, hidden: Option<KindSet>) -> mpsc::Receiver<Bytes> {
+    let (tx, rx) = mpsc::channel(EXPORT_QUEUE);
+    tokio::task::spawn_blocking(move || {
+        let mut buf = Vec::with_capacity(CHUNK_SIZE + 512);
+        let mut first = true;
+        for v in index
+            .iter()
+            .filter(|v| hidden.as_ref().is_none_or(|h| !h.contains(v.kind)))
+        {
+            if !first {
+                buf.push(b'\n');
+            }
+            first = false;
+            let line = serde_json::json!({
+                "kind": v.kind.as_u16(),
+                "pubkey": v.pubkey.to_hex(),
+                "d": v.identifier,
+                "created_at": v.created_at.as_secs(),
+                "id": v.id.to_hex(),
+            });
+            if let Err(e) = serde_json::to_writer(&mut buf, &line) {
+                warn!("Failed to write replaceable export: {}", e);
+                return;
+            }
+            if buf.len() >= CHUNK_SIZE
+                && tx
+                    .blocking_send(Bytes::from(std::mem::take(&mut buf)))
+                    .is_err()
+            {
+                // the client went away
+                return;
+            }
+        }
+        if !buf.is_empty() {
+            let _ = tx.blocking_send(Bytes::from(buf));
+        }
+    });
+    rx
+}
@@ -0,0 +1,85 @@
+use anyhow::Result;
+use rocksdb::{BlockBasedOptions, Cache, DB, Options};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::OnceLock;
+
+const MB: usize = 1024 * 1024;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct RocksdbSettings {
+    /// Block cache shared by every rocksdb store in MiB, default 64
+    pub cache_mb: Option<usize>,
+    /// Memtable of each store in MiB, default 16
+    pub write_buffer_mb: Option<usize>,
+    /// Memtables a store fills before writes wait for a flush, default 2
+    pub max_write_buffers: Option<i32>,
+}
+
+impl RocksdbSettings {
+    pub fn cache_bytes(&self) -> usize {
+        self.cache_mb.unwrap_or(64) * MB
+    }
+
+    pub fn write_buffer_bytes(&self) -> usize {
+        self.write_buffer_mb.unwrap_or(16).max(1) * MB
+    }
+
+    pub fn max_write_buffers(&self) -> i32 {
+        self.max_write_buffers.unwrap_or(2).max(2)
+    }
+}
+
+struct Tuning {
+    settings: RocksdbSettings,
+    cache: Cache,
+}
+
+/// `rocksdb` of the config, set once at startup before a store is opened
+static TUNING: OnceLock<Tuning> = OnceLock::new();
+
+pub fn configure(settings: RocksdbSettings) {
+    let _ = TUNING.set(Tuning {
+        cache: Cache::new_lru_cache(settings.cache_bytes()),
+        settings,
+    });
+}
+
+fn tuning() -> &'static Tuning {
+    TUNING.get_or_init(|| {
+        let settings = RocksdbSettings::default();
+        Tuning {
+            cache: Cache::new_lru_cache(settings.cache_bytes()),
+            settings,
+        }
+    })
+}
+
+/// The configured settings, defaults until [configure] was called
+pub fn settings() -> &'static RocksdbSettings {
+    &tuning().settings
+}
+
+/// Read blocks of `opts` from the shared cache
+pub fn use_cache(opts: &mut Options) {
+    let mut table = BlockBasedOptions::default();
+    table.set_block_cache(&tuning().cache);
+    opts.set_block_based_table_factory(&table);
+}
+
+/// Options of every store: created when missing, with the configured memtables and the
+/// shared block cache
+pub fn options() -> Options {
+    let settings = settings();
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    opts.set_write_buffer_size(settings.write_buffer_bytes());
+    opts.set_max_write_buffer_number(settings.max_write_buffers());
+    use_cache(&mut opts);
+    opts
+}
+
+/// Open or create the store at `path` with [options]
+pub fn open(path: &Path) -> Result<DB> {
+    Ok(DB::open(&options(), path)?)
+}
@@ -0,0 +1,364 @@
+use crate::db::{ArchiveDatabase, decode_archive_strict, is_active, is_archive, is_fresh};
+use crate::jobs::{ArchiveJobs, Scanned, open_input, scan_files};
+use crate::layout::is_date_dir;
+use crate::summary::read_summary;
+use anyhow::Result;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often finalized archives are checked for a missing rollup
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Events kept in the top lists of each rollup
+pub const TOP_EVENTS: usize = 10;
+
+const REACTION: u16 = 7;
+const ZAP_RECEIPT: u16 = 9735;
+
+/// Contents of `events_YYYYMMDD.zaps.json` next to each finalized archive, written from
+/// the whole archive so writing it again replaces the counts instead of adding to them
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ArchiveRollup {
+    pub reactions: u64,
+    pub zaps: u64,
+    /// Sum of the zapped amounts in millisats
+    pub zapped_msats: u64,
+    /// Zap receipts without a readable bolt11 or `amount`
+    pub unparsed_zaps: u64,
+    /// Most reacted events and their reactions
+    pub top_reacted: Vec<(String, u64)>,
+    /// Most zapped events and their millisats
+    pub top_zapped: Vec<(String, u64)>,
+}
+
+#[derive(Deserialize)]
+struct KindOnly {
+    kind: u16,
+}
+
+#[derive(Deserialize)]
+struct RollupFields {
+    id: String,
+    kind: u16,
+    #[serde(default)]
+    tags: Vec<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct ZapRequest {
+    #[serde(default)]
+    tags: Vec<Vec<String>>,
+}
+
+/// `events_YYYYMMDD.zaps.json` in the directory of the archive
+pub fn rollup_path(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let day = name.split('.').next().unwrap_or(name);
+    archive.with_file_name(format!("{}.zaps.json", day))
+}
+
+/// Rollup of an archive, None until it has been written for the current file
+pub async fn read_rollup(archive: &Path) -> Option<ArchiveRollup> {
+    let path = rollup_path(archive);
+    if !is_fresh(&path, archive).await {
+        return None;
+    }
+    serde_json::from_slice(&tokio::fs::read(&path).await.ok()?).ok()
+}
+
+fn tag<'a>(tags: &'a [Vec<String>], name: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|t| t.first().is_some_and(|n| n == name))
+        .and_then(|t| t.get(1))
+        .map(String::as_str)
+}
+
+/// Reacted or zapped event, the last `e` tag as NIP-25 asks
+fn target(tags: &[Vec<String>]) -> Option<&str> {
+    tags.iter()
+        .rev()
+        .find(|t| t.first().is_some_and(|n| n == "e"))
+        .and_then(|t| t.get(1))
+        .map(String::as_str)
+        .filter(|id| id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Millisats of a bolt11 invoice, None for invoices without an amount or that can't be read
+pub fn bolt11_msats(invoice: &str) -> Option<u64> {
+    let invoice = invoice.trim().to_ascii_lowercase();
+    let invoice = invoice.strip_prefix("lightning:").unwrap_or(&invoice);
+    let hrp = &invoice.strip_prefix("ln")?[..invoice.rfind('1')?.checked_sub(2)?];
+    let amount = hrp.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let (digits, multiplier) = match amount.chars().last()? {
+        c if c.is_ascii_digit() => (amount, None),
+        c if c.is_ascii_alphabetic() => (&amount[..amount.len() - 1], Some(c)),
+        _ => return None,
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n: u64 = digits.parse().ok()?;
+    // 1 BTC = 10^11 msat
+    match multiplier {
+        None => n.checked_mul(100_000_000_000),
+        Some('m') => n.checked_mul(100_000_000),
+        Some('u') => n.checked_mul(100_000),
+        Some('n') => n.checked_mul(100),
+        Some('p') if n.is_multiple_of(10) => Some(n / 10),
+        _ => None,
+    }
+}
+
+/// Amount of a zap receipt from its bolt11, or the `amount` of the zap request in its
+/// `description` when the invoice can't be read
+fn zap_msats(tags: &[Vec<String>]) -> Option<u64> {
+    if let Some(m) = tag(tags, "bolt11").and_then(bolt11_msats) {
+        return Some(m);
+    }
+    let request: ZapRequest = serde_json::from_str(tag(tags, "description")?).ok()?;
+    tag(&request.tags, "amount")?.parse().ok()
+}
+
+fn top(counts: HashMap<String, u64>) -> Vec<(String, u64)> {
+    let mut ret: Vec<_> = counts.into_iter().collect();
+    ret.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ret.truncate(TOP_EVENTS);
+    ret
+}
+
+fn roll_up(path: &Path) -> Result<ArchiveRollup> {
+    let mut rollup = ArchiveRollup::default();
+    let mut seen = HashSet::new();
+    let (mut reacted, mut zapped) = (HashMap::new(), HashMap::<String, u64>::new());
+    let input = decode_archive_strict(path, open_input(path, "Rolling up")?)?;
+    for line in input.split(b'\n') {
+        let line = line?;
+        match serde_json::from_slice::<KindOnly>(&line) {
+            Ok(k) if k.kind == REACTION || k.kind == ZAP_RECEIPT => {}
+            _ => continue,
+        }
+        let Ok(e) = serde_json::from_slice::<RollupFields>(&line) else {
+            continue;
+        };
+        // a duplicated line isn't counted twice
+        if !seen.insert(e.id) {
+            continue;
+        }
+        let id = target(&e.tags).map(str::to_string);
+        if e.kind == REACTION {
+            rollup.reactions += 1;
+            if let Some(id) = id {
+                *reacted.entry(id).or_default() += 1;
+            }
+            continue;
+        }
+        rollup.zaps += 1;
+        match zap_msats(&e.tags) {
+            Some(m) => {
+                rollup.zapped_msats = rollup.zapped_msats.saturating_add(m);
+                if let Some(id) = id {
+                    let z = zapped.entry(id).or_default();
+                    *z = z.saturating_add(m);
+                }
+            }
+            None => rollup.unparsed_zaps += 1,
+        }
+    }
+    rollup.top_reacted = top(reacted);
+    rollup.top_zapped = top(zapped);
+    Ok(rollup)
+}
+
+/// Count the reactions and zaps of an archive and write its rollup, archives whose
+/// summary has neither kind aren't read
+pub async fn write_rollup(archive: &Path, jobs: &ArchiveJobs) -> Result<ArchiveRollup> {
+    let rollup = match read_summary(archive).await {
+        Some(s) if !s.kinds.contains_key(&REACTION) && !s.kinds.contains_key(&ZAP_RECEIPT) => {
+            ArchiveRollup::default()
+        }
+        _ => {
+            let p = archive.to_path_buf();
+            jobs.run(move || roll_up(&p)).await?
+        }
+    };
+    tokio::fs::write(rollup_path(archive), serde_json::to_vec(&rollup)?).await?;
+    Ok(rollup)
+}
+
+/// Roll up archives finalized while running, older ones are rolled up with `stats --rollup`
+pub async fn run_rollups(db: ArchiveDatabase, jobs: ArchiveJobs) -> Result<()> {
+    let started = SystemTime::now();
+    loop {
+        match db.list_archives().await {
+            Ok(files) => {
+                for f in files.iter() {
+                    if is_active(&f.path)
+                        || db.is_pending(&f.path).await
+                        || is_fresh(&rollup_path(&f.path), &f.path).await
+                        || tokio::fs::metadata(&f.path)
+                            .await
+                            .and_then(|m| m.modified())
+                            .is_ok_and(|m| m < started)
+                    {
+                        continue;
+                    }
+                    let name = db.archive_name(&f.path);
+                    match write_rollup(&f.path, &jobs).await {
+                        Ok(r) => info!(
+                            "Rolled up {}: {} reactions, {} zaps",
+                            name, r.reactions, r.zaps
+                        ),
+                        Err(e) => error!("Failed to roll up {}: {}", name, e),
+                    }
+                }
+            }
+            Err(e) => error!("Failed to list archives: {}", e),
+        }
+        tokio::time::sleep(ROLLUP_INTERVAL).await;
+    }
+}
+
+/// Write missing rollups for all finalized archives in `out_dir` and one level of
+/// partition directories, nested `YYYY/MM` directories included, reading them with
+/// [scan_files]; returns the number written and the number that failed
+pub async fn rebuild(out_dir: &Path) -> Result<(usize, usize)> {
+    let jobs = ArchiveJobs::new(1);
+    let (mut written, mut failed) = (0, 0);
+    let mut files = Vec::new();
+    let mut dirs = vec![(out_dir.to_path_buf(), true)];
+    while let Some((d, top)) = dirs.pop() {
+        let mut dir = tokio::fs::read_dir(&d).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if (top && !name.starts_with('.')) || is_date_dir(&name) {
+                    dirs.push((path, false));
+                }
+                continue;
+            }
+            if !is_archive(&path) || is_active(&path) || is_fresh(&rollup_path(&path), &path).await
+            {
+                continue;
+            }
+            match read_summary(&path).await {
+                // nothing to read, written right away
+                Some(s)
+                    if !s.kinds.contains_key(&REACTION) && !s.kinds.contains_key(&ZAP_RECEIPT) =>
+                {
+                    match write_rollup(&path, &jobs).await {
+                        Ok(_) => {
+                            println!("{} 0 reactions, 0 zaps, 0 sats", path.display());
+                            written += 1;
+                        }
+                        Err(e) => {
+                            println!("{} failed: {}", path.display(), e);
+                            failed += 1;
+                        }
+                    }
+                }
+                _ => files.push(path),
+            }
+        }
+    }
+    tokio::task::spawn_blocking(move || {
+        scan_files::<(), _>(
+            &files,
+            |path, _| roll_up(path),
+            |path, msg, progress| {
+                let Scanned::Done(rollup) = msg else {
+                    return Ok(());
+                };
+                let res = rollup.and_then(|r| {
+                    std::fs::write(rollup_path(path), serde_json::to_vec(&r)?)?;
+                    Ok(r)
+                });
+                match res {
+                    Ok(r) => {
+                        println!(
+                            "{} {} {} reactions, {} zaps, {} sats",
+                            progress,
+                            path.display(),
+                            r.reactions,
+                            r.zaps,
+                            r.zapped_msats / 1000
+                        );
+                        written += 1;
+                    }
+                    Err(e) => {
+                        println!("{} {} failed: {}", progress, path.display(), e);
+                        failed += 1;
+                    }
+                }
+                Ok(())
+            },
+        )?;
+        Ok((written, failed))
+    })
+    .await?
+}
+
+/// Reactions and zaps of one UTC day
+#[derive(Serialize)]
+pub struct ZapDay {
+    pub date: String,
+    pub reactions: u64,
+    pub zaps: u64,
+    pub sats: u64,
+}
+
+/// Rollups of the archives in a range of days, the top lists are merged from the top
+/// lists of each archive
+#[derive(Serialize, Default)]
+pub struct ZapStats {
+    pub reactions: u64,
+    pub zaps: u64,
+    pub sats: u64,
+    pub unparsed_zaps: u64,
+    /// Per UTC day with a rollup, oldest first
+    pub days: Vec<ZapDay>,
+    pub top_reacted: Vec<(String, u64)>,
+    /// Most zapped events and their sats
+    pub top_zapped: Vec<(String, u64)>,
+}
+
+impl ZapStats {
+    /// Add the rollup of an archive of `date`
+    pub fn add(&mut self, date: String, r: &ArchiveRollup) {
+        self.reactions += r.reactions;
+        self.zaps += r.zaps;
+        self.unparsed_zaps += r.unparsed_zaps;
+        match self.days.iter_mut().find(|d| d.date == date) {
+            Some(d) => {
+                d.reactions += r.reactions;
+                d.zaps += r.zaps;
+                d.sats += r.zapped_msats / 1000;
+            }
+            None => self.days.push(ZapDay {
+                date,
+                reactions: r.reactions,
+                zaps: r.zaps,
+                sats: r.zapped_msats / 1000,
+            }),
+        }
+        self.sats = self.days.iter().map(|d| d.sats).sum();
+        let merge = |list: &mut Vec<(String, u64)>, add: &[(String, u64)], scale: u64| {
+            let mut counts: BTreeMap<String, u64> = list.drain(..).collect();
+            for (id, n) in add {
+                *counts.entry(id.clone()).or_default() += n / scale;
+            }
+            *list = top(counts.into_iter().collect());
+        };
+        merge(&mut self.top_reacted, &r.top_reacted, 1);
+        merge(&mut self.top_zapped, &r.top_zapped, 1000);
+        self.days.sort_by(|a, b| a.date.cmp(&b.date));
+    }
+}
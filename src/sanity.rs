@@ -0,0 +1,138 @@
+use crate::scan::QUARANTINE_DIR;
+use chrono::Utc;
+use log::{info, warn};
+use nostr_sdk::prelude::JsonUtil;
+use nostr_sdk::{Event, EventId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct EventLimitSettings {
+    /// Most tags an event may have
+    pub max_tags: Option<usize>,
+
+    /// Longest tag value in bytes
+    pub max_tag_value_bytes: Option<usize>,
+
+    /// Largest content in bytes
+    pub max_content_bytes: Option<usize>,
+
+    /// Write events over the limits to `quarantine/quarantine_YYYYMMDD.jsonl` instead of dropping them
+    pub quarantine: Option<bool>,
+}
+
+#[derive(Serialize, Default)]
+pub struct LimitCounts {
+    pub quarantined: u64,
+    pub rejected: u64,
+}
+
+#[derive(Default)]
+struct Quarantined {
+    day: String,
+    /// Ids already written today, the same event often arrives from several relays
+    ids: HashSet<EventId>,
+}
+
+/// Sanity limits checked before events are written to the archive
+#[derive(Clone)]
+pub struct EventLimits {
+    settings: Arc<EventLimitSettings>,
+    /// Quarantine directory, None when events are rejected
+    dir: Option<PathBuf>,
+    written: Arc<Mutex<Quarantined>>,
+    quarantined: Arc<AtomicU64>,
+    rejected: Arc<AtomicU64>,
+}
+
+impl EventLimits {
+    pub fn new(settings: EventLimitSettings, out_dir: PathBuf) -> Self {
+        Self {
+            dir: settings
+                .quarantine
+                .unwrap_or(false)
+                .then(|| out_dir.join(QUARANTINE_DIR)),
+            settings: Arc::new(settings),
+            written: Arc::new(Mutex::new(Quarantined::default())),
+            quarantined: Arc::new(AtomicU64::new(0)),
+            rejected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn counts(&self) -> LimitCounts {
+        LimitCounts {
+            quarantined: self.quarantined.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reason the event breaks a limit, None when it may be archived
+    pub fn check(&self, event: &Event) -> Option<String> {
+        let s = &self.settings;
+        if let Some(max) = s.max_tags
+            && event.tags.len() > max
+        {
+            return Some(format!("{} tags", event.tags.len()));
+        }
+        if let Some(max) = s.max_tag_value_bytes
+            && let Some(len) = event
+                .tags
+                .iter()
+                .flat_map(|t| t.as_slice().iter().map(|v| v.len()))
+                .find(|l| *l > max)
+        {
+            return Some(format!("{} byte tag value", len));
+        }
+        if let Some(max) = s.max_content_bytes
+            && event.content.len() > max
+        {
+            return Some(format!("{} byte content", event.content.len()));
+        }
+        // the archive must parse back to the same event
+        if Event::from_json(event.as_json()).ok().as_ref() != Some(event) {
+            return Some("JSON round-trip mismatch".to_string());
+        }
+        None
+    }
+
+    /// Count and store an event that failed [EventLimits::check]
+    pub async fn quarantine(&self, event: &Event, reason: &str) {
+        let Some(dir) = &self.dir else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        let day = Utc::now().format("%Y%m%d").to_string();
+        let mut written = self.written.lock().await;
+        if written.day != day {
+            written.day = day.clone();
+            written.ids.clear();
+        }
+        if !written.ids.insert(event.id) {
+            return;
+        }
+        info!("Quarantined event {}: {}", event.id, reason);
+        let path = dir.join(format!("quarantine_{}.jsonl", day));
+        let res = async {
+            tokio::fs::create_dir_all(dir).await?;
+            let mut f = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            f.write_all(format!("{}\n", event.as_json()).as_bytes())
+                .await
+        }
+        .await;
+        match res {
+            Ok(_) => {
+                self.quarantined.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => warn!("Failed to write {}: {}", path.display(), e),
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use crate::authors::AuthorIndex;
+use crate::db::decode_archive_strict;
+use crate::index::{IndexBackend, index_path, open_index, open_path};
+use crate::jobs::{JOB_BUFFER, Scanned, scan_files};
+use crate::layout;
+use crate::sketch::Hll;
+use crate::store::EventStore;
+use crate::times::TimeIndex;
+use crate::writer::WriterOptions;
+use anyhow::Result;
+use anyhow::bail;
+use log::{info, warn};
+use nostr_sdk::{EventId, Timestamp};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory next to the archives holding copies of lines that couldn't be read
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Directory next to the archives holding the kind 5 events saved each day
+pub const DELETIONS_DIR: &str = "deletions";
+
+/// Directory next to the archives holding links to the finalized ones, see [crate::published]
+pub const PUBLISHED_DIR: &str = "published";
+
+/// Directories next to the archives that never hold archives
+pub fn is_reserved_dir(name: &str) -> bool {
+    name == QUARANTINE_DIR || name == DELETIONS_DIR || name == PUBLISHED_DIR
+}
+
+/// Prefix of event indexes that couldn't be opened, moved aside by [open_database]
+const CORRUPT_PREFIX: &str = ".index-corrupt-";
+
+/// Malformed lines logged per file, the rest are only counted
+const MAX_LOGGED: u64 = 10;
+
+/// Time index entries of an archive sent to the index writer at once
+const TIME_BATCH: usize = 100_000;
+
+/// Lines read from an archive by [scan_lines]
+#[derive(Default, Clone, Copy)]
+pub struct LineScan {
+    /// Lines accepted as events
+    pub events: u64,
+    /// Lines that weren't events
+    pub skipped: u64,
+    /// The file couldn't be decompressed past this line
+    pub undecodable: Option<u64>,
+}
+
+impl LineScan {
+    pub fn is_clean(&self) -> bool {
+        self.skipped == 0 && self.undecodable.is_none()
+    }
+}
+
+/// `quarantine/malformed_<archive>.txt` in the directory of the archive
+pub fn quarantine_path(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    archive
+        .with_file_name(QUARANTINE_DIR)
+        .join(format!("malformed_{}.txt", name))
+}
+
+/// Read the lines of the archive at `path`, `f` returns false for lines that aren't events
+///
+/// Those lines are logged with their line number, counted and copied to [quarantine_path]
+/// as `<line>\t<content>`, a decode error ends the file instead of failing the scan
+pub fn scan_lines(
+    path: &Path,
+    mut input: impl BufRead,
+    mut f: impl FnMut(&[u8]) -> bool,
+) -> Result<LineScan> {
+    let mut scan = LineScan::default();
+    let mut quarantine: Option<BufWriter<File>> = None;
+    let mut line = Vec::new();
+    let mut n = 0u64;
+    loop {
+        line.clear();
+        match input.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                warn!(
+                    "{}: can't be decoded after line {}, skipping the rest: {}",
+                    path.display(),
+                    n,
+                    e
+                );
+                scan.undecodable = Some(n);
+                break;
+            }
+        }
+        n += 1;
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        if content.is_empty() {
+            continue;
+        }
+        if f(content) {
+            scan.events += 1;
+            continue;
+        }
+        scan.skipped += 1;
+        if scan.skipped <= MAX_LOGGED {
+            warn!("{}:{}: skipping malformed line", path.display(), n);
+        }
+        let out = match &mut quarantine {
+            Some(q) => q,
+            None => {
+                let dst = quarantine_path(path);
+                if let Some(dir) = dst.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                quarantine.insert(BufWriter::new(File::create(dst)?))
+            }
+        };
+        write!(out, "{}\t", n)?;
+        out.write_all(content)?;
+        out.write_all(b"\n")?;
+    }
+    if let Some(mut q) = quarantine {
+        q.flush()?;
+    }
+    Ok(scan)
+}
+
+#[derive(Deserialize)]
+struct IndexFields<'a> {
+    id: &'a str,
+    created_at: u64,
+    #[serde(borrow, default)]
+    pubkey: Option<&'a str>,
+}
+
+/// Lines of an archive the index can use, counting the author in `authors` and `sketch`
+/// and adding the event to `times`
+fn is_indexable(
+    line: &[u8],
+    authors: Option<&mut HashMap<[u8; 32], u64>>,
+    sketch: &mut Hll,
+    times: &mut Vec<(u64, [u8; 32])>,
+) -> bool {
+    let Ok(e) = serde_json::from_slice::<IndexFields>(line) else {
+        return false;
+    };
+    let mut id = [0u8; 32];
+    if hex::decode_to_slice(e.id, &mut id).is_err() {
+        return false;
+    }
+    times.push((e.created_at, id));
+    let mut pubkey = [0u8; 32];
+    if let Some(Ok(())) = e.pubkey.map(|p| hex::decode_to_slice(p, &mut pubkey)) {
+        sketch.insert(&pubkey);
+        if let Some(authors) = authors {
+            *authors.entry(pubkey).or_default() += 1;
+        }
+    }
+    true
+}
+
+/// Rebuild the index of `dir`, malformed lines are quarantined and reported per file;
+/// returns the number of files with skipped lines or decode errors
+///
+/// The counts of `authors` for the archives of `dir` are rebuilt with it, and its events
+/// added to `times` and its unique author sketch. Archives, encrypted ones included, are
+/// read by [scan_files] with this thread writing every index
+pub fn rebuild_index(
+    db: &mut EventStore,
+    dir: &Path,
+    authors: Option<&AuthorIndex>,
+    times: &TimeIndex,
+) -> Result<u64> {
+    if let Some(a) = authors {
+        a.clear(dir)?;
+    }
+    db.setup_for_reindex()?;
+    let files = layout::archive_paths(dir)?;
+    let total = files.len();
+    let mut scans = Vec::with_capacity(total);
+    scan_files(
+        &files,
+        |path, send| {
+            let mut counts = authors.map(|_| HashMap::new());
+            let mut entries = Vec::new();
+            let mut sketch = Hll::default();
+            let scan = File::open(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|f| decode_archive_strict(path, BufReader::with_capacity(JOB_BUFFER, f)))
+                .and_then(|input| {
+                    scan_lines(path, input, |l| {
+                        let ok = is_indexable(l, counts.as_mut(), &mut sketch, &mut entries);
+                        if entries.len() >= TIME_BATCH {
+                            send(std::mem::take(&mut entries));
+                        }
+                        ok
+                    })
+                });
+            if !entries.is_empty() {
+                send(entries);
+            }
+            (scan, counts, sketch)
+        },
+        |path, msg, progress| {
+            match msg {
+                Scanned::Batch(entries) => {
+                    if let Err(e) = times.insert_batch(&entries) {
+                        warn!("{}: failed to index times: {}", path.display(), e);
+                    }
+                    let ids = entries
+                        .into_iter()
+                        .map(|(t, id)| (EventId::from_byte_array(id), Timestamp::from_secs(t)))
+                        .collect();
+                    if let Err(e) = db.insert_batch(ids) {
+                        warn!("{}: failed to index event ids: {}", path.display(), e);
+                    }
+                }
+                Scanned::Done((scan, counts, sketch)) => {
+                    if let (Ok(_), Some(a), Some(c)) = (&scan, authors, &counts)
+                        && let Err(e) = a.insert_archive(path, c)
+                    {
+                        warn!("{}: failed to index authors: {}", path.display(), e);
+                    }
+                    times.sketch().merge(&sketch);
+                    if let Ok(s) = &scan {
+                        info!("{} {}: {} events", progress, path.display(), s.events);
+                    }
+                    scans.push((path.to_path_buf(), scan));
+                }
+            }
+            Ok(())
+        },
+    )?;
+
+    times.sketch().save()?;
+
+    scans.sort_by(|a, b| a.0.cmp(&b.0));
+    let (mut skipped, mut damaged) = (0, 0);
+    for (path, scan) in scans {
+        match scan {
+            Ok(s) => {
+                skipped += s.skipped;
+                if !s.is_clean() {
+                    damaged += 1;
+                    warn!(
+                        "{}: {} events, {} lines skipped{}",
+                        path.display(),
+                        s.events,
+                        s.skipped,
+                        s.undecodable
+                            .map(|n| format!(", undecodable after line {}", n))
+                            .unwrap_or_default()
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("{}: can't be read, skipped: {}", path.display(), e);
+                damaged += 1;
+            }
+        }
+    }
+    info!(
+        "Indexed {} events from {} files in {}, {} lines skipped in {} files",
+        db.count_keys()?,
+        total,
+        dir.display(),
+        skipped,
+        damaged
+    );
+    Ok(damaged)
+}
+
+/// Open the archives of `dir`, with `recover` an index that fails to open is moved aside
+/// to `.index-corrupt-<time>` and a fresh one is created
+///
+/// Returns the moved index, the caller rebuilds the new one from the archives. Older moved
+/// indexes are deleted, only the last is kept. Archives that can't be recovered fail
+/// without touching the index
+pub fn open_database(
+    dir: &Path,
+    recover: bool,
+    backend: IndexBackend,
+    options: WriterOptions,
+) -> Result<(EventStore, Option<PathBuf>)> {
+    EventStore::prepare(dir, &options)?;
+    let err = match open_index(dir, backend) {
+        Ok(index) => {
+            let db = EventStore::with_index(dir.to_path_buf(), backend, index, options);
+            return Ok((db, None));
+        }
+        Err(e) => e,
+    };
+    let index = index_path(dir, backend);
+    if !recover || !index.exists() {
+        bail!(
+            "Failed to open the event index {}: {}\nSet auto_recover_index: true to move it aside \
+             and rebuild it from the archives, or move it away and run `nostrhole index --rebuild`",
+            index.display(),
+            err
+        );
+    }
+    warn!(
+        "Failed to open the event index {}: {}, moving it aside",
+        index.display(),
+        err
+    );
+    let name = format!("{}{}", CORRUPT_PREFIX, Timestamp::now().as_secs());
+    let moved = dir.join(&name);
+    std::fs::rename(&index, &moved)?;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let old = entry.file_name();
+        let old = old.to_string_lossy();
+        if !old.starts_with(CORRUPT_PREFIX) || old == name {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    let index = open_index(dir, backend)?;
+    Ok((
+        EventStore::with_index(dir.to_path_buf(), backend, index, options),
+        Some(moved),
+    ))
+}
+
+/// Log how many events the rebuilt index of `db` gained or lost compared to the moved
+/// index, when that one can still be read
+pub fn compare_recovered(db: &EventStore, moved: &Path) {
+    let new = match db.count_keys() {
+        Ok(n) => n,
+        Err(e) => {
+            warn!("Recovered index can't be counted: {}", e);
+            return;
+        }
+    };
+    match open_path(moved, db.backend()).and_then(|old| old.count_keys()) {
+        Ok(old) => {
+            info!(
+                "Recovered index holds {} events, the broken one {} ({:+})",
+                new,
+                old,
+                new as i64 - old as i64
+            );
+        }
+        Err(e) => info!(
+            "Recovered index holds {} events, the broken one can't be read: {}",
+            new, e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt::{EncryptWriter, test_identity};
+    use crate::test_util::{TempDir, event, lines};
+    use nostr_sdk::{Event, JsonUtil};
+
+    fn jsonl(events: &[Event]) -> String {
+        events.iter().map(|e| e.as_json() + "\n").collect()
+    }
+
+    /// [scan_lines] the archive at `path` the way [rebuild_index] does
+    fn scan(path: &Path) -> LineScan {
+        let input = decode_archive_strict(path, BufReader::new(File::open(path).unwrap())).unwrap();
+        let mut sketch = Hll::default();
+        scan_lines(path, input, |l| {
+            is_indexable(l, None, &mut sketch, &mut Vec::new())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn truncated_last_line_is_skipped() {
+        let dir = TempDir::new();
+        let events: Vec<Event> = (0..3).map(|i| event(1, &i.to_string())).collect();
+        let torn = event(1, "torn").as_json();
+        let torn = &torn[..torn.len() / 2];
+        let path = dir.path().join("events_20250101.jsonl");
+        std::fs::write(&path, jsonl(&events) + torn).unwrap();
+
+        let s = scan(&path);
+        assert_eq!((s.events, s.skipped, s.undecodable), (3, 1, None));
+        assert_eq!(lines(&quarantine_path(&path)), vec![format!("4\t{}", torn)]);
+    }
+
+    #[test]
+    fn garbage_lines_are_skipped_and_the_rest_read() {
+        let dir = TempDir::new();
+        let events: Vec<Event> = (0..4).map(|i| event(1, &i.to_string())).collect();
+        let path = dir.path().join("events_20250101.jsonl");
+        let content =
+            jsonl(&events[..2]) + "not an event\n{\"id\":\"zz\"}\n" + &jsonl(&events[2..]);
+        std::fs::write(&path, content).unwrap();
+
+        let s = scan(&path);
+        assert_eq!((s.events, s.skipped, s.undecodable), (4, 2, None));
+        assert_eq!(
+            lines(&quarantine_path(&path)),
+            vec!["3\tnot an event", "4\t{\"id\":\"zz\"}"]
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_garbage_ends_the_archive() {
+        let dir = TempDir::new();
+        let events: Vec<Event> = (0..4).map(|i| event(1, &i.to_string())).collect();
+        let path = dir.path().join("events_20250101.jsonl.zst");
+        let mut content = zstd::encode_all(jsonl(&events[..2]).as_bytes(), 0).unwrap();
+        content.extend_from_slice(b"garbage between frames");
+        content.extend(zstd::encode_all(jsonl(&events[2..]).as_bytes(), 0).unwrap());
+        std::fs::write(&path, content).unwrap();
+
+        let s = scan(&path);
+        assert_eq!((s.events, s.skipped, s.undecodable), (2, 0, Some(2)));
+        assert!(!s.is_clean());
+        assert!(!quarantine_path(&path).exists());
+
+        // reported as damaged, with the events before the garbage indexed
+        let mut db = EventStore::open(
+            dir.path().to_path_buf(),
+            IndexBackend::Redb,
+            WriterOptions::default(),
+        )
+        .unwrap();
+        let times = TimeIndex::open(&dir.path().join("times")).unwrap();
+        assert_eq!(rebuild_index(&mut db, dir.path(), None, &times).unwrap(), 1);
+        assert_eq!(db.count_keys().unwrap(), 2);
+        assert!(db.contains(&events[1].id).unwrap());
+        assert!(!db.contains(&events[2].id).unwrap());
+    }
+
+    #[tokio::test]
+    async fn rebuild_indexes_encrypted_archives() {
+        let dir = TempDir::new();
+        let plain: Vec<Event> = (0..3).map(|i| event(1, &i.to_string())).collect();
+        let sealed: Vec<Event> = (0..4).map(|i| event(1059, &i.to_string())).collect();
+        std::fs::write(dir.path().join("events_20250101.jsonl"), jsonl(&plain)).unwrap();
+        let f = File::create(dir.path().join("events_20250102.jsonl.zst.enc")).unwrap();
+        let mut out = EncryptWriter::new(f, &[test_identity().public_key()]).unwrap();
+        out.write_all(&zstd::encode_all(jsonl(&sealed).as_bytes(), 0).unwrap())
+            .unwrap();
+        out.finish().unwrap();
+
+        let mut db = EventStore::open(
+            dir.path().to_path_buf(),
+            IndexBackend::Redb,
+            WriterOptions::default(),
+        )
+        .unwrap();
+        let times = TimeIndex::open(&dir.path().join("times")).unwrap();
+        assert_eq!(rebuild_index(&mut db, dir.path(), None, &times).unwrap(), 0);
+        assert_eq!(db.count_keys().unwrap(), 7);
+        for e in plain.iter().chain(&sealed) {
+            assert!(db.contains(&e.id).unwrap());
+        }
+    }
+
+    #[test]
+    fn unrecoverable_archive_leaves_the_index() {
+        let dir = TempDir::new();
+        let id = EventId::from_byte_array([1; 32]);
+        open_index(dir.path(), IndexBackend::Redb)
+            .unwrap()
+            .insert_batch(vec![(id, Timestamp::from_secs(1))])
+            .unwrap();
+        let older = dir.path().join(format!("{}1", CORRUPT_PREFIX));
+        std::fs::create_dir(&older).unwrap();
+        // the newest compressed archive can't be opened to finish its frame
+        std::fs::create_dir(dir.path().join("events_20250101.jsonl.zst")).unwrap();
+
+        let options = WriterOptions {
+            write_compressed: true,
+            ..Default::default()
+        };
+        assert!(open_database(dir.path(), true, IndexBackend::Redb, options).is_err());
+        let moved: Vec<PathBuf> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_string_lossy().contains(CORRUPT_PREFIX))
+            .collect();
+        assert_eq!(moved, vec![older]);
+        let index = open_index(dir.path(), IndexBackend::Redb).unwrap();
+        assert_eq!(index.count_keys().unwrap(), 1);
+        assert!(index.contains(&id).unwrap());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::stats::RelayStats;
+use crate::upstream::Upstream;
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use nostr_sdk::prelude::{AdmitPolicy, AdmitStatus, BoxedFuture, PolicyError};
+use nostr_sdk::{Client, Event, Filter, Kind, PublicKey, RelayUrl, SubscriptionId, Timestamp};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Clone)]
+pub struct IngestScopeSettings {
+    /// Only archive events from the pubkeys this npub follows
+    pub contacts_of: String,
+
+    /// Hours between refreshing the follow list
+    pub refresh_hours: Option<u64>,
+
+    /// Hours of history fetched for newly followed authors
+    pub backfill_hours: Option<u64>,
+
+    /// Authors per subscription filter, for relays limiting filter size
+    pub authors_per_filter: Option<usize>,
+}
+
+/// Authors ingestion is limited to, shared by the subscriptions, the client
+/// admit policy and the relay write policy
+#[derive(Clone, Default)]
+pub struct AuthorScope(Arc<RwLock<HashSet<PublicKey>>>);
+
+impl Debug for AuthorScope {
+    fn fmt(&self, _f: &mut Formatter<'_>) -> std::fmt::Result {
+        Ok(())
+    }
+}
+
+impl AuthorScope {
+    pub fn contains(&self, pubkey: &PublicKey) -> bool {
+        self.0.read().unwrap().contains(pubkey)
+    }
+
+    /// Replace the set, returns the newly added authors or None when unchanged
+    fn replace(&self, authors: HashSet<PublicKey>) -> Option<Vec<PublicKey>> {
+        let mut set = self.0.write().unwrap();
+        if *set == authors {
+            return None;
+        }
+        let added = authors.difference(&set).copied().collect();
+        *set = authors;
+        Some(added)
+    }
+}
+
+/// Counts events like [RelayStats] and drops those outside the scope,
+/// including ones arriving from sync or discovered relays
+#[derive(Debug, Clone)]
+pub struct ScopedAdmit {
+    stats: RelayStats,
+    scope: AuthorScope,
+}
+
+impl ScopedAdmit {
+    pub fn new(stats: RelayStats, scope: AuthorScope) -> Self {
+        Self { stats, scope }
+    }
+}
+
+impl AdmitPolicy for ScopedAdmit {
+    fn admit_event<'a>(
+        &'a self,
+        relay_url: &'a RelayUrl,
+        subscription_id: &'a SubscriptionId,
+        event: &'a Event,
+    ) -> BoxedFuture<'a, Result<AdmitStatus, PolicyError>> {
+        Box::pin(async move {
+            let status = self
+                .stats
+                .admit_event(relay_url, subscription_id, event)
+                .await?;
+            if !matches!(status, AdmitStatus::Success) {
+                return Ok(status);
+            }
+            if self.scope.contains(&event.pubkey) {
+                Ok(AdmitStatus::Success)
+            } else {
+                self.stats.record_out_of_scope(relay_url);
+                Ok(AdmitStatus::rejected("author not in ingest scope"))
+            }
+        })
+    }
+}
+
+/// Keep the ingest subscriptions scoped to the follow list of `contacts_of`,
+/// backfilling authors followed since the last refresh
+pub async fn run_scope(
+    client: Client,
+    upstream: Upstream,
+    filter_base: Filter,
+    limit: Option<usize>,
+    admit: ScopedAdmit,
+    settings: IngestScopeSettings,
+    relays: Vec<String>,
+) -> Result<()> {
+    let ScopedAdmit { stats, scope } = admit;
+    let pubkey = PublicKey::parse(&settings.contacts_of)?;
+    let interval = Duration::from_secs(settings.refresh_hours.unwrap_or(12) * 60 * 60);
+    let backfill = settings.backfill_hours.unwrap_or(24) * 60 * 60;
+    let per_filter = settings.authors_per_filter.unwrap_or(500).max(1);
+
+    // the follow list itself is outside the scope, fetch it with a separate client
+    let lookup = upstream.client(&relays).await?;
+
+    let mut subscriptions = 0;
+    let mut first = true;
+    loop {
+        match follows(&lookup, pubkey).await {
+            Ok(authors) => {
+                let mut list: Vec<PublicKey> = authors.iter().copied().collect();
+                list.sort();
+                let added = match scope.replace(authors) {
+                    Some(added) => added,
+                    None if !first => {
+                        tokio::time::sleep(interval).await;
+                        continue;
+                    }
+                    None => vec![],
+                };
+
+                let chunks: Vec<&[PublicKey]> = list.chunks(per_filter).collect();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    // same id replaces the previous filter on every relay
+                    let mut filter = filter_base.clone().authors(chunk.iter().copied());
+                    if let Some(l) = limit {
+                        filter = filter.limit(l);
+                    }
+                    let id = SubscriptionId::new(format!("scope-{}", i));
+                    stats.track_filter(&id, &filter);
+                    if let Err(e) = client.subscribe_with_id(id, filter, None).await {
+                        warn!("Failed to subscribe: {}", e);
+                    }
+                }
+                for i in chunks.len()..subscriptions {
+                    let id = SubscriptionId::new(format!("scope-{}", i));
+                    stats.untrack_filter(&id);
+                    client.unsubscribe(&id).await;
+                }
+                subscriptions = chunks.len();
+
+                info!(
+                    "Ingest scope: {} authors in {} filters, {} new",
+                    list.len(),
+                    chunks.len(),
+                    added.len()
+                );
+                if !first && !added.is_empty() {
+                    let since = Timestamp::now() - backfill;
+                    for chunk in added.chunks(per_filter) {
+                        let filter = filter_base
+                            .clone()
+                            .authors(chunk.iter().copied())
+                            .since(since);
+                        // fetched events are saved by the client
+                        match client.fetch_events(filter, FETCH_TIMEOUT).await {
+                            Ok(events) => info!("Backfilled {} events", events.len()),
+                            Err(e) => warn!("Backfill failed: {}", e),
+                        }
+                    }
+                }
+                first = false;
+            }
+            Err(e) => warn!("Failed to refresh ingest scope: {}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn follows(lookup: &Client, pubkey: PublicKey) -> Result<HashSet<PublicKey>> {
+    Ok(lookup
+        .fetch_events(
+            Filter::new().kind(Kind::ContactList).author(pubkey),
+            FETCH_TIMEOUT,
+        )
+        .await?
+        .into_iter()
+        .filter(|e| e.kind == Kind::ContactList && e.pubkey == pubkey)
+        .max_by_key(|e| e.created_at)
+        .ok_or(anyhow!("No contact list found for {}", pubkey))?
+        .tags
+        .public_keys()
+        .copied()
+        .collect())
+}
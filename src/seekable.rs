@@ -0,0 +1,372 @@
+use crate::db::{ArchiveDatabase, is_active, is_fresh, is_published};
+use crate::jobs::{ArchiveJobs, JOB_BUFFER, open_input};
+use crate::metrics;
+use anyhow::{Result, bail};
+use dashmap::DashSet;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often finalized archives are checked for a seek table
+const SEEKABLE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Magic of the skippable frame holding the seek table
+const SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+
+/// Last 4 bytes of a seekable zstd file
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+
+/// Frame count, descriptor and magic
+const FOOTER_LEN: u64 = 9;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct SeekableSettings {
+    /// Rewrite finalized zstd archives in the seekable format, default true,
+    /// false keeps the single frame written by the archiver
+    pub enabled: Option<bool>,
+
+    /// Uncompressed size of each frame in KiB, default 4096
+    pub frame_size_kb: Option<u64>,
+}
+
+/// Compressed and uncompressed size of a frame
+pub struct Frame {
+    pub compressed: u64,
+    pub decompressed: u64,
+}
+
+/// Contents of `events_YYYYMMDD.frames.json` next to seekable archives, the oldest and
+/// newest created_at of each frame in the seek table, None for frames without events
+#[derive(Serialize, Deserialize, Default)]
+pub struct FrameTimes {
+    pub frames: Vec<Option<[u64; 2]>>,
+}
+
+#[derive(Deserialize)]
+struct CreatedAt {
+    created_at: u64,
+}
+
+impl FrameTimes {
+    /// created_at range of the lines of one frame
+    fn add(&mut self, lines: &[u8]) {
+        let range = lines
+            .split(|b| *b == b'\n')
+            .filter_map(|l| serde_json::from_slice::<CreatedAt>(l).ok())
+            .fold(None, |r: Option<[u64; 2]>, e| {
+                let c = e.created_at;
+                Some(r.map_or([c, c], |[a, b]| [a.min(c), b.max(c)]))
+            });
+        self.frames.push(range);
+    }
+}
+
+/// `events_YYYYMMDD.frames.json` in the directory of the archive
+pub fn frames_path(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let day = name.split('.').next().unwrap_or(name);
+    archive.with_file_name(format!("{}.frames.json", day))
+}
+
+/// Frame times of a seekable archive, None until they were written for the current file
+pub async fn read_frame_times(archive: &Path) -> Option<FrameTimes> {
+    let path = frames_path(archive);
+    if !is_fresh(&path, archive).await {
+        return None;
+    }
+    serde_json::from_slice(&tokio::fs::read(&path).await.ok()?).ok()
+}
+
+/// Rewrites finalized zstd archives as independent frames followed by a seek table
+/// ([format](https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md)),
+/// so lines can be read without decompressing the file from the start
+#[derive(Clone)]
+pub struct Seekable {
+    frame_size: usize,
+    jobs: ArchiveJobs,
+    /// Archives that failed to rewrite, left as they are
+    failed: Arc<DashSet<PathBuf>>,
+}
+
+impl Seekable {
+    pub fn new(settings: &SeekableSettings, jobs: ArchiveJobs) -> Self {
+        Self {
+            frame_size: (settings.frame_size_kb.unwrap_or(4096).max(64) * 1024) as usize,
+            jobs,
+            failed: Arc::new(DashSet::new()),
+        }
+    }
+
+    /// Finalized zstd archive that will still be rewritten, its checksum isn't published
+    /// until then
+    ///
+    /// Archives that already have a checksum, torrent or attestation are never rewritten
+    pub async fn is_pending(&self, path: &Path) -> bool {
+        if !matches!(path.extension().and_then(|e| e.to_str()), Some("zst"))
+            || self.failed.contains(path)
+        {
+            return false;
+        }
+        // the writer is still compressing while the jsonl exists
+        if tokio::fs::try_exists(path.with_extension(""))
+            .await
+            .unwrap_or(true)
+            || is_active(path)
+            || is_published(path).await
+        {
+            return false;
+        }
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            File::open(path).and_then(|mut f| seek_table(&mut f)).ok()
+        })
+        .await
+        .ok()
+        .flatten()
+        .is_some_and(|t| t.is_none())
+    }
+
+    /// Seekable archive written before frame times were recorded
+    async fn needs_frame_times(&self, path: &Path) -> bool {
+        if !matches!(path.extension().and_then(|e| e.to_str()), Some("zst"))
+            || self.failed.contains(path)
+            || is_fresh(&frames_path(path), path).await
+            || is_active(path)
+            || tokio::fs::try_exists(path.with_extension(""))
+                .await
+                .unwrap_or(true)
+        {
+            return false;
+        }
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            File::open(path).and_then(|mut f| seek_table(&mut f)).ok()
+        })
+        .await
+        .ok()
+        .flatten()
+        .is_some_and(|t| t.is_some())
+    }
+
+    async fn write_frame_times(&self, path: &Path) -> Result<usize> {
+        let p = path.to_path_buf();
+        let res = self.jobs.run(move || index_frames(&p)).await;
+        if res.is_err() {
+            self.failed.insert(path.to_path_buf());
+        }
+        res
+    }
+
+    async fn rewrite(&self, path: &Path) -> Result<usize> {
+        let frame_size = self.frame_size;
+        let p = path.to_path_buf();
+        let res = self.jobs.run(move || reframe(&p, frame_size)).await;
+        if res.is_err() {
+            self.failed.insert(path.to_path_buf());
+        }
+        res
+    }
+}
+
+/// Periodically rewrite newly finalized zstd archives in the seekable format
+pub async fn run_seekable(db: ArchiveDatabase, seekable: Seekable) -> Result<()> {
+    loop {
+        match db.list_archives().await {
+            Ok(files) => {
+                let mut rewritten = 0;
+                for f in files.iter() {
+                    if seekable.needs_frame_times(&f.path).await {
+                        match seekable.write_frame_times(&f.path).await {
+                            Ok(frames) => info!(
+                                "Recorded the times of {} frames of {}",
+                                frames,
+                                db.archive_name(&f.path)
+                            ),
+                            Err(e) => {
+                                error!("Failed to read frames of {}: {}", f.path.display(), e)
+                            }
+                        }
+                        continue;
+                    }
+                    if !seekable.is_pending(&f.path).await {
+                        continue;
+                    }
+                    match seekable.rewrite(&f.path).await {
+                        Ok(frames) => {
+                            info!(
+                                "Rewrote {} as {} seekable frames",
+                                db.archive_name(&f.path),
+                                frames
+                            );
+                            rewritten += 1;
+                        }
+                        Err(e) => error!("Failed to rewrite {}: {}", f.path.display(), e),
+                    }
+                }
+                if rewritten > 0 {
+                    // sizes changed
+                    db.invalidate_archives().await;
+                }
+            }
+            Err(e) => error!("Failed to list archives: {}", e),
+        }
+        tokio::time::sleep(SEEKABLE_INTERVAL).await;
+    }
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/// Frames listed in the seek table, None when the file has none
+pub fn seek_table(f: &mut File) -> std::io::Result<Option<Vec<Frame>>> {
+    let len = f.metadata()?.len();
+    if len < FOOTER_LEN + 8 {
+        return Ok(None);
+    }
+    let mut footer = [0u8; FOOTER_LEN as usize];
+    f.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+    f.read_exact(&mut footer)?;
+    if read_u32(&footer[5..]) != SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+    // entries carry a checksum when the top bit of the descriptor is set
+    let entry = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+    let table = read_u32(&footer) as u64 * entry;
+    if table + FOOTER_LEN + 8 > len {
+        return Ok(None);
+    }
+    let mut buf = vec![0u8; (table + 8) as usize];
+    f.seek(SeekFrom::End(-((table + FOOTER_LEN + 8) as i64)))?;
+    f.read_exact(&mut buf)?;
+    if read_u32(&buf) != SKIPPABLE_MAGIC || read_u32(&buf[4..]) as u64 != table + FOOTER_LEN {
+        return Ok(None);
+    }
+    Ok(Some(
+        buf[8..]
+            .chunks_exact(entry as usize)
+            .map(|e| Frame {
+                compressed: read_u32(e) as u64,
+                decompressed: read_u32(&e[4..]) as u64,
+            })
+            .collect(),
+    ))
+}
+
+/// Reader at `offset` of the uncompressed archive, decompressing from the frame
+/// holding it, None when the archive is not seekable
+pub fn open_at(path: &Path, offset: u64) -> Result<Option<Box<dyn BufRead + Send>>> {
+    let mut f = File::open(path)?;
+    let Some(frames) = seek_table(&mut f)? else {
+        return Ok(None);
+    };
+    let (mut compressed, mut start) = (0u64, 0u64);
+    for frame in frames {
+        if offset < start + frame.decompressed {
+            f.seek(SeekFrom::Start(compressed))?;
+            // later frames are read too when the line continues past this one
+            let mut r = BufReader::new(zstd::Decoder::new(f)?);
+            std::io::copy(&mut r.by_ref().take(offset - start), &mut std::io::sink())?;
+            return Ok(Some(Box::new(r)));
+        }
+        compressed += frame.compressed;
+        start += frame.decompressed;
+    }
+    Ok(Some(Box::new(std::io::empty())))
+}
+
+/// Decompress every frame of a seekable archive and write its frame times, returns the
+/// number of frames
+fn index_frames(path: &Path) -> Result<usize> {
+    let mut f = File::open(path)?;
+    let Some(frames) = seek_table(&mut f)? else {
+        bail!("No seek table");
+    };
+    f.seek(SeekFrom::Start(0))?;
+    let mut input = BufReader::with_capacity(JOB_BUFFER, f);
+    let mut times = FrameTimes::default();
+    let mut buf = Vec::new();
+    for frame in &frames {
+        buf.clear();
+        zstd::Decoder::with_buffer((&mut input).take(frame.compressed))?
+            .single_frame()
+            .read_to_end(&mut buf)?;
+        times.add(&buf);
+    }
+    std::fs::write(frames_path(path), serde_json::to_vec(&times)?)?;
+    Ok(frames.len())
+}
+
+/// Rewrite a zstd archive as frames of at least `frame_size` uncompressed bytes ending
+/// on a line and write its frame times, returns the number of frames
+fn reframe(path: &Path, frame_size: usize) -> Result<usize> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!("{}.tmp", name));
+    let res = write_frames(path, &tmp, frame_size);
+    match res {
+        Ok(times) => {
+            std::fs::rename(&tmp, path)?;
+            // written after the rename so it is newer than the archive
+            std::fs::write(frames_path(path), serde_json::to_vec(&times)?)?;
+            Ok(times.frames.len())
+        }
+        Err(e) => {
+            if let Err(e) = std::fs::remove_file(&tmp) {
+                warn!("Failed to remove {}: {}", tmp.display(), e);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn write_frames(path: &Path, tmp: &Path, frame_size: usize) -> Result<FrameTimes> {
+    let started = Instant::now();
+    // decode errors must fail the rewrite, a truncated archive is left untouched
+    let mut input = BufReader::with_capacity(
+        JOB_BUFFER,
+        zstd::Decoder::with_buffer(open_input(path, "Rewriting")?)?,
+    );
+    let mut out = BufWriter::with_capacity(JOB_BUFFER, File::create(tmp)?);
+    let mut table = Vec::new();
+    let mut times = FrameTimes::default();
+    let mut buf = Vec::with_capacity(frame_size + 64 * 1024);
+    let mut plain = 0;
+    loop {
+        let n = input.read_until(b'\n', &mut buf)?;
+        if buf.len() >= frame_size || (n == 0 && !buf.is_empty()) {
+            let frame = zstd::bulk::compress(&buf, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            let (Ok(c), Ok(d)) = (u32::try_from(frame.len()), u32::try_from(buf.len())) else {
+                bail!("Frame too large");
+            };
+            out.write_all(&frame)?;
+            plain += buf.len() as u64;
+            table.extend_from_slice(&c.to_le_bytes());
+            table.extend_from_slice(&d.to_le_bytes());
+            times.add(&buf);
+            buf.clear();
+        }
+        if n == 0 {
+            break;
+        }
+    }
+    let frames = table.len() / 8;
+    out.write_all(&SKIPPABLE_MAGIC.to_le_bytes())?;
+    out.write_all(&(table.len() as u32 + FOOTER_LEN as u32).to_le_bytes())?;
+    out.write_all(&table)?;
+    out.write_all(&(frames as u32).to_le_bytes())?;
+    out.write_all(&[0])?;
+    out.write_all(&SEEKABLE_MAGIC.to_le_bytes())?;
+    out.into_inner()?.sync_all()?;
+    metrics::record_compression(plain, started.elapsed());
+    Ok(times)
+}
@@ -0,0 +1,140 @@
+use crate::prune;
+use crate::rocks;
+use anyhow::{Result, anyhow};
+use log::warn;
+use nostr_sdk::{EventId, Timestamp};
+use rocksdb::{DB, WriteBatch};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Key of the [SeenTotals], event keys are 32 bytes
+const TOTALS_KEY: &[u8] = b"totals";
+
+/// Deliveries of an archived event after it was saved
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+pub struct SeenInfo {
+    /// Last time it was delivered again
+    pub last_seen: u64,
+    /// Times it was delivered again
+    pub count: u64,
+}
+
+/// Sightings of every archived event
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+pub struct SeenTotals {
+    /// Events delivered again at least once
+    pub events: u64,
+    /// Deliveries of already archived events
+    pub sightings: u64,
+}
+
+fn pair(v: &[u8]) -> (u64, u64) {
+    let n = |i: usize| {
+        v.get(i * 8..i * 8 + 8)
+            .and_then(|b| b.try_into().ok())
+            .map(u64::from_le_bytes)
+            .unwrap_or_default()
+    };
+    (n(0), n(1))
+}
+
+fn to_bytes(a: u64, b: u64) -> [u8; 16] {
+    let mut ret = [0u8; 16];
+    ret[..8].copy_from_slice(&a.to_le_bytes());
+    ret[8..].copy_from_slice(&b.to_le_bytes());
+    ret
+}
+
+/// Index of event id -> last time and number of times an archived event was delivered
+/// again, the archives and the event index are left as they are
+#[derive(Clone)]
+pub struct EventSeen {
+    database: Arc<DB>,
+    /// Held across the read-modify-write of an event and the totals
+    lock: Arc<Mutex<()>>,
+}
+
+impl EventSeen {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = rocks::open(path)?;
+        Ok(Self {
+            database: Arc::new(db),
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Forget every sighting
+    pub fn clear(&self) -> Result<u64> {
+        prune::clear(&self.database)
+    }
+
+    /// Forget the sightings of `ids` and take them off the totals, returns the number of
+    /// events that had been seen
+    pub fn remove(&self, ids: &[[u8; 32]]) -> Result<u64> {
+        let _lock = self
+            .lock
+            .lock()
+            .map_err(|_| anyhow!("Seen index lock poisoned"))?;
+        let mut totals = self.totals();
+        let mut batch = WriteBatch::default();
+        let mut n = 0;
+        for (id, v) in ids.iter().zip(self.database.multi_get(ids)) {
+            let Some(v) = v.map_err(|e| anyhow!(e))? else {
+                continue;
+            };
+            let (_, count) = pair(&v);
+            totals.events = totals.events.saturating_sub(1);
+            totals.sightings = totals.sightings.saturating_sub(count);
+            batch.delete(id);
+            n += 1;
+        }
+        if n > 0 {
+            batch.put(TOTALS_KEY, to_bytes(totals.events, totals.sightings));
+            self.database.write(batch).map_err(|e| anyhow!(e))?;
+        }
+        Ok(n)
+    }
+
+    /// Count a delivery of the already archived `id`
+    pub fn record(&self, id: &EventId) {
+        let Ok(_lock) = self.lock.lock() else {
+            return;
+        };
+        let mut info = self.get(id).unwrap_or_default();
+        let mut totals = self.totals();
+        if info.count == 0 {
+            totals.events += 1;
+        }
+        totals.sightings += 1;
+        info.count += 1;
+        info.last_seen = Timestamp::now().as_secs();
+        let mut batch = WriteBatch::default();
+        batch.put(id.as_bytes(), to_bytes(info.last_seen, info.count));
+        batch.put(TOTALS_KEY, to_bytes(totals.events, totals.sightings));
+        if let Err(e) = self.database.write(batch) {
+            warn!("Failed to record sighting of {}: {}", id, e);
+        }
+    }
+
+    /// Sightings of `id`, None when it wasn't delivered again
+    pub fn get(&self, id: &EventId) -> Option<SeenInfo> {
+        match self.database.get(id.as_bytes()) {
+            Ok(Some(v)) => {
+                let (last_seen, count) = pair(&v);
+                Some(SeenInfo { last_seen, count })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn totals(&self) -> SeenTotals {
+        match self.database.get(TOTALS_KEY) {
+            Ok(Some(v)) => {
+                let (events, sightings) = pair(&v);
+                SeenTotals { events, sightings }
+            }
+            _ => SeenTotals::default(),
+        }
+    }
+}
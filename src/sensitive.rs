@@ -0,0 +1,49 @@
+use crate::kinds::{KindEntry, KindSet};
+use anyhow::{Result, bail};
+use nostr_sdk::Kind;
+
+/// Private messages and their wrappers: NIP-04 DMs (4), NIP-59 seals and gift wraps
+/// (13, 1059), NIP-17 chat and file messages (14, 15) and 1060
+pub const SENSITIVE_KINDS: [u16; 6] = [4, 13, 14, 15, 1059, 1060];
+
+/// Kinds that are only archived when the operator opted in with `allow_sensitive_kinds`
+#[derive(Clone, Debug)]
+pub struct SensitiveKinds {
+    kinds: KindSet,
+    allowed: bool,
+}
+
+impl SensitiveKinds {
+    /// [SENSITIVE_KINDS] and the `sensitive_kinds` of the config
+    pub fn new(extra: &[KindEntry], allowed: bool) -> Result<Self> {
+        let entries: Vec<KindEntry> = SENSITIVE_KINDS
+            .iter()
+            .map(|k| KindEntry::Kind(*k))
+            .chain(extra.iter().cloned())
+            .collect();
+        let kinds = KindSet::parse(&entries)?;
+        if kinds.is_all() {
+            bail!("sensitive_kinds can't be all, that would archive nothing");
+        }
+        Ok(Self { kinds, allowed })
+    }
+
+    /// Kinds kept out of ingestion and relay writes, None when they are allowed
+    pub fn blocked(&self) -> Option<&KindSet> {
+        (!self.allowed).then_some(&self.kinds)
+    }
+
+    /// Kinds left out of public http responses, None when they aren't archived or
+    /// downloads need a token
+    pub fn hidden(&self, download_auth: bool) -> Option<&KindSet> {
+        (self.allowed && !download_auth).then_some(&self.kinds)
+    }
+
+    /// `kinds` of an upstream filter without the blocked kinds
+    pub fn filter(&self, kinds: Vec<Kind>) -> Vec<Kind> {
+        match self.blocked() {
+            Some(b) => kinds.into_iter().filter(|k| !b.contains(*k)).collect(),
+            None => kinds,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use rocksdb::DB;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// log2 of the HyperLogLog register count, ~0.8% standard error
+const HLL_BITS: u32 = 14;
+
+/// Key of the serialized registers in the database holding the sketch
+const SKETCH_KEY: &[u8] = b"sketch_authors";
+
+/// How often a changed sketch is written
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// HyperLogLog counter for unique pubkeys
+#[derive(Clone)]
+pub struct Hll {
+    registers: Vec<u8>,
+}
+
+impl Default for Hll {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; 1 << HLL_BITS],
+        }
+    }
+}
+
+impl Hll {
+    /// Registers written by [Hll::to_bytes], None when the length doesn't match
+    fn from_bytes(v: &[u8]) -> Option<Self> {
+        (v.len() == 1 << HLL_BITS).then(|| Self {
+            registers: v.to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// Count a pubkey, inserting one again changes nothing
+    pub fn insert(&mut self, pubkey: &[u8; 32]) {
+        // vanity keys share their leading bits, the trailing ones are as random as the key
+        let hash = u64::from_le_bytes(pubkey[24..].try_into().unwrap());
+        let index = (hash >> (64 - HLL_BITS)) as usize;
+        let rank = ((hash << HLL_BITS) | (1 << (HLL_BITS - 1))).leading_zeros() as u8 + 1;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    /// Add the pubkeys counted by `other`
+    pub fn merge(&mut self, other: &Hll) {
+        for (r, o) in self.registers.iter_mut().zip(&other.registers) {
+            *r = (*r).max(*o);
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        // linear counting is more accurate for small sets
+        if raw <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+}
+
+/// Estimated unique authors of every archived event, kept in memory and written to
+/// `database` by [run_sketch]
+///
+/// Pubkeys are only ever added, authors whose archives were deleted are still counted
+#[derive(Clone)]
+pub struct AuthorSketch {
+    database: Arc<DB>,
+    hll: Arc<Mutex<Hll>>,
+    /// Changed since it was last written
+    dirty: Arc<AtomicBool>,
+}
+
+impl AuthorSketch {
+    /// Sketch stored in `database`, an empty one when it is missing or unreadable
+    pub fn load(database: Arc<DB>) -> Self {
+        let hll = match database.get(SKETCH_KEY) {
+            Ok(Some(v)) => Hll::from_bytes(&v).unwrap_or_else(|| {
+                warn!("Unique author sketch has an unexpected size, starting over");
+                Hll::default()
+            }),
+            Ok(None) => Hll::default(),
+            Err(e) => {
+                warn!("Failed to read unique author sketch: {}", e);
+                Hll::default()
+            }
+        };
+        Self {
+            database,
+            hll: Arc::new(Mutex::new(hll)),
+            dirty: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn insert(&self, pubkey: &[u8; 32]) {
+        self.hll.lock().unwrap().insert(pubkey);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Add the authors of an archive counted while it was indexed
+    pub fn merge(&self, other: &Hll) {
+        self.hll.lock().unwrap().merge(other);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn estimate(&self) -> u64 {
+        self.hll.lock().unwrap().estimate()
+    }
+
+    /// Forget every author, the stored sketch is cleared with its database
+    pub fn reset(&self) {
+        *self.hll.lock().unwrap() = Hll::default();
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Write the sketch when it changed since the last save
+    pub fn save(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let hll = self.hll.lock().unwrap().clone();
+        self.database
+            .put(SKETCH_KEY, hll.to_bytes())
+            .map_err(|e| anyhow!(e))
+            .inspect_err(|_| self.dirty.store(true, Ordering::Relaxed))
+    }
+}
+
+/// Write the unique author sketch once a minute when it changed
+pub async fn run_sketch(sketch: AuthorSketch) -> Result<()> {
+    info!("Unique authors: ~{}", sketch.estimate());
+    loop {
+        tokio::time::sleep(SAVE_INTERVAL).await;
+        let s = sketch.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || s.save()).await? {
+            warn!("Failed to save unique author sketch: {}", e);
+        }
+    }
+}
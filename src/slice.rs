@@ -0,0 +1,222 @@
+use crate::db::{ArchiveDatabase, open_archive};
+use crate::doctor::is_compressing;
+use crate::encrypt::is_encrypted;
+use crate::jobs::JOB_BUFFER;
+use crate::kinds::KindSet;
+use crate::naming::archive_day;
+use crate::seekable::{read_frame_times, seek_table};
+use crate::summary::read_summary;
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate};
+use hyper::body::Bytes;
+use log::warn;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+
+/// Chunks buffered per slice before reading waits for the client
+const SLICE_QUEUE: usize = 16;
+
+/// Bytes collected before a chunk is sent
+const CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct SliceSettings {
+    /// Uncompressed archive data a `/slice` request may read in MiB, default 1024;
+    /// larger ranges are refused with 413
+    pub max_scan_mb: Option<u64>,
+}
+
+impl SliceSettings {
+    pub fn max_scan(&self) -> u64 {
+        self.max_scan_mb.unwrap_or(1024) * 1024 * 1024
+    }
+}
+
+/// Part of an archive read by a slice
+enum SlicePart {
+    /// Compressed byte ranges of the overlapping frames of a seekable archive
+    Frames(PathBuf, Vec<(u64, u64)>),
+    /// Archive read from the start
+    Whole(PathBuf),
+}
+
+/// Archives and frames overlapping a time range
+pub struct SlicePlan {
+    parts: Vec<SlicePart>,
+    /// Uncompressed bytes to read, the file size of archives without a summary
+    pub bytes: u64,
+}
+
+/// Fields of a line a slice is filtered by
+#[derive(Deserialize)]
+struct LineKey {
+    created_at: u64,
+    kind: u16,
+}
+
+fn day(ts: u64) -> Option<NaiveDate> {
+    DateTime::from_timestamp(ts as i64, 0).map(|t| t.date_naive())
+}
+
+/// Find the archives and frames that may hold events with `from <= created_at <= to`
+///
+/// Archives with a summary are picked by their oldest and newest event, others by their
+/// day; encrypted archives are left out as they can't be read here
+pub async fn plan(db: &ArchiveDatabase, from: u64, to: u64) -> Result<SlicePlan> {
+    let mut files: Vec<_> = db.list_archives().await?.iter().cloned().collect();
+    files.sort_by_key(|f| archive_day(&f.path));
+    let (first_day, last_day) = (day(from), day(to));
+    let mut plan = SlicePlan {
+        parts: Vec::new(),
+        bytes: 0,
+    };
+    for f in files {
+        if is_encrypted(&f.path) || is_compressing(&f.path) {
+            continue;
+        }
+        let summary = read_summary(&f.path).await;
+        let overlaps = match &summary {
+            Some(s) => match (s.first, s.last) {
+                (Some(first), Some(last)) => first <= to && last >= from,
+                _ => false,
+            },
+            None => archive_day(&f.path).is_some_and(|d| {
+                first_day.is_none_or(|f| d >= f) && last_day.is_none_or(|l| d <= l)
+            }),
+        };
+        if !overlaps {
+            continue;
+        }
+        if let Some(times) = read_frame_times(&f.path).await {
+            let path = f.path.clone();
+            let table = tokio::task::spawn_blocking(move || {
+                File::open(path).and_then(|mut h| seek_table(&mut h))
+            })
+            .await??;
+            if let Some(table) = table.filter(|t| t.len() == times.frames.len()) {
+                let mut ranges: Vec<(u64, u64)> = Vec::new();
+                let mut offset = 0;
+                for (frame, t) in table.iter().zip(times.frames) {
+                    if t.is_some_and(|[min, max]| min <= to && max >= from) {
+                        plan.bytes += frame.decompressed;
+                        match ranges.last_mut() {
+                            // neighbouring frames are read as one
+                            Some((start, len)) if *start + *len == offset => {
+                                *len += frame.compressed
+                            }
+                            _ => ranges.push((offset, frame.compressed)),
+                        }
+                    }
+                    offset += frame.compressed;
+                }
+                if !ranges.is_empty() {
+                    plan.parts.push(SlicePart::Frames(f.path.clone(), ranges));
+                }
+                continue;
+            }
+        }
+        plan.bytes += summary.map_or(f.size, |s| s.bytes);
+        plan.parts.push(SlicePart::Whole(f.path.clone()));
+    }
+    Ok(plan)
+}
+
+/// Sends matching lines in chunks, fails once the client went away
+struct Output {
+    tx: mpsc::Sender<std::io::Result<Bytes>>,
+    buf: Vec<u8>,
+    /// Kinds left out, the sensitive kinds without download_auth
+    hidden: Option<KindSet>,
+}
+
+impl Output {
+    fn send(&mut self) -> bool {
+        if self.buf.is_empty() {
+            return true;
+        }
+        let data = Bytes::from(std::mem::take(&mut self.buf));
+        self.tx.blocking_send(Ok(data)).is_ok()
+    }
+
+    /// Copy the lines of `input` with `from <= created_at <= to`, false once the client
+    /// went away
+    fn filter(&mut self, input: impl BufRead, from: u64, to: u64) -> std::io::Result<bool> {
+        for line in input.split(b'\n') {
+            let line = line?;
+            let Ok(e) = serde_json::from_slice::<LineKey>(&line) else {
+                continue;
+            };
+            if e.created_at < from
+                || e.created_at > to
+                || self
+                    .hidden
+                    .as_ref()
+                    .is_some_and(|h| h.contains(e.kind.into()))
+            {
+                continue;
+            }
+            self.buf.extend_from_slice(&line);
+            self.buf.push(b'\n');
+            if self.buf.len() >= CHUNK_SIZE && !self.send() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn part(&mut self, part: &SlicePart, from: u64, to: u64) -> Result<bool> {
+        match part {
+            SlicePart::Whole(path) => Ok(self.filter(open_archive(path)?, from, to)?),
+            SlicePart::Frames(path, ranges) => {
+                let mut f = File::open(path)?;
+                for (start, len) in ranges {
+                    f.seek(SeekFrom::Start(*start))?;
+                    let input = zstd::Decoder::new((&mut f).take(*len))?;
+                    if !self.filter(BufReader::with_capacity(JOB_BUFFER, input), from, to)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Stream the events of `plan` with `from <= created_at <= to` and kinds outside `hidden`
+/// as ndjson into the returned channel, an archive that fails to read ends the stream
+/// with its error
+pub fn stream(
+    plan: SlicePlan,
+    from: u64,
+    to: u64,
+    hidden: Option<KindSet>,
+) -> mpsc::Receiver<std::io::Result<Bytes>> {
+    let (tx, rx) = mpsc::channel(SLICE_QUEUE);
+    tokio::task::spawn_blocking(move || {
+        let mut out = Output {
+            tx: tx.clone(),
+            buf: Vec::with_capacity(CHUNK_SIZE + 64 * 1024),
+            hidden,
+        };
+        for part in &plan.parts {
+            match out.part(part, from, to) {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => {
+                    let path = match part {
+                        SlicePart::Whole(p) | SlicePart::Frames(p, _) => p,
+                    };
+                    warn!("Failed to slice {}: {}", path.display(), e);
+                    out.send();
+                    let _ = tx.blocking_send(Err(std::io::Error::other(e.to_string())));
+                    return;
+                }
+            }
+        }
+        out.send();
+    });
+    rx
+}
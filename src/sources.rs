@@ -0,0 +1,144 @@
+use crate::prune;
+use crate::rocks;
+use anyhow::{Result, anyhow};
+use log::warn;
+use nostr_sdk::prelude::{AdmitPolicy, AdmitStatus, BoxedFuture, PolicyError};
+use nostr_sdk::{Event, EventId, RelayUrl, SubscriptionId};
+use rocksdb::DB;
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Key of the relay id -> url table, a json list indexed by relay id
+const RELAYS_KEY: &[u8] = b"relays";
+
+/// Index of event id -> ids (u16) of the upstream relays that delivered it
+#[derive(Clone)]
+pub struct EventSources {
+    database: Arc<DB>,
+    /// Relay urls by id, held across the read-modify-write of an event's relays
+    relays: Arc<Mutex<Vec<String>>>,
+}
+
+impl EventSources {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = rocks::open(path)?;
+        let relays = match db.get(RELAYS_KEY).map_err(|e| anyhow!(e))? {
+            Some(v) => serde_json::from_slice(&v)?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            database: Arc::new(db),
+            relays: Arc::new(Mutex::new(relays)),
+        })
+    }
+
+    /// Forget every recorded source and relay id
+    pub fn clear(&self) -> Result<u64> {
+        let mut relays = self
+            .relays
+            .lock()
+            .map_err(|_| anyhow!("Sources lock poisoned"))?;
+        relays.clear();
+        prune::clear(&self.database)
+    }
+
+    /// Forget the sources of `ids`
+    pub fn remove(&self, ids: &[[u8; 32]]) -> Result<()> {
+        prune::remove_ids(&self.database, ids)
+    }
+
+    /// Add `relay` to the sources of `id`
+    pub fn record(&self, id: &EventId, relay: &RelayUrl) -> Result<()> {
+        let mut relays = self
+            .relays
+            .lock()
+            .map_err(|_| anyhow!("Sources lock poisoned"))?;
+        let url = relay.as_str_without_trailing_slash();
+        let relay_id = match relays.iter().position(|r| r == url) {
+            Some(i) => i,
+            None => {
+                if relays.len() > u16::MAX as usize {
+                    return Err(anyhow!("Too many relays to track sources"));
+                }
+                relays.push(url.to_string());
+                self.database
+                    .put(RELAYS_KEY, serde_json::to_vec(&*relays)?)
+                    .map_err(|e| anyhow!(e))?;
+                relays.len() - 1
+            }
+        } as u16;
+        let mut value = self
+            .database
+            .get(id.as_bytes())
+            .map_err(|e| anyhow!(e))?
+            .unwrap_or_default();
+        if value.chunks_exact(2).any(|c| c == relay_id.to_be_bytes()) {
+            return Ok(());
+        }
+        value.extend_from_slice(&relay_id.to_be_bytes());
+        self.database
+            .put(id.as_bytes(), value)
+            .map_err(|e| anyhow!(e))
+    }
+
+    /// Urls of the relays that delivered `id`, in the order they were first seen
+    pub fn get(&self, id: &EventId) -> Result<Vec<String>> {
+        let Some(value) = self.database.get(id.as_bytes()).map_err(|e| anyhow!(e))? else {
+            return Ok(Vec::new());
+        };
+        let relays = self
+            .relays
+            .lock()
+            .map_err(|_| anyhow!("Sources lock poisoned"))?;
+        Ok(value
+            .chunks_exact(2)
+            .filter_map(|c| relays.get(u16::from_be_bytes([c[0], c[1]]) as usize))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Records the relays delivering each event admitted by `inner`, duplicates
+/// included so every source is kept
+pub struct SourceAdmit<P> {
+    inner: P,
+    sources: EventSources,
+}
+
+impl<P> SourceAdmit<P> {
+    pub fn new(inner: P, sources: EventSources) -> Self {
+        Self { inner, sources }
+    }
+}
+
+impl<P: Debug> Debug for SourceAdmit<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SourceAdmit")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<P: AdmitPolicy> AdmitPolicy for SourceAdmit<P> {
+    fn admit_event<'a>(
+        &'a self,
+        relay_url: &'a RelayUrl,
+        subscription_id: &'a SubscriptionId,
+        event: &'a Event,
+    ) -> BoxedFuture<'a, Result<AdmitStatus, PolicyError>> {
+        Box::pin(async move {
+            let status = self
+                .inner
+                .admit_event(relay_url, subscription_id, event)
+                .await?;
+            // ids were checked by RelayStats, the signature is checked after admission
+            if matches!(status, AdmitStatus::Success)
+                && let Err(e) = self.sources.record(&event.id, relay_url)
+            {
+                warn!("Failed to record event source: {}", e);
+            }
+            Ok(status)
+        })
+    }
+}
@@ -0,0 +1,179 @@
+use crate::db::decode_archive_strict;
+use crate::deletions::Tombstones;
+use crate::jobs::{JOB_BUFFER, Scanned, scan_files};
+use crate::scan::scan_lines;
+use anyhow::{Result, bail};
+use nostr_sdk::Event;
+use nostr_sdk::prelude::JsonUtil;
+use rusqlite::{Connection, params};
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// Events inserted per transaction
+const BATCH: usize = 10_000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    id TEXT PRIMARY KEY,
+    pubkey TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    kind INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    sig TEXT NOT NULL,
+    raw TEXT NOT NULL
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS tags (
+    event_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    value TEXT,
+    position INTEGER NOT NULL
+);
+";
+
+/// Created after loading, inserting into indexed tables is several times slower
+const INDEXES: &str = "
+CREATE INDEX IF NOT EXISTS events_kind ON events (kind, created_at);
+CREATE INDEX IF NOT EXISTS events_pubkey ON events (pubkey, created_at);
+CREATE INDEX IF NOT EXISTS events_created_at ON events (created_at);
+CREATE INDEX IF NOT EXISTS tags_event_id ON tags (event_id);
+CREATE INDEX IF NOT EXISTS tags_name_value ON tags (name, value);
+";
+
+/// Events written by [export]
+#[derive(Default)]
+pub struct SqliteExport {
+    pub files: usize,
+    pub events: u64,
+    /// Already in the database by id
+    pub duplicates: u64,
+    pub skipped: u64,
+    /// Left out by `tombstones`
+    pub deleted: u64,
+}
+
+/// Load the events of `files` into the SQLite database at `out`, printing progress per
+/// file; with `append` an existing database is added to and events already in it are skipped,
+/// events deleted by their author are left out with `tombstones`
+///
+/// Archives are read and parsed by [scan_files], this thread inserts their batches
+pub fn export(
+    files: &[PathBuf],
+    out: &Path,
+    append: bool,
+    tombstones: Option<&Tombstones>,
+) -> Result<SqliteExport> {
+    if !append && out.exists() {
+        bail!("{} exists, pass --append to add to it", out.display());
+    }
+    let mut conn = Connection::open(out)?;
+    // a failed export is re-run, durability of each batch doesn't matter
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "OFF")?;
+    conn.execute_batch(SCHEMA)?;
+
+    let mut totals = SqliteExport::default();
+    // (inserted, duplicates) of archives still being read
+    let mut counts: HashMap<PathBuf, (u64, u64)> = HashMap::new();
+    scan_files(files, read_file, |path, msg, progress| {
+        match msg {
+            Scanned::Batch(mut batch) => {
+                if let Some(t) = tombstones {
+                    let len = batch.len();
+                    batch.retain(|(e, _)| !t.is_deleted(e));
+                    totals.deleted += (len - batch.len()) as u64;
+                }
+                let n = insert(&mut conn, &batch)?;
+                let c = counts.entry(path.to_path_buf()).or_default();
+                c.0 += n;
+                c.1 += batch.len() as u64 - n;
+            }
+            Scanned::Done(skipped) => {
+                let skipped = skipped?;
+                let (events, duplicates) = counts.remove(path).unwrap_or_default();
+                println!(
+                    "{} {} {} events, {} already present, {} lines skipped",
+                    progress,
+                    path.display(),
+                    events,
+                    duplicates,
+                    skipped
+                );
+                totals.files += 1;
+                totals.events += events;
+                totals.duplicates += duplicates;
+                totals.skipped += skipped;
+            }
+        }
+        Ok(())
+    })?;
+    println!("Creating indexes..");
+    conn.execute_batch(INDEXES)?;
+    Ok(totals)
+}
+
+/// Parse the events of one archive into batches for the writer, returns the number of
+/// lines skipped
+fn read_file(path: &Path, send: &mut dyn FnMut(Vec<(Event, String)>) -> bool) -> Result<u64> {
+    let input = decode_archive_strict(
+        path,
+        BufReader::with_capacity(JOB_BUFFER, std::fs::File::open(path)?),
+    )?;
+    let mut batch = Vec::with_capacity(BATCH);
+    let mut stopped = false;
+    let scan = scan_lines(path, input, |line| {
+        let Ok(ev) = Event::from_json(line) else {
+            return false;
+        };
+        if stopped {
+            return true;
+        }
+        batch.push((ev, String::from_utf8_lossy(line).into_owned()));
+        if batch.len() >= BATCH {
+            stopped = !send(std::mem::replace(&mut batch, Vec::with_capacity(BATCH)));
+        }
+        true
+    })?;
+    if !batch.is_empty() {
+        send(batch);
+    }
+    Ok(scan.skipped)
+}
+
+/// Insert events and their tags in one transaction, returns the number of new events
+fn insert(conn: &mut Connection, batch: &[(Event, String)]) -> Result<u64> {
+    let tx = conn.transaction()?;
+    let mut inserted = 0;
+    {
+        let mut events = tx.prepare_cached(
+            "INSERT OR IGNORE INTO events (id, pubkey, created_at, kind, content, sig, raw) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        let mut tags = tx.prepare_cached(
+            "INSERT INTO tags (event_id, name, value, position) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (ev, raw) in batch {
+            let id = ev.id.to_hex();
+            let added = events.execute(params![
+                id,
+                ev.pubkey.to_hex(),
+                ev.created_at.as_secs() as i64,
+                ev.kind.as_u16(),
+                ev.content,
+                ev.sig.to_string(),
+                raw,
+            ])?;
+            // tags were written with the event the first time
+            if added == 0 {
+                continue;
+            }
+            inserted += 1;
+            for (position, tag) in ev.tags.iter().enumerate() {
+                let tag = tag.as_slice();
+                tags.execute(params![id, tag[0], tag.get(1), position as i64])?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(inserted)
+}
@@ -0,0 +1,343 @@
+use crate::ingest_jobs::job_name;
+use dashmap::DashMap;
+use log::{debug, warn};
+use nostr_sdk::prelude::{AdmitPolicy, AdmitStatus, BoxedFuture, MatchEventOptions, PolicyError};
+use nostr_sdk::{Client, Event, Filter, RelayUrl, SubscriptionId, Timestamp};
+use serde::Serialize;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Answer to an ingested event whose id doesn't match its content
+pub const INVALID_ID: &str = "invalid: event id doesn't match its content";
+
+#[derive(Default)]
+struct RelayCounters {
+    /// Events delivered by the relay, including ones we already had
+    received: AtomicU64,
+    /// Events first seen from this relay
+    new: AtomicU64,
+    /// Unix time of the last event delivered by the relay
+    last_event: AtomicU64,
+    /// NOTICE messages sent by the relay
+    notices: AtomicU64,
+    /// Subscriptions the relay closed
+    closed: AtomicU64,
+    /// Events whose id doesn't match their content
+    invalid_ids: AtomicU64,
+    /// Events the relay answered with a failed OK
+    rejected: AtomicU64,
+    /// Events outside the kinds, authors or tags of the subscription they were delivered on
+    out_of_scope: AtomicU64,
+    last_notice: Mutex<Option<RelayNotice>>,
+}
+
+/// Fields of a subscription's filter a delivered event has to match, the time range is left
+/// out since subscriptions are reopened with a different `since`
+const SCOPE_MATCH: MatchEventOptions = MatchEventOptions::new()
+    .id(false)
+    .since(false)
+    .until(false)
+    .nip50(false);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum NoticeKind {
+    Notice,
+    /// A subscription was closed
+    Closed,
+    /// An event sent to the relay was refused
+    Ok,
+}
+
+/// Most recent NOTICE, CLOSED or failed OK message of a relay
+#[derive(Serialize, Clone)]
+pub struct RelayNotice {
+    pub at: u64,
+    pub kind: NoticeKind,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct JobCounters {
+    /// Events delivered to the job's subscriptions, including ones we already had
+    received: AtomicU64,
+    /// Events first seen on the job's subscriptions
+    saved: AtomicU64,
+    /// Unix time of the last event delivered to the job
+    last_event: AtomicU64,
+}
+
+/// Per upstream relay and per ingest job ingestion counters
+#[derive(Clone, Default)]
+pub struct RelayStats {
+    relays: Arc<DashMap<RelayUrl, RelayCounters>>,
+    jobs: Arc<DashMap<String, JobCounters>>,
+    /// Filters of the open ingest subscriptions, relays ignoring a clause of them still
+    /// only get matching events archived
+    filters: Arc<DashMap<SubscriptionId, Filter>>,
+    /// Delivered events whose id doesn't match their content, of every relay
+    invalid_ids: Arc<AtomicU64>,
+    /// Ids of delivered events aren't recomputed
+    skip_id_check: bool,
+}
+
+#[derive(Serialize)]
+pub struct JobInfo {
+    pub name: String,
+    pub received: u64,
+    pub saved: u64,
+    pub duplicate: u64,
+    pub last_event: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct RelayInfo {
+    pub url: String,
+    pub status: String,
+    pub received: u64,
+    pub new: u64,
+    pub duplicate: u64,
+    pub last_event: Option<u64>,
+    pub reconnects: usize,
+    pub notices: u64,
+    pub closed: u64,
+    pub rejected: u64,
+    pub invalid_ids: u64,
+    pub out_of_scope: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_notice: Option<RelayNotice>,
+}
+
+impl Debug for RelayStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RelayStats").finish_non_exhaustive()
+    }
+}
+
+impl RelayStats {
+    /// Recompute the id of every delivered event and reject mismatches, on by default
+    pub fn with_verify_ids(mut self, verify: bool) -> Self {
+        self.skip_id_check = !verify;
+        self
+    }
+
+    /// Delivered events rejected because their id doesn't match their content
+    pub fn invalid_ids(&self) -> u64 {
+        self.invalid_ids.load(Ordering::Relaxed)
+    }
+
+    /// Count a newly saved event from `relay`
+    pub fn record_new(&self, relay: &RelayUrl, subscription_id: &SubscriptionId) {
+        if let Some(c) = self.relays.get(relay) {
+            c.new.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(c) = job_name(subscription_id).and_then(|n| self.jobs.get(n)) {
+            c.saved.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Only admit events matching `filter` from subscription `id`
+    pub fn track_filter(&self, id: &SubscriptionId, filter: &Filter) {
+        self.filters.insert(id.clone(), filter.clone());
+    }
+
+    /// Forget the filter of a closed subscription
+    pub fn untrack_filter(&self, id: &SubscriptionId) {
+        self.filters.remove(id);
+    }
+
+    /// Count an event from `relay` that was dropped for being outside the ingest scope
+    pub fn record_out_of_scope(&self, relay: &RelayUrl) {
+        if let Some(c) = self.relays.get(relay) {
+            c.out_of_scope.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Start counting the events of ingest job `name`, counters of a restarted job are kept
+    pub fn track_job(&self, name: &str) {
+        self.jobs.entry(name.to_string()).or_default();
+    }
+
+    /// Drop the counters of a removed ingest job
+    pub fn untrack_job(&self, name: &str) {
+        self.jobs.remove(name);
+    }
+
+    /// If `subscription_id` belongs to a running ingest job
+    pub fn is_job(&self, subscription_id: &SubscriptionId) -> bool {
+        job_name(subscription_id).is_some_and(|n| self.jobs.contains_key(n))
+    }
+
+    /// Counters of the running ingest jobs, by name
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        let mut ret: Vec<JobInfo> = self
+            .jobs
+            .iter()
+            .map(|c| {
+                let received = c.received.load(Ordering::Relaxed);
+                let saved = c.saved.load(Ordering::Relaxed);
+                let last_event = c.last_event.load(Ordering::Relaxed);
+                JobInfo {
+                    name: c.key().clone(),
+                    received,
+                    saved,
+                    duplicate: received.saturating_sub(saved),
+                    last_event: (last_event > 0).then_some(last_event),
+                }
+            })
+            .collect();
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+        ret
+    }
+
+    /// Count a NOTICE, CLOSED or failed OK from `relay` and keep it as its last notice
+    pub fn record_notice(&self, relay: &RelayUrl, kind: NoticeKind, message: String) {
+        let c = self.relays.entry(relay.clone()).or_default();
+        match kind {
+            NoticeKind::Notice => &c.notices,
+            NoticeKind::Closed => &c.closed,
+            NoticeKind::Ok => &c.rejected,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        *c.last_notice.lock().unwrap() = Some(RelayNotice {
+            at: Timestamp::now().as_secs(),
+            kind,
+            message,
+        });
+    }
+
+    /// Current counters for every relay in the client pool
+    ///
+    /// Relays no longer in the pool have their counters dropped
+    pub async fn snapshot(&self, client: &Client) -> Vec<RelayInfo> {
+        let relays = client.relays().await;
+        self.relays.retain(|k, _| relays.contains_key(k));
+
+        let mut ret: Vec<RelayInfo> = relays
+            .iter()
+            .map(|(url, relay)| {
+                let c = self.relays.get(url);
+                let load = |f: fn(&RelayCounters) -> &AtomicU64| {
+                    c.as_ref()
+                        .map(|c| f(c).load(Ordering::Relaxed))
+                        .unwrap_or_default()
+                };
+                let (received, new, last_event, invalid_ids, out_of_scope) = (
+                    load(|c| &c.received),
+                    load(|c| &c.new),
+                    load(|c| &c.last_event),
+                    load(|c| &c.invalid_ids),
+                    load(|c| &c.out_of_scope),
+                );
+                RelayInfo {
+                    url: url.to_string(),
+                    status: relay.status().to_string(),
+                    received,
+                    new,
+                    duplicate: received.saturating_sub(new + invalid_ids + out_of_scope),
+                    last_event: if last_event == 0 {
+                        None
+                    } else {
+                        Some(last_event)
+                    },
+                    reconnects: relay.stats().success().saturating_sub(1),
+                    notices: load(|c| &c.notices),
+                    closed: load(|c| &c.closed),
+                    rejected: load(|c| &c.rejected),
+                    invalid_ids,
+                    out_of_scope,
+                    last_notice: c
+                        .as_ref()
+                        .and_then(|c| c.last_notice.lock().unwrap().clone()),
+                }
+            })
+            .collect();
+        ret.sort_by(|a, b| a.url.cmp(&b.url));
+        ret
+    }
+}
+
+impl AdmitPolicy for RelayStats {
+    fn admit_event<'a>(
+        &'a self,
+        relay_url: &'a RelayUrl,
+        subscription_id: &'a SubscriptionId,
+        event: &'a Event,
+    ) -> BoxedFuture<'a, Result<AdmitStatus, PolicyError>> {
+        Box::pin(async move {
+            let now = Timestamp::now().as_secs();
+            let c = self.relays.entry(relay_url.clone()).or_default();
+            c.received.fetch_add(1, Ordering::Relaxed);
+            c.last_event.store(now, Ordering::Relaxed);
+            // the client only verifies events it hasn't seen, and only once per id, a
+            // mismatch would be counted and indexed under the wrong id
+            if !self.skip_id_check && !event.verify_id() {
+                c.invalid_ids.fetch_add(1, Ordering::Relaxed);
+                self.invalid_ids.fetch_add(1, Ordering::Relaxed);
+                warn!(relay:% = relay_url, id:% = event.id; "Event id doesn't match its content");
+                return Ok(AdmitStatus::rejected(INVALID_ID));
+            }
+            if let Some(f) = self.filters.get(subscription_id)
+                && !f.match_event(event, SCOPE_MATCH)
+            {
+                c.out_of_scope.fetch_add(1, Ordering::Relaxed);
+                debug!(relay:% = relay_url, id:% = event.id; "Event outside of subscription {}", subscription_id);
+                return Ok(AdmitStatus::rejected(
+                    "event doesn't match the subscription",
+                ));
+            }
+            if let Some(c) = job_name(subscription_id).and_then(|n| self.jobs.get(n)) {
+                c.received.fetch_add(1, Ordering::Relaxed);
+                c.last_event.store(now, Ordering::Relaxed);
+            }
+            Ok(AdmitStatus::Success)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::event;
+    use nostr_sdk::EventId;
+
+    /// `e` delivered with the id of another event
+    fn tampered(e: &Event) -> Event {
+        Event::new(
+            EventId::all_zeros(),
+            e.pubkey,
+            e.created_at,
+            e.kind,
+            e.tags.clone(),
+            e.content.clone(),
+            e.sig,
+        )
+    }
+
+    #[tokio::test]
+    async fn mismatched_ids_are_rejected() {
+        let relay = RelayUrl::parse("wss://relay.example.com").unwrap();
+        let sub = SubscriptionId::new("ingest");
+        let e = event(1, "hi");
+        let bad = tampered(&e);
+
+        let stats = RelayStats::default();
+        assert!(matches!(
+            stats.admit_event(&relay, &sub, &e).await.unwrap(),
+            AdmitStatus::Success
+        ));
+        match stats.admit_event(&relay, &sub, &bad).await.unwrap() {
+            AdmitStatus::Rejected { reason } => assert_eq!(reason.as_deref(), Some(INVALID_ID)),
+            AdmitStatus::Success => panic!("admitted an event with a wrong id"),
+        }
+        assert_eq!(stats.invalid_ids(), 1);
+
+        let unchecked = RelayStats::default().with_verify_ids(false);
+        assert!(matches!(
+            unchecked.admit_event(&relay, &sub, &bad).await.unwrap(),
+            AdmitStatus::Success
+        ));
+        assert_eq!(unchecked.invalid_ids(), 0);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::jobs::ArchiveJobs;
+use nostr_sdk::Timestamp;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Minutes of saves counted by [LiveStatus::last_hour]
+const WINDOW_MINUTES: u64 = 60;
+
+/// Recent saves and running archive jobs for the landing page, shared by the database
+/// and the http server
+#[derive(Clone, Default)]
+pub struct LiveStatus {
+    saves: Arc<Saves>,
+    jobs: Option<ArchiveJobs>,
+}
+
+#[derive(Default)]
+struct Saves {
+    /// Saved events per unix minute, oldest first
+    minutes: Mutex<VecDeque<(u64, u64)>>,
+    /// Unix time of the last save
+    last: AtomicU64,
+}
+
+impl LiveStatus {
+    /// Report the jobs running in `jobs`
+    pub fn with_jobs(mut self, jobs: ArchiveJobs) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    /// Count a saved event
+    pub fn saved(&self) {
+        let now = Timestamp::now().as_secs();
+        self.saves.last.store(now, Ordering::Relaxed);
+        let minute = now / 60;
+        let mut minutes = self.saves.minutes.lock().unwrap();
+        match minutes.back_mut() {
+            Some((m, n)) if *m == minute => *n += 1,
+            _ => minutes.push_back((minute, 1)),
+        }
+        while minutes
+            .front()
+            .is_some_and(|(m, _)| *m + WINDOW_MINUTES <= minute)
+        {
+            minutes.pop_front();
+        }
+    }
+
+    /// Events saved in the last hour
+    pub fn last_hour(&self) -> u64 {
+        let minute = Timestamp::now().as_secs() / 60;
+        self.saves
+            .minutes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(m, _)| m + WINDOW_MINUTES > minute)
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    /// Unix time of the last save since startup
+    pub fn last_saved(&self) -> Option<u64> {
+        let last = self.saves.last.load(Ordering::Relaxed);
+        (last > 0).then_some(last)
+    }
+
+    /// Archive jobs (conversions, seek tables, bloom filters, encryption,
+    /// rollups) running now
+    pub fn running_jobs(&self) -> usize {
+        self.jobs.as_ref().map_or(0, |j| j.running())
+    }
+}
@@ -0,0 +1,200 @@
+use crate::authors::dir_size;
+use crate::db::list_dir;
+use crate::index::{EventIndex, IndexBackend, index_path, open_index};
+use crate::writer::{ArchiveWriter, WriterOptions, Written, recover_compressed};
+use anyhow::{Result, anyhow};
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use nostr_archive_cursor::ArchiveFile;
+use nostr_sdk::{Event, EventId, Timestamp};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// Event being saved, saves of the same event wait until it is dropped
+struct Writing {
+    ids: Arc<DashMap<EventId, Arc<Mutex<()>>>>,
+    id: EventId,
+    _held: OwnedMutexGuard<()>,
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        self.ids.remove(&self.id);
+    }
+}
+
+/// Archives of a directory and their event id index, `<dir>/index` or `<dir>/index.redb`
+#[derive(Clone)]
+pub struct EventStore {
+    out_dir: PathBuf,
+    backend: IndexBackend,
+    index: Arc<dyn EventIndex>,
+    writer: ArchiveWriter,
+    /// Ids of the events being saved
+    writing: Arc<DashMap<EventId, Arc<Mutex<()>>>>,
+}
+
+impl EventStore {
+    pub fn open(dir: PathBuf, backend: IndexBackend, options: WriterOptions) -> Result<Self> {
+        Self::prepare(&dir, &options)?;
+        let index = open_index(&dir, backend)?;
+        Ok(Self::with_index(dir, backend, index, options))
+    }
+
+    /// Create `dir` and finish the zstd frame left open in its active archive, before the
+    /// index is opened
+    pub fn prepare(dir: &Path, options: &WriterOptions) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        if options.write_compressed {
+            recover_compressed(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Store of a directory passed to [Self::prepare], with its opened index
+    pub fn with_index(
+        dir: PathBuf,
+        backend: IndexBackend,
+        index: Arc<dyn EventIndex>,
+        options: WriterOptions,
+    ) -> Self {
+        Self {
+            writer: ArchiveWriter::spawn(dir.clone(), options),
+            out_dir: dir,
+            backend,
+            index,
+            writing: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub fn backend(&self) -> IndexBackend {
+        self.backend
+    }
+
+    /// Bytes on disk of the event id index
+    pub fn index_size(&self) -> u64 {
+        let path = index_path(&self.out_dir, self.backend);
+        match self.backend {
+            IndexBackend::Rocksdb => dir_size(&path),
+            IndexBackend::Redb => path.parent().map_or(0, dir_size),
+        }
+    }
+
+    /// Files directly in the directory
+    pub async fn list_files(&self) -> Result<Vec<ArchiveFile>> {
+        list_dir(&self.out_dir).await
+    }
+
+    /// Ids and created_at of the indexed events created in `since..=until`
+    pub fn list_ids(&self, since: u64, until: u64) -> Result<Vec<(EventId, Timestamp)>> {
+        self.index.list_ids(since, until)
+    }
+
+    /// Events in the index, reads every entry the first time
+    pub fn count_keys(&self) -> Result<u64> {
+        self.index.count_keys()
+    }
+
+    pub fn is_index_empty(&self) -> Result<bool> {
+        self.index.is_index_empty()
+    }
+
+    pub fn contains(&self, id: &EventId) -> Result<bool> {
+        self.index.contains(id)
+    }
+
+    /// Drop `ids` from the index, their events are accepted again
+    pub fn remove(&self, ids: &[EventId]) -> Result<()> {
+        self.index.remove(ids)
+    }
+
+    /// Drop every entry of the index, returns the number dropped
+    pub fn clear(&self) -> Result<u64> {
+        self.index.clear()
+    }
+
+    /// Write `event` and add it to the index, None when it is already indexed
+    ///
+    /// It is only indexed once the write reached the configured durability, the index never
+    /// holds ids whose event can still be lost. Saves of the same event wait for each other,
+    /// so it is written once
+    pub async fn save(&self, event: &Event) -> Result<Option<Written>> {
+        let _writing = loop {
+            let lock = Arc::new(Mutex::new(()));
+            let held = lock.clone().lock_owned().await;
+            let other = match self.writing.entry(event.id) {
+                Entry::Occupied(e) => e.get().clone(),
+                Entry::Vacant(e) => {
+                    e.insert(lock);
+                    break Writing {
+                        ids: self.writing.clone(),
+                        id: event.id,
+                        _held: held,
+                    };
+                }
+            };
+            drop(other.lock().await);
+        };
+        let (index, id, created_at) = (self.index.clone(), event.id, event.created_at);
+        if tokio::task::spawn_blocking(move || index.contains(&id)).await?? {
+            return Ok(None);
+        }
+        let written = self.writer.write(event).await?;
+        let index = self.index.clone();
+        tokio::task::spawn_blocking(move || index.insert(id, created_at)).await??;
+        Ok(Some(written))
+    }
+
+    /// Returns once every event saved before was written and indexed durably
+    pub async fn flush(&self) -> Result<()> {
+        self.writer.flush().await?;
+        let index = self.index.clone();
+        tokio::task::spawn_blocking(move || index.flush()).await?
+    }
+
+    /// Prepare the index for a rebuild adding every archived event with [Self::insert_batch]
+    pub fn setup_for_reindex(&mut self) -> Result<()> {
+        Arc::get_mut(&mut self.index)
+            .ok_or_else(|| anyhow!("The event index is in use, it can't be rebuilt"))?
+            .setup_for_reindex()
+    }
+
+    pub fn insert_batch(&self, items: Vec<(EventId, Timestamp)>) -> Result<()> {
+        self.index.insert_batch(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::durability::{Durability, Fsync};
+    use crate::test_util::{TempDir, event, lines};
+    use chrono::Utc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn failed_writes_are_not_indexed() {
+        let dir = TempDir::new();
+        let options = WriterOptions {
+            fsync: Fsync::new(Durability::EveryEvent, Duration::from_secs(5), None),
+            ..Default::default()
+        };
+        let store =
+            EventStore::open(dir.path().to_path_buf(), IndexBackend::default(), options).unwrap();
+        let today = dir
+            .path()
+            .join(format!("events_{}.jsonl", Utc::now().format("%Y%m%d")));
+        // today's archive can't be opened
+        std::fs::create_dir(&today).unwrap();
+
+        let e = event(1, "hi");
+        assert!(store.save(&e).await.is_err());
+        assert!(!store.contains(&e.id).unwrap());
+
+        std::fs::remove_dir(&today).unwrap();
+        assert!(store.save(&e).await.unwrap().is_some());
+        assert!(store.contains(&e.id).unwrap());
+        assert_eq!(lines(&today).len(), 1);
+    }
+}
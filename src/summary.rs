@@ -0,0 +1,212 @@
+use crate::db::{ArchiveDatabase, decode_archive_strict, is_active, is_archive, is_fresh};
+use crate::jobs::{ArchiveJobs, Scanned, open_input, scan_files};
+use crate::layout::is_date_dir;
+use crate::scan::scan_lines;
+use anyhow::Result;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often finalized archives are checked for a missing summary
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Contents of `events_YYYYMMDD.stats.json` next to each finalized archive
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ArchiveSummary {
+    pub events: u64,
+    /// Uncompressed size
+    pub bytes: u64,
+    /// Events per kind
+    pub kinds: BTreeMap<u16, u64>,
+    /// Unique pubkeys, estimated in summaries written before they were counted exactly
+    pub authors: u64,
+    /// Oldest and newest created_at
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+    /// Lines that weren't events, copied to `quarantine/`
+    #[serde(default)]
+    pub skipped: u64,
+    /// Decoding failed before the end of the file
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+impl ArchiveSummary {
+    /// Were all lines of the archive read
+    pub fn is_clean(&self) -> bool {
+        self.skipped == 0 && !self.truncated
+    }
+}
+
+#[derive(Deserialize)]
+struct SummaryFields<'a> {
+    kind: u16,
+    pubkey: &'a str,
+    created_at: u64,
+}
+
+/// `events_YYYYMMDD.stats.json` in the directory of the archive
+pub fn summary_path(archive: &Path) -> PathBuf {
+    let name = archive
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let day = name.split('.').next().unwrap_or(name);
+    archive.with_file_name(format!("{}.stats.json", day))
+}
+
+/// Summary of an archive, None until it has been written for the current file
+pub async fn read_summary(archive: &Path) -> Option<ArchiveSummary> {
+    let path = summary_path(archive);
+    if !is_fresh(&path, archive).await {
+        return None;
+    }
+    serde_json::from_slice(&tokio::fs::read(&path).await.ok()?).ok()
+}
+
+/// Count events, kinds and authors of an archive and write its summary
+pub async fn write_summary(archive: &Path, jobs: &ArchiveJobs) -> Result<ArchiveSummary> {
+    let p = archive.to_path_buf();
+    let summary = jobs.run(move || summarize(&p)).await?;
+    tokio::fs::write(summary_path(archive), serde_json::to_vec(&summary)?).await?;
+    Ok(summary)
+}
+
+/// Count events, kinds and authors of an archive, malformed lines are copied to
+/// `quarantine/`
+pub fn summarize(path: &Path) -> Result<ArchiveSummary> {
+    let mut summary = ArchiveSummary {
+        events: 0,
+        bytes: 0,
+        kinds: BTreeMap::new(),
+        authors: 0,
+        first: None,
+        last: None,
+        skipped: 0,
+        truncated: false,
+    };
+    let mut authors = HashSet::new();
+    let input = decode_archive_strict(path, open_input(path, "Summarizing")?)?;
+    let scan = scan_lines(path, input, |line| {
+        summary.bytes += line.len() as u64 + 1;
+        let Ok(e) = serde_json::from_slice::<SummaryFields>(line) else {
+            return false;
+        };
+        *summary.kinds.entry(e.kind).or_default() += 1;
+        let mut pubkey = [0u8; 32];
+        if hex::decode_to_slice(e.pubkey, &mut pubkey).is_ok() {
+            authors.insert(pubkey);
+        }
+        summary.first = Some(summary.first.map_or(e.created_at, |f| f.min(e.created_at)));
+        summary.last = Some(summary.last.map_or(e.created_at, |l| l.max(e.created_at)));
+        true
+    })?;
+    summary.events = scan.events;
+    summary.skipped = scan.skipped;
+    summary.truncated = scan.undecodable.is_some();
+    summary.authors = authors.len() as u64;
+    Ok(summary)
+}
+
+/// Summarize archives finalized while running, older ones are summarized with `stats --rebuild`
+pub async fn run_summaries(db: ArchiveDatabase, jobs: ArchiveJobs) -> Result<()> {
+    let started = SystemTime::now();
+    loop {
+        match db.list_archives().await {
+            Ok(files) => {
+                for f in files.iter() {
+                    if is_active(&f.path)
+                        || db.is_pending(&f.path).await
+                        || is_fresh(&summary_path(&f.path), &f.path).await
+                        || tokio::fs::metadata(&f.path)
+                            .await
+                            .and_then(|m| m.modified())
+                            .is_ok_and(|m| m < started)
+                    {
+                        continue;
+                    }
+                    let name = db.archive_name(&f.path);
+                    match write_summary(&f.path, &jobs).await {
+                        Ok(s) => info!(
+                            "Summarized {}: {} events, {} authors",
+                            name, s.events, s.authors
+                        ),
+                        Err(e) => error!("Failed to summarize {}: {}", name, e),
+                    }
+                }
+            }
+            Err(e) => error!("Failed to list archives: {}", e),
+        }
+        tokio::time::sleep(SUMMARY_INTERVAL).await;
+    }
+}
+
+/// Write missing summaries for all finalized archives in `out_dir` and one level of
+/// partition directories, nested `YYYY/MM` directories included, reading them with
+/// [scan_files]; returns the number written and the number of archives with skipped lines
+pub async fn rebuild(out_dir: &Path) -> Result<(usize, usize)> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(out_dir.to_path_buf(), true)];
+    while let Some((d, top)) = dirs.pop() {
+        let mut dir = tokio::fs::read_dir(&d).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if (top && !name.starts_with('.')) || is_date_dir(&name) {
+                    dirs.push((path, false));
+                }
+                continue;
+            }
+            if !is_archive(&path) || is_active(&path) || is_fresh(&summary_path(&path), &path).await
+            {
+                continue;
+            }
+            files.push(path);
+        }
+    }
+    tokio::task::spawn_blocking(move || {
+        let (mut written, mut damaged) = (0, 0);
+        scan_files::<(), _>(
+            &files,
+            |path, _| summarize(path),
+            |path, msg, progress| {
+                let Scanned::Done(summary) = msg else {
+                    return Ok(());
+                };
+                let res = summary.and_then(|s| {
+                    std::fs::write(summary_path(path), serde_json::to_vec(&s)?)?;
+                    Ok(s)
+                });
+                match res {
+                    Ok(s) => {
+                        if s.is_clean() {
+                            println!("{} {} {} events", progress, path.display(), s.events);
+                        } else {
+                            println!(
+                                "{} {} {} events, {} lines skipped{}",
+                                progress,
+                                path.display(),
+                                s.events,
+                                s.skipped,
+                                if s.truncated { ", truncated" } else { "" }
+                            );
+                            damaged += 1;
+                        }
+                        written += 1;
+                    }
+                    Err(e) => {
+                        println!("{} {} failed: {}", progress, path.display(), e);
+                        damaged += 1;
+                    }
+                }
+                Ok(())
+            },
+        )?;
+        Ok((written, damaged))
+    })
+    .await?
+}
@@ -0,0 +1,61 @@
+use log::{debug, info, warn};
+use nostr_sdk::prelude::SyncOptions;
+use nostr_sdk::{Client, Filter, Timestamp};
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct SyncSettings {
+    /// Run negentropy sync against upstream relays
+    pub enabled: Option<bool>,
+
+    /// Hours between sync rounds
+    pub interval_hours: Option<u64>,
+
+    /// How many days back to reconcile each round
+    pub window_days: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct NegentropySettings {
+    /// Allow peers to reconcile against this archive over the websocket
+    pub enabled: Option<bool>,
+
+    /// Reconciliations allowed per IP per hour
+    pub max_per_hour: Option<u32>,
+}
+
+/// Periodically reconcile the local index with each upstream relay (NIP-77)
+/// and fetch only the events we are missing
+pub async fn run_sync(client: Client, filter_base: Filter, settings: SyncSettings) {
+    let interval = Duration::from_secs(settings.interval_hours.unwrap_or(24) * 60 * 60);
+    let window = settings.window_days.unwrap_or(7) * 24 * 60 * 60;
+    loop {
+        let now = Timestamp::now();
+        let filter = filter_base.clone().since(now - window).until(now);
+        for url in client.relays().await.into_keys() {
+            match client
+                .sync_with([url.clone()], filter.clone(), &SyncOptions::default())
+                .await
+            {
+                Ok(output) if output.success.contains(&url) => info!(
+                    "Sync {}: {} missing, {} received",
+                    url,
+                    output.remote.len(),
+                    output.received.len()
+                ),
+                Ok(output) => debug!(
+                    "Sync {} failed: {}",
+                    url,
+                    output
+                        .failed
+                        .get(&url)
+                        .map(|s| s.as_str())
+                        .unwrap_or("unknown")
+                ),
+                Err(e) => warn!("Sync {} failed: {}", url, e),
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,114 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+
+/// Byte token bucket allowing up to one second of burst
+struct RateBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateBucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            rate: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Refill, returns how long until there are tokens to spend
+    fn wait(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate)
+            .min(self.rate);
+        self.updated = now;
+        if self.tokens > 0.0 {
+            None
+        } else {
+            // tokens can go negative after a large chunk, wait until the debt is paid
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    fn take(&mut self, n: usize) {
+        self.tokens -= n as f64;
+    }
+}
+
+/// Download bandwidth limits, per connection and shared by all downloads
+#[derive(Clone, Default)]
+pub struct DownloadThrottle {
+    per_conn: Option<u64>,
+    global: Option<Arc<Mutex<RateBucket>>>,
+}
+
+impl DownloadThrottle {
+    /// Rates in bytes per second, zero is unlimited
+    pub fn new(per_conn: u64, global: u64) -> Self {
+        Self {
+            per_conn: Some(per_conn).filter(|r| *r > 0),
+            global: Some(global)
+                .filter(|r| *r > 0)
+                .map(|r| Arc::new(Mutex::new(RateBucket::new(r)))),
+        }
+    }
+
+    /// Throttle for a new download, None when unlimited
+    pub fn start(&self) -> Option<Throttle> {
+        if self.per_conn.is_none() && self.global.is_none() {
+            return None;
+        }
+        Some(Throttle {
+            conn: self.per_conn.map(RateBucket::new),
+            global: self.global.clone(),
+            sleep: None,
+        })
+    }
+}
+
+/// Paces a single download against its own and the global bucket
+pub struct Throttle {
+    conn: Option<RateBucket>,
+    global: Option<Arc<Mutex<RateBucket>>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    /// Ready when the next chunk may be sent, otherwise sleeps until it can
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(s) = self.sleep.as_mut() {
+                if s.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.sleep = None;
+            }
+            let wait = [
+                self.conn.as_mut().and_then(|b| b.wait()),
+                self.global.as_ref().and_then(|b| b.lock().unwrap().wait()),
+            ]
+            .into_iter()
+            .flatten()
+            .max();
+            match wait {
+                Some(w) => self.sleep = Some(Box::pin(tokio::time::sleep(w))),
+                None => return Poll::Ready(()),
+            }
+        }
+    }
+
+    /// Account for `n` bytes sent
+    pub fn consume(&mut self, n: usize) {
+        if let Some(b) = self.conn.as_mut() {
+            b.take(n);
+        }
+        if let Some(b) = self.global.as_ref() {
+            b.lock().unwrap().take(n);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::prune;
+use crate::rocks;
+use crate::sketch::AuthorSketch;
+use anyhow::{Result, anyhow};
+use log::warn;
+use nostr_sdk::{Event, EventId, Timestamp};
+use rocksdb::{DB, Direction, IteratorMode, WriteBatch};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Prefix of `created_at + id` keys
+const TIME_PREFIX: u8 = b't';
+
+/// Set once every archive was indexed, range scans fall back to the event index until then
+const COMPLETE_KEY: &[u8] = b"complete";
+
+/// Event ids ordered by created_at, so time ranges are read without walking the whole
+/// event index
+///
+/// Keys are `created_at` big endian followed by the event id, covering the archives of
+/// every partition; the unique author sketch of the same events is stored with them
+#[derive(Clone)]
+pub struct TimeIndex {
+    database: Arc<DB>,
+    sketch: AuthorSketch,
+}
+
+fn time_key(created_at: u64, id: &[u8; 32]) -> [u8; 41] {
+    let mut key = [0u8; 41];
+    key[0] = TIME_PREFIX;
+    key[1..9].copy_from_slice(&created_at.to_be_bytes());
+    key[9..].copy_from_slice(id);
+    key
+}
+
+impl TimeIndex {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Arc::new(rocks::open(path)?);
+        Ok(Self {
+            sketch: AuthorSketch::load(db.clone()),
+            database: db,
+        })
+    }
+
+    /// Forget every entry, before all archives are indexed again
+    pub fn clear(&self) -> Result<u64> {
+        self.sketch.reset();
+        prune::clear(&self.database)
+    }
+
+    pub fn sketch(&self) -> &AuthorSketch {
+        &self.sketch
+    }
+
+    /// Every archive was indexed since the index was created
+    pub fn is_complete(&self) -> bool {
+        self.database.get(COMPLETE_KEY).is_ok_and(|v| v.is_some())
+    }
+
+    pub fn set_complete(&self) -> Result<()> {
+        self.database.put(COMPLETE_KEY, []).map_err(|e| anyhow!(e))
+    }
+
+    /// Add a saved event and count its author
+    pub fn record(&self, event: &Event) {
+        self.sketch.insert(&event.pubkey.to_bytes());
+        let key = time_key(event.created_at.as_secs(), event.id.as_bytes());
+        if let Err(e) = self.database.put(key, []) {
+            warn!("Failed to update time index: {}", e);
+        }
+    }
+
+    /// Add the `(created_at, id)` entries of an archive
+    pub fn insert_batch(&self, entries: &[(u64, [u8; 32])]) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        for (created_at, id) in entries {
+            batch.put(time_key(*created_at, id), []);
+        }
+        self.database.write(batch).map_err(|e| anyhow!(e))
+    }
+
+    /// Delete entries, returns how many were given
+    pub fn delete_batch(&self, entries: &[(u64, [u8; 32])]) -> Result<u64> {
+        let mut batch = WriteBatch::default();
+        for (created_at, id) in entries {
+            batch.delete(time_key(*created_at, id));
+        }
+        self.database.write(batch).map_err(|e| anyhow!(e))?;
+        Ok(entries.len() as u64)
+    }
+
+    /// Events with `since <= created_at <= until`, oldest first
+    ///
+    /// Blocking, entries are read from the index as the iterator advances
+    pub fn ids_in_range(
+        &self,
+        since: u64,
+        until: u64,
+    ) -> impl Iterator<Item = Result<(EventId, Timestamp)>> + '_ {
+        let start = time_key(since, &[0u8; 32]);
+        self.database
+            .iterator(IteratorMode::From(&start, Direction::Forward))
+            .map(|x| x.map_err(|e| anyhow!(e)))
+            .map_while(move |x| {
+                let (k, _) = match x {
+                    Ok(kv) => kv,
+                    Err(e) => return Some(Err(e)),
+                };
+                if k.len() != 41 || k[0] != TIME_PREFIX {
+                    return None;
+                }
+                let created_at = u64::from_be_bytes(k[1..9].try_into().ok()?);
+                if created_at > until {
+                    return None;
+                }
+                let id = EventId::from_slice(&k[9..]).ok()?;
+                Some(Ok((id, Timestamp::from_secs(created_at))))
+            })
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::{Result, anyhow};
+use log::{error, info};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+#[derive(Deserialize, Clone)]
+pub struct TlsSettings {
+    /// PEM certificate chain
+    pub cert_path: PathBuf,
+
+    /// PEM private key
+    pub key_path: PathBuf,
+}
+
+/// TLS acceptor which re-reads the certificate and key on SIGHUP
+#[derive(Clone)]
+pub struct ReloadableTls {
+    settings: TlsSettings,
+    /// Offer h2 before http/1.1 over ALPN
+    http2: bool,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl ReloadableTls {
+    pub fn new(settings: TlsSettings, http2: bool) -> Result<Self> {
+        let acceptor = Self::load(&settings, http2)?;
+        let ret = Self {
+            settings,
+            http2,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        };
+        let reload = ret.clone();
+        let mut hup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hup.recv().await.is_some() {
+                match Self::load(&reload.settings, reload.http2) {
+                    Ok(a) => {
+                        *reload.acceptor.write().unwrap() = a;
+                        info!("Reloaded TLS certificate");
+                    }
+                    Err(e) => error!("Failed to reload TLS certificate, keeping old one: {}", e),
+                }
+            }
+        });
+        Ok(ret)
+    }
+
+    /// Load the certificate and key once, without watching for SIGHUP
+    pub fn check(settings: &TlsSettings, http2: bool) -> Result<()> {
+        Self::load(settings, http2).map(|_| ()).map_err(|e| {
+            anyhow!(
+                "{} / {}: {}",
+                settings.cert_path.display(),
+                settings.key_path.display(),
+                e
+            )
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    fn load(settings: &TlsSettings, http2: bool) -> Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_slice_iter(&std::fs::read(&settings.cert_path)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                anyhow!(
+                    "Invalid certificate {}: {}",
+                    settings.cert_path.display(),
+                    e
+                )
+            })?;
+        let key = PrivateKeyDer::from_pem_slice(&std::fs::read(&settings.key_path)?)
+            .map_err(|e| anyhow!("Invalid key {}: {}", settings.key_path.display(), e))?;
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        config.alpn_protocols = match http2 {
+            true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            false => vec![b"http/1.1".to_vec()],
+        };
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
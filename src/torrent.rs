@@ -0,0 +1,231 @@
+use crate::db::ArchiveDatabase;
+use anyhow::{Result, bail};
+use dashmap::DashMap;
+use log::{error, info};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often finalized archives are checked for missing torrents
+const TORRENT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Deserialize, Clone, Default)]
+pub struct TorrentSettings {
+    /// Generate `.torrent` files for finalized archives
+    pub enabled: Option<bool>,
+
+    /// Piece size in KiB, a power of two, default 1024
+    pub piece_size_kb: Option<u64>,
+
+    /// Announce urls, the first one is the primary tracker
+    pub trackers: Option<Vec<String>>,
+}
+
+/// Builds v1 torrents for archive files, with the HTTP url as a web seed
+#[derive(Clone)]
+pub struct TorrentMaker {
+    piece_len: u64,
+    trackers: Vec<String>,
+    /// Base url archives are served from, used as the web seed
+    web_seed: Option<String>,
+    /// Magnet links by torrent path and size
+    magnets: Arc<DashMap<PathBuf, (u64, String)>>,
+}
+
+impl TorrentMaker {
+    pub fn new(settings: TorrentSettings, web_seed: Option<String>) -> Result<Self> {
+        let piece_len = settings.piece_size_kb.unwrap_or(1024) * 1024;
+        if !piece_len.is_power_of_two() || piece_len < 16 * 1024 {
+            bail!("Torrent piece_size_kb must be a power of two of at least 16");
+        }
+        Ok(Self {
+            piece_len,
+            trackers: settings.trackers.unwrap_or_default(),
+            web_seed: web_seed.map(|u| u.trim_end_matches('/').to_string()),
+            magnets: Arc::new(DashMap::new()),
+        })
+    }
+
+    pub fn piece_len(&self) -> u64 {
+        self.piece_len
+    }
+
+    /// Sidecar path of the torrent for an archive
+    pub fn path_for(archive: &Path) -> PathBuf {
+        let name = archive
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        archive.with_file_name(format!("{}.torrent", name))
+    }
+
+    /// Bencoded torrent for a file of `length` bytes with concatenated SHA-1 piece hashes,
+    /// `url_path` is where the archive is served relative to the web seed
+    pub fn build(&self, name: &str, url_path: &str, length: u64, pieces: &[u8]) -> Vec<u8> {
+        let mut out = b"d".to_vec();
+        if let Some(first) = self.trackers.first() {
+            bstr(&mut out, "announce");
+            bstr(&mut out, first);
+            bstr(&mut out, "announce-list");
+            out.push(b'l');
+            for t in &self.trackers {
+                out.push(b'l');
+                bstr(&mut out, t);
+                out.push(b'e');
+            }
+            out.push(b'e');
+        }
+        bstr(&mut out, "info");
+        out.push(b'd');
+        bstr(&mut out, "length");
+        out.extend_from_slice(format!("i{}e", length).as_bytes());
+        bstr(&mut out, "name");
+        bstr(&mut out, name);
+        bstr(&mut out, "piece length");
+        out.extend_from_slice(format!("i{}e", self.piece_len).as_bytes());
+        bstr(&mut out, "pieces");
+        out.extend_from_slice(format!("{}:", pieces.len()).as_bytes());
+        out.extend_from_slice(pieces);
+        out.push(b'e');
+        if let Some(base) = &self.web_seed {
+            bstr(&mut out, "url-list");
+            out.push(b'l');
+            bstr(&mut out, &format!("{}/{}", base, url_path));
+            out.push(b'e');
+        }
+        out.push(b'e');
+        out
+    }
+
+    /// Magnet link for an archive, None until its torrent exists
+    pub async fn magnet(&self, archive: &Path, url_path: &str) -> Option<String> {
+        let path = Self::path_for(archive);
+        let size = tokio::fs::metadata(&path).await.ok()?.len();
+        if let Some(m) = self.magnets.get(&path)
+            && m.0 == size
+        {
+            return Some(m.1.clone());
+        }
+        let torrent = tokio::fs::read(&path).await.ok()?;
+        let hash = info_hash(&torrent)?;
+        let name = archive.file_name()?.to_str()?;
+        let mut magnet = url::form_urlencoded::Serializer::new(String::new());
+        magnet.append_pair("dn", name);
+        for t in &self.trackers {
+            magnet.append_pair("tr", t);
+        }
+        if let Some(base) = &self.web_seed {
+            magnet.append_pair("ws", &format!("{}/{}", base, url_path));
+        }
+        let magnet = format!("magnet:?xt=urn:btih:{}&{}", hash, magnet.finish());
+        self.magnets.insert(path, (size, magnet.clone()));
+        Some(magnet)
+    }
+}
+
+/// SHA-1 of every `piece_len` bytes written
+pub struct PieceHasher {
+    piece_len: u64,
+    filled: u64,
+    current: Sha1,
+    pieces: Vec<u8>,
+}
+
+impl PieceHasher {
+    pub fn new(piece_len: u64) -> Self {
+        Self {
+            piece_len,
+            filled: 0,
+            current: Sha1::new(),
+            pieces: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = ((self.piece_len - self.filled) as usize).min(data.len());
+            self.current.update(&data[..n]);
+            self.filled += n as u64;
+            data = &data[n..];
+            if self.filled == self.piece_len {
+                self.pieces
+                    .extend_from_slice(&std::mem::take(&mut self.current).finalize());
+                self.filled = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.filled > 0 {
+            self.pieces.extend_from_slice(&self.current.finalize());
+        }
+        self.pieces
+    }
+}
+
+fn bstr(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(format!("{}:", s.len()).as_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// End offset of the bencoded value starting at `pos`
+fn bvalue_end(buf: &[u8], pos: usize) -> Option<usize> {
+    match *buf.get(pos)? {
+        b'i' => Some(pos + buf[pos..].iter().position(|b| *b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut p = pos + 1;
+            while *buf.get(p)? != b'e' {
+                p = bvalue_end(buf, p)?;
+            }
+            Some(p + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = pos + buf[pos..].iter().position(|b| *b == b':')?;
+            let len: usize = std::str::from_utf8(&buf[pos..colon]).ok()?.parse().ok()?;
+            Some(colon + 1 + len).filter(|e| *e <= buf.len())
+        }
+        _ => None,
+    }
+}
+
+/// Hex SHA-1 of the bencoded info dictionary
+pub fn info_hash(torrent: &[u8]) -> Option<String> {
+    if torrent.first() != Some(&b'd') {
+        return None;
+    }
+    let mut p = 1;
+    while *torrent.get(p)? != b'e' {
+        let key_end = bvalue_end(torrent, p)?;
+        let value_end = bvalue_end(torrent, key_end)?;
+        if &torrent[p..key_end] == b"4:info" {
+            return Some(hex::encode(Sha1::digest(&torrent[key_end..value_end])));
+        }
+        p = value_end;
+    }
+    None
+}
+
+/// Periodically hash finalized archives, writing checksum and torrent sidecars
+pub async fn run_torrents(db: ArchiveDatabase) -> Result<()> {
+    loop {
+        let mut made = 0;
+        match db.list_archives().await {
+            Ok(files) => {
+                for f in files.iter() {
+                    match db.finalize(f).await {
+                        Ok(true) => made += 1,
+                        Ok(false) => {}
+                        Err(e) => error!("Failed to finalize {}: {}", f.path.display(), e),
+                    }
+                }
+            }
+            Err(e) => error!("Failed to list archives: {}", e),
+        }
+        if made > 0 {
+            info!("Generated torrents for {} archives", made);
+        }
+        tokio::time::sleep(TORRENT_INTERVAL).await;
+    }
+}
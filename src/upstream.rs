@@ -0,0 +1,251 @@
+use anyhow::{Result, anyhow, bail};
+use itertools::Itertools;
+use log::warn;
+use nostr_sdk::prelude::{Connection, ConnectionMode, RelayOptions};
+use nostr_sdk::{Client, ClientBuilder, ClientOptions, RelayUrl};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use url::Url;
+
+/// Outbound connection settings for upstream relays, read from the top level of the config
+#[derive(Deserialize, Clone, Default)]
+pub struct UpstreamSettings {
+    /// SOCKS5 proxy for upstream relay connections, eg. socks5://127.0.0.1:9050
+    pub proxy: Option<String>,
+
+    /// Per-relay proxy overrides, "direct" connects without the global proxy
+    pub relay_proxy: Option<HashMap<String, String>>,
+
+    /// Seconds to wait for relays to connect before retrying in the background
+    pub connect_timeout_secs: Option<u64>,
+
+    /// Seconds between reconnect attempts
+    pub reconnect_interval_secs: Option<u64>,
+
+    /// Reconnect to relays that dropped the connection, default true
+    pub relay_reconnect: Option<bool>,
+
+    /// Ping relays to keep connections alive and measure latency, default true
+    pub relay_ping: Option<bool>,
+
+    /// Per-relay read / write flags, relays default to both
+    pub relay_options: Option<HashMap<String, RelayFlags>>,
+
+    /// User-Agent of HTTP requests, default `nostrhole/<version>`
+    pub user_agent: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+pub struct RelayFlags {
+    /// Subscribe to the relay, default true
+    pub read: Option<bool>,
+
+    /// Publish attestations and announcements to the relay, default true
+    pub write: Option<bool>,
+}
+
+/// How clients connect to upstream relays
+#[derive(Clone, Default)]
+pub struct Upstream {
+    proxy: Option<SocketAddr>,
+    overrides: HashMap<RelayUrl, Option<SocketAddr>>,
+    connect_timeout: Duration,
+    reconnect_interval: Option<Duration>,
+    reconnect: bool,
+    ping: bool,
+    flags: HashMap<RelayUrl, RelayFlags>,
+}
+
+impl Upstream {
+    pub fn new(settings: &UpstreamSettings) -> Result<Self> {
+        let proxy = match &settings.proxy {
+            Some(p) => Some(parse_proxy(p)?),
+            None => None,
+        };
+        let mut overrides = HashMap::new();
+        for (relay, p) in settings.relay_proxy.iter().flatten() {
+            let url = RelayUrl::parse(relay)?;
+            let p = if p == "direct" {
+                None
+            } else {
+                Some(parse_proxy(p)?)
+            };
+            overrides.insert(url, p);
+        }
+        let mut flags = HashMap::new();
+        for (relay, f) in settings.relay_options.iter().flatten() {
+            flags.insert(RelayUrl::parse(relay)?, *f);
+        }
+        // proxied handshakes are slower, default to a longer timeout
+        let default_timeout = if proxy.is_some() { 60 } else { 20 };
+        Ok(Self {
+            proxy,
+            overrides,
+            connect_timeout: Duration::from_secs(
+                settings.connect_timeout_secs.unwrap_or(default_timeout),
+            ),
+            reconnect_interval: settings.reconnect_interval_secs.map(Duration::from_secs),
+            reconnect: settings.relay_reconnect.unwrap_or(true),
+            ping: settings.relay_ping.unwrap_or(true),
+            flags,
+        })
+    }
+
+    /// Is the relay subscribed to, and published to
+    fn flags_for(&self, relay: &RelayUrl) -> (bool, bool) {
+        let f = self.flags.get(relay).copied().unwrap_or_default();
+        (f.read.unwrap_or(true), f.write.unwrap_or(true))
+    }
+
+    /// Can anything be published through `relays`
+    pub fn is_writable(&self, relays: &[String]) -> bool {
+        relays
+            .iter()
+            .filter_map(|r| RelayUrl::parse(r).ok())
+            .any(|u| self.flags_for(&u).1)
+    }
+
+    /// Effective options of a relay, for the startup log
+    pub fn describe(&self, relay: &str) -> Result<String> {
+        let url = RelayUrl::parse(relay)?;
+        let (read, write) = self.flags_for(&url);
+        let mut opts = vec![
+            match (read, write) {
+                (true, true) => "read/write".to_string(),
+                (true, false) => "read only".to_string(),
+                (false, _) => "write only".to_string(),
+            },
+            format!("connect timeout {}s", self.connect_timeout.as_secs()),
+        ];
+        match (self.reconnect, self.reconnect_interval) {
+            (false, _) => opts.push("no reconnect".to_string()),
+            (true, Some(i)) => opts.push(format!("reconnect every {}s", i.as_secs())),
+            (true, None) => opts.push("reconnect".to_string()),
+        }
+        if !self.ping {
+            opts.push("no ping".to_string());
+        }
+        if let Some(p) = self.proxy_for(&url) {
+            opts.push(format!("via {}", p));
+        }
+        Ok(opts.iter().join(", "))
+    }
+
+    /// Proxy used for a relay, None for a direct connection
+    pub fn proxy_for(&self, relay: &RelayUrl) -> Option<SocketAddr> {
+        self.overrides.get(relay).copied().unwrap_or(self.proxy)
+    }
+
+    /// Whether .onion relays can be reached
+    pub fn has_proxy(&self) -> bool {
+        self.proxy.is_some()
+    }
+
+    /// Fail when a relay can only be reached through a proxy that isn't configured
+    pub fn validate(&self, relays: &[String]) -> Result<()> {
+        for r in relays {
+            let url = RelayUrl::parse(r).map_err(|e| anyhow!("Invalid relay {}: {}", r, e))?;
+            if url.is_onion() && self.proxy_for(&url).is_none() {
+                bail!(
+                    "Relay {} is a .onion address, set `proxy` to a Tor SOCKS5 proxy (eg. socks5://127.0.0.1:9050) or add it to `relay_proxy`",
+                    r
+                );
+            }
+        }
+        for (url, f) in &self.flags {
+            if !relays
+                .iter()
+                .any(|r| RelayUrl::parse(r).is_ok_and(|r| &r == url))
+            {
+                bail!("relay_options has {} which isn't in relays", url);
+            }
+            if f.read == Some(false) && f.write == Some(false) {
+                bail!(
+                    "Relay {} is neither read nor written, remove it from relays",
+                    url
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Client builder routing connections through the global proxy
+    pub fn client_builder(&self) -> ClientBuilder {
+        let mut opts = ClientOptions::new();
+        if let Some(p) = self.proxy {
+            opts = opts.connection(Connection::new().proxy(p));
+        }
+        Client::builder().opts(opts)
+    }
+
+    /// Client for lookups against `relays`, connected
+    pub async fn client(&self, relays: &[String]) -> Result<Client> {
+        let client = self.client_builder().build();
+        for r in relays {
+            self.add_relay(&client, r).await?;
+        }
+        self.connect(&client).await;
+        Ok(client)
+    }
+
+    /// Options of a relay with its proxy override, reconnect and ping settings
+    fn relay_options(&self, url: &RelayUrl) -> RelayOptions {
+        let mut opts = RelayOptions::new()
+            .reconnect(self.reconnect)
+            .ping(self.ping);
+        if let Some(p) = self.proxy_for(url) {
+            opts = opts.connection_mode(ConnectionMode::proxy(p));
+        }
+        if let Some(i) = self.reconnect_interval {
+            opts = opts.retry_interval(i);
+        }
+        opts
+    }
+
+    /// Add a relay with its options and read / write flags
+    pub async fn add_relay(&self, client: &Client, relay: &str) -> Result<bool> {
+        let url = RelayUrl::parse(relay)?;
+        let (read, write) = self.flags_for(&url);
+        let opts = self.relay_options(&url).read(read).write(write);
+        Ok(client.pool().add_relay(url, opts).await?)
+    }
+
+    /// Add a relay that events are only published to, nothing is subscribed from it
+    pub async fn add_write_relay(&self, client: &Client, relay: &str) -> Result<bool> {
+        let url = RelayUrl::parse(relay)?;
+        let opts = self.relay_options(&url).read(false);
+        Ok(client.pool().add_relay(url, opts).await?)
+    }
+
+    /// Connect all relays, waiting up to the connect timeout
+    ///
+    /// Relays failing to connect keep retrying in the background
+    pub async fn connect(&self, client: &Client) {
+        let res = client.try_connect(self.connect_timeout).await;
+        for (url, e) in res.failed {
+            warn!("Failed to connect to relay {}: {}", url, e);
+            if let Err(e) = client.connect_relay(&url).await {
+                warn!("Failed to connect to relay {}: {}", url, e);
+            }
+        }
+    }
+}
+
+/// Socket address of a socks5:// proxy url
+fn parse_proxy(proxy: &str) -> Result<SocketAddr> {
+    let url = Url::parse(proxy)?;
+    if !matches!(url.scheme(), "socks5" | "socks5h") {
+        bail!("Proxy {} must be a socks5:// url", proxy);
+    }
+    let host = url
+        .host_str()
+        .ok_or(anyhow!("Proxy {} has no host", proxy))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    (host, url.port().unwrap_or(1080))
+        .to_socket_addrs()?
+        .next()
+        .ok_or(anyhow!("Proxy {} did not resolve", proxy))
+}
@@ -0,0 +1,183 @@
+use crate::kinds::{KindEntry, KindSet};
+use crate::upstream::Upstream;
+use anyhow::Result;
+use log::{info, warn};
+use nostr_relay_builder::prelude::{PolicyResult, WritePolicy};
+use nostr_sdk::prelude::BoxedFuture;
+use nostr_sdk::{Client, Event, Filter, Kind, PublicKey};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait before fetching again when the contact list of the root wasn't found
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Deserialize, Clone)]
+pub struct WotSettings {
+    /// Pubkey (npub or hex) the graph starts from
+    pub root: String,
+
+    /// Hops of follows from `root` that may write, 1 is only its follows
+    pub depth: Option<u8>,
+
+    /// Hours between refreshing the graph
+    pub refresh_hours: Option<u64>,
+
+    /// Authors per contact list filter
+    pub authors_per_filter: Option<usize>,
+
+    /// Kinds accepted from any author, eg. 9735 zap receipts
+    pub allow_unknown_kinds: Option<Vec<KindEntry>>,
+}
+
+/// Pubkeys within the configured hops of the root, swapped as a whole on refresh
+#[derive(Clone, Default)]
+pub struct WotSet(Arc<RwLock<Arc<HashSet<PublicKey>>>>);
+
+impl Debug for WotSet {
+    fn fmt(&self, _f: &mut Formatter<'_>) -> std::fmt::Result {
+        Ok(())
+    }
+}
+
+impl WotSet {
+    fn get(&self) -> Arc<HashSet<PublicKey>> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn replace(&self, set: HashSet<PublicKey>) {
+        *self.0.write().unwrap() = Arc::new(set);
+    }
+}
+
+/// Only accept events from pubkeys in the web of trust, and the allowed kinds from anyone
+#[derive(Debug)]
+pub struct WotPolicy {
+    set: WotSet,
+    allow: KindSet,
+}
+
+impl WotPolicy {
+    pub fn new(set: WotSet, settings: &WotSettings) -> Result<Self> {
+        PublicKey::parse(&settings.root)?;
+        Ok(Self {
+            set,
+            allow: KindSet::parse(settings.allow_unknown_kinds.as_deref().unwrap_or_default())?,
+        })
+    }
+}
+
+impl WritePolicy for WotPolicy {
+    fn admit_event<'a>(
+        &'a self,
+        event: &'a Event,
+        _addr: &'a SocketAddr,
+    ) -> BoxedFuture<'a, PolicyResult> {
+        let set = self.set.get();
+        Box::pin(async move {
+            if self.allow.contains(event.kind) || set.contains(&event.pubkey) {
+                PolicyResult::Accept
+            } else if set.is_empty() {
+                PolicyResult::Reject("web of trust not loaded yet".to_string())
+            } else {
+                PolicyResult::Reject("Author not in web of trust".to_string())
+            }
+        })
+    }
+}
+
+/// Newest contact list of each of `authors`, chunks that fail are skipped
+async fn contact_lists(
+    client: &Client,
+    authors: &[PublicKey],
+    per_filter: usize,
+) -> HashMap<PublicKey, Event> {
+    let mut ret: HashMap<PublicKey, Event> = HashMap::new();
+    for (i, chunk) in authors.chunks(per_filter).enumerate() {
+        let filter = Filter::new()
+            .kind(Kind::ContactList)
+            .authors(chunk.iter().copied());
+        match client.fetch_events(filter, FETCH_TIMEOUT).await {
+            Ok(events) => {
+                for e in events {
+                    if e.kind != Kind::ContactList || !chunk.contains(&e.pubkey) {
+                        continue;
+                    }
+                    if ret
+                        .get(&e.pubkey)
+                        .is_none_or(|x| x.created_at < e.created_at)
+                    {
+                        ret.insert(e.pubkey, e);
+                    }
+                }
+            }
+            Err(e) => warn!("Web of trust: contact lists of chunk {} failed: {}", i, e),
+        }
+    }
+    ret
+}
+
+/// Walk the follow graph of `root` up to `depth` hops, partial when fetches fail
+async fn build(
+    client: &Client,
+    root: PublicKey,
+    depth: u8,
+    per_filter: usize,
+) -> HashSet<PublicKey> {
+    let mut set = HashSet::from([root]);
+    let mut frontier = vec![root];
+    for hop in 1..=depth {
+        let lists = contact_lists(client, &frontier, per_filter).await;
+        frontier = lists
+            .values()
+            .flat_map(|e| e.tags.public_keys().copied())
+            .filter(|p| set.insert(*p))
+            .collect();
+        info!(
+            "Web of trust: {} contact lists, {} new pubkeys at hop {}",
+            lists.len(),
+            frontier.len(),
+            hop
+        );
+        if frontier.is_empty() {
+            break;
+        }
+    }
+    set
+}
+
+/// Keep `set` filled with the follow graph of `root`, fetched from the upstream relays
+pub async fn run_wot(
+    upstream: Upstream,
+    relays: Vec<String>,
+    set: WotSet,
+    settings: WotSettings,
+) -> Result<()> {
+    let root = PublicKey::parse(&settings.root)?;
+    let depth = settings.depth.unwrap_or(2).max(1);
+    let interval = Duration::from_secs(settings.refresh_hours.unwrap_or(24) * 60 * 60);
+    let per_filter = settings.authors_per_filter.unwrap_or(500).max(1);
+    let client = upstream.client(&relays).await?;
+    loop {
+        let next = build(&client, root, depth, per_filter).await;
+        let previous = set.get().len();
+        // a failed refresh must not drop a graph that loaded before
+        let wait = if next.len() > 1 {
+            info!("Web of trust: {} pubkeys, {} before", next.len(), previous);
+            set.replace(next);
+            interval
+        } else {
+            if previous == 0 {
+                set.replace(next);
+            }
+            warn!("Web of trust: no contact list found for {}, retrying", root);
+            RETRY_INTERVAL.min(interval)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
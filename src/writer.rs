@@ -0,0 +1,827 @@
+use crate::compression::{CompressionFormat, compress_plain};
+use crate::db::is_published_blocking;
+use crate::durability::{Durability, Fsync};
+use crate::jobs::ArchiveJobs;
+use anyhow::{Result, anyhow, bail};
+use chrono::Utc;
+use log::{error, info, warn};
+use nostr_sdk::{Event, JsonUtil};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+/// Events waiting for the flusher task
+const WRITE_QUEUE: usize = 4096;
+
+/// Most events written by one blocking call
+const MAX_BATCH: usize = 1024;
+
+/// Most undecodable bytes cut off a zstd archive when the writer reopens it
+const MAX_TORN_FRAME: u64 = 64 * 1024 * 1024;
+
+/// Bytes read at once decoding a zstd archive the writer reopens
+const FRAME_READ: usize = 1024 * 1024;
+
+/// Where an event was written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Written {
+    /// Day of the archive, YYYYMMDD
+    pub day: u32,
+    /// Byte offset of its line in the archive
+    pub offset: u64,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct WriterSettings {
+    /// Write buffer of the active archive in KiB, default 256
+    pub buffer_kb: Option<usize>,
+    /// Longest a written event stays in the buffer, default 1000
+    pub flush_interval_ms: Option<u64>,
+}
+
+/// How the archives of every directory are written
+#[derive(Clone)]
+pub struct WriterOptions {
+    /// Format archives of past days are compressed to at rotation
+    pub format: CompressionFormat,
+    /// Where compressions at rotation queue with the other archive jobs
+    pub jobs: ArchiveJobs,
+    /// Write through zstd to `events_YYYYMMDD.jsonl.zst` instead
+    pub write_compressed: bool,
+    pub fsync: Fsync,
+    /// Capacity of the write buffer
+    pub buffer: usize,
+    /// Time between flushes of the write buffer
+    pub flush_interval: Duration,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self::new(
+            &WriterSettings::default(),
+            Default::default(),
+            Fsync::default(),
+        )
+    }
+}
+
+impl WriterOptions {
+    pub fn new(settings: &WriterSettings, format: CompressionFormat, fsync: Fsync) -> Self {
+        Self {
+            format,
+            jobs: ArchiveJobs::new(1),
+            write_compressed: false,
+            fsync,
+            buffer: settings.buffer_kb.unwrap_or(256).max(1) * 1024,
+            flush_interval: Duration::from_millis(
+                settings.flush_interval_ms.unwrap_or(1000).max(1),
+            ),
+        }
+    }
+}
+
+enum Op {
+    Write(Vec<u8>, oneshot::Sender<Result<Written, String>>),
+    Flush(oneshot::Sender<Result<(), String>>),
+}
+
+/// Appends events to `events_YYYYMMDD.jsonl` (or `.jsonl.zst` with `write_compressed`) in
+/// a directory, rotated at midnight UTC
+///
+/// Saves only queue their line, a flusher task owns the file handle and writes the queued
+/// events in batches and in order through a buffer flushed every `flush_interval`; a save
+/// returns once its line was buffered or, with `durability: every_event`, synced to disk
+#[derive(Clone)]
+pub struct ArchiveWriter {
+    queue: mpsc::Sender<Op>,
+}
+
+impl ArchiveWriter {
+    /// Start the flusher task for `dir`
+    pub fn spawn(dir: PathBuf, options: WriterOptions) -> Self {
+        let (queue, rx) = mpsc::channel(WRITE_QUEUE);
+        let flusher = Flusher {
+            dir,
+            options,
+            active: None,
+            unsynced: 0,
+        };
+        tokio::spawn(flusher.run(rx));
+        Self { queue }
+    }
+
+    pub async fn write(&self, event: &Event) -> Result<Written> {
+        let mut line = event.as_json().into_bytes();
+        line.push(b'\n');
+        let (tx, rx) = oneshot::channel();
+        self.queue
+            .send(Op::Write(line, tx))
+            .await
+            .map_err(|_| anyhow!("Archive writer stopped"))?;
+        rx.await
+            .map_err(|_| anyhow!("Archive writer stopped"))?
+            .map_err(|e| anyhow!(e))
+    }
+
+    /// Returns once every event queued before was written and synced
+    pub async fn flush(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.queue
+            .send(Op::Flush(tx))
+            .await
+            .map_err(|_| anyhow!("Archive writer stopped"))?;
+        rx.await
+            .map_err(|_| anyhow!("Archive writer stopped"))?
+            .map_err(|e| anyhow!(e))
+    }
+}
+
+/// Where the lines of the active archive go
+enum Output {
+    Plain(BufWriter<File>),
+    /// A flush writes out the blocks zstd buffered without ending the frame, so the file
+    /// decodes up to the last flush; the frame is finished when the archive is closed
+    Zstd {
+        enc: zstd::Encoder<'static, BufWriter<File>>,
+        /// Lines written since the last flush
+        pending: bool,
+    },
+}
+
+impl Output {
+    fn write_all(&mut self, line: &[u8]) -> std::io::Result<()> {
+        match self {
+            Output::Plain(f) => f.write_all(line),
+            Output::Zstd { enc, pending } => {
+                *pending = true;
+                enc.write_all(line)
+            }
+        }
+    }
+
+    /// Write out the buffer, the zstd frame stays open
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(f) => f.flush(),
+            Output::Zstd { enc, pending } => {
+                enc.flush()?;
+                *pending = false;
+                Ok(())
+            }
+        }
+    }
+
+    /// Are there lines not written to the file yet
+    fn is_pending(&self) -> bool {
+        match self {
+            Output::Plain(f) => !f.buffer().is_empty(),
+            Output::Zstd { pending, .. } => *pending,
+        }
+    }
+
+    /// The file, only valid after a flush
+    fn file(&self) -> &File {
+        match self {
+            Output::Plain(f) => f.get_ref(),
+            Output::Zstd { enc, .. } => enc.get_ref().get_ref(),
+        }
+    }
+
+    /// Write out the buffer and end the zstd frame
+    fn finish(self) -> std::io::Result<File> {
+        let out = match self {
+            Output::Plain(f) => f,
+            Output::Zstd { enc, .. } => enc.finish()?,
+        };
+        out.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+/// The file events are appended to
+struct Active {
+    day: u32,
+    path: PathBuf,
+    out: Output,
+    /// Uncompressed bytes in the archive, the offset of the next line
+    len: u64,
+}
+
+impl Active {
+    /// Write out the buffer and fsync the file
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.out.flush()?;
+        self.out.file().sync_data()
+    }
+
+    fn close(self) -> std::io::Result<()> {
+        self.out.finish()?.sync_all()
+    }
+}
+
+struct Flusher {
+    dir: PathBuf,
+    options: WriterOptions,
+    active: Option<Active>,
+    /// Events written since the active file was last synced
+    unsynced: u64,
+}
+
+impl Flusher {
+    async fn run(mut self, mut queue: mpsc::Receiver<Op>) {
+        let mut ops = Vec::with_capacity(MAX_BATCH);
+        let mut timer = tokio::time::interval(self.options.fsync.interval);
+        let interval = self.options.fsync.mode == Durability::Interval;
+        let mut flush = tokio::time::interval(self.options.flush_interval);
+        loop {
+            tokio::select! {
+                n = queue.recv_many(&mut ops, MAX_BATCH) => {
+                    if n == 0 {
+                        break;
+                    }
+                }
+                _ = flush.tick() => {
+                    if self.active.as_ref().is_none_or(|a| !a.out.is_pending()) {
+                        continue;
+                    }
+                    let Some((this, res)) = self.blocking(|f| f.flush()).await else {
+                        return;
+                    };
+                    self = this;
+                    if let Err(e) = res {
+                        error!("Failed to write archive: {}", e);
+                    }
+                    continue;
+                }
+                _ = timer.tick(), if interval => {
+                    let Some((this, res)) = self.blocking(|f| f.sync()).await else {
+                        return;
+                    };
+                    self = this;
+                    if let Err(e) = res {
+                        error!("Failed to sync archive: {}", e);
+                    }
+                    continue;
+                }
+            }
+            let mut lines = Vec::with_capacity(ops.len());
+            let mut acks = Vec::with_capacity(ops.len());
+            let mut flushes = Vec::new();
+            for op in ops.drain(..) {
+                match op {
+                    Op::Write(line, tx) => {
+                        lines.push(line);
+                        acks.push(tx);
+                    }
+                    Op::Flush(tx) => flushes.push(tx),
+                }
+            }
+            let has_flush = !flushes.is_empty();
+            let Some((this, (written, rotated, synced))) = self
+                .blocking(move |f| {
+                    let (written, rotated) = f.write_batch(&lines);
+                    let synced = if has_flush || f.needs_sync() {
+                        f.sync()
+                    } else {
+                        Ok(())
+                    };
+                    (written, rotated, synced)
+                })
+                .await
+            else {
+                return;
+            };
+            self = this;
+            let synced = synced.map_err(|e| e.to_string());
+            if let Err(e) = &synced {
+                error!("Failed to sync archive: {}", e);
+            }
+            let every_event = self.options.fsync.mode == Durability::EveryEvent;
+            for (tx, w) in acks.into_iter().zip(written) {
+                let w = match (w, &synced) {
+                    (Err(e), _) => Err(e.to_string()),
+                    // written but not durable, the save fails
+                    (Ok(_), Err(e)) if every_event => Err(e.clone()),
+                    (Ok(w), _) => Ok(w),
+                };
+                let _ = tx.send(w);
+            }
+            for tx in flushes {
+                let _ = tx.send(synced.clone());
+            }
+            if let Some(path) = rotated {
+                self.finalize(path);
+            }
+        }
+        if let Some(a) = self.active.take() {
+            match tokio::task::spawn_blocking(move || a.close()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to close archive: {}", e),
+                Err(e) => error!("Failed to close archive: {}", e),
+            }
+        }
+    }
+
+    /// Run `f` on the blocking pool, None when it panicked
+    async fn blocking<T: Send + 'static>(
+        mut self,
+        f: impl FnOnce(&mut Self) -> T + Send + 'static,
+    ) -> Option<(Self, T)> {
+        tokio::task::spawn_blocking(move || {
+            let r = f(&mut self);
+            (self, r)
+        })
+        .await
+        .inspect_err(|e| error!("Archive writer failed: {}", e))
+        .ok()
+    }
+
+    /// Has the configured durability point been passed without a sync
+    fn needs_sync(&self) -> bool {
+        match self.options.fsync.mode {
+            Durability::None => false,
+            Durability::EveryEvent => self.unsynced > 0,
+            Durability::Interval => self
+                .options
+                .fsync
+                .events
+                .is_some_and(|e| self.unsynced >= e),
+        }
+    }
+
+    /// Write out the buffer
+    fn flush(&mut self) -> Result<()> {
+        if let Some(a) = &mut self.active {
+            a.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// fsync the file the last events were written to
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced == 0 {
+            return Ok(());
+        }
+        if let Some(a) = &mut self.active {
+            a.sync()?;
+        }
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Queue the compression of the archive of the previous day, unless it was written
+    /// compressed
+    fn finalize(&self, path: PathBuf) {
+        let format = self.options.format;
+        if format == CompressionFormat::None || path.extension().is_none_or(|e| e != "jsonl") {
+            return;
+        }
+        let jobs = self.options.jobs.clone();
+        tokio::spawn(async move {
+            let p = path.clone();
+            match jobs.run(move || compress_plain(&p, format)).await {
+                Ok(dst) => info!("Compressed {}", dst.display()),
+                Err(e) => error!("Failed to compress {}: {}", path.display(), e),
+            }
+        });
+    }
+
+    /// Append `lines` to today's archive, returns where each was written and the archive
+    /// closed by a rotation
+    fn write_batch(&mut self, lines: &[Vec<u8>]) -> (Vec<Result<Written>>, Option<PathBuf>) {
+        let day = Utc::now()
+            .format("%Y%m%d")
+            .to_string()
+            .parse()
+            .unwrap_or_default();
+        let mut rotated = None;
+        if self.active.as_ref().is_some_and(|a| a.day != day)
+            && let Some(a) = self.active.take()
+        {
+            info!("Closing file {}", a.path.display());
+            let path = a.path.clone();
+            if let Err(e) = a.close() {
+                error!("Failed to sync {}: {}", path.display(), e);
+            }
+            self.unsynced = 0;
+            rotated = Some(path);
+        }
+        let mut written = Vec::with_capacity(lines.len());
+        for line in lines {
+            let res = self.append(day, line);
+            if res.is_err()
+                && let Some(a) = self.active.take()
+            {
+                // reopened for the next event, lines after a failed write are appended
+                // to whatever part of it reached the file
+                let path = a.path.clone();
+                if let Err(e) = a.close() {
+                    error!("Failed to sync {}: {}", path.display(), e);
+                }
+                self.unsynced = 0;
+            }
+            written.push(res);
+        }
+        (written, rotated)
+    }
+
+    fn append(&mut self, day: u32, line: &[u8]) -> Result<Written> {
+        let active = match &mut self.active {
+            Some(a) => a,
+            None => self.active.insert(self.open(day)?),
+        };
+        active.out.write_all(line)?;
+        let offset = active.len;
+        active.len += line.len() as u64;
+        self.unsynced += 1;
+        Ok(Written { day, offset })
+    }
+
+    /// Open the archive of `day`, a day started in the other format is finished in it
+    fn open(&self, day: u32) -> Result<Active> {
+        let plain = self.dir.join(format!("events_{}.jsonl", day));
+        let zst = plain.with_extension("jsonl.zst");
+        let compressed = if self.options.write_compressed {
+            !plain.exists()
+        } else {
+            zst.exists() && !plain.exists()
+        };
+        let path = if compressed { zst } else { plain };
+        info!("Opening file {}", path.display());
+        // recovery replaces the file, it is opened after
+        let len = match compressed && path.exists() {
+            true => recover_frames(&path)?,
+            false => 0,
+        };
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let (len, out) = if compressed {
+            let out = BufWriter::with_capacity(self.options.buffer, file);
+            (
+                len,
+                Output::Zstd {
+                    enc: zstd::Encoder::new(out, 0)?,
+                    pending: false,
+                },
+            )
+        } else {
+            let len = file.metadata()?.len();
+            (
+                len,
+                Output::Plain(BufWriter::with_capacity(self.options.buffer, file)),
+            )
+        };
+        Ok(Active {
+            day,
+            path,
+            out,
+            len,
+        })
+    }
+}
+
+/// Finish the zstd frame [ArchiveWriter] was writing to an archive when the process
+/// stopped: its complete lines are compressed again as a finished frame and the rest is
+/// cut off
+///
+/// Returns how many bytes the archive decompresses to. Fails when over [MAX_TORN_FRAME]
+/// bytes don't decode, that isn't a frame cut short but a damaged archive
+fn recover_frames(path: &Path) -> Result<u64> {
+    let total = std::fs::metadata(path)?.len();
+    let mut input = File::open(path)?;
+    let mut dec = zstd::stream::raw::Decoder::new()?;
+    let (mut chunk, mut out) = (vec![0u8; FRAME_READ], vec![0u8; FRAME_READ]);
+    // end and decompressed size of the complete frames
+    let (mut complete, mut size) = (0u64, 0u64);
+    // decompressed bytes of the frame being read, and up to its last complete line
+    let (mut frame, mut lines) = (0u64, 0u64);
+    let mut read = 0u64;
+    // end of the bytes that decode
+    let decoded = 'read: loop {
+        let n = input.read(&mut chunk)?;
+        if n == 0 {
+            break total;
+        }
+        let mut src = InBuffer::around(&chunk[..n]);
+        loop {
+            let mut dst = OutBuffer::around(&mut out[..]);
+            let hint = dec.run(&mut src, &mut dst);
+            let produced = dst.pos();
+            let Ok(hint) = hint else {
+                break 'read read + src.pos() as u64;
+            };
+            if let Some(i) = out[..produced].iter().rposition(|b| *b == b'\n') {
+                lines = frame + i as u64 + 1;
+            }
+            frame += produced as u64;
+            if hint == 0 {
+                complete = read + src.pos() as u64;
+                size += frame;
+                (frame, lines) = (0, 0);
+            }
+            if src.pos() == n && produced < out.len() {
+                break;
+            }
+        }
+        read += n as u64;
+    };
+    if complete == total {
+        return Ok(size);
+    }
+    if total - decoded > MAX_TORN_FRAME {
+        bail!(
+            "{} doesn't decode after {} bytes, move it aside to write today's archive",
+            path.display(),
+            decoded
+        );
+    }
+    warn!(
+        "{} was cut short, keeping {} bytes of its last frame",
+        path.display(),
+        lines
+    );
+    if lines == 0 {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(complete)?;
+        file.sync_all()?;
+        return Ok(size);
+    }
+    // written aside and renamed over the archive, a crash meanwhile leaves it as it was
+    let tmp = path.with_extension("zst.tmp");
+    let mut out = File::create(&tmp)?;
+    let mut input = File::open(path)?;
+    std::io::copy(&mut (&mut input).take(complete), &mut out)?;
+    let mut enc = zstd::Encoder::new(out, 0)?;
+    std::io::copy(
+        &mut zstd::Decoder::new(input)?.single_frame().take(lines),
+        &mut enc,
+    )?;
+    enc.finish()?.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(size + lines)
+}
+
+/// Finish the frame the writer left open in the newest zstd archive of `dir`, before
+/// anything reads it
+pub fn recover_compressed(dir: &Path) -> Result<()> {
+    let mut newest = None;
+    for e in std::fs::read_dir(dir)? {
+        let path = e?.path();
+        let Some(day) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("events_"))
+            .and_then(|n| n.strip_suffix(".jsonl.zst"))
+            .filter(|d| d.len() == 8 && d.bytes().all(|b| b.is_ascii_digit()))
+            .map(|d| d.to_string())
+        else {
+            continue;
+        };
+        if newest.as_ref().is_none_or(|(d, _)| *d < day) {
+            newest = Some((day, path));
+        }
+    }
+    let Some((_, path)) = newest else {
+        return Ok(());
+    };
+    // compressed at rotation, or already checksummed and must not change
+    if path.with_extension("").exists() || is_published_blocking(&path) {
+        return Ok(());
+    }
+    recover_frames(&path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::open_archive;
+    use crate::test_util::{TempDir, event, lines};
+    use std::io::{BufRead, BufReader, Seek, SeekFrom};
+
+    fn today(dir: &TempDir) -> PathBuf {
+        dir.path()
+            .join(format!("events_{}.jsonl", Utc::now().format("%Y%m%d")))
+    }
+
+    #[tokio::test]
+    async fn timed_flush_survives_a_crash() {
+        let dir = TempDir::new();
+        let options = WriterOptions {
+            buffer: 1024 * 1024,
+            flush_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let writer = ArchiveWriter::spawn(dir.path().to_path_buf(), options);
+        let events: Vec<Event> = (0..100).map(|i| event(1, &i.to_string())).collect();
+        for e in &events {
+            writer.write(e).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // read while the writer still holds its buffer, what a killed process leaves behind
+        let written = lines(&today(&dir));
+        assert_eq!(written.len(), events.len());
+        for (l, e) in written.iter().zip(&events) {
+            assert_eq!(l, &e.as_json());
+        }
+        assert!(std::fs::read(today(&dir)).unwrap().ends_with(b"\n"));
+    }
+
+    #[tokio::test]
+    async fn offsets_point_at_the_line() {
+        let dir = TempDir::new();
+        let mut events = Vec::new();
+        // a second writer appends to the archive of the first
+        for _ in 0..2 {
+            let writer = ArchiveWriter::spawn(dir.path().to_path_buf(), WriterOptions::default());
+            for i in 0..3 {
+                let e = event(1, &"x".repeat(i * 10));
+                events.push((writer.write(&e).await.unwrap(), e));
+            }
+            writer.flush().await.unwrap();
+        }
+
+        let mut f = BufReader::new(File::open(today(&dir)).unwrap());
+        for (w, e) in events {
+            f.seek(SeekFrom::Start(w.offset)).unwrap();
+            let mut line = String::new();
+            f.read_line(&mut line).unwrap();
+            assert_eq!(line.trim_end(), e.as_json());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn backed_up_queue_loses_no_acknowledged_event() {
+        let dir = TempDir::new();
+        // a buffer smaller than a line writes every event to the file on its own
+        let options = WriterOptions {
+            buffer: 64,
+            ..Default::default()
+        };
+        let writer = ArchiveWriter::spawn(dir.path().to_path_buf(), options);
+        let mut tasks = tokio::task::JoinSet::new();
+        // more saves than the queue holds, the rest wait for the flusher to catch up
+        for i in 0..WRITE_QUEUE * 3 {
+            let writer = writer.clone();
+            tasks.spawn(async move {
+                let e = event(1, &i.to_string());
+                (writer.write(&e).await, e)
+            });
+        }
+        let mut acked = Vec::new();
+        while let Some(r) = tasks.join_next().await {
+            let (w, e) = r.unwrap();
+            acked.push((w.unwrap(), e));
+        }
+        writer.flush().await.unwrap();
+
+        let data = std::fs::read(today(&dir)).unwrap();
+        assert_eq!(lines(&today(&dir)).len(), acked.len());
+        for (w, e) in acked {
+            let line = data[w.offset as usize..]
+                .split(|b| *b == b'\n')
+                .next()
+                .unwrap();
+            assert_eq!(line, e.as_json().as_bytes());
+        }
+    }
+
+    fn compressed() -> WriterOptions {
+        WriterOptions {
+            write_compressed: true,
+            flush_interval: Duration::from_millis(50),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_archive_decodes_after_a_timed_flush() {
+        let dir = TempDir::new();
+        let writer = ArchiveWriter::spawn(dir.path().to_path_buf(), compressed());
+        let events: Vec<Event> = (0..100).map(|i| event(1, &i.to_string())).collect();
+        for e in &events {
+            writer.write(e).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // the frame is still open, readers stop at the last flushed block
+        assert!(!today(&dir).exists());
+        let path = today(&dir).with_extension("jsonl.zst");
+        let written: Vec<String> = open_archive(&path)
+            .unwrap()
+            .lines()
+            .map(|l| l.unwrap())
+            .collect();
+        assert_eq!(written.len(), events.len());
+        for (l, e) in written.iter().zip(&events) {
+            assert_eq!(*l, e.as_json());
+        }
+
+        // flushes don't end the frame, closing the archive does
+        drop(writer);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(
+            zstd::zstd_safe::find_frame_compressed_size(&data),
+            Ok(data.len())
+        );
+        assert_eq!(
+            zstd::decode_all(data.as_slice())
+                .unwrap()
+                .split(|b| *b == b'\n')
+                .filter(|l| !l.is_empty())
+                .count(),
+            events.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn open_frame_is_finished_when_reopened() {
+        let (live, dir) = (TempDir::new(), TempDir::new());
+        let path = today(&dir).with_extension("jsonl.zst");
+        let mut events = Vec::new();
+        let writer = ArchiveWriter::spawn(live.path().to_path_buf(), compressed());
+        for i in 0..3 {
+            let e = event(1, &"x".repeat(i * 10));
+            events.push((writer.write(&e).await.unwrap(), e));
+        }
+        writer.flush().await.unwrap();
+        let flushed = std::fs::metadata(today(&live).with_extension("jsonl.zst"))
+            .unwrap()
+            .len() as usize;
+        writer.write(&event(1, "lost")).await.unwrap();
+        writer.flush().await.unwrap();
+        // what a crash leaves behind: flushed blocks of an unfinished frame, the last torn
+        let mut data = std::fs::read(today(&live).with_extension("jsonl.zst")).unwrap();
+        assert!(data.len() > flushed + 1);
+        data.truncate(data.len() - 1);
+        std::fs::write(&path, &data).unwrap();
+
+        let writer = ArchiveWriter::spawn(dir.path().to_path_buf(), compressed());
+        for i in 0..3 {
+            let e = event(1, &i.to_string());
+            events.push((writer.write(&e).await.unwrap(), e));
+        }
+        writer.flush().await.unwrap();
+        drop(writer);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let data = std::fs::read(&path).unwrap();
+        let text = zstd::decode_all(data.as_slice()).unwrap();
+        assert_eq!(
+            text.split(|b| *b == b'\n')
+                .filter(|l| !l.is_empty())
+                .count(),
+            6
+        );
+        for (w, e) in events {
+            let line = text[w.offset as usize..]
+                .split(|b| *b == b'\n')
+                .next()
+                .unwrap();
+            assert_eq!(line, e.as_json().as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn torn_frame_is_cut_when_reopened() {
+        let dir = TempDir::new();
+        let path = today(&dir).with_extension("jsonl.zst");
+        let mut events = Vec::new();
+        for round in 0..2 {
+            let writer = ArchiveWriter::spawn(dir.path().to_path_buf(), compressed());
+            for i in 0..3 {
+                let e = event(1, &"x".repeat(i * 10));
+                events.push((writer.write(&e).await.unwrap(), e));
+            }
+            writer.flush().await.unwrap();
+            drop(writer);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if round == 0 {
+                // a frame cut short by a crash
+                let frame = zstd::encode_all("lost\n".repeat(100).as_bytes(), 0).unwrap();
+                let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+                f.write_all(&frame[..frame.len() / 2]).unwrap();
+            }
+        }
+
+        let data = std::fs::read(&path).unwrap();
+        let text = zstd::decode_all(data.as_slice()).unwrap();
+        assert_eq!(
+            text.split(|b| *b == b'\n')
+                .filter(|l| !l.is_empty())
+                .count(),
+            6
+        );
+        for (w, e) in events {
+            let line = text[w.offset as usize..]
+                .split(|b| *b == b'\n')
+                .next()
+                .unwrap();
+            assert_eq!(line, e.as_json().as_bytes());
+        }
+    }
+}